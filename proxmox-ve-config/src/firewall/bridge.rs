@@ -3,10 +3,12 @@ use std::io;
 use anyhow::Error;
 use serde::Deserialize;
 
+use crate::firewall::types::address::Fqdn;
 use crate::firewall::types::log::LogLevel;
 use crate::firewall::types::rule::{Direction, Verdict};
 
-use super::common::ParserConfig;
+use super::common::{ParseErrors, ParserConfig};
+use super::diff::ConfigDiff;
 use super::types::Rule;
 
 pub struct Config {
@@ -19,18 +21,41 @@ pub const BRIDGE_ENABLED_DEFAULT: bool = false;
 pub const BRIDGE_POLICY_FORWARD: Verdict = Verdict::Accept;
 
 impl Config {
-    pub fn parse<R: io::BufRead>(input: R) -> Result<Self, Error> {
-        let parser_config = ParserConfig {
+    fn parser_config() -> ParserConfig {
+        ParserConfig {
             guest_iface_names: false,
             ipset_scope: None,
             allowed_directions: vec![Direction::Forward],
-        };
+            allow_aliases: true,
+            allow_groups: true,
+            allow_smac_vlan: true,
+        }
+    }
+
+    pub fn parse<R: io::BufRead>(input: R) -> Result<Self, Error> {
+        Ok(Self {
+            config: super::common::Config::parse(input, &Self::parser_config())?,
+        })
+    }
 
+    /// Parses the config like [`Config::parse`], but returns all errors instead of only the
+    /// first one.
+    pub fn parse_collect_errors<R: io::BufRead>(input: R) -> Result<Self, ParseErrors> {
         Ok(Self {
-            config: super::common::Config::parse(input, &parser_config)?,
+            config: super::common::Config::parse_collect_errors(input, &Self::parser_config())?,
         })
     }
 
+    /// Writes the config in the `<bridge>.fw` format.
+    pub fn write<W: io::Write>(&self, output: W) -> Result<(), Error> {
+        self.config.write(output)
+    }
+
+    /// Computes the changes from this config to `new`.
+    pub fn diff(&self, new: &Self) -> ConfigDiff {
+        self.config.diff(&new.config)
+    }
+
     pub fn enabled(&self) -> bool {
         self.config.options.enable.unwrap_or(BRIDGE_ENABLED_DEFAULT)
     }
@@ -39,6 +64,11 @@ impl Config {
         self.config.rules.iter()
     }
 
+    /// Returns the domain names in the aliases of the bridge.
+    pub fn fqdns(&self) -> Vec<&Fqdn> {
+        self.config.fqdns()
+    }
+
     pub fn log_level_forward(&self) -> LogLevel {
         self.config.options.log_level_forward.unwrap_or_default()
     }
@@ -51,8 +81,7 @@ impl Config {
     }
 }
 
-#[derive(Debug, Default, Deserialize)]
-#[cfg_attr(test, derive(Eq, PartialEq))]
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
 pub struct Options {
     #[serde(default, deserialize_with = "proxmox_serde::perl::deserialize_bool")]
     enable: Option<bool>,
@@ -1,10 +1,13 @@
 use std::collections::BTreeMap;
 use std::io;
 
-use anyhow::Error;
+use anyhow::{bail, Error};
 use serde::Deserialize;
 
-use crate::firewall::common::ParserConfig;
+use crate::firewall::common::{ParseErrors, ParserConfig, Section};
+use crate::firewall::diff::ConfigDiff;
+use crate::firewall::types::address::Fqdn;
+use crate::firewall::types::alias::FqdnAlias;
 use crate::firewall::types::ipset::{Ipset, IpsetScope};
 use crate::firewall::types::log::LogRateLimit;
 use crate::firewall::types::rule::{Direction, Verdict};
@@ -29,18 +32,41 @@ pub const CLUSTER_POLICY_OUT_DEFAULT: Verdict = Verdict::Accept;
 pub const CLUSTER_POLICY_FORWARD_DEFAULT: Verdict = Verdict::Accept;
 
 impl Config {
-    pub fn parse<R: io::BufRead>(input: R) -> Result<Self, Error> {
-        let parser_config = ParserConfig {
+    fn parser_config() -> ParserConfig {
+        ParserConfig {
             guest_iface_names: false,
             ipset_scope: Some(IpsetScope::Datacenter),
             allowed_directions: vec![Direction::In, Direction::Out, Direction::Forward],
-        };
+            allow_aliases: true,
+            allow_groups: true,
+            allow_smac_vlan: false,
+        }
+    }
+
+    pub fn parse<R: io::BufRead>(input: R) -> Result<Self, Error> {
+        Ok(Self {
+            config: super::common::Config::parse(input, &Self::parser_config())?,
+        })
+    }
 
+    /// Parses the config like [`Config::parse`], but returns all errors instead of only the
+    /// first one.
+    pub fn parse_collect_errors<R: io::BufRead>(input: R) -> Result<Self, ParseErrors> {
         Ok(Self {
-            config: super::common::Config::parse(input, &parser_config)?,
+            config: super::common::Config::parse_collect_errors(input, &Self::parser_config())?,
         })
     }
 
+    /// Writes the config in the `cluster.fw` format.
+    pub fn write<W: io::Write>(&self, output: W) -> Result<(), Error> {
+        self.config.write(output)
+    }
+
+    /// Computes the changes from this config to `new`.
+    pub fn diff(&self, new: &Self) -> ConfigDiff {
+        self.config.diff(&new.config)
+    }
+
     pub fn rules(&self) -> &Vec<Rule> {
         &self.config.rules
     }
@@ -57,10 +83,54 @@ impl Config {
         self.config.ipsets.get(name)
     }
 
+    /// Adds an ipset to the config, replacing and returning an existing ipset with the same name.
+    ///
+    /// This allows updating ipsets from external address lists, see [`Ipset::import`]. The comment
+    /// lines of a replaced ipset are removed as well.
+    pub fn insert_ipset(&mut self, ipset: Ipset) -> Result<Option<Ipset>, Error> {
+        if ipset.name().scope() != IpsetScope::Datacenter {
+            bail!(
+                "ipset {} does not belong to the cluster config",
+                ipset.name()
+            );
+        }
+
+        let name = ipset.name().name().to_string();
+
+        let replaced = self.config.ipsets.insert(name.clone(), ipset);
+
+        if replaced.is_some() {
+            self.config.remove_comment_lines(&Section::Ipset(name));
+        }
+
+        Ok(replaced)
+    }
+
+    /// Sets an option, replacing its previous value. The value is checked like an option in a
+    /// parsed config.
+    pub fn set_option(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        self.config.set_option(key, value)
+    }
+
+    /// Removes an option, returning its previous value.
+    pub fn remove_option(&mut self, key: &str) -> Result<Option<String>, Error> {
+        self.config.remove_option(key)
+    }
+
     pub fn alias(&self, name: &str) -> Option<&Alias> {
         self.config.alias(name)
     }
 
+    pub fn fqdn_alias(&self, name: &str) -> Option<&FqdnAlias> {
+        self.config.fqdn_alias(name)
+    }
+
+    /// Returns the domain names in the aliases and ipsets, which have to be resolved with a
+    /// [`FqdnCache`](crate::firewall::fqdn::FqdnCache).
+    pub fn fqdns(&self) -> Vec<&Fqdn> {
+        self.config.fqdns()
+    }
+
     pub fn is_enabled(&self) -> bool {
         self.config
             .options
@@ -119,8 +189,7 @@ impl Config {
     }
 }
 
-#[derive(Debug, Default, Deserialize)]
-#[cfg_attr(test, derive(Eq, PartialEq))]
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
 pub struct Options {
     #[serde(default, deserialize_with = "proxmox_serde::perl::deserialize_bool")]
     enable: Option<bool>,
@@ -152,6 +221,8 @@ mod tests {
         },
     };
 
+    use crate::firewall::ipset_format::IpsetFormat;
+
     use super::*;
 
     #[test]
@@ -293,6 +364,7 @@ IN BGP(REJECT) -log crit -source 1.2.3.4
                 kind: Kind::Group(RuleGroup {
                     group: "tgr".to_string(),
                     iface: Some("eth0".to_string()),
+                    params: Default::default(),
                 }),
             },
         );
@@ -304,7 +376,7 @@ IN BGP(REJECT) -log crit -source 1.2.3.4
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::In,
-                    verdict: Verdict::Accept,
+                    action: Verdict::Accept.into(),
                     proto: Some(Protocol::Udp(Udp::new(Ports::from_u16(22, 33)))),
                     log: Some(LogLevel::Warning),
                     ..Default::default()
@@ -325,7 +397,7 @@ IN BGP(REJECT) -log crit -source 1.2.3.4
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::Out,
-                    verdict: Verdict::Accept,
+                    action: Verdict::Accept.into(),
                     ip: Some(IpMatch {
                         src: Some(IpAddrMatch::Ip(IpList::from(
                             Cidr::new_v6(
@@ -358,7 +430,7 @@ IN BGP(REJECT) -log crit -source 1.2.3.4
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::Out,
-                    verdict: Verdict::Accept,
+                    action: Verdict::Accept.into(),
                     proto: Some(Protocol::Tcp(Tcp::new(Ports::from_u16(33, None)))),
                     log: Some(LogLevel::Nolog),
                     ..Default::default()
@@ -373,7 +445,7 @@ IN BGP(REJECT) -log crit -source 1.2.3.4
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::In,
-                    verdict: Verdict::Reject,
+                    action: Verdict::Reject.into(),
                     log: Some(LogLevel::Critical),
                     fw_macro: Some("BGP".to_string()),
                     ip: Some(IpMatch {
@@ -387,6 +459,11 @@ IN BGP(REJECT) -log crit -source 1.2.3.4
             },
         );
 
+        let mut written = Vec::new();
+        config.write(&mut written).expect("can write config");
+        let reparsed = Config::parse(written.as_slice()).expect("written config is valid");
+        assert_eq!(config.config, reparsed.config);
+
         let empty_config = Config::parse("".as_bytes()).expect("empty config is invalid");
 
         assert_eq!(empty_config.config.options, Options::default());
@@ -457,5 +534,204 @@ IN ACCEPT -source dc/alias1 -p tcp -sport 1111 -log nolog # comment # on testgro
             entry.rules()[0].comment.as_deref(),
             Some("comment # on testgroup #1")
         );
+
+        let mut written = Vec::new();
+        config.write(&mut written).expect("can write config");
+        let reparsed = Config::parse(written.as_slice()).expect("written config is valid");
+        assert_eq!(config.config, reparsed.config);
+    }
+
+    #[test]
+    fn test_write_config() {
+        const CONFIG: &str = r#"
+[OPTIONS]
+enable: on
+policy_in: DROP
+
+[RULES]
+|GROUP tgr -i eth0 # acomm
+IN ACCEPT -p udp -dport 33 -sport 22 -log warning
+OUT SSH(DROP) --dest 10.0.0.0/8,192.168.0.0/24 --source +dc/a-set
+
+[ALIASES]
+anAlias 7.7.0.0/16 # much
+updates repo.example.com
+amirror Mirror.example.com. # mirror
+
+[group tgr] # comment for tgr
+IN ACCEPT -p icmp -icmp-type port-unreachable
+IN ACCEPT -p tcp -dport 22,1000:2000
+
+[IPSET a-set]
+!fqdn:api.example.com
+!5.5.5.0/24
+dc/analias # a comment
+"#;
+
+        const EXPECTED: &str = r#"[OPTIONS]
+
+enable: on
+policy_in: DROP
+
+[RULES]
+
+|GROUP tgr -i eth0 # acomm
+IN ACCEPT -p udp -dport 33 -sport 22 -log warn
+OUT SSH(DROP) -source +dc/a-set -dest 10.0.0.0/8,192.168.0.0/24
+
+[ALIASES]
+
+amirror mirror.example.com # mirror
+analias 7.7.0.0/16 # much
+updates repo.example.com
+
+[group tgr] # comment for tgr
+
+IN ACCEPT -p icmp -icmp-type port-unreachable
+IN ACCEPT -p tcp -dport 22,1000:2000
+
+[IPSET a-set]
+
+!fqdn:api.example.com
+!5.5.5.0/24
+dc/analias # a comment
+
+"#;
+
+        let config = Config::parse(CONFIG.as_bytes()).expect("valid config");
+
+        let mut written = Vec::new();
+        config.write(&mut written).expect("can write config");
+
+        assert_eq!(String::from_utf8(written).unwrap(), EXPECTED);
+
+        let reparsed = Config::parse(EXPECTED.as_bytes()).expect("written config is valid");
+        assert_eq!(config.config, reparsed.config);
+    }
+
+    #[test]
+    fn test_write_comment_lines() {
+        const CONFIG: &str = r#"# managed by hand
+[OPTIONS]
+# enable later
+enable: 0
+
+[RULES]
+# ssh
+IN SSH(ACCEPT)
+#IN ACCEPT -p tcp -dport 8006
+OUT ACCEPT -p udp
+
+# end of rules
+
+[IPSET blocked]
+# scanners
+192.0.2.0/24
+fqdn:scanner.example.com
+# known bad
+198.51.100.0/24
+[group web]
+# no rules yet
+"#;
+
+        const EXPECTED: &str = r#"# managed by hand
+[OPTIONS]
+
+# enable later
+enable: 0
+
+[RULES]
+
+# ssh
+IN SSH(ACCEPT)
+#IN ACCEPT -p tcp -dport 8006
+OUT ACCEPT -p udp
+# end of rules
+
+[IPSET blocked]
+
+# scanners
+192.0.2.0/24
+fqdn:scanner.example.com
+# known bad
+198.51.100.0/24
+
+[group web]
+
+# no rules yet
+
+"#;
+
+        let config = Config::parse(CONFIG.as_bytes()).expect("valid config");
+
+        let mut written = Vec::new();
+        config.write(&mut written).expect("can write config");
+
+        assert_eq!(String::from_utf8(written).unwrap(), EXPECTED);
+
+        let reparsed = Config::parse(EXPECTED.as_bytes()).expect("written config is valid");
+        assert_eq!(config.config, reparsed.config);
+    }
+
+    #[test]
+    fn test_set_option() {
+        let mut config = Config::parse("[OPTIONS]\n\nenable: 1\npolicy_in: DROP\n".as_bytes())
+            .expect("valid config");
+
+        config
+            .set_option("policy_in", "ACCEPT")
+            .expect("valid option");
+        config.set_option("ebtables", "0").expect("valid option");
+        config
+            .set_option("policy_out", "FOO")
+            .expect_err("invalid option");
+
+        assert_eq!(config.default_policy(Direction::In), Verdict::Accept);
+        assert!(!config.ebtables());
+
+        assert_eq!(
+            config
+                .remove_option("enable")
+                .expect("option can be removed"),
+            Some("1".to_string())
+        );
+        assert!(!config.is_enabled());
+
+        let mut written = Vec::new();
+        config.write(&mut written).expect("can write config");
+
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "[OPTIONS]\n\npolicy_in: ACCEPT\nebtables: 0\n\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_ipset() {
+        let mut config = Config::parse("[IPSET drop]\n# old entries\n192.0.2.1\n".as_bytes())
+            .expect("valid config");
+
+        let mut ipset = Ipset::from_parts(IpsetScope::Datacenter, "drop");
+        ipset
+            .import(
+                IpsetFormat::Spamhaus,
+                "; Spamhaus DROP List\n198.51.100.0/24 ; SBL000001\n",
+            )
+            .expect("valid spamhaus list");
+
+        let replaced = config.insert_ipset(ipset).expect("ipset can be inserted");
+        assert_eq!(replaced.map(|ipset| ipset.len()), Some(1));
+
+        let mut written = Vec::new();
+        config.write(&mut written).expect("can write config");
+
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "[IPSET drop]\n\n198.51.100.0/24 # SBL000001\n\n"
+        );
+
+        config
+            .insert_ipset(Ipset::from_parts(IpsetScope::Guest, "drop"))
+            .expect_err("guest ipsets cannot be inserted");
     }
 }
@@ -1,15 +1,24 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io;
 
 use anyhow::{bail, format_err, Error};
 use serde::de::IntoDeserializer;
 
-use crate::firewall::parse::{parse_named_section_tail, split_key_value, SomeString};
-use crate::firewall::types::ipset::{IpsetName, IpsetScope};
-use crate::firewall::types::rule::{Direction, Kind};
+use proxmox_network_types::ip_address::{Cidr, Family};
+
+use crate::firewall::diff::{diff_configs, ConfigDiff};
+use crate::firewall::parse::{match_name, parse_named_section_tail, split_key_value, SomeString};
+use crate::firewall::types::address::Fqdn;
+use crate::firewall::types::alias::{AliasScope, FqdnAlias, RuleAliasName};
+use crate::firewall::types::ipset::{
+    IpsetAddress, IpsetFqdnEntry, IpsetName, IpsetScope, RuleIpsetName,
+};
+use crate::firewall::types::rule::{Direction, Kind, RuleGroup};
+use crate::firewall::types::rule_match::{check_families, parse_action, IpAddrMatch};
 use crate::firewall::types::{Alias, Group, Ipset, Rule};
 
 #[derive(Debug, Default)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct Config<O>
 where
     O: Default + std::fmt::Debug + serde::de::DeserializeOwned,
@@ -17,12 +26,46 @@ where
     pub(crate) options: O,
     pub(crate) rules: Vec<Rule>,
     pub(crate) aliases: BTreeMap<String, Alias>,
+    pub(crate) fqdn_aliases: BTreeMap<String, FqdnAlias>,
     pub(crate) ipsets: BTreeMap<String, Ipset>,
     pub(crate) groups: BTreeMap<String, Group>,
+
+    /// The options as they were written in the config, in their original order and spelling.
+    pub(crate) raw_options: Vec<(String, String)>,
+    /// The order in which the sections appeared in the parsed config.
+    pub(crate) sections: Vec<Section>,
+    /// The lines of the parsed config that only contain a comment, in their original order.
+    pub(crate) comment_lines: Vec<CommentLine>,
+}
+
+/// Identifies a section of a firewall config file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Section {
+    Options,
+    Aliases,
+    Rules,
+    Ipset(String),
+    Group(String),
+}
+
+/// A line of a firewall config file that only contains a comment.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct CommentLine {
+    /// The section containing the comment, `None` for comments before the first section.
+    section: Option<Section>,
+    /// The number of entries of the section preceding the comment.
+    ///
+    /// Aliases are written ordered by their name, so comments in the aliases section only keep
+    /// their position relative to the number of preceding aliases.
+    position: usize,
+    /// The comment, including the leading `#`.
+    text: String,
 }
 
 enum Sec {
     None,
+    /// Contents of a section with an invalid header, which are skipped.
+    Invalid,
     Options,
     Aliases,
     Rules,
@@ -36,8 +79,253 @@ pub struct ParserConfig {
     pub guest_iface_names: bool,
     pub ipset_scope: Option<IpsetScope>,
     pub allowed_directions: Vec<Direction>,
+    pub allow_aliases: bool,
+    pub allow_groups: bool,
+    /// Rules can match on the source MAC address and the VLAN ID of a packet.
+    pub allow_smac_vlan: bool,
+}
+
+impl ParserConfig {
+    /// Checks whether a rule is allowed in the config that is being parsed.
+    fn check_rule(&self, rule: &Rule) -> Result<(), Error> {
+        if self.guest_iface_names {
+            if let Some(iface) = rule.iface() {
+                let _ = iface
+                    .strip_prefix("net")
+                    .ok_or_else(|| {
+                        format_err!("interface name must be of the form \"net<number>\"")
+                    })?
+                    .parse::<u16>()
+                    .map_err(|_| {
+                        format_err!("interface name must be of the form \"net<number>\"")
+                    })?;
+            }
+        }
+
+        if let Kind::Match(rule) = rule.kind() {
+            if !self.allowed_directions.contains(&rule.dir) {
+                bail!(
+                    "found not allowed direction in firewall config: {0}",
+                    rule.dir
+                );
+            }
+
+            if !self.allow_smac_vlan && (rule.smac.is_some() || rule.vlan.is_some()) {
+                bail!("smac and vlan can only be used in guest and bridge firewall configs");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An error in a line of a firewall config file.
+#[derive(Debug)]
+pub struct ParseError {
+    file: Option<String>,
+    line: usize,
+    column: usize,
+    section: Option<String>,
+    token: String,
+    error: Error,
+}
+
+impl ParseError {
+    pub(crate) fn new(
+        line: usize,
+        column: usize,
+        section: Option<String>,
+        token: &str,
+        error: Error,
+    ) -> Self {
+        Self {
+            file: None,
+            line,
+            column,
+            section,
+            token: token.to_string(),
+            error,
+        }
+    }
+
+    /// Sets the name of the file that contained the error, which is then included in the
+    /// error message.
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// The 1-based line number, 0 if the error is not tied to a single line.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The 1-based column of the first character of [`Self::token`].
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// The section containing the line, e.g. `RULES` or `IPSET name`.
+    pub fn section(&self) -> Option<&str> {
+        self.section.as_deref()
+    }
+
+    /// The part of the line that caused the error, or the whole line if it cannot be narrowed
+    /// down any further.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{file}:{}:{}: ", self.line, self.column)?,
+            None => write!(f, "line {}, column {}: ", self.line, self.column)?,
+        }
+
+        if let Some(section) = &self.section {
+            write!(f, "in section [{section}]: ")?;
+        }
+
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// The position of a token in a config file, for errors that are only found after parsing the
+/// line containing the token.
+#[derive(Clone, Debug)]
+pub(crate) struct Location {
+    line: usize,
+    column: usize,
+    token: String,
+}
+
+impl Location {
+    /// Creates the location of `token`, which has to be a part of `raw_line`.
+    fn new(line: usize, raw_line: &str, token: &str) -> Self {
+        let offset = token.as_ptr() as usize - raw_line.as_ptr() as usize;
+
+        Self {
+            line,
+            column: raw_line[..offset].chars().count() + 1,
+            token: token.to_string(),
+        }
+    }
+
+    fn error(&self, section: Option<String>, error: Error) -> ParseError {
+        ParseError::new(self.line, self.column, section, &self.token, error)
+    }
+}
+
+/// Identifies a rule of a config, either in the `RULES` section or in a security group.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum RuleIndex<'a> {
+    Rules(usize),
+    Group(&'a str, usize),
 }
 
+/// The locations of the rules of a parsed config.
+#[derive(Debug, Default)]
+pub(crate) struct RulePositions {
+    rules: Vec<Location>,
+    groups: HashMap<String, Vec<Location>>,
+}
+
+impl RulePositions {
+    /// Creates the error for a rule of the parsed config.
+    pub(crate) fn error(&self, rule: RuleIndex, error: Error) -> ParseError {
+        let (section, location) = match rule {
+            RuleIndex::Rules(index) => ("RULES".to_string(), self.rules.get(index)),
+            RuleIndex::Group(name, index) => (
+                format!("group {name}"),
+                self.groups.get(name).and_then(|rules| rules.get(index)),
+            ),
+        };
+
+        match location {
+            Some(location) => location.error(Some(section), error),
+            None => ParseError::new(0, 0, Some(section), "", error),
+        }
+    }
+}
+
+/// All errors that were found in a firewall config file.
+#[derive(Debug, Default)]
+pub struct ParseErrors(Vec<ParseError>);
+
+impl ParseErrors {
+    pub(crate) fn push(&mut self, error: ParseError) {
+        self.0.push(error);
+    }
+
+    /// Converts the errors into the first error, for parsers stopping at the first error.
+    pub(crate) fn into_first(self) -> Error {
+        self.0
+            .into_iter()
+            .next()
+            .map(Error::from)
+            .unwrap_or_else(|| format_err!("failed to parse firewall config"))
+    }
+
+    /// Sets the file name of all contained errors, see [`ParseError::with_file`].
+    pub fn with_file(self, file: &str) -> Self {
+        Self(
+            self.0
+                .into_iter()
+                .map(|error| error.with_file(file))
+                .collect(),
+        )
+    }
+}
+
+impl std::ops::Deref for ParseErrors {
+    type Target = [ParseError];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl IntoIterator for ParseErrors {
+    type Item = ParseError;
+    type IntoIter = std::vec::IntoIter<ParseError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl std::fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (index, error) in self.0.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{error}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseErrors {}
+
 impl<O> Config<O>
 where
     O: Default + std::fmt::Debug + serde::de::DeserializeOwned,
@@ -46,90 +334,362 @@ where
         Self::default()
     }
 
+    /// Parses a config, stopping at the first invalid line.
+    ///
+    /// The returned error is a [`ParseError`] pointing to the location of the error.
     pub fn parse<R: io::BufRead>(input: R, parser_cfg: &ParserConfig) -> Result<Self, Error> {
+        Self::parse_inner(input, parser_cfg, false)
+            .map(|(config, _)| config)
+            .map_err(ParseErrors::into_first)
+    }
+
+    /// Parses a config, but does not stop at the first invalid line.
+    ///
+    /// Invalid lines are skipped, as are all lines of a section with an invalid header. If any
+    /// errors were found, all of them are returned, ordered by their line.
+    pub fn parse_collect_errors<R: io::BufRead>(
+        input: R,
+        parser_cfg: &ParserConfig,
+    ) -> Result<Self, ParseErrors> {
+        Self::parse_inner(input, parser_cfg, true).map(|(config, _)| config)
+    }
+
+    /// Parses a config, returning it together with the positions of its rules.
+    ///
+    /// This allows reporting errors in rules that are found after parsing, e.g. by checking the
+    /// rules against the network devices of a guest. If `collect_errors` is not set, only the
+    /// first error is returned.
+    pub(crate) fn parse_inner<R: io::BufRead>(
+        input: R,
+        parser_cfg: &ParserConfig,
+        collect_errors: bool,
+    ) -> Result<(Self, RulePositions), ParseErrors> {
         let mut section = Sec::None;
 
         let mut this = Self::new();
-        let mut options = HashMap::new();
+        let mut errors = ParseErrors::default();
 
-        for line in input.lines() {
-            let line = line?;
-            let line = line.trim();
+        let mut positions = RulePositions::default();
+        let mut option_locations = Vec::new();
+
+        for (index, line) in input.lines().enumerate() {
+            let line_number = index + 1;
+
+            let raw_line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    errors.push(ParseError::new(line_number, 1, None, "", err.into()));
+                    return Err(errors);
+                }
+            };
+
+            let line = raw_line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
 
-            if line.is_empty() || line.starts_with('#') {
+            if line.starts_with('#') {
+                this.add_comment_line(&section, line);
                 continue;
             }
 
             log::trace!("parsing config line {line}");
 
-            if line.eq_ignore_ascii_case("[OPTIONS]") {
-                this.set_section(&mut section, Sec::Options)?;
-            } else if line.eq_ignore_ascii_case("[ALIASES]") {
-                this.set_section(&mut section, Sec::Aliases)?;
-            } else if line.eq_ignore_ascii_case("[RULES]") {
-                this.set_section(&mut section, Sec::Rules)?;
-            } else if let Some(line) = line.strip_prefix("[IPSET") {
-                let (name, comment) = parse_named_section_tail("ipset", line)?;
-
-                let scope = parser_cfg.ipset_scope.ok_or_else(|| {
-                    format_err!("IPSET in config, but no scope set in parser config")
-                })?;
-
-                let ipset_name = IpsetName::new(scope, name.to_string());
-                let mut ipset = Ipset::new(ipset_name);
-                ipset.comment = comment.map(str::to_owned);
-
-                this.set_section(&mut section, Sec::Ipset(name.to_string(), ipset))?;
-            } else if let Some(line) = line.strip_prefix("[group") {
-                let (name, comment) = parse_named_section_tail("group", line)?;
-                let mut group = Group::new();
-
-                group.set_comment(comment.map(str::to_owned));
-
-                this.set_section(&mut section, Sec::Group(name.to_owned(), group))?;
-            } else if line.starts_with('[') {
-                bail!("invalid section {line:?}");
-            } else {
-                match &mut section {
-                    Sec::None => bail!("config line with no section: {line:?}"),
-                    Sec::Options => Self::parse_option(line, &mut options)?,
-                    Sec::Aliases => this.parse_alias(line)?,
-                    Sec::Rules => this.parse_rule(line, parser_cfg)?,
-                    Sec::Ipset(_name, ipset) => ipset.parse_entry(line)?,
-                    Sec::Group(_name, group) => group.parse_entry(line)?,
+            let is_header = line.starts_with('[');
+
+            let section_name = match &section {
+                _ if is_header => None,
+                Sec::Options => Some("OPTIONS".to_string()),
+                Sec::Aliases => Some("ALIASES".to_string()),
+                Sec::Rules => Some("RULES".to_string()),
+                Sec::Ipset(name, _) => Some(format!("IPSET {name}")),
+                Sec::Group(name, _) => Some(format!("group {name}")),
+                Sec::None | Sec::Invalid => None,
+            };
+
+            if let Err(err) = this.parse_line(line, &mut section, parser_cfg) {
+                // `token` is always a part of `raw_line`
+                let token = this
+                    .locate_error(&section, line, parser_cfg)
+                    .unwrap_or(line);
+
+                errors.push(Location::new(line_number, &raw_line, token).error(section_name, err));
+
+                if !collect_errors {
+                    return Err(errors);
+                }
+
+                if is_header {
+                    // skip the contents of sections with an invalid header
+                    this.set_section(&mut section, Sec::Invalid);
+                }
+
+                continue;
+            }
+
+            if is_header {
+                continue;
+            }
+
+            match &section {
+                Sec::Options => {
+                    let value = split_key_value(line).map_or(line, |(_, value)| value);
+                    option_locations.push(Location::new(line_number, &raw_line, value));
                 }
+                Sec::Rules => positions
+                    .rules
+                    .push(Location::new(line_number, &raw_line, line)),
+                Sec::Group(name, _) => positions
+                    .groups
+                    .entry(name.clone())
+                    .or_default()
+                    .push(Location::new(line_number, &raw_line, line)),
+                _ => (),
             }
         }
-        this.set_section(&mut section, Sec::None)?;
 
-        this.options = O::deserialize(IntoDeserializer::<
+        this.set_section(&mut section, Sec::None);
+
+        match Self::deserialize_options(&this.raw_options) {
+            Ok(options) => this.options = options,
+            Err(err) => {
+                // every option is valid on its own, so the first option that cannot be combined
+                // with the preceding ones is at fault
+                let location = (1..=this.raw_options.len())
+                    .find(|len| Self::deserialize_options(&this.raw_options[..*len]).is_err())
+                    .and_then(|len| option_locations.get(len - 1));
+
+                errors.push(match location {
+                    Some(location) => location.error(Some("OPTIONS".to_string()), err),
+                    None => ParseError::new(0, 0, Some("OPTIONS".to_string()), "", err),
+                });
+            }
+        }
+
+        // aliases and ipsets can be defined after the rules using them
+        for (rule, err) in this.check_families(parser_cfg) {
+            errors.push(positions.error(rule, err));
+        }
+
+        if parser_cfg.allow_groups {
+            for (rule, err) in this.check_groups() {
+                errors.push(positions.error(rule, err));
+            }
+        }
+
+        if !errors.is_empty() {
+            // errors in references are only found after all lines are parsed
+            errors.0.sort_by_key(|error| error.line);
+            return Err(errors);
+        }
+
+        Ok((this, positions))
+    }
+
+    fn parse_line(
+        &mut self,
+        line: &str,
+        section: &mut Sec,
+        parser_cfg: &ParserConfig,
+    ) -> Result<(), Error> {
+        if line.eq_ignore_ascii_case("[OPTIONS]") {
+            self.set_section(section, Sec::Options);
+        } else if line.eq_ignore_ascii_case("[ALIASES]") {
+            if !parser_cfg.allow_aliases {
+                bail!("this firewall config cannot declare aliases");
+            }
+
+            self.set_section(section, Sec::Aliases);
+        } else if line.eq_ignore_ascii_case("[RULES]") {
+            self.set_section(section, Sec::Rules);
+        } else if let Some(line) = line.strip_prefix("[IPSET") {
+            let (name, comment) = parse_named_section_tail("ipset", line)?;
+
+            let scope = parser_cfg
+                .ipset_scope
+                .ok_or_else(|| format_err!("IPSET in config, but no scope set in parser config"))?;
+
+            if self.ipsets.contains_key(name)
+                || matches!(section, Sec::Ipset(current, _) if current == name)
+            {
+                bail!("duplicate ipset: {name:?}");
+            }
+
+            let ipset_name = IpsetName::new(scope, name.to_string());
+            let mut ipset = Ipset::new(ipset_name);
+            ipset.comment = comment.map(str::to_owned);
+
+            self.set_section(section, Sec::Ipset(name.to_string(), ipset));
+        } else if let Some(line) = line.strip_prefix("[group") {
+            let (name, comment) = parse_named_section_tail("group", line)?;
+
+            if !parser_cfg.allow_groups {
+                bail!("this firewall config cannot declare groups");
+            }
+
+            if self.groups.contains_key(name)
+                || matches!(section, Sec::Group(current, _) if current == name)
+            {
+                bail!("duplicate group: {name:?}");
+            }
+
+            let mut group = Group::new();
+
+            group.set_comment(comment.map(str::to_owned));
+
+            self.set_section(section, Sec::Group(name.to_owned(), group));
+        } else if line.starts_with('[') {
+            bail!("invalid section {line:?}");
+        } else {
+            match section {
+                Sec::None => bail!("config line with no section: {line:?}"),
+                Sec::Invalid => (),
+                Sec::Options => self.parse_option(line)?,
+                Sec::Aliases => self.parse_alias(line)?,
+                Sec::Rules => self.parse_rule(line, parser_cfg)?,
+                Sec::Ipset(_name, ipset) => ipset.parse_entry(line)?,
+                Sec::Group(_name, group) => group.add_rule(line.parse()?),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the part of an invalid line that caused parsing it to fail.
+    ///
+    /// The individual parts of the line are checked one after another, the first one that is
+    /// invalid on its own is returned. Returns `None` if no single part is at fault.
+    fn locate_error<'a>(
+        &self,
+        section: &Sec,
+        line: &'a str,
+        parser_cfg: &ParserConfig,
+    ) -> Option<&'a str> {
+        if line.starts_with('[') {
+            return None;
+        }
+
+        let line = match line.split_once('#') {
+            Some((line, _comment)) => line.trim_end(),
+            None => line,
+        };
+
+        match section {
+            Sec::Options => {
+                let (key, value) = split_key_value(line)?;
+                Self::check_option(key, value).err().map(|_| value)
+            }
+            Sec::Aliases => locate_alias_error(line),
+            Sec::Rules => locate_rule_error(line, Some(parser_cfg)),
+            Sec::Group(..) => locate_rule_error(line, None),
+            Sec::Ipset(..) => locate_ipset_entry_error(line),
+            Sec::None | Sec::Invalid => None,
+        }
+    }
+
+    /// Checks whether a single option has a valid value.
+    fn check_option(key: &str, value: &str) -> Result<(), Error> {
+        Self::parse_single_option(key, value)?;
+        Ok(())
+    }
+
+    /// Parses the options struct from a single option, all other options are left unset.
+    pub(crate) fn parse_single_option(key: &str, value: &str) -> Result<O, Error> {
+        Self::deserialize_options(&[(key.to_string(), value.to_string())])
+    }
+
+    /// Parses the options struct from the raw options.
+    fn deserialize_options(raw_options: &[(String, String)]) -> Result<O, Error> {
+        let options: HashMap<String, SomeString> = raw_options
+            .iter()
+            .map(|(key, value)| (key.clone(), SomeString::from(value.as_str())))
+            .collect();
+
+        Ok(O::deserialize(IntoDeserializer::<
             '_,
             crate::firewall::parse::SerdeStringError,
-        >::into_deserializer(options))?;
-
-        Ok(this)
+        >::into_deserializer(options))?)
     }
 
-    fn parse_option(line: &str, options: &mut HashMap<String, SomeString>) -> Result<(), Error> {
+    fn parse_option(&mut self, line: &str) -> Result<(), Error> {
         let (key, value) = split_key_value(line)
             .ok_or_else(|| format_err!("expected colon separated key and value, found {line:?}"))?;
 
-        if options.insert(key.to_string(), value.into()).is_some() {
+        Self::check_option(key, value)?;
+
+        if self.raw_options.iter().any(|(option, _)| option == key) {
             bail!("duplicate option {key:?}");
         }
 
+        self.raw_options.push((key.to_string(), value.to_string()));
+
         Ok(())
     }
 
+    /// Sets an option, replacing its previous value.
+    ///
+    /// The value is checked like an option in a parsed config. The options are written with
+    /// their raw values, which are kept in sync with the parsed options by this method and
+    /// [`Config::remove_option`].
+    pub fn set_option(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        Self::check_option(key, value)?;
+
+        let mut raw_options = self.raw_options.clone();
+
+        match raw_options.iter_mut().find(|(option, _)| option == key) {
+            Some((_, raw_value)) => *raw_value = value.to_string(),
+            None => raw_options.push((key.to_string(), value.to_string())),
+        }
+
+        self.options = Self::deserialize_options(&raw_options)?;
+        self.raw_options = raw_options;
+
+        Ok(())
+    }
+
+    /// Removes an option, returning its previous value.
+    pub fn remove_option(&mut self, key: &str) -> Result<Option<String>, Error> {
+        let Some(index) = self
+            .raw_options
+            .iter()
+            .position(|(option, _)| option == key)
+        else {
+            return Ok(None);
+        };
+
+        let mut raw_options = self.raw_options.clone();
+        let (_, value) = raw_options.remove(index);
+
+        self.options = Self::deserialize_options(&raw_options)?;
+        self.raw_options = raw_options;
+
+        Ok(Some(value))
+    }
+
     fn parse_alias(&mut self, line: &str) -> Result<(), Error> {
-        let alias: Alias = line.parse()?;
+        let contains = |config: &Self, name: &str| {
+            config.aliases.contains_key(name) || config.fqdn_aliases.contains_key(name)
+        };
 
-        if self
-            .aliases
-            .insert(alias.name().to_string(), alias)
-            .is_some()
-        {
-            bail!("duplicate alias: {line}");
+        match line.parse::<Alias>() {
+            Ok(alias) => {
+                if contains(self, alias.name()) {
+                    bail!("duplicate alias: {line}");
+                }
+
+                self.aliases.insert(alias.name().to_string(), alias);
+            }
+            Err(err) => {
+                // report why the value is no address, unless it is a domain name
+                let alias: FqdnAlias = line.parse().map_err(|_| err)?;
+
+                if contains(self, alias.name()) {
+                    bail!("duplicate alias: {line}");
+                }
+
+                self.fqdn_aliases.insert(alias.name().to_string(), alias);
+            }
         }
 
         Ok(())
@@ -138,51 +698,272 @@ where
     fn parse_rule(&mut self, line: &str, parser_cfg: &ParserConfig) -> Result<(), Error> {
         let rule: Rule = line.parse()?;
 
-        if parser_cfg.guest_iface_names {
-            if let Some(iface) = rule.iface() {
-                let _ = iface
-                    .strip_prefix("net")
-                    .ok_or_else(|| {
-                        format_err!("interface name must be of the form \"net<number>\"")
-                    })?
-                    .parse::<u16>()
-                    .map_err(|_| {
-                        format_err!("interface name must be of the form \"net<number>\"")
-                    })?;
+        parser_cfg.check_rule(&rule)?;
+
+        if let Some(param) = rule.params().first() {
+            bail!("parameter ${param} can only be used in security groups");
+        }
+
+        self.rules.push(rule);
+        Ok(())
+    }
+
+    /// Checks the references between the security groups of this config, returning the errors
+    /// together with the rule including the group.
+    ///
+    /// Groups must only include existing groups, must not include themselves, directly or
+    /// through other groups, and have to pass every parameter of an included group. Groups
+    /// included by other configs are checked by the rule resolver.
+    fn check_groups(&self) -> Vec<(RuleIndex<'_>, Error)> {
+        let mut errors = Vec::new();
+
+        for (name, group) in &self.groups {
+            for (index, include) in group_includes(group.rules()) {
+                let result = match self.groups.get(include.group()) {
+                    Some(included) => included.check_include(include),
+                    None => Err(format_err!("unknown security group {}", include.group())),
+                };
+
+                if let Err(err) = result {
+                    errors.push((RuleIndex::Group(name, index), err));
+                }
             }
         }
 
-        if let Kind::Match(rule) = rule.kind() {
-            if !parser_cfg.allowed_directions.contains(&rule.dir) {
-                bail!(
-                    "found not allowed direction in firewall config: {0}",
-                    rule.dir
-                );
+        for (index, include) in group_includes(&self.rules) {
+            if let Some(Err(err)) = self
+                .groups
+                .get(include.group())
+                .map(|included| included.check_include(include))
+            {
+                errors.push((RuleIndex::Rules(index), err));
             }
         }
 
-        self.rules.push(rule);
-        Ok(())
+        let mut finished = BTreeSet::new();
+
+        for name in self.groups.keys() {
+            self.find_group_cycles(name, &mut Vec::new(), &mut finished, &mut errors);
+        }
+
+        errors
     }
 
-    fn set_section(&mut self, sec: &mut Sec, to: Sec) -> Result<(), Error> {
-        let prev = std::mem::replace(sec, to);
+    /// Searches for cycles in the groups included by a group, with a depth-first search.
+    ///
+    /// The path contains the groups that are currently searched, together with the index of the
+    /// rule including the next group on the path.
+    fn find_group_cycles<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<(&'a str, usize)>,
+        finished: &mut BTreeSet<&'a str>,
+        errors: &mut Vec<(RuleIndex<'a>, Error)>,
+    ) {
+        if finished.contains(name) {
+            return;
+        }
 
-        match prev {
-            Sec::Ipset(name, ipset) => {
-                if self.ipsets.insert(name.clone(), ipset).is_some() {
-                    bail!("duplicate ipset: {name:?}");
+        if let Some(start) = path.iter().position(|(group, _)| *group == name) {
+            let cycle: Vec<&str> = path[start..].iter().map(|(group, _)| *group).collect();
+            let cycle = cycle.join(" -> ");
+
+            // the last group on the path contains the rule closing the cycle
+            let (group, index) = path[path.len() - 1];
+
+            errors.push((
+                RuleIndex::Group(group, index),
+                format_err!("circular reference between security groups {cycle} -> {name}"),
+            ));
+
+            return;
+        }
+
+        let Some(group) = self.groups.get(name) else {
+            return;
+        };
+
+        for (index, include) in group_includes(group.rules()) {
+            path.push((name, index));
+            self.find_group_cycles(include.group(), path, finished, errors);
+            path.pop();
+        }
+
+        finished.insert(name);
+    }
+
+    /// Checks the address families of all rules, returning the errors together with the rule
+    /// they were found in.
+    fn check_families(&self, parser_cfg: &ParserConfig) -> Vec<(RuleIndex<'_>, Error)> {
+        let rules = self
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| (RuleIndex::Rules(index), rule));
+
+        let group_rules = self.groups.iter().flat_map(|(name, group)| {
+            group
+                .rules()
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| (RuleIndex::Group(name, index), rule))
+        });
+
+        rules
+            .chain(group_rules)
+            .filter_map(|(index, rule)| {
+                self.check_rule_families(rule, parser_cfg)
+                    .err()
+                    .map(|err| (index, err))
+            })
+            .collect()
+    }
+
+    /// Checks that the addresses and the protocol of a rule have a common address family.
+    ///
+    /// Aliases and ipsets are only taken into account if they are defined in this config, the
+    /// rule resolver checks the remaining ones.
+    fn check_rule_families(&self, rule: &Rule, parser_cfg: &ParserConfig) -> Result<(), Error> {
+        let Kind::Match(rule) = rule.kind() else {
+            return Ok(());
+        };
+
+        let Some(ip) = rule.ip() else {
+            return Ok(());
+        };
+
+        let src = ip
+            .src()
+            .and_then(|src| self.address_families(src, parser_cfg));
+        let dst = ip
+            .dst()
+            .and_then(|dst| self.address_families(dst, parser_cfg));
+
+        check_families(src.as_deref(), dst.as_deref(), rule.proto())
+    }
+
+    /// Returns the address families contained in an address of a rule, or `None` if they are not
+    /// known.
+    fn address_families(
+        &self,
+        address: &IpAddrMatch,
+        parser_cfg: &ParserConfig,
+    ) -> Option<Vec<Family>> {
+        match address {
+            IpAddrMatch::Ip(list) => Some(vec![list.family()]),
+            // parameters are only known once the group is included
+            IpAddrMatch::Param(_) => None,
+            // aliases of domain names are not found, their families are only known once they
+            // are resolved
+            IpAddrMatch::Alias(name) => self
+                .local_alias(name, parser_cfg)
+                .map(|alias| vec![alias.address().family()]),
+            IpAddrMatch::Set(name) => {
+                let ipset = match name {
+                    RuleIpsetName::Scoped(name) if Some(name.scope()) == parser_cfg.ipset_scope => {
+                        self.ipsets.get(name.name())?
+                    }
+                    RuleIpsetName::Scoped(_) => return None,
+                    RuleIpsetName::Legacy(name) => self.ipsets.get(name.as_ref())?,
+                };
+
+                // the families of domain names are only known once they are resolved
+                if ipset.fqdns().len() > 0 {
+                    return None;
+                }
+
+                let mut families = Vec::new();
+
+                for entry in ipset.iter() {
+                    let family = match &entry.address {
+                        IpsetAddress::Alias(name) => {
+                            self.local_alias(name, parser_cfg)?.address().family()
+                        }
+                        IpsetAddress::Cidr(cidr) => cidr.family(),
+                        IpsetAddress::Range(range) => range.family(),
+                    };
+
+                    if !entry.nomatch && !families.contains(&family) {
+                        families.push(family);
+                    }
                 }
+
+                Some(families)
             }
-            Sec::Group(name, group) => {
-                if self.groups.insert(name.clone(), group).is_some() {
-                    bail!("duplicate group: {name:?}");
+        }
+    }
+
+    /// Looks up an alias referenced in this config, if it is defined in this config.
+    fn local_alias(&self, name: &RuleAliasName, parser_cfg: &ParserConfig) -> Option<&Alias> {
+        match name {
+            RuleAliasName::Scoped(name) => {
+                let local = match name.scope() {
+                    AliasScope::Datacenter => Some(IpsetScope::Datacenter),
+                    AliasScope::Guest => Some(IpsetScope::Guest),
+                };
+
+                if local != parser_cfg.ipset_scope {
+                    return None;
                 }
+
+                self.aliases.get(name.name())
             }
-            _ => (),
+            RuleAliasName::Legacy(name) => self.aliases.get(name.as_ref()),
         }
+    }
 
-        Ok(())
+    /// Records a comment line, together with its position in the current section.
+    fn add_comment_line(&mut self, section: &Sec, line: &str) {
+        let (section, position) = match section {
+            Sec::None => (None, 0),
+            // the contents of sections with an invalid header are dropped
+            Sec::Invalid => return,
+            Sec::Options => (Some(Section::Options), self.raw_options.len()),
+            Sec::Aliases => (
+                Some(Section::Aliases),
+                self.aliases.len() + self.fqdn_aliases.len(),
+            ),
+            Sec::Rules => (Some(Section::Rules), self.rules.len()),
+            Sec::Ipset(name, ipset) => (
+                Some(Section::Ipset(name.clone())),
+                ipset.len() + ipset.fqdns().len(),
+            ),
+            Sec::Group(name, group) => (Some(Section::Group(name.clone())), group.rules().len()),
+        };
+
+        self.comment_lines.push(CommentLine {
+            section,
+            position,
+            text: line.to_string(),
+        });
+    }
+
+    fn set_section(&mut self, sec: &mut Sec, to: Sec) {
+        let section = match &to {
+            Sec::None | Sec::Invalid => None,
+            Sec::Options => Some(Section::Options),
+            Sec::Aliases => Some(Section::Aliases),
+            Sec::Rules => Some(Section::Rules),
+            Sec::Ipset(name, _) => Some(Section::Ipset(name.clone())),
+            Sec::Group(name, _) => Some(Section::Group(name.clone())),
+        };
+
+        if let Some(section) = section {
+            if !self.sections.contains(&section) {
+                self.sections.push(section);
+            }
+        }
+
+        // duplicates are rejected when encountering the section header
+        match std::mem::replace(sec, to) {
+            Sec::Ipset(name, ipset) => {
+                self.ipsets.insert(name, ipset);
+            }
+            Sec::Group(name, group) => {
+                self.groups.insert(name, group);
+            }
+            _ => (),
+        }
     }
 
     pub fn ipsets(&self) -> &BTreeMap<String, Ipset> {
@@ -196,4 +977,690 @@ where
     pub fn alias(&self, name: &str) -> Option<&Alias> {
         self.aliases.get(name)
     }
+
+    pub fn fqdn_alias(&self, name: &str) -> Option<&FqdnAlias> {
+        self.fqdn_aliases.get(name)
+    }
+
+    /// Returns the domain names contained in the aliases and ipsets of this config.
+    pub fn fqdns(&self) -> Vec<&Fqdn> {
+        let aliases = self.fqdn_aliases.values().map(FqdnAlias::fqdn);
+
+        let ipsets = self
+            .ipsets
+            .values()
+            .flat_map(|ipset| ipset.fqdns())
+            .map(|entry| &entry.fqdn);
+
+        aliases.chain(ipsets).collect()
+    }
+
+    /// Writes the config in the firewall config file format.
+    ///
+    /// Sections are written in the order in which they appeared when the config was parsed,
+    /// followed by all remaining non-empty sections in the canonical order. Options are written
+    /// with the same spelling that was used in the parsed config. Lines only containing a comment
+    /// are written at the same position of their section.
+    ///
+    /// Fails if any comment contains a line break, since it cannot be written as a single line,
+    /// or if a rule cannot be represented in the config format.
+    pub fn write<W: io::Write>(&self, mut output: W) -> Result<(), Error> {
+        for rule in self
+            .rules
+            .iter()
+            .chain(self.groups.values().flat_map(Group::rules))
+        {
+            rule.check_representable()?;
+        }
+
+        let mut sections = self.sections.clone();
+
+        let mut remaining = Vec::new();
+
+        if !self.raw_options.is_empty() {
+            remaining.push(Section::Options);
+        }
+
+        if !self.aliases.is_empty() || !self.fqdn_aliases.is_empty() {
+            remaining.push(Section::Aliases);
+        }
+
+        remaining.extend(self.ipsets.keys().cloned().map(Section::Ipset));
+
+        if !self.rules.is_empty() {
+            remaining.push(Section::Rules);
+        }
+
+        remaining.extend(self.groups.keys().cloned().map(Section::Group));
+
+        for section in remaining {
+            if !sections.contains(&section) {
+                sections.push(section);
+            }
+        }
+
+        for comment in self.comment_lines(None) {
+            writeln!(output, "{}", comment.text)?;
+        }
+
+        for section in &sections {
+            self.write_section(&mut output, section)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_section<W: io::Write>(&self, output: &mut W, section: &Section) -> Result<(), Error> {
+        match section {
+            Section::Options => {
+                writeln!(output, "[OPTIONS]\n")?;
+
+                let options = self
+                    .raw_options
+                    .iter()
+                    .map(|(key, value)| format!("{key}: {value}"));
+
+                self.write_entries(output, section, options)?;
+            }
+            Section::Aliases => {
+                writeln!(output, "[ALIASES]\n")?;
+                self.write_entries(output, section, self.alias_lines().into_values())?;
+            }
+            Section::Rules => {
+                writeln!(output, "[RULES]\n")?;
+                self.write_entries(output, section, &self.rules)?;
+            }
+            Section::Ipset(name) => {
+                let Some(ipset) = self.ipsets.get(name) else {
+                    return Ok(());
+                };
+
+                write!(output, "[IPSET {name}]")?;
+                write_section_comment(output, ipset.comment.as_deref())?;
+                self.write_entries(output, section, ipset.lines())?;
+            }
+            Section::Group(name) => {
+                let Some(group) = self.groups.get(name) else {
+                    return Ok(());
+                };
+
+                write!(output, "[group {name}]")?;
+                write_section_comment(output, group.comment())?;
+                self.write_entries(output, section, group.rules())?;
+            }
+        }
+
+        writeln!(output)?;
+
+        Ok(())
+    }
+
+    /// The lines of the aliases section, ordered by the name of the alias.
+    pub(crate) fn alias_lines(&self) -> BTreeMap<&str, String> {
+        let aliases = self
+            .aliases
+            .values()
+            .map(|alias| (alias.name(), alias.to_string()));
+
+        let fqdn_aliases = self
+            .fqdn_aliases
+            .values()
+            .map(|alias| (alias.name(), alias.to_string()));
+
+        aliases.chain(fqdn_aliases).collect()
+    }
+
+    /// Writes the entries of a section, one per line, with the comment lines of the section in
+    /// between them.
+    fn write_entries<W: io::Write, T: std::fmt::Display>(
+        &self,
+        output: &mut W,
+        section: &Section,
+        entries: impl IntoIterator<Item = T>,
+    ) -> Result<(), Error> {
+        let mut comments = self.comment_lines(Some(section)).peekable();
+
+        for (index, entry) in entries.into_iter().enumerate() {
+            while let Some(comment) = comments.next_if(|comment| comment.position <= index) {
+                writeln!(output, "{}", comment.text)?;
+            }
+
+            let line = entry.to_string();
+
+            // a comment spanning multiple lines would add arbitrary lines to the config
+            if line.contains(['\n', '\r']) {
+                bail!("cannot write entry containing a line break: {line:?}");
+            }
+
+            writeln!(output, "{line}")?;
+        }
+
+        // comments after the last entry, or positioned after entries that have been removed
+        for comment in comments {
+            writeln!(output, "{}", comment.text)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the comment lines of a section, e.g. when replacing all of its entries.
+    pub(crate) fn remove_comment_lines(&mut self, section: &Section) {
+        self.comment_lines
+            .retain(|comment| comment.section.as_ref() != Some(section));
+    }
+
+    fn comment_lines<'a>(
+        &'a self,
+        section: Option<&'a Section>,
+    ) -> impl Iterator<Item = &'a CommentLine> + 'a {
+        self.comment_lines
+            .iter()
+            .filter(move |comment| comment.section.as_ref() == section)
+    }
+}
+
+impl<O> Config<O>
+where
+    O: Default + std::fmt::Debug + serde::de::DeserializeOwned + PartialEq,
+{
+    /// Computes the changes from this config to `new`.
+    pub fn diff(&self, new: &Self) -> ConfigDiff {
+        diff_configs(self, new)
+    }
+}
+
+/// Returns the rules including other security groups, together with their index.
+fn group_includes(rules: &[Rule]) -> impl Iterator<Item = (usize, &RuleGroup)> + '_ {
+    rules
+        .iter()
+        .enumerate()
+        .filter_map(|(index, rule)| match rule.kind() {
+            Kind::Group(include) => Some((index, include)),
+            Kind::Match(_) => None,
+        })
+}
+
+/// Returns the part of an invalid alias line that is at fault.
+fn locate_alias_error(line: &str) -> Option<&str> {
+    let mut tokens = line.split_ascii_whitespace();
+
+    let name = tokens.next()?;
+    if match_name(name) != Some((name, "")) {
+        return Some(name);
+    }
+
+    let Some(address) = tokens.next() else {
+        return Some(name);
+    };
+
+    if address.parse::<Cidr>().is_err() && address.parse::<Fqdn>().is_err() {
+        return Some(address);
+    }
+
+    tokens.next()
+}
+
+/// Returns the part of an invalid ipset entry that is at fault.
+fn locate_ipset_entry_error(line: &str) -> Option<&str> {
+    let line = line.strip_prefix('!').unwrap_or(line);
+    let mut tokens = line.split_ascii_whitespace();
+
+    let address = tokens.next()?;
+    if address.parse::<IpsetAddress>().is_err() && address.parse::<IpsetFqdnEntry>().is_err() {
+        return Some(address);
+    }
+
+    tokens.next()
+}
+
+/// Returns the part of an invalid rule that is at fault.
+///
+/// Options are checked one by one, together with the protocol of the rule, since the validity of
+/// the ports depends on it. Rules in security groups are parsed without a [`ParserConfig`].
+fn locate_rule_error<'a>(line: &'a str, parser_cfg: Option<&ParserConfig>) -> Option<&'a str> {
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let mut tokens = line.split_ascii_whitespace();
+
+    let first = tokens.next()?;
+
+    let Some(second) = tokens.next() else {
+        return Some(first);
+    };
+
+    if first.starts_with("GROUP") {
+        // the parameters of the group are part of its name
+        if format!("{first} {second}").parse::<RuleGroup>().is_err() {
+            return Some(second);
+        }
+    } else {
+        match first.parse::<Direction>() {
+            Ok(dir) if parser_cfg.is_none_or(|cfg| cfg.allowed_directions.contains(&dir)) => (),
+            _ => return Some(first),
+        }
+
+        if !matches!(parse_action(second), Ok((_, _, ""))) {
+            return Some(second);
+        }
+    }
+
+    let tokens: Vec<&str> = tokens.collect();
+
+    let options: Vec<&str> = tokens
+        .chunks(2)
+        .map(|option| match option {
+            [name, value] => span(line, name, value),
+            [name] => name,
+            _ => unreachable!(),
+        })
+        .collect();
+
+    let proto = options
+        .iter()
+        .find(|option| matches!(option.split_once(' '), Some(("-p" | "--proto", _))));
+
+    for option in &options {
+        if !option.contains(' ') {
+            return Some(option);
+        }
+
+        let mut rule = format!("{first} {second}");
+
+        if let Some(proto) = proto.filter(|proto| *proto != option) {
+            rule = format!("{rule} {proto}");
+        }
+
+        let valid = format!("{rule} {option}")
+            .parse::<Rule>()
+            .and_then(|rule| match parser_cfg {
+                Some(parser_cfg) => parser_cfg.check_rule(&rule),
+                None => Ok(()),
+            });
+
+        if valid.is_err() {
+            return Some(option);
+        }
+    }
+
+    None
+}
+
+/// Returns the part of `line` from the start of `first` to the end of `last`, which both need to
+/// be contained in `line`.
+fn span<'a>(line: &'a str, first: &str, last: &str) -> &'a str {
+    let start = first.as_ptr() as usize - line.as_ptr() as usize;
+    let end = last.as_ptr() as usize - line.as_ptr() as usize + last.len();
+
+    &line[start..end]
+}
+
+fn write_section_comment<W: io::Write>(output: &mut W, comment: Option<&str>) -> Result<(), Error> {
+    if let Some(comment) = comment.filter(|comment| comment.contains(['\n', '\r'])) {
+        bail!("cannot write section comment containing a line break: {comment:?}");
+    }
+
+    match comment {
+        Some(comment) => writeln!(output, " # {comment}\n")?,
+        None => writeln!(output, "\n")?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::firewall::cluster::Options;
+    use crate::firewall::types::rule_match::{Icmp, IcmpCode, IcmpType, Protocol};
+
+    fn parser_config() -> ParserConfig {
+        ParserConfig {
+            guest_iface_names: false,
+            ipset_scope: Some(IpsetScope::Datacenter),
+            allowed_directions: vec![Direction::In, Direction::Out],
+            allow_aliases: true,
+            allow_groups: true,
+            allow_smac_vlan: false,
+        }
+    }
+
+    fn parse_error(input: &str) -> ParseError {
+        Config::<Options>::parse(input.as_bytes(), &parser_config())
+            .expect_err("invalid config")
+            .downcast::<ParseError>()
+            .expect("error is a parse error")
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let error = parse_error("[OPTIONS]\n\nenable: 1\npolicy_in: FOO\n");
+        assert_eq!(error.line(), 4);
+        assert_eq!(error.column(), 12);
+        assert_eq!(error.section(), Some("OPTIONS"));
+        assert_eq!(error.token(), "FOO");
+
+        let error = parse_error("[RULES]\n\n  IN ACCEPT -p tcp -dport 22 -sport qwe # comment\n");
+        assert_eq!(error.line(), 3);
+        assert_eq!(error.column(), 30);
+        assert_eq!(error.section(), Some("RULES"));
+        assert_eq!(error.token(), "-sport qwe");
+
+        let error = parse_error("[RULES]\n\n|IN ACCEPTT -p tcp\n");
+        assert_eq!((error.line(), error.column()), (3, 5));
+        assert_eq!(error.token(), "ACCEPTT");
+
+        let error = parse_error("[RULES]\n\nFORWARD ACCEPT -p tcp\n");
+        assert_eq!((error.line(), error.column()), (3, 1));
+        assert_eq!(error.token(), "FORWARD");
+
+        let error = parse_error("[RULES]\n\nIN ACCEPT -p tcp -dport 22 -i\n");
+        assert_eq!((error.line(), error.column()), (3, 28));
+        assert_eq!(error.token(), "-i");
+
+        let error = parse_error("[group test]\n\nIN ACCEPT -log foo\n");
+        assert_eq!(error.section(), Some("group test"));
+        assert_eq!(error.token(), "-log foo");
+
+        let error = parse_error("[ALIASES]\n\nalias 10.0.0.0/33\n");
+        assert_eq!((error.line(), error.column()), (3, 7));
+        assert_eq!(error.token(), "10.0.0.0/33");
+
+        let error = parse_error("[IPSET test]\n\n10.0.0.0/8\n!10.0.0.0/40 # comment\n");
+        assert_eq!((error.line(), error.column()), (4, 2));
+        assert_eq!(error.section(), Some("IPSET test"));
+        assert_eq!(error.token(), "10.0.0.0/40");
+
+        let error = parse_error("[IPSET test]\n\n[IPSET test]\n");
+        assert_eq!((error.line(), error.column()), (3, 1));
+        assert_eq!(error.section(), None);
+        assert_eq!(error.token(), "[IPSET test]");
+
+        let error = error.with_file("cluster.fw");
+        assert_eq!(
+            error.to_string(),
+            "cluster.fw:3:1: duplicate ipset: \"test\""
+        );
+    }
+
+    #[test]
+    fn test_parse_family_errors() {
+        const CONFIG: &str = r#"
+[ALIASES]
+
+network4 10.0.0.0/8
+network6 fd00::/64
+
+[IPSET mixed]
+
+network4
+fd01::/64
+
+[IPSET v4]
+
+dc/network4
+192.168.0.0/16
+
+[RULES]
+
+IN ACCEPT -p icmpv6 -source 10.0.0.0/8
+IN ACCEPT -p icmp -source network6
+IN ACCEPT -source dc/network4 -dest +dc/v4
+IN ACCEPT -source network4 -dest +v4 -p icmp
+IN ACCEPT -source network6 -dest +dc/mixed -p icmpv6
+IN ACCEPT -source network4 -dest +v4 -p icmpv6
+IN ACCEPT -source network6 -dest +v4
+IN ACCEPT -source guest/unknown -dest +v4 -p icmpv6
+
+[group test]
+
+IN ACCEPT -dest +mixed -source fd00::1 -p icmp
+IN ACCEPT -dest +late -p icmpv6
+
+[IPSET late]
+
+192.0.2.0/24
+"#;
+
+        let errors = Config::<Options>::parse_collect_errors(CONFIG.as_bytes(), &parser_config())
+            .expect_err("invalid config");
+
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|error| (error.line(), error.error().to_string()))
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    19,
+                    "source address is IPv4, but protocol icmpv6 requires IPv6".to_string()
+                ),
+                (
+                    20,
+                    "source address is IPv6, but protocol icmp requires IPv4".to_string()
+                ),
+                (
+                    24,
+                    "source address is IPv4, but protocol icmpv6 requires IPv6".to_string()
+                ),
+                (
+                    25,
+                    "source address is IPv6, but destination address is IPv4".to_string()
+                ),
+                (
+                    26,
+                    "destination address is IPv4, but protocol icmpv6 requires IPv6".to_string()
+                ),
+                (
+                    30,
+                    "source address is IPv6, but protocol icmp requires IPv4".to_string()
+                ),
+                (
+                    31,
+                    "destination address is IPv4, but protocol icmpv6 requires IPv6".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_smac_vlan() {
+        let error = parse_error("[RULES]\n\nIN ACCEPT -vlan 100\n");
+        assert_eq!(
+            error.error().to_string(),
+            "smac and vlan can only be used in guest and bridge firewall configs"
+        );
+
+        // security groups can be included in guest configs, the resolver checks them
+        Config::<Options>::parse(
+            "[group test]\n\nIN ACCEPT -vlan 100\n".as_bytes(),
+            &parser_config(),
+        )
+        .expect("valid config");
+    }
+
+    #[test]
+    fn test_parse_group_references() {
+        const CONFIG: &str = r#"
+[RULES]
+
+GROUP web(clients=10.0.0.0/8)
+GROUP web
+
+[group web]
+
+IN ACCEPT -source $clients -p tcp -dport 443
+GROUP base(clients=$clients)
+
+[group base]
+
+IN ACCEPT -p icmp
+GROUP missing
+
+[group loop-a]
+
+GROUP loop-b
+
+[group loop-b]
+
+GROUP loop-a
+"#;
+
+        let errors = Config::<Options>::parse_collect_errors(CONFIG.as_bytes(), &parser_config())
+            .expect_err("invalid config");
+
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|error| {
+                (
+                    error.line(),
+                    error.section().unwrap(),
+                    error.token(),
+                    error.error().to_string(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    5,
+                    "RULES",
+                    "GROUP web",
+                    "security group web requires parameter clients".to_string()
+                ),
+                (
+                    10,
+                    "group web",
+                    "GROUP base(clients=$clients)",
+                    "security group base has no parameter clients".to_string()
+                ),
+                (
+                    15,
+                    "group base",
+                    "GROUP missing",
+                    "unknown security group missing".to_string()
+                ),
+                (
+                    23,
+                    "group loop-b",
+                    "GROUP loop-a",
+                    "circular reference between security groups loop-a -> loop-b -> loop-a"
+                        .to_string()
+                ),
+            ]
+        );
+
+        let error = parse_error("[RULES]\n\nIN ACCEPT -source $clients\n");
+        assert_eq!(
+            error.error().to_string(),
+            "parameter $clients can only be used in security groups"
+        );
+
+        let error = parse_error("[RULES]\n\nGROUP web(clients=10.0.0.0/8 -i net0\n");
+        assert_eq!(error.token(), "web(clients=10.0.0.0/8");
+    }
+
+    #[test]
+    fn test_write_line_breaks() {
+        let mut config = Config::<Options>::parse(
+            "[RULES]\n\nIN ACCEPT -p tcp # ssh\n".as_bytes(),
+            &parser_config(),
+        )
+        .expect("valid config");
+
+        config.rules[0].comment = Some("ssh\nIN ACCEPT".to_string());
+        config
+            .write(io::sink())
+            .expect_err("comments cannot contain line breaks");
+
+        config.rules[0].comment = None;
+
+        let mut group = Group::new();
+        group.set_comment(Some("web\r\n[RULES]".to_string()));
+        config.groups.insert("web".to_string(), group);
+
+        config
+            .write(io::sink())
+            .expect_err("section comments cannot contain line breaks");
+    }
+
+    #[test]
+    fn test_write_unrepresentable_rules() {
+        let mut config = Config::<Options>::parse(
+            "[RULES]\n\nIN ACCEPT -p icmp -icmp-type port-unreachable\n".as_bytes(),
+            &parser_config(),
+        )
+        .expect("valid config");
+
+        config.write(io::sink()).expect("rule can be written");
+
+        let Kind::Match(rule) = &mut config.rules[0].kind else {
+            unreachable!("rule is a match rule");
+        };
+
+        rule.proto = Some(Protocol::Icmp(Icmp::new_ty_and_code(
+            IcmpType::Numeric(3),
+            IcmpCode::Numeric(99),
+        )));
+
+        config
+            .write(io::sink())
+            .expect_err("icmp type and code have no name");
+    }
+
+    #[test]
+    fn test_parse_collect_errors() {
+        const CONFIG: &str = r#"
+[OPTIONS]
+
+enable: 2
+policy_in: DROP
+
+[RULES]
+
+IN ACCEPT -p tcp -dport 22
+IN FOO -p tcp
+OUT ACCEPT -p udp -dport qwe
+
+[IPSET test
+
+10.0.0.0/8
+
+[ALIASES]
+
+valid 10.0.0.1
+"#;
+
+        let errors = Config::<Options>::parse_collect_errors(CONFIG.as_bytes(), &parser_config())
+            .expect_err("invalid config");
+
+        let locations: Vec<_> = errors
+            .iter()
+            .map(|error| (error.line(), error.column(), error.token()))
+            .collect();
+
+        assert_eq!(
+            locations,
+            vec![
+                (4, 9, "2"),
+                (10, 4, "FOO"),
+                (11, 19, "-dport qwe"),
+                (13, 1, "[IPSET test"),
+            ]
+        );
+
+        assert_eq!(
+            errors.with_file("cluster.fw").to_string().lines().nth(1),
+            Some("cluster.fw:10:4: in section [RULES]: invalid verdict \"FOO\", expected one of 'ACCEPT', 'REJECT' or 'DROP'")
+        );
+
+        Config::<Options>::parse_collect_errors(
+            "[OPTIONS]\n\nenable: 1\n".as_bytes(),
+            &parser_config(),
+        )
+        .expect("valid config");
+    }
 }
@@ -0,0 +1,361 @@
+//! Computation of the addresses that are effectively covered by a list of address entries.
+//!
+//! Entries can overlap, be adjacent or contain holes punched by `nomatch` entries. The
+//! [`EffectiveAddresses`] of such a list are the minimal list of CIDRs per address family that
+//! covers exactly the same addresses.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use proxmox_network_types::ip_address::{Cidr, Family, IpRange, Ipv4Cidr, Ipv6Cidr};
+
+use crate::firewall::types::address::IpEntry;
+
+/// A set of numbers, stored as sorted and non-overlapping inclusive intervals.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Intervals(pub(crate) Vec<(u128, u128)>);
+
+impl Intervals {
+    pub(crate) fn new(intervals: impl IntoIterator<Item = (u128, u128)>) -> Self {
+        let mut sorted: Vec<_> = intervals.into_iter().collect();
+        sorted.sort_unstable();
+
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(sorted.len());
+
+        for (start, end) in sorted {
+            match merged.last_mut() {
+                Some((_, last)) if start <= last.saturating_add(1) => *last = end.max(*last),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        Self(merged)
+    }
+
+    pub(crate) fn subtract(&self, other: &Intervals) -> Self {
+        let mut result = Vec::new();
+
+        for &(mut start, end) in &self.0 {
+            let mut empty = false;
+
+            for &(other_start, other_end) in &other.0 {
+                if other_end < start || other_start > end {
+                    continue;
+                }
+
+                if other_start > start {
+                    result.push((start, other_start - 1));
+                }
+
+                if other_end >= end {
+                    empty = true;
+                    break;
+                }
+
+                start = other_end + 1;
+            }
+
+            if !empty {
+                result.push((start, end));
+            }
+        }
+
+        Self(result)
+    }
+
+    pub(crate) fn is_subset(&self, other: &Intervals) -> bool {
+        self.0.iter().all(|(start, end)| {
+            other
+                .0
+                .iter()
+                .any(|(other_start, other_end)| other_start <= start && end <= other_end)
+        })
+    }
+
+    /// Splits the intervals into the minimal number of aligned prefixes, returned as the start
+    /// of the prefix and its length, for numbers with the given number of bits.
+    fn prefixes(&self, bits: u32) -> Vec<(u128, u8)> {
+        let mut prefixes = Vec::new();
+
+        for &(mut start, end) in &self.0 {
+            loop {
+                // the largest block starting at `start` that does not extend beyond `end`
+                let mut host_bits = start.trailing_zeros().min(bits);
+
+                let last = loop {
+                    let last = match 1u128.checked_shl(host_bits) {
+                        Some(size) => start | (size - 1),
+                        None => u128::MAX,
+                    };
+
+                    if last <= end {
+                        break last;
+                    }
+
+                    host_bits -= 1;
+                };
+
+                prefixes.push((start, (bits - host_bits) as u8));
+
+                if last >= end {
+                    break;
+                }
+
+                start = last + 1;
+            }
+        }
+
+        prefixes
+    }
+}
+
+/// Returns the address family of an entry and the interval of addresses it contains.
+pub(crate) fn ip_interval(entry: &IpEntry) -> (Family, u128, u128) {
+    match entry {
+        IpEntry::Cidr(Cidr::Ipv4(cidr)) => {
+            let host_bits = 32 - u32::from(cidr.mask());
+            let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+            let start = u32::from(*cidr.address()) & mask;
+            (Family::V4, start.into(), (start | !mask).into())
+        }
+        IpEntry::Cidr(Cidr::Ipv6(cidr)) => {
+            let host_bits = 128 - u32::from(cidr.mask());
+            let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+            let start = u128::from(*cidr.address()) & mask;
+            (Family::V6, start, start | !mask)
+        }
+        IpEntry::Range(IpRange::V4(range)) => (
+            Family::V4,
+            u32::from(*range.start()).into(),
+            u32::from(*range.last()).into(),
+        ),
+        IpEntry::Range(IpRange::V6(range)) => (
+            Family::V6,
+            u128::from(*range.start()),
+            u128::from(*range.last()),
+        ),
+    }
+}
+
+/// A set of addresses, split by address family.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct AddressSet {
+    pub(crate) v4: Intervals,
+    pub(crate) v6: Intervals,
+}
+
+impl AddressSet {
+    /// The set containing all addresses of both families.
+    pub(crate) fn all() -> Self {
+        Self {
+            v4: Intervals(vec![(0, u32::MAX.into())]),
+            v6: Intervals(vec![(0, u128::MAX)]),
+        }
+    }
+
+    fn from_entries(entries: &[IpEntry]) -> Self {
+        let intervals: Vec<_> = entries.iter().map(ip_interval).collect();
+
+        let family = |family: Family| {
+            Intervals::new(
+                intervals
+                    .iter()
+                    .filter(|(entry_family, _, _)| *entry_family == family)
+                    .map(|(_, start, end)| (*start, *end)),
+            )
+        };
+
+        Self {
+            v4: family(Family::V4),
+            v6: family(Family::V6),
+        }
+    }
+
+    /// The addresses contained in any of the `entries`, but in none of the `nomatch` entries.
+    pub(crate) fn new(entries: &[IpEntry], nomatch: &[IpEntry]) -> Self {
+        let entries = Self::from_entries(entries);
+        let nomatch = Self::from_entries(nomatch);
+
+        Self {
+            v4: entries.v4.subtract(&nomatch.v4),
+            v6: entries.v6.subtract(&nomatch.v6),
+        }
+    }
+
+    pub(crate) fn is_subset(&self, other: &AddressSet) -> bool {
+        self.v4.is_subset(&other.v4) && self.v6.is_subset(&other.v6)
+    }
+}
+
+/// The minimal list of CIDRs covering the same addresses as a list of entries.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EffectiveAddresses {
+    v4: Vec<Ipv4Cidr>,
+    v6: Vec<Ipv6Cidr>,
+}
+
+impl EffectiveAddresses {
+    /// Computes the addresses contained in any of the `entries`, but in none of the `nomatch`
+    /// entries.
+    pub fn new(entries: &[IpEntry], nomatch: &[IpEntry]) -> Self {
+        Self::from(AddressSet::new(entries, nomatch))
+    }
+
+    pub fn v4(&self) -> &[Ipv4Cidr] {
+        &self.v4
+    }
+
+    pub fn v6(&self) -> &[Ipv6Cidr] {
+        &self.v6
+    }
+
+    /// Returns the CIDRs of both families, IPv4 first, each family sorted by address.
+    pub fn cidrs(&self) -> impl Iterator<Item = Cidr> + '_ {
+        self.v4
+            .iter()
+            .copied()
+            .map(Cidr::from)
+            .chain(self.v6.iter().copied().map(Cidr::from))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    pub fn contains_address(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.v4.iter().any(|cidr| cidr.contains_address(ip)),
+            IpAddr::V6(ip) => self.v6.iter().any(|cidr| cidr.contains_address(ip)),
+        }
+    }
+
+    /// Returns the number of addresses of a family, saturating at `u128::MAX` for all IPv6
+    /// addresses.
+    pub fn address_count(&self, family: Family) -> u128 {
+        let (bits, masks): (u32, Vec<u8>) = match family {
+            Family::V4 => (32, self.v4.iter().map(Ipv4Cidr::mask).collect()),
+            Family::V6 => (128, self.v6.iter().map(Ipv6Cidr::mask).collect()),
+        };
+
+        masks.into_iter().fold(0u128, |count, mask| {
+            let size = 1u128
+                .checked_shl(bits - u32::from(mask))
+                .unwrap_or(u128::MAX);
+
+            count.saturating_add(size)
+        })
+    }
+}
+
+impl From<AddressSet> for EffectiveAddresses {
+    fn from(set: AddressSet) -> Self {
+        let v4 = set
+            .v4
+            .prefixes(32)
+            .into_iter()
+            .map(|(start, mask)| {
+                Ipv4Cidr::new(Ipv4Addr::from(start as u32), mask).expect("valid IPv4 prefix")
+            })
+            .collect();
+
+        let v6 = set
+            .v6
+            .prefixes(128)
+            .into_iter()
+            .map(|(start, mask)| {
+                Ipv6Cidr::new(Ipv6Addr::from(start), mask).expect("valid IPv6 prefix")
+            })
+            .collect();
+
+        Self { v4, v6 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(entries: &[&str]) -> Vec<IpEntry> {
+        entries
+            .iter()
+            .map(|entry| entry.parse().expect("valid IP entry"))
+            .collect()
+    }
+
+    fn cidrs(addresses: &EffectiveAddresses) -> Vec<String> {
+        addresses.cidrs().map(|cidr| cidr.to_string()).collect()
+    }
+
+    #[test]
+    fn test_intervals() {
+        let intervals = Intervals::new([(5, 10), (0, 2), (3, 4), (20, 30)]);
+        assert_eq!(intervals, Intervals(vec![(0, 10), (20, 30)]));
+
+        let subtracted = intervals.subtract(&Intervals::new([(2, 3), (10, 25)]));
+        assert_eq!(subtracted, Intervals(vec![(0, 1), (4, 9), (26, 30)]));
+
+        assert!(subtracted.is_subset(&intervals));
+        assert!(!intervals.is_subset(&subtracted));
+        assert!(Intervals::default().is_subset(&subtracted));
+
+        assert_eq!(
+            Intervals::new([(1, 6)]).prefixes(8),
+            vec![(1, 8), (2, 7), (4, 7), (6, 8)]
+        );
+        assert_eq!(Intervals::new([(0, 255)]).prefixes(8), vec![(0, 0)]);
+        assert_eq!(Intervals::new([(0, u128::MAX)]).prefixes(128), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_effective_addresses() {
+        // overlapping and adjacent entries are merged
+        let addresses = EffectiveAddresses::new(
+            &entries(&[
+                "10.0.1.0/24",
+                "10.0.0.0/24",
+                "10.0.0.128/25",
+                "10.0.2.0-10.0.3.255",
+                "fd00::/65",
+                "fd00:0:0:0:8000::/65",
+            ]),
+            &[],
+        );
+        assert_eq!(cidrs(&addresses), ["10.0.0.0/22", "fd00::/64"]);
+        assert_eq!(addresses.address_count(Family::V4), 1024);
+
+        // nomatch entries punch holes into the covered addresses
+        let addresses = EffectiveAddresses::new(
+            &entries(&["10.0.0.0/24", "192.168.0.1"]),
+            &entries(&["10.0.0.1", "192.168.0.0/16", "fd00::/8"]),
+        );
+        assert_eq!(
+            cidrs(&addresses),
+            [
+                "10.0.0.0/32",
+                "10.0.0.2/31",
+                "10.0.0.4/30",
+                "10.0.0.8/29",
+                "10.0.0.16/28",
+                "10.0.0.32/27",
+                "10.0.0.64/26",
+                "10.0.0.128/25",
+            ]
+        );
+        assert_eq!(addresses.address_count(Family::V4), 255);
+        assert!(addresses.v6().is_empty());
+        assert!(!addresses.contains_address(&"10.0.0.1".parse().unwrap()));
+        assert!(addresses.contains_address(&"10.0.0.2".parse().unwrap()));
+
+        // ranges that do not start on a prefix boundary
+        let addresses = EffectiveAddresses::new(&entries(&["10.0.0.5-10.0.0.12"]), &[]);
+        assert_eq!(
+            cidrs(&addresses),
+            ["10.0.0.5/32", "10.0.0.6/31", "10.0.0.8/30", "10.0.0.12/32"]
+        );
+
+        let addresses = EffectiveAddresses::new(&entries(&["0.0.0.0/0", "::/0"]), &[]);
+        assert_eq!(cidrs(&addresses), ["0.0.0.0/0", "::/0"]);
+        assert_eq!(addresses.address_count(Family::V6), u128::MAX);
+
+        assert!(EffectiveAddresses::new(&[], &entries(&["10.0.0.0/8"])).is_empty());
+    }
+}
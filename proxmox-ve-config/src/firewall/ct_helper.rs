@@ -1,4 +1,4 @@
-use anyhow::{bail, Error};
+use anyhow::{bail, format_err, Error};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::OnceLock;
@@ -11,6 +11,8 @@ pub struct CtHelperMacroJson {
     pub v4: Option<bool>,
     pub v6: Option<bool>,
     pub name: String,
+    /// The kernel conntrack helper to use, defaults to `name`
+    pub helper: Option<String>,
     pub tcp: Option<u16>,
     pub udp: Option<u16>,
 }
@@ -33,6 +35,7 @@ impl TryFrom<CtHelperMacroJson> for CtHelperMacro {
         let mut ct_helper = CtHelperMacro {
             family,
             name: value.name,
+            helper: value.helper,
             tcp: None,
             udp: None,
         };
@@ -56,6 +59,7 @@ impl TryFrom<CtHelperMacroJson> for CtHelperMacro {
 pub struct CtHelperMacro {
     family: Option<Family>,
     name: String,
+    helper: Option<String>,
     tcp: Option<Protocol>,
     udp: Option<Protocol>,
 }
@@ -81,6 +85,11 @@ impl CtHelperMacro {
         self.name.as_ref()
     }
 
+    /// The name of the kernel conntrack helper
+    pub fn helper(&self) -> &str {
+        self.helper.as_deref().unwrap_or(&self.name)
+    }
+
     pub fn tcp(&self) -> Option<&Protocol> {
         self.tcp.as_ref()
     }
@@ -110,6 +119,34 @@ fn hashmap() -> &'static HashMap<String, CtHelperMacro> {
     })
 }
 
+/// Parses user-defined CT helpers, whose names must not collide with a built-in helper.
+pub(crate) fn parse_user_cthelpers(
+    definitions: Vec<CtHelperMacroJson>,
+) -> Result<HashMap<String, CtHelperMacro>, Error> {
+    let mut parsed = HashMap::new();
+
+    for definition in definitions {
+        let name = definition.name.clone();
+
+        if name.is_empty() || name.contains(|c: char| c == ',' || c.is_whitespace()) {
+            bail!("invalid CT helper name '{name}'");
+        }
+
+        if hashmap().contains_key(&name) {
+            bail!("CT helper {name} conflicts with built-in CT helper");
+        }
+
+        let helper = CtHelperMacro::try_from(definition)
+            .map_err(|err| format_err!("invalid CT helper {name}: {err}"))?;
+
+        if parsed.insert(name.clone(), helper).is_some() {
+            bail!("duplicate CT helper {name}");
+        }
+    }
+
+    Ok(parsed)
+}
+
 pub fn get_cthelper(name: &str) -> Option<&'static CtHelperMacro> {
     hashmap().get(name)
 }
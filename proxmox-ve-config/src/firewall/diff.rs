@@ -0,0 +1,729 @@
+//! Differences between two versions of a firewall config.
+//!
+//! This is a two-way diff from an old to a new version of a config, there is no common base
+//! version and therefore no detection of conflicting changes.
+//!
+//! Rules are compared by what they match instead of by their text, so reformatting a rule or
+//! reordering its options does not show up as a change. A rule that only changed its comment or
+//! whether it is enabled is reported as modified, a rule at a different position as moved. A rule
+//! that was replaced by another one at the same position is reported as modified as well. Options
+//! are compared by their parsed values, so e.g. `enable: 1` and `enable: yes` are the same.
+//!
+//! A [`ConfigDiff`] can be displayed in a human-readable format or serialized to JSON.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::firewall::common::Config;
+use crate::firewall::types::ipset::IpsetFqdnEntry;
+use crate::firewall::types::rule::Kind;
+use crate::firewall::types::{Group, Ipset, Rule};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Moved,
+    Modified,
+}
+
+impl ChangeKind {
+    fn symbol(self) -> char {
+        match self {
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+            ChangeKind::Moved => '>',
+            ChangeKind::Modified => '~',
+        }
+    }
+}
+
+/// A changed option, alias or ipset entry, identified by its key.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<String>,
+}
+
+impl Change {
+    fn new(key: impl Into<String>, old: Option<String>, new: Option<String>) -> Self {
+        let kind = match (&old, &new) {
+            (None, _) => ChangeKind::Added,
+            (_, None) => ChangeKind::Removed,
+            _ => ChangeKind::Modified,
+        };
+
+        Self {
+            kind,
+            key: key.into(),
+            old,
+            new,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, "~ {}: {old} => {new}", self.key),
+            (Some(value), None) | (None, Some(value)) => {
+                write!(f, "{} {}: {value}", self.kind.symbol(), self.key)
+            }
+            (None, None) => write!(f, "{} {}", self.kind.symbol(), self.key),
+        }
+    }
+}
+
+/// A changed rule, positions are 1-based.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RuleChange {
+    pub kind: ChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<String>,
+}
+
+impl fmt::Display for RuleChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} rule", self.kind.symbol())?;
+
+        match (self.old_position, self.new_position) {
+            (Some(old), Some(new)) if old != new => write!(f, " {old} -> {new}")?,
+            (_, Some(position)) | (Some(position), None) => write!(f, " {position}")?,
+            (None, None) => (),
+        }
+
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) if old != new => write!(f, ": {old} => {new}"),
+            (_, Some(rule)) | (Some(rule), None) => write!(f, ": {rule}"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// The changes of an ipset, which is either added, removed or modified as a whole.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IpsetDiff {
+    pub name: String,
+    pub kind: ChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<Change>,
+    pub entries: Vec<Change>,
+}
+
+/// The changes of a security group, which is either added, removed or modified as a whole.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GroupDiff {
+    pub name: String,
+    pub kind: ChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<Change>,
+    pub rules: Vec<RuleChange>,
+}
+
+/// All changes between two versions of a firewall config.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigDiff {
+    pub options: Vec<Change>,
+    pub aliases: Vec<Change>,
+    pub ipsets: Vec<IpsetDiff>,
+    pub rules: Vec<RuleChange>,
+    pub groups: Vec<GroupDiff>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+            && self.aliases.is_empty()
+            && self.ipsets.is_empty()
+            && self.rules.is_empty()
+            && self.groups.is_empty()
+    }
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn section<T: fmt::Display>(
+            f: &mut fmt::Formatter,
+            header: &str,
+            kind: Option<ChangeKind>,
+            comment: Option<&Change>,
+            changes: &[T],
+        ) -> fmt::Result {
+            if changes.is_empty() && comment.is_none() && kind == Some(ChangeKind::Modified) {
+                return Ok(());
+            }
+
+            match kind {
+                Some(kind) => writeln!(f, "{} [{header}]", kind.symbol())?,
+                None => writeln!(f, "[{header}]")?,
+            }
+
+            if let Some(comment) = comment {
+                writeln!(f, "  {comment}")?;
+            }
+
+            for change in changes {
+                writeln!(f, "  {change}")?;
+            }
+
+            Ok(())
+        }
+
+        if !self.options.is_empty() {
+            section(f, "OPTIONS", None, None, &self.options)?;
+        }
+
+        if !self.aliases.is_empty() {
+            section(f, "ALIASES", None, None, &self.aliases)?;
+        }
+
+        for ipset in &self.ipsets {
+            let header = format!("IPSET {}", ipset.name);
+            section(
+                f,
+                &header,
+                Some(ipset.kind),
+                ipset.comment.as_ref(),
+                &ipset.entries,
+            )?;
+        }
+
+        if !self.rules.is_empty() {
+            section(f, "RULES", None, None, &self.rules)?;
+        }
+
+        for group in &self.groups {
+            let header = format!("group {}", group.name);
+            section(
+                f,
+                &header,
+                Some(group.kind),
+                group.comment.as_ref(),
+                &group.rules,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn diff_configs<O>(old: &Config<O>, new: &Config<O>) -> ConfigDiff
+where
+    O: Default + fmt::Debug + serde::de::DeserializeOwned + PartialEq,
+{
+    ConfigDiff {
+        options: diff_options(old, new),
+        aliases: diff_aliases(old, new),
+        ipsets: diff_ipsets(old, new),
+        rules: diff_rules(&old.rules, &new.rules),
+        groups: diff_groups(old, new),
+    }
+}
+
+fn diff_options<O>(old: &Config<O>, new: &Config<O>) -> Vec<Change>
+where
+    O: Default + fmt::Debug + serde::de::DeserializeOwned + PartialEq,
+{
+    let old_options: HashMap<&str, &str> = old
+        .raw_options
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+
+    let new_options: HashMap<&str, &str> = new
+        .raw_options
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+
+    let keys: BTreeSet<&str> = old_options
+        .keys()
+        .chain(new_options.keys())
+        .copied()
+        .collect();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let old_value = old_options.get(key).copied();
+            let new_value = new_options.get(key).copied();
+
+            if let (Some(old_value), Some(new_value)) = (old_value, new_value) {
+                let parse = |value| Config::<O>::parse_single_option(key, value).ok();
+
+                if old_value == new_value
+                    || parse(old_value).is_some_and(|old| Some(old) == parse(new_value))
+                {
+                    return None;
+                }
+            }
+
+            Some(Change::new(
+                key,
+                old_value.map(str::to_string),
+                new_value.map(str::to_string),
+            ))
+        })
+        .collect()
+}
+
+fn diff_aliases<O>(old: &Config<O>, new: &Config<O>) -> Vec<Change>
+where
+    O: Default + fmt::Debug + serde::de::DeserializeOwned,
+{
+    let mut old_aliases = old.alias_lines();
+    let mut new_aliases = new.alias_lines();
+
+    let names: BTreeSet<&str> = old_aliases
+        .keys()
+        .chain(new_aliases.keys())
+        .copied()
+        .collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old_alias = old_aliases.remove(name);
+            let new_alias = new_aliases.remove(name);
+
+            (old_alias != new_alias).then(|| Change::new(name, old_alias, new_alias))
+        })
+        .collect()
+}
+
+fn diff_comment(old: Option<&str>, new: Option<&str>) -> Option<Change> {
+    (old != new).then(|| Change::new("comment", old.map(str::to_string), new.map(str::to_string)))
+}
+
+fn diff_ipset_entries(old: Option<&Ipset>, new: Option<&Ipset>) -> Vec<Change> {
+    // entries are identified by their address, so toggling nomatch is a modification
+    let entries = |ipset: Option<&Ipset>| -> Vec<(String, String)> {
+        let Some(ipset) = ipset else {
+            return Vec::new();
+        };
+
+        let fqdns = ipset.fqdns().map(|entry| {
+            (
+                format!("{}{}", IpsetFqdnEntry::PREFIX, entry.fqdn),
+                entry.to_string(),
+            )
+        });
+
+        ipset
+            .iter()
+            .map(|entry| (entry.address.to_string(), entry.to_string()))
+            .chain(fqdns)
+            .collect()
+    };
+
+    let old_entries = entries(old);
+    let new_entries = entries(new);
+
+    let old_map: HashMap<&str, &str> = old_entries
+        .iter()
+        .map(|(address, entry)| (address.as_str(), entry.as_str()))
+        .collect();
+
+    let new_map: HashMap<&str, &str> = new_entries
+        .iter()
+        .map(|(address, entry)| (address.as_str(), entry.as_str()))
+        .collect();
+
+    let mut changes = Vec::new();
+
+    for (address, entry) in &old_entries {
+        match new_map.get(address.as_str()) {
+            None => changes.push(Change::new(address, Some(entry.clone()), None)),
+            Some(new_entry) if new_entry != entry => changes.push(Change::new(
+                address,
+                Some(entry.clone()),
+                Some(new_entry.to_string()),
+            )),
+            Some(_) => (),
+        }
+    }
+
+    for (address, entry) in &new_entries {
+        if !old_map.contains_key(address.as_str()) {
+            changes.push(Change::new(address, None, Some(entry.clone())));
+        }
+    }
+
+    changes
+}
+
+fn diff_ipsets<O>(old: &Config<O>, new: &Config<O>) -> Vec<IpsetDiff>
+where
+    O: Default + fmt::Debug + serde::de::DeserializeOwned,
+{
+    let names: BTreeSet<&String> = old.ipsets.keys().chain(new.ipsets.keys()).collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old_ipset = old.ipsets.get(name);
+            let new_ipset = new.ipsets.get(name);
+
+            let kind = section_kind(old_ipset.is_some(), new_ipset.is_some());
+            let comment = diff_comment(
+                old_ipset.and_then(|ipset| ipset.comment.as_deref()),
+                new_ipset.and_then(|ipset| ipset.comment.as_deref()),
+            );
+            let entries = diff_ipset_entries(old_ipset, new_ipset);
+
+            (kind != ChangeKind::Modified || comment.is_some() || !entries.is_empty()).then(|| {
+                IpsetDiff {
+                    name: name.clone(),
+                    kind,
+                    comment,
+                    entries,
+                }
+            })
+        })
+        .collect()
+}
+
+fn diff_groups<O>(old: &Config<O>, new: &Config<O>) -> Vec<GroupDiff>
+where
+    O: Default + fmt::Debug + serde::de::DeserializeOwned,
+{
+    let names: BTreeSet<&String> = old.groups.keys().chain(new.groups.keys()).collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old_group = old.groups.get(name);
+            let new_group = new.groups.get(name);
+
+            let kind = section_kind(old_group.is_some(), new_group.is_some());
+            let comment = diff_comment(
+                old_group.and_then(Group::comment),
+                new_group.and_then(Group::comment),
+            );
+            let rules = diff_rules(
+                old_group
+                    .map(|group| group.rules().as_slice())
+                    .unwrap_or_default(),
+                new_group
+                    .map(|group| group.rules().as_slice())
+                    .unwrap_or_default(),
+            );
+
+            (kind != ChangeKind::Modified || comment.is_some() || !rules.is_empty()).then(|| {
+                GroupDiff {
+                    name: name.clone(),
+                    kind,
+                    comment,
+                    rules,
+                }
+            })
+        })
+        .collect()
+}
+
+fn section_kind(old: bool, new: bool) -> ChangeKind {
+    match (old, new) {
+        (false, _) => ChangeKind::Added,
+        (_, false) => ChangeKind::Removed,
+        _ => ChangeKind::Modified,
+    }
+}
+
+/// The canonical form of what a rule matches, without its comment and enabled state.
+fn rule_key(rule: &Rule) -> String {
+    match rule.kind() {
+        Kind::Group(group) => group.to_string(),
+        Kind::Match(rule) => rule.to_string(),
+    }
+}
+
+/// Pairs up old and new rules with the same key in order, reports pairs that are out of order
+/// relative to the other pairs as moved.
+///
+/// The remaining rules are paired up by their position between the rules that are in order, so a
+/// rule whose match changed is reported as modified instead of as removed and added.
+fn diff_rules(old: &[Rule], new: &[Rule]) -> Vec<RuleChange> {
+    let mut unmatched: HashMap<String, Vec<usize>> = HashMap::new();
+
+    for (index, rule) in new.iter().enumerate().rev() {
+        unmatched.entry(rule_key(rule)).or_default().push(index);
+    }
+
+    let mut pairs = Vec::new();
+    let mut removed = Vec::new();
+
+    for (index, rule) in old.iter().enumerate() {
+        match unmatched.get_mut(&rule_key(rule)).and_then(Vec::pop) {
+            Some(new_index) => pairs.push((index, new_index)),
+            None => removed.push(index),
+        }
+    }
+
+    let in_order: Vec<(usize, usize)> = longest_increasing(&pairs)
+        .into_iter()
+        .map(|pair| pairs[pair])
+        .collect();
+
+    let mut added: Vec<usize> = unmatched.into_values().flatten().collect();
+    added.sort_unstable();
+
+    let mut modified = Vec::new();
+
+    removed.retain(|&old_index| {
+        let gap = in_order.partition_point(|(index, _)| *index < old_index);
+
+        let position = added.iter().position(|&new_index| {
+            in_order.partition_point(|(_, index)| *index < new_index) == gap
+                && is_group(&old[old_index]) == is_group(&new[new_index])
+        });
+
+        match position {
+            Some(position) => {
+                modified.push((old_index, added.remove(position)));
+                false
+            }
+            None => true,
+        }
+    });
+
+    pairs.extend(modified);
+    pairs.sort_unstable();
+
+    let mut changes = Vec::new();
+
+    for index in removed {
+        changes.push(RuleChange {
+            kind: ChangeKind::Removed,
+            old_position: Some(index + 1),
+            new_position: None,
+            old: Some(old[index].to_string()),
+            new: None,
+        });
+    }
+
+    for (old_index, new_index) in pairs {
+        let old_rule = old[old_index].to_string();
+        let new_rule = new[new_index].to_string();
+
+        let kind = if old_rule != new_rule {
+            ChangeKind::Modified
+        } else if in_order.binary_search(&(old_index, new_index)).is_err() {
+            ChangeKind::Moved
+        } else {
+            continue;
+        };
+
+        changes.push(RuleChange {
+            kind,
+            old_position: Some(old_index + 1),
+            new_position: Some(new_index + 1),
+            old: Some(old_rule),
+            new: Some(new_rule),
+        });
+    }
+
+    for index in added {
+        changes.push(RuleChange {
+            kind: ChangeKind::Added,
+            old_position: None,
+            new_position: Some(index + 1),
+            old: None,
+            new: Some(new[index].to_string()),
+        });
+    }
+
+    changes
+}
+
+fn is_group(rule: &Rule) -> bool {
+    matches!(rule.kind(), Kind::Group(_))
+}
+
+/// Returns the indices of the longest subsequence of `pairs` whose second elements are
+/// increasing, `pairs` has to be ordered by its first elements.
+fn longest_increasing(pairs: &[(usize, usize)]) -> BTreeSet<usize> {
+    // tails[len] is the index of the pair ending the best subsequence of length len + 1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; pairs.len()];
+
+    for (index, (_, value)) in pairs.iter().enumerate() {
+        let len = tails.partition_point(|&tail| pairs[tail].1 < *value);
+
+        previous[index] = len.checked_sub(1).map(|len| tails[len]);
+
+        if len == tails.len() {
+            tails.push(index);
+        } else {
+            tails[len] = index;
+        }
+    }
+
+    let mut result = BTreeSet::new();
+    let mut current = tails.last().copied();
+
+    while let Some(index) = current {
+        result.insert(index);
+        current = previous[index];
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::firewall::cluster::Config as ClusterConfig;
+
+    use super::*;
+
+    const OLD_CONFIG: &str = r#"
+[OPTIONS]
+
+enable: 1
+policy_in: DROP
+log_ratelimit: enable=1,rate=1/second
+
+[ALIASES]
+
+gateway 10.0.0.1
+dns 10.0.0.53 # resolver
+
+[IPSET management] # admins
+
+10.0.0.0/24
+!10.0.0.5
+192.168.0.0/24
+
+[RULES]
+
+OUT DROP -dest gateway
+IN SSH(ACCEPT) -source +management
+IN ACCEPT -p tcp -dport 80
+IN ACCEPT -p tcp -dport 443 # https
+IN DROP -p udp
+IN REJECT -source dns
+
+[group web]
+
+IN ACCEPT -p tcp -dport 80
+
+[group old]
+
+IN DROP
+"#;
+
+    const NEW_CONFIG: &str = r#"
+[OPTIONS]
+
+enable: yes
+policy_in: ACCEPT
+
+[ALIASES]
+
+gateway 10.0.0.254
+dns 10.0.0.53 # resolver
+ntp 10.0.0.123
+
+[IPSET management] # admins
+
+10.0.0.0/24
+10.0.0.5
+172.16.0.0/12
+
+[RULES]
+
+IN DROP -p udp
+IN SSH(ACCEPT)   -source +management
+IN ACCEPT -dport 80 -p tcp
+|IN ACCEPT -p tcp -dport 443 # https is disabled
+IN ACCEPT -p tcp -dport 8080
+
+[group web] # web servers
+
+IN ACCEPT -p tcp -dport 80
+IN ACCEPT -p tcp -dport 443
+"#;
+
+    #[test]
+    fn test_diff() {
+        let old = ClusterConfig::parse(OLD_CONFIG.as_bytes()).expect("valid cluster config");
+        let new = ClusterConfig::parse(NEW_CONFIG.as_bytes()).expect("valid cluster config");
+
+        assert!(old.diff(&old).is_empty());
+
+        let diff = old.diff(&new);
+
+        assert_eq!(
+            diff.to_string(),
+            "\
+[OPTIONS]
+  - log_ratelimit: enable=1,rate=1/second
+  ~ policy_in: DROP => ACCEPT
+[ALIASES]
+  ~ gateway: gateway 10.0.0.1/32 => gateway 10.0.0.254/32
+  + ntp: ntp 10.0.0.123/32
+~ [IPSET management]
+  ~ 10.0.0.5/32: !10.0.0.5/32 => 10.0.0.5/32
+  - 192.168.0.0/24: 192.168.0.0/24
+  + 172.16.0.0/12: 172.16.0.0/12
+[RULES]
+  - rule 1: OUT DROP -dest gateway
+  ~ rule 4: IN ACCEPT -p tcp -dport 443 # https => |IN ACCEPT -p tcp -dport 443 # https is disabled
+  > rule 5 -> 1: IN DROP -p udp
+  ~ rule 6 -> 5: IN REJECT -source dns => IN ACCEPT -p tcp -dport 8080
+- [group old]
+  - rule 1: IN DROP
+~ [group web]
+  + comment: web servers
+  + rule 2: IN ACCEPT -p tcp -dport 443
+"
+        );
+
+        let json = serde_json::to_value(&diff).expect("diff can be serialized");
+
+        assert_eq!(
+            json["rules"][2],
+            serde_json::json!({
+                "kind": "moved",
+                "old-position": 5,
+                "new-position": 1,
+                "old": "IN DROP -p udp",
+                "new": "IN DROP -p udp",
+            })
+        );
+
+        assert_eq!(
+            json["ipsets"][0]["entries"][1],
+            serde_json::json!({
+                "kind": "removed",
+                "key": "192.168.0.0/24",
+                "old": "192.168.0.0/24",
+            })
+        );
+    }
+
+    #[test]
+    fn test_longest_increasing() {
+        assert_eq!(
+            longest_increasing(&[(0, 3), (1, 0), (2, 1), (3, 2)]),
+            BTreeSet::from([1, 2, 3])
+        );
+        assert_eq!(longest_increasing(&[]), BTreeSet::new());
+    }
+}
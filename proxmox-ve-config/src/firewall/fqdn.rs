@@ -0,0 +1,250 @@
+//! Resolution of the domain names contained in aliases and ipsets.
+//!
+//! Domain names are never looked up while resolving rules or generating a ruleset. Instead, a
+//! [`FqdnCache`] is refreshed with a [`FqdnResolver`] beforehand, for example periodically by a
+//! daemon, and handed to the [`RuleResolver`]. If looking up a name fails, the last known
+//! addresses stay in use, so a DNS outage does not change the generated rules.
+//!
+//! Names without any known addresses match nothing.
+//!
+//! [`RuleResolver`]: crate::firewall::resolve::RuleResolver
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use proxmox_network_types::ip_address::Cidr;
+
+use crate::firewall::types::address::Fqdn;
+
+/// Lower bound for the time the addresses of a name are used before looking it up again, in
+/// seconds.
+pub const FQDN_MIN_TTL: u32 = 60;
+
+/// The result of looking up a domain name.
+#[derive(Clone, Debug, Default)]
+pub struct Resolution {
+    pub addresses: Vec<Cidr>,
+    /// How long the addresses are valid, in seconds.
+    pub ttl: u32,
+}
+
+/// Looks up the addresses of domain names.
+pub trait FqdnResolver {
+    fn resolve(&self, name: &Fqdn) -> Result<Resolution, Error>;
+}
+
+/// The last known addresses of a domain name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+#[serde(rename_all = "kebab-case")]
+pub struct FqdnEntry {
+    addresses: Vec<Cidr>,
+    /// The time of the last successful lookup, as UNIX epoch.
+    resolved_at: i64,
+    ttl: u32,
+}
+
+impl FqdnEntry {
+    pub fn new(addresses: Vec<Cidr>, resolved_at: i64, ttl: u32) -> Self {
+        Self {
+            addresses,
+            resolved_at,
+            ttl,
+        }
+    }
+
+    pub fn addresses(&self) -> &[Cidr] {
+        &self.addresses
+    }
+
+    pub fn resolved_at(&self) -> i64 {
+        self.resolved_at
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        now >= self.resolved_at + i64::from(self.ttl)
+    }
+}
+
+/// The last known addresses of the domain names referenced in the firewall configs.
+///
+/// The cache can be serialized, so the addresses survive restarts and can be used without any
+/// network access.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct FqdnCache {
+    entries: BTreeMap<Fqdn, FqdnEntry>,
+}
+
+impl FqdnCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &Fqdn) -> Option<&FqdnEntry> {
+        self.entries.get(name)
+    }
+
+    pub fn insert(&mut self, name: Fqdn, entry: FqdnEntry) {
+        self.entries.insert(name, entry);
+    }
+
+    /// Returns the last known addresses of a name, which are empty if it was never resolved.
+    pub fn addresses(&self, name: &Fqdn) -> &[Cidr] {
+        self.entries
+            .get(name)
+            .map(FqdnEntry::addresses)
+            .unwrap_or_default()
+    }
+
+    /// Looks up all names that are not cached yet or whose TTL has expired.
+    ///
+    /// Names that fail to resolve keep their last known addresses, the errors are returned
+    /// together with the name. Cached names that are not contained in `names` anymore are
+    /// removed.
+    pub fn refresh<'a>(
+        &mut self,
+        resolver: &dyn FqdnResolver,
+        names: impl IntoIterator<Item = &'a Fqdn>,
+        now: i64,
+    ) -> Vec<(Fqdn, Error)> {
+        let names: BTreeSet<&Fqdn> = names.into_iter().collect();
+
+        self.entries.retain(|name, _| names.contains(name));
+
+        let mut errors = Vec::new();
+
+        for name in names {
+            if self
+                .entries
+                .get(name)
+                .is_some_and(|entry| !entry.is_expired(now))
+            {
+                continue;
+            }
+
+            match resolver.resolve(name) {
+                Ok(resolution) => {
+                    let entry =
+                        FqdnEntry::new(resolution.addresses, now, resolution.ttl.max(FQDN_MIN_TTL));
+
+                    self.entries.insert(name.clone(), entry);
+                }
+                Err(err) => errors.push((name.clone(), err)),
+            }
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::format_err;
+
+    use super::*;
+
+    /// A resolver returning fixed addresses, without any network access.
+    #[derive(Default)]
+    pub(crate) struct StaticResolver {
+        names: HashMap<String, Vec<Cidr>>,
+        ttl: u32,
+    }
+
+    impl StaticResolver {
+        pub(crate) fn new(names: &[(&str, &[&str])], ttl: u32) -> Self {
+            let names = names
+                .iter()
+                .map(|(name, addresses)| {
+                    let addresses = addresses
+                        .iter()
+                        .map(|address| address.parse().expect("valid CIDR"))
+                        .collect();
+
+                    (name.to_string(), addresses)
+                })
+                .collect();
+
+            Self { names, ttl }
+        }
+    }
+
+    impl FqdnResolver for StaticResolver {
+        fn resolve(&self, name: &Fqdn) -> Result<Resolution, Error> {
+            let addresses = self
+                .names
+                .get(name.as_str())
+                .ok_or_else(|| format_err!("{name}: no such domain"))?;
+
+            Ok(Resolution {
+                addresses: addresses.clone(),
+                ttl: self.ttl,
+            })
+        }
+    }
+
+    fn fqdn(name: &str) -> Fqdn {
+        name.parse().expect("valid domain name")
+    }
+
+    fn cidr(cidr: &str) -> Cidr {
+        cidr.parse().expect("valid CIDR")
+    }
+
+    #[test]
+    fn test_fqdn_cache() {
+        let api = fqdn("api.vendor.com");
+        let repo = fqdn("repo.example.com");
+
+        let resolver = StaticResolver::new(
+            &[
+                ("api.vendor.com", &["198.51.100.1", "2001:db8::1"]),
+                ("repo.example.com", &["203.0.113.10"]),
+            ],
+            300,
+        );
+
+        let mut cache = FqdnCache::new();
+        assert!(cache.addresses(&api).is_empty());
+
+        let errors = cache.refresh(&resolver, [&api, &repo], 1000);
+        assert!(errors.is_empty());
+        assert_eq!(
+            cache.addresses(&api),
+            &[cidr("198.51.100.1/32"), cidr("2001:db8::1/128")]
+        );
+        assert_eq!(cache.get(&repo).unwrap().resolved_at(), 1000);
+
+        // the vendor changed its addresses, but the old ones are valid until the TTL expires
+        let resolver = StaticResolver::new(&[("api.vendor.com", &["198.51.100.2"])], 0);
+
+        assert!(cache.refresh(&resolver, [&api, &repo], 1200).is_empty());
+        assert_eq!(cache.addresses(&api).len(), 2);
+
+        let errors = cache.refresh(&resolver, [&api, &repo], 1300);
+        assert_eq!(cache.addresses(&api), &[cidr("198.51.100.2/32")]);
+        assert_eq!(cache.get(&api).unwrap().ttl(), FQDN_MIN_TTL);
+
+        // failed lookups keep the last known addresses
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, repo);
+        assert_eq!(cache.addresses(&repo), &[cidr("203.0.113.10/32")]);
+
+        // names that are not referenced anymore are dropped
+        cache.refresh(&resolver, [&api], 1300);
+        assert!(cache.get(&repo).is_none());
+
+        let serialized = serde_json::to_string(&cache).expect("cache can be serialized");
+        let deserialized: FqdnCache =
+            serde_json::from_str(&serialized).expect("cache can be deserialized");
+        assert_eq!(deserialized.get(&api), cache.get(&api));
+    }
+}
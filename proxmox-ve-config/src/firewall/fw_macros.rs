@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 
+use anyhow::{bail, format_err, Error};
 use serde::Deserialize;
 use std::sync::OnceLock;
 
+use crate::firewall::parse::match_name;
+use crate::firewall::types::rule::ACTIONS_WITH_VALUE;
 use crate::firewall::types::rule_match::Protocol;
 
 use super::types::rule_match::RuleOptions;
@@ -14,6 +17,36 @@ struct FwMacroData {
     pub code: Vec<RuleOptions>,
 }
 
+/// A single protocol entry of a user-defined macro, using the same option names as a rule.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MacroEntry {
+    pub proto: String,
+    pub dport: Option<String>,
+    pub sport: Option<String>,
+    pub icmp_type: Option<String>,
+}
+
+impl From<MacroEntry> for RuleOptions {
+    fn from(entry: MacroEntry) -> Self {
+        Self {
+            proto: Some(entry.proto),
+            dport: entry.dport,
+            sport: entry.sport,
+            icmp_type: entry.icmp_type,
+            ..Default::default()
+        }
+    }
+}
+
+/// A user-defined macro, in the same format as the built-in macro definitions.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MacroDefinition {
+    #[serde(rename = "desc", default)]
+    pub description: String,
+    pub code: Vec<MacroEntry>,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct FwMacro {
     pub _description: &'static str,
@@ -67,3 +100,75 @@ fn macros() -> &'static HashMap<String, FwMacro> {
 pub fn get_macro(name: &str) -> Option<&'static FwMacro> {
     macros().get(name)
 }
+
+/// A user-defined macro, see [`Overlay`](crate::firewall::overlay::Overlay).
+#[derive(Clone, Debug)]
+pub struct UserMacro {
+    description: String,
+    code: Vec<Protocol>,
+}
+
+impl UserMacro {
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn code(&self) -> &[Protocol] {
+        &self.code
+    }
+}
+
+/// Parses user-defined macros.
+///
+/// Every entry is checked the same way as the options of a rule. Macro names must be valid
+/// in a rule and must not collide with a built-in macro, regardless of case.
+pub(crate) fn parse_user_macros(
+    definitions: HashMap<String, MacroDefinition>,
+) -> Result<HashMap<String, UserMacro>, Error> {
+    let builtin = macros();
+    let mut parsed = HashMap::new();
+
+    for (name, definition) in definitions {
+        if !matches!(match_name(&name), Some((_, ""))) {
+            bail!("invalid macro name '{name}'");
+        }
+
+        if ACTIONS_WITH_VALUE
+            .iter()
+            .any(|verdict| verdict.eq_ignore_ascii_case(&name))
+        {
+            bail!("macro {name} conflicts with action {}", name.to_uppercase());
+        }
+
+        if let Some(other) = builtin
+            .keys()
+            .find(|other| other.eq_ignore_ascii_case(&name))
+        {
+            bail!("macro {name} conflicts with built-in macro {other}");
+        }
+
+        if definition.code.is_empty() {
+            bail!("macro {name} does not contain any entries");
+        }
+
+        let code = definition
+            .code
+            .into_iter()
+            .map(|entry| {
+                Protocol::from_options(&entry.into())?
+                    .ok_or_else(|| format_err!("entry without protocol"))
+            })
+            .collect::<Result<Vec<_>, Error>>()
+            .map_err(|err| format_err!("invalid entry in macro {name}: {err}"))?;
+
+        parsed.insert(
+            name,
+            UserMacro {
+                description: definition.description,
+                code,
+            },
+        );
+    }
+
+    Ok(parsed)
+}
@@ -4,13 +4,17 @@ use std::io;
 use crate::guest::types::Vmid;
 use crate::guest::vm::NetworkConfig;
 
+use crate::firewall::common::{ParseError, ParseErrors, ParserConfig, RuleIndex};
+use crate::firewall::diff::ConfigDiff;
+use crate::firewall::types::address::Fqdn;
 use crate::firewall::types::alias::Alias;
 use crate::firewall::types::ipset::IpsetScope;
 use crate::firewall::types::log::LogLevel;
-use crate::firewall::types::rule::{Direction, Rule, Verdict};
+use crate::firewall::types::rule::{Direction, Kind, Rule, Verdict};
+use crate::firewall::types::rule_match::RuleMatch;
 use crate::firewall::types::Ipset;
 
-use anyhow::{bail, Error};
+use anyhow::{bail, format_err, Error};
 use serde::Deserialize;
 
 /// default return value for [`Config::is_enabled()`]
@@ -32,8 +36,7 @@ pub const GUEST_POLICY_OUT_DEFAULT: Verdict = Verdict::Accept;
 /// default return value for [`Config::default_policy()`]
 pub const GUEST_POLICY_FORWARD_DEFAULT: Verdict = Verdict::Accept;
 
-#[derive(Debug, Default, Deserialize)]
-#[cfg_attr(test, derive(Eq, PartialEq))]
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
 pub struct Options {
     #[serde(default, deserialize_with = "proxmox_serde::perl::deserialize_bool")]
     dhcp: Option<bool>,
@@ -73,7 +76,7 @@ pub struct Config {
     iface_prefix: &'static str,
 
     network_config: NetworkConfig,
-    config: super::common::Config<Options>,
+    pub(crate) config: super::common::Config<Options>,
 }
 
 impl Config {
@@ -83,25 +86,153 @@ impl Config {
         firewall_input: T,
         network_input: U,
     ) -> Result<Self, Error> {
-        let parser_cfg = super::common::ParserConfig {
-            guest_iface_names: true,
-            ipset_scope: Some(IpsetScope::Guest),
-            allowed_directions: vec![Direction::In, Direction::Out],
-        };
-
-        let config = super::common::Config::parse(firewall_input, &parser_cfg)?;
-        if !config.groups.is_empty() {
-            bail!("guest firewall config cannot declare groups");
-        }
+        Self::parse_inner(vmid, iface_prefix, firewall_input, network_input, false)
+            .map_err(ParseErrors::into_first)
+    }
 
-        let network_config = NetworkConfig::parse(network_input)?;
+    /// Parses the config like [`Config::parse`], but returns all errors in the firewall config
+    /// as [`ParseErrors`] instead of only the first one.
+    ///
+    /// An invalid network config is reported as an error that is not tied to a line of the
+    /// firewall config.
+    pub fn parse_collect_errors<T: io::BufRead, U: io::BufRead>(
+        vmid: &Vmid,
+        iface_prefix: &'static str,
+        firewall_input: T,
+        network_input: U,
+    ) -> Result<Self, ParseErrors> {
+        Self::parse_inner(vmid, iface_prefix, firewall_input, network_input, true)
+    }
 
-        Ok(Self {
+    fn parse_inner<T: io::BufRead, U: io::BufRead>(
+        vmid: &Vmid,
+        iface_prefix: &'static str,
+        firewall_input: T,
+        network_input: U,
+        collect_errors: bool,
+    ) -> Result<Self, ParseErrors> {
+        let (config, positions) = super::common::Config::parse_inner(
+            firewall_input,
+            &Self::parser_config(),
+            collect_errors,
+        )?;
+
+        let network_config = NetworkConfig::parse(network_input).map_err(|err| {
+            let mut errors = ParseErrors::default();
+            errors.push(ParseError::new(
+                0,
+                0,
+                None,
+                "",
+                err.context("invalid network config"),
+            ));
+            errors
+        })?;
+
+        let config = Self {
             vmid: *vmid,
             iface_prefix,
             config,
             network_config,
-        })
+        };
+
+        let mut errors = ParseErrors::default();
+
+        for (index, err) in config.check_network_devices() {
+            errors.push(positions.error(RuleIndex::Rules(index), err));
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(config)
+    }
+
+    /// Checks the rules matching on a source MAC address or a VLAN ID against the network devices
+    /// of the guest.
+    ///
+    /// The network device a rule is restricted to has to exist. If the MAC filter is enabled,
+    /// outgoing packets can only have the MAC address of a network device of the guest, so rules
+    /// matching on any other source MAC address would never match.
+    ///
+    /// Whether the VLAN of a rule reaches the guest is not checked here, since it depends on the
+    /// trunks of the network devices, which can change independently of the firewall config. See
+    /// [`lint_vlans`](crate::firewall::lint::lint_vlans) instead.
+    ///
+    /// Returns the errors together with the index of the rule they were found in.
+    fn check_network_devices(&self) -> Vec<(usize, Error)> {
+        self.config
+            .rules
+            .iter()
+            .enumerate()
+            .filter_map(|(index, rule)| match rule.kind() {
+                Kind::Match(rule) => self.check_rule_devices(rule).err().map(|err| (index, err)),
+                Kind::Group(_) => None,
+            })
+            .collect()
+    }
+
+    fn check_rule_devices(&self, rule: &RuleMatch) -> Result<(), Error> {
+        if rule.smac().is_none() && rule.vlan().is_none() {
+            return Ok(());
+        }
+
+        let devices = match rule.iface() {
+            Some(iface) => {
+                let index = NetworkConfig::index_from_net_key(iface)?;
+
+                let device = self
+                    .network_config
+                    .network_devices()
+                    .get(&index)
+                    .ok_or_else(|| {
+                        format_err!("rule uses {iface}, but the guest has no such network device")
+                    })?;
+
+                vec![device]
+            }
+            None => self.network_config.network_devices().values().collect(),
+        };
+
+        if let Some(smac) = rule.smac() {
+            if rule.direction() == Direction::Out
+                && self.macfilter()
+                && !devices.iter().any(|device| device.mac_address() == smac)
+            {
+                bail!(
+                    "source MAC address {smac} does not belong to {}, outgoing packets are \
+                    dropped by the MAC filter",
+                    rule.iface().unwrap_or("any network device of the guest"),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Guest firewall configs cannot declare groups.
+    fn parser_config() -> ParserConfig {
+        ParserConfig {
+            guest_iface_names: true,
+            ipset_scope: Some(IpsetScope::Guest),
+            allowed_directions: vec![Direction::In, Direction::Out],
+            allow_aliases: true,
+            allow_groups: false,
+            allow_smac_vlan: true,
+        }
+    }
+
+    /// Writes the firewall part of the config in the `<vmid>.fw` format.
+    ///
+    /// The network config of the guest is not written, since it is stored in the guest config.
+    pub fn write<W: io::Write>(&self, output: W) -> Result<(), Error> {
+        self.config.write(output)
+    }
+
+    /// Computes the changes from this config to `new`.
+    pub fn diff(&self, new: &Self) -> ConfigDiff {
+        self.config.diff(&new.config)
     }
 
     pub fn vmid(&self) -> Vmid {
@@ -116,6 +247,11 @@ impl Config {
         self.config.alias(name)
     }
 
+    /// Returns the domain names in the aliases and ipsets of the guest.
+    pub fn fqdns(&self) -> Vec<&Fqdn> {
+        self.config.fqdns()
+    }
+
     pub fn iface_name_by_key(&self, key: &str) -> Result<String, Error> {
         let index = NetworkConfig::index_from_net_key(key)?;
         Ok(format!("{}{}i{index}", self.iface_prefix, self.vmid))
@@ -248,6 +384,17 @@ policy_forward: DROP
                 policy_forward: Some(Verdict::Drop),
             }
         );
+
+        let mut written = Vec::new();
+        config.write(&mut written).expect("can write config");
+        let reparsed = Config::parse(
+            &Vmid::new(100),
+            "tap",
+            written.as_slice(),
+            network_config.as_slice(),
+        )
+        .expect("written config is valid");
+        assert_eq!(config.config, reparsed.config);
     }
 
     #[test]
@@ -290,6 +437,102 @@ OUT ACCEPT -p udp -dport 33 -sport 22 -log warning
         Config::parse(&Vmid::new(100), "tap", config, network_config.as_slice()).unwrap();
     }
 
+    #[test]
+    fn test_parse_smac_vlan() {
+        const NETWORK_CONFIG: &str = r#"
+net0: virtio=BC:24:11:49:8D:75,bridge=vmbr0,firewall=1,trunks=10
+net1: virtio=BC:24:11:49:8D:76,bridge=vmbr1,firewall=1,trunks=100;200-300
+net2: virtio=BC:24:11:49:8D:77,bridge=vmbr1,firewall=1,tag=20,trunks=20;30
+"#;
+
+        let parse = |rules: &str| {
+            Config::parse(
+                &Vmid::new(100),
+                "tap",
+                format!("[RULES]\n\n{rules}\n").as_bytes(),
+                NETWORK_CONFIG.as_bytes(),
+            )
+        };
+
+        for rules in [
+            "IN ACCEPT -i net1 -vlan 100 -p tcp -dport 80",
+            "IN ACCEPT -i net1 -vlan 250",
+            "IN DROP -vlan 10",
+            "IN DROP -smac 02:00:00:00:00:01",
+            "OUT ACCEPT -smac BC:24:11:49:8D:76",
+            "OUT ACCEPT -i net0 -smac BC:24:11:49:8D:75 -vlan 10",
+            // VLANs that do not reach the guest are reported by the lint
+            "IN ACCEPT -i net1 -vlan 10",
+            "IN ACCEPT -vlan 400",
+        ] {
+            parse(rules).expect("valid rule");
+        }
+
+        for (rules, error) in [
+            ("IN ACCEPT -i net3 -vlan 100", "no such network device"),
+            (
+                "OUT ACCEPT -i net0 -smac BC:24:11:49:8D:76",
+                "does not belong to net0",
+            ),
+            (
+                "OUT ACCEPT -smac 02:00:00:00:00:01",
+                "does not belong to any network device",
+            ),
+        ] {
+            let err = parse(rules).expect_err("invalid rule");
+            assert!(err.to_string().contains(error), "unexpected error: {err}");
+        }
+
+        // without the MAC filter, the guest can send packets with any source MAC address
+        Config::parse(
+            &Vmid::new(100),
+            "tap",
+            "[OPTIONS]\nmacfilter: 0\n\n[RULES]\n\nOUT ACCEPT -smac 02:00:00:00:00:01\n".as_bytes(),
+            NETWORK_CONFIG.as_bytes(),
+        )
+        .expect("valid rule");
+    }
+
+    #[test]
+    fn test_parse_collect_errors() {
+        const CONFIG: &str = r#"
+[RULES]
+
+IN ACCEPT -i net2 -vlan 100
+IN ACCEPT -p tcp -dport 22
+  OUT ACCEPT -smac 02:00:00:00:00:01
+"#;
+
+        let errors = Config::parse_collect_errors(
+            &Vmid::new(100),
+            "tap",
+            CONFIG.as_bytes(),
+            "net0: virtio=BC:24:11:49:8D:75,bridge=vmbr0\n".as_bytes(),
+        )
+        .expect_err("invalid rules");
+
+        let locations: Vec<_> = errors
+            .iter()
+            .map(|error| (error.line(), error.column(), error.section()))
+            .collect();
+
+        assert_eq!(
+            locations,
+            vec![(4, 1, Some("RULES")), (6, 3, Some("RULES"))]
+        );
+
+        let errors = Config::parse_collect_errors(
+            &Vmid::new(100),
+            "tap",
+            CONFIG.as_bytes(),
+            "net0: virtio=BC:24:11:49:8D:75,bridge=vmbr0,rate=qwe\n".as_bytes(),
+        )
+        .expect_err("invalid network config");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line(), 0);
+    }
+
     #[test]
     fn test_parse_invalid_direction() {
         const CONFIG: &str = r#"
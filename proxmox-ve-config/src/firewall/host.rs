@@ -1,7 +1,7 @@
 use std::io;
 use std::net::IpAddr;
 
-use anyhow::{bail, Error};
+use anyhow::Error;
 use serde::Deserialize;
 
 use proxmox_network_types::ip_address::Cidr;
@@ -9,6 +9,8 @@ use proxmox_network_types::ip_address::Cidr;
 use crate::host::utils::{host_ips, network_interface_cidrs};
 use proxmox_sys::nodename;
 
+use crate::firewall::common::{ParseErrors, ParserConfig};
+use crate::firewall::diff::ConfigDiff;
 use crate::firewall::parse;
 use crate::firewall::types::log::LogLevel;
 use crate::firewall::types::rule::Direction;
@@ -35,8 +37,7 @@ pub const HOST_BLOCK_INVALID_CONNTRACK: bool = false;
 /// default setting for logging of invalid conntrack entries
 pub const HOST_LOG_INVALID_CONNTRACK: bool = false;
 
-#[derive(Debug, Default, Deserialize)]
-#[cfg_attr(test, derive(Eq, PartialEq))]
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
 pub struct Options {
     #[serde(default, deserialize_with = "proxmox_serde::perl::deserialize_bool")]
     enable: Option<bool>,
@@ -96,28 +97,40 @@ impl Config {
         }
     }
 
-    pub fn parse<R: io::BufRead>(input: R) -> Result<Self, Error> {
-        let parser_cfg = super::common::ParserConfig {
+    /// Host firewall configs cannot declare groups, aliases or ipsets.
+    fn parser_config() -> ParserConfig {
+        ParserConfig {
             guest_iface_names: false,
             ipset_scope: None,
             allowed_directions: vec![Direction::In, Direction::Out, Direction::Forward],
-        };
-
-        let config = super::common::Config::parse(input, &parser_cfg)?;
-
-        if !config.groups.is_empty() {
-            bail!("host firewall config cannot declare groups");
+            allow_aliases: false,
+            allow_groups: false,
+            allow_smac_vlan: false,
         }
+    }
 
-        if !config.aliases.is_empty() {
-            bail!("host firewall config cannot declare aliases");
-        }
+    pub fn parse<R: io::BufRead>(input: R) -> Result<Self, Error> {
+        Ok(Self {
+            config: super::common::Config::parse(input, &Self::parser_config())?,
+        })
+    }
 
-        if !config.ipsets.is_empty() {
-            bail!("host firewall config cannot declare ipsets");
-        }
+    /// Parses the config like [`Config::parse`], but returns all errors instead of only the
+    /// first one.
+    pub fn parse_collect_errors<R: io::BufRead>(input: R) -> Result<Self, ParseErrors> {
+        Ok(Self {
+            config: super::common::Config::parse_collect_errors(input, &Self::parser_config())?,
+        })
+    }
 
-        Ok(Self { config })
+    /// Writes the config in the `host.fw` format.
+    pub fn write<W: io::Write>(&self, output: W) -> Result<(), Error> {
+        self.config.write(output)
+    }
+
+    /// Computes the changes from this config to `new`.
+    pub fn diff(&self, new: &Self) -> ConfigDiff {
+        self.config.diff(&new.config)
     }
 
     pub fn rules(&self) -> &[Rule] {
@@ -355,6 +368,7 @@ IN ACCEPT -p udp -dport 33 -sport 22 -log warning
                 kind: Kind::Group(RuleGroup {
                     group: "tgr".to_string(),
                     iface: Some("eth0".to_string()),
+                    params: Default::default(),
                 }),
             },
         );
@@ -366,7 +380,7 @@ IN ACCEPT -p udp -dport 33 -sport 22 -log warning
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::In,
-                    verdict: Verdict::Accept,
+                    action: Verdict::Accept.into(),
                     proto: Some(Protocol::Udp(Udp::new(Ports::from_u16(22, 33)))),
                     log: Some(LogLevel::Warning),
                     ..Default::default()
@@ -374,6 +388,11 @@ IN ACCEPT -p udp -dport 33 -sport 22 -log warning
             },
         );
 
+        let mut written = Vec::new();
+        config.write(&mut written).expect("can write config");
+        let reparsed = Config::parse(written.as_slice()).expect("written config is valid");
+        assert_eq!(config.config, reparsed.config);
+
         Config::parse("[ALIASES]\ntest 127.0.0.1".as_bytes())
             .expect_err("host config cannot contain aliases");
 
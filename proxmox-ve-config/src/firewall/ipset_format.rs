@@ -0,0 +1,406 @@
+//! Import and export of ipset entries in the formats of external address lists.
+//!
+//! This allows syncing blocklists and threat intelligence feeds into ipsets of the firewall
+//! config without any external tooling.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use anyhow::{bail, format_err, Error};
+use serde::{Deserialize, Serialize};
+
+use serde_json::value::RawValue;
+
+use crate::firewall::common::{ParseError, ParseErrors};
+use crate::firewall::types::address::IpEntry;
+use crate::firewall::types::ipset::{IpsetAddress, IpsetEntry};
+
+/// The format of an external address list.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpsetFormat {
+    /// One address, CIDR or IP range per line, comments start with `#`.
+    ///
+    /// ```text
+    /// # blocklist
+    /// 192.0.2.0/24
+    /// 198.51.100.7 # scanner
+    /// ```
+    Plain,
+    /// The format of the Spamhaus DROP and EDROP lists, comments start with `;`.
+    ///
+    /// ```text
+    /// ; Spamhaus DROP List
+    /// 192.0.2.0/24 ; SBL000001
+    /// ```
+    Spamhaus,
+    /// A JSON array containing either plain addresses or objects with an address and a comment.
+    ///
+    /// ```json
+    /// ["192.0.2.0/24", { "cidr": "198.51.100.7", "comment": "scanner" }]
+    /// ```
+    Json,
+}
+
+impl FromStr for IpsetFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "plain" => Self::Plain,
+            "spamhaus" => Self::Spamhaus,
+            "json" => Self::Json,
+            _ => bail!("invalid ipset format: {s}"),
+        })
+    }
+}
+
+impl fmt::Display for IpsetFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Plain => "plain",
+            Self::Spamhaus => "spamhaus",
+            Self::Json => "json",
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum JsonEntry {
+    Address(String),
+    Entry {
+        cidr: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+    },
+}
+
+/// Parses an address of a list, which can be an IP address, a CIDR or an IP range.
+fn parse_address(address: &str) -> Result<IpsetAddress, Error> {
+    Ok(match address.parse()? {
+        IpEntry::Cidr(cidr) => IpsetAddress::Cidr(cidr),
+        IpEntry::Range(range) => IpsetAddress::Range(range),
+    })
+}
+
+/// Checks that a comment of a list can be written to the firewall config.
+///
+/// Lists are usually downloaded from external sources, a comment containing a line break would
+/// allow them to add arbitrary lines to the firewall config.
+fn check_comment(comment: &str) -> Result<(), Error> {
+    if comment.chars().any(char::is_control) {
+        bail!("comment must not contain control characters: {comment:?}");
+    }
+
+    Ok(())
+}
+
+/// Returns the 1-based line and column of the character of `data` at byte `offset`.
+fn position(data: &str, offset: usize) -> (usize, usize) {
+    let before = &data[..offset];
+
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .map_or(0, |line| line.chars().count())
+        + 1;
+
+    (line, column)
+}
+
+impl IpsetFormat {
+    fn comment_prefix(self) -> char {
+        match self {
+            Self::Spamhaus => ';',
+            _ => '#',
+        }
+    }
+
+    /// Parses the entries of an address list.
+    ///
+    /// Comments containing control characters are rejected, since they cannot be written to the
+    /// firewall config.
+    ///
+    /// All invalid lines are reported, together with their position. The position of an invalid
+    /// entry of a JSON list is the position of its first character. No entries are returned if
+    /// any line is invalid.
+    pub fn parse(self, data: &str) -> Result<Vec<IpsetEntry>, ParseErrors> {
+        match self {
+            Self::Plain | Self::Spamhaus => self.parse_lines(data),
+            Self::Json => Self::parse_json(data),
+        }
+    }
+
+    fn parse_lines(self, data: &str) -> Result<Vec<IpsetEntry>, ParseErrors> {
+        let prefix = self.comment_prefix();
+
+        let mut entries = Vec::new();
+        let mut errors = ParseErrors::default();
+
+        for (index, line) in data.lines().enumerate() {
+            let trimmed = line.trim_start();
+
+            if trimmed.is_empty() || trimmed.starts_with(prefix) {
+                continue;
+            }
+
+            let (address, comment) = match trimmed.split_once(prefix) {
+                Some((address, comment)) => (address.trim(), Some(comment.trim())),
+                None => (trimmed.trim(), None),
+            };
+
+            let comment = comment.filter(|comment| !comment.is_empty());
+
+            if let Some(comment) = comment {
+                if let Err(err) = check_comment(comment) {
+                    // `comment` is a part of `line`
+                    let offset = comment.as_ptr() as usize - line.as_ptr() as usize;
+                    let column = line[..offset].chars().count() + 1;
+
+                    errors.push(ParseError::new(index + 1, column, None, comment, err));
+                    continue;
+                }
+            }
+
+            let column = line.len() - trimmed.len() + 1;
+
+            match parse_address(address) {
+                Ok(address) => {
+                    entries.push(IpsetEntry::new(address, false, comment.map(str::to_string)))
+                }
+                Err(err) => {
+                    errors.push(ParseError::new(index + 1, column, None, address, err));
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(entries)
+    }
+
+    fn parse_json(data: &str) -> Result<Vec<IpsetEntry>, ParseErrors> {
+        let mut errors = ParseErrors::default();
+
+        let json: Vec<&RawValue> = match serde_json::from_str(data) {
+            Ok(json) => json,
+            Err(err) => {
+                let (line, column) = (err.line(), err.column());
+                errors.push(ParseError::new(line, column, None, "", err.into()));
+                return Err(errors);
+            }
+        };
+
+        let mut entries = Vec::new();
+
+        for (index, raw) in json.into_iter().enumerate() {
+            // `raw` is a part of `data`
+            let offset = raw.get().as_ptr() as usize - data.as_ptr() as usize;
+            let (line, column) = position(data, offset);
+
+            let entry = serde_json::from_str(raw.get())
+                .map_err(Error::from)
+                .and_then(|entry| {
+                    let (address, comment) = match entry {
+                        JsonEntry::Address(address) => (address, None),
+                        JsonEntry::Entry { cidr, comment } => (cidr, comment),
+                    };
+
+                    if let Some(comment) = &comment {
+                        check_comment(comment)?;
+                    }
+
+                    Ok(IpsetEntry::new(parse_address(&address)?, false, comment))
+                });
+
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(err) => errors.push(ParseError::new(
+                    line,
+                    column,
+                    None,
+                    raw.get(),
+                    format_err!("entry {index}: {err}"),
+                )),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(entries)
+    }
+
+    /// Writes entries as an address list.
+    ///
+    /// Only CIDRs and IP ranges can be written, nomatch entries are not supported by any of the
+    /// formats. Ipsets containing them have to be optimized first, see [`Ipset::optimized`].
+    ///
+    /// [`Ipset::optimized`]: crate::firewall::types::Ipset::optimized
+    pub fn write<W: io::Write>(self, entries: &[IpsetEntry], mut output: W) -> Result<(), Error> {
+        for entry in entries {
+            if entry.nomatch {
+                bail!("cannot export nomatch entry {entry} in {self} format");
+            }
+
+            if !matches!(
+                entry.address,
+                IpsetAddress::Cidr(_) | IpsetAddress::Range(_)
+            ) {
+                bail!("cannot export {} in {self} format", entry.address);
+            }
+        }
+
+        if self == Self::Json {
+            let json: Vec<JsonEntry> = entries
+                .iter()
+                .map(|entry| JsonEntry::Entry {
+                    cidr: entry.address.to_string(),
+                    comment: entry.comment.clone(),
+                })
+                .collect();
+
+            serde_json::to_writer_pretty(&mut output, &json)?;
+            writeln!(output)?;
+
+            return Ok(());
+        }
+
+        let prefix = self.comment_prefix();
+
+        for entry in entries {
+            match &entry.comment {
+                Some(comment) => writeln!(output, "{} {prefix} {comment}", entry.address)?,
+                None => writeln!(output, "{}", entry.address)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(entries: &[IpsetEntry]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_ipset_formats() {
+        let entries = IpsetFormat::Plain
+            .parse("# blocklist\n\n192.0.2.0/24\n  198.51.100.7 # scanner\n10.0.0.1-10.0.0.9 # range\n")
+            .expect("valid plain list");
+
+        assert_eq!(
+            addresses(&entries),
+            [
+                "192.0.2.0/24",
+                "198.51.100.7/32 # scanner",
+                "10.0.0.1-10.0.0.9 # range",
+            ]
+        );
+
+        let entries = IpsetFormat::Spamhaus
+            .parse(
+                "; Spamhaus DROP List 2024/01/01\n; Last-Modified: Mon, 01 Jan 2024\n\
+                 192.0.2.0/24 ; SBL000001\n2001:db8::/32 ; SBL000002\n",
+            )
+            .expect("valid spamhaus list");
+
+        assert_eq!(
+            addresses(&entries),
+            ["192.0.2.0/24 # SBL000001", "2001:db8::/32 # SBL000002"]
+        );
+
+        let entries = IpsetFormat::Json
+            .parse(r#"["192.0.2.0/24", { "cidr": "198.51.100.7", "comment": "scanner" }]"#)
+            .expect("valid json list");
+
+        assert_eq!(
+            addresses(&entries),
+            ["192.0.2.0/24", "198.51.100.7/32 # scanner"]
+        );
+
+        let errors = IpsetFormat::Spamhaus
+            .parse("192.0.2.0/24 ; SBL000001\n  192.0.2.0/33 ; SBL000002\nexample ; SBL000003\n")
+            .expect_err("invalid spamhaus list");
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].line(), errors[0].column()), (2, 3));
+        assert_eq!(errors[0].token(), "192.0.2.0/33");
+        assert_eq!((errors[1].line(), errors[1].column()), (3, 1));
+
+        let errors = IpsetFormat::Json
+            .parse("[\"192.0.2.0/24\", \"dc/servers\",\n  { \"cidr\": \"10.0.0.1\", \"comment\": \"a\\nb\" }]")
+            .expect_err("invalid json list");
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].line(), errors[0].column()), (1, 18));
+        assert_eq!(errors[0].token(), "\"dc/servers\"");
+        assert_eq!((errors[1].line(), errors[1].column()), (2, 3));
+
+        let errors = IpsetFormat::Plain
+            .parse("192.0.2.0/24 # scanner\r[RULES]\n")
+            .expect_err("invalid plain list");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line(), errors[0].column()), (1, 16));
+
+        IpsetFormat::Json
+            .parse("{}")
+            .expect_err("json list must be an array");
+    }
+
+    #[test]
+    fn test_write_ipset_formats() {
+        let entries: Vec<IpsetEntry> = [
+            "192.0.2.0/24 # SBL000001",
+            "2001:db8::/32",
+            "10.0.0.1-10.0.0.9",
+        ]
+        .iter()
+        .map(|entry| entry.parse().expect("valid ipset entry"))
+        .collect();
+
+        for (format, expected) in [
+            (
+                IpsetFormat::Plain,
+                "192.0.2.0/24 # SBL000001\n2001:db8::/32\n10.0.0.1-10.0.0.9\n",
+            ),
+            (
+                IpsetFormat::Spamhaus,
+                "192.0.2.0/24 ; SBL000001\n2001:db8::/32\n10.0.0.1-10.0.0.9\n",
+            ),
+        ] {
+            let mut output = Vec::new();
+            format
+                .write(&entries, &mut output)
+                .expect("entries can be written");
+
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+
+        let mut output = Vec::new();
+        IpsetFormat::Json
+            .write(&entries, &mut output)
+            .expect("entries can be written");
+
+        let parsed = IpsetFormat::Json
+            .parse(std::str::from_utf8(&output).unwrap())
+            .expect("written list can be parsed");
+
+        assert_eq!(parsed, entries);
+
+        let nomatch: IpsetEntry = "!192.0.2.1".parse().expect("valid ipset entry");
+        IpsetFormat::Plain
+            .write(&[nomatch], io::sink())
+            .expect_err("nomatch entries cannot be written");
+    }
+}
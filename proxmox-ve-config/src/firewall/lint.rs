@@ -0,0 +1,484 @@
+//! Detection of firewall rules that never match or have no effect.
+//!
+//! [`lint`] compares every rule in a list of [`ResolvedRule`]s with the rules before it. A rule
+//! is reported if all packets it matches are already matched by a single earlier rule, since it
+//! then can never match any packet. Rules that are only covered by several earlier rules taken
+//! together are not detected.
+//!
+//! [`lint_vlans`] additionally finds rules of a guest matching on a VLAN whose packets never reach
+//! the guest.
+//!
+//! The addresses of domain names change whenever they are resolved again, so a source or
+//! destination containing domain names is only known to cover another one if it matches all
+//! addresses, and never known to be covered by another one.
+
+use std::fmt;
+
+use crate::firewall::coverage::{AddressSet, Intervals};
+use crate::firewall::guest::Config as GuestConfig;
+use crate::firewall::resolve::{ResolvedAddress, ResolvedRule, RuleLocation};
+use crate::firewall::types::port::{PortEntry, PortList};
+use crate::firewall::types::rule::Verdict;
+use crate::firewall::types::rule_match::{
+    IcmpCode, IcmpType, Icmpv6Code, Icmpv6Type, Ports, Protocol,
+};
+use crate::guest::vm::NetworkConfig;
+
+/// The kind of problem found by [`lint`] or [`lint_vlans`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FindingKind {
+    /// The rule matches exactly the same packets as an earlier rule with the same verdict.
+    Duplicate,
+    /// The rule never matches, because an earlier rule matches all of its packets.
+    Shadowed,
+    /// The rule accepts packets that a later rule is supposed to drop or reject. The later rule
+    /// never matches those packets.
+    Contradicted,
+    /// The rule matches a VLAN whose packets do not reach the network devices of the guest.
+    VlanNotPassed(u16),
+}
+
+/// A rule found by [`lint`] or [`lint_vlans`], together with the rule that causes the problem.
+#[derive(Clone, Debug)]
+pub struct Finding {
+    kind: FindingKind,
+    rule: RuleLocation,
+    other: Option<RuleLocation>,
+}
+
+impl Finding {
+    pub fn kind(&self) -> FindingKind {
+        self.kind
+    }
+
+    /// The location of the rule the finding is about.
+    pub fn rule(&self) -> &RuleLocation {
+        &self.rule
+    }
+
+    /// The location of the earlier rule that covers [`Self::rule`], or the later rule that is
+    /// contradicted by it. Findings about the VLAN of a rule have no other rule.
+    pub fn other(&self) -> Option<&RuleLocation> {
+        self.other.as_ref()
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let other = self
+            .other
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+
+        match self.kind {
+            FindingKind::Duplicate => write!(f, "{}: duplicate of {other}", self.rule),
+            FindingKind::Shadowed => {
+                write!(f, "{}: never matches, shadowed by {other}", self.rule)
+            }
+            FindingKind::Contradicted => write!(
+                f,
+                "{}: accepts packets that are dropped by later {other}",
+                self.rule
+            ),
+            FindingKind::VlanNotPassed(vlan) => write!(
+                f,
+                "{}: never matches, VLAN {vlan} does not reach the guest, it needs a network \
+                 device without VLAN tag whose trunks contain the VLAN, if any are set",
+                self.rule
+            ),
+        }
+    }
+}
+
+/// The addresses matched by the source or destination of a rule, `None` if they depend on domain
+/// names.
+fn address_set(address: Option<&ResolvedAddress>) -> Option<AddressSet> {
+    match address {
+        None => Some(AddressSet::all()),
+        Some(address) if !address.fqdns().is_empty() => None,
+        Some(address) => Some(AddressSet::new(address.entries(), address.nomatch())),
+    }
+}
+
+/// Returns whether `address` matches all addresses matched by `other`.
+fn address_covers(address: Option<&AddressSet>, other: Option<&AddressSet>) -> bool {
+    match (address, other) {
+        (Some(address), Some(other)) => other.is_subset(address),
+        (Some(address), None) => AddressSet::all().is_subset(address),
+        (None, _) => false,
+    }
+}
+
+fn port_intervals(ports: Option<&PortList>) -> Intervals {
+    match ports {
+        None => Intervals(vec![(0, u16::MAX.into())]),
+        Some(ports) => Intervals::new(ports.iter().map(|entry| match entry {
+            PortEntry::Port(port) => ((*port).into(), (*port).into()),
+            PortEntry::Range(beg, end) => ((*beg).into(), (*end).into()),
+        })),
+    }
+}
+
+fn ports_cover(ports: &Ports, other: &Ports) -> bool {
+    port_intervals(other.sport()).is_subset(&port_intervals(ports.sport()))
+        && port_intervals(other.dport()).is_subset(&port_intervals(ports.dport()))
+}
+
+/// Returns whether a rule matching `number` matches everything matched by `other`, where `None`
+/// matches everything.
+fn value_covers(number: Option<u8>, other: Option<u8>) -> bool {
+    number.is_none() || number == other
+}
+
+/// Returns whether `proto` matches all packets matched by `other`.
+fn protocol_covers(proto: Option<&Protocol>, other: Option<&Protocol>) -> bool {
+    let (proto, other) = match (proto, other) {
+        (None, _) => return true,
+        (Some(_), None) => return false,
+        (Some(proto), Some(other)) => (proto, other),
+    };
+
+    match (proto, other) {
+        (Protocol::Icmp(icmp), Protocol::Icmp(other)) => {
+            value_covers(
+                icmp.ty().and_then(IcmpType::number),
+                other.ty().and_then(IcmpType::number),
+            ) && value_covers(
+                icmp.code().and_then(IcmpCode::number),
+                other.code().and_then(IcmpCode::number),
+            )
+        }
+        (Protocol::Icmpv6(icmp), Protocol::Icmpv6(other)) => {
+            value_covers(
+                icmp.ty().and_then(Icmpv6Type::number),
+                other.ty().and_then(Icmpv6Type::number),
+            ) && value_covers(
+                icmp.code().and_then(Icmpv6Code::number),
+                other.code().and_then(Icmpv6Code::number),
+            )
+        }
+        (Protocol::Named(name), Protocol::Named(other)) => name == other,
+        (Protocol::Numeric(num), Protocol::Numeric(other)) => num == other,
+        (proto, other) => match (proto.ports(), other.ports()) {
+            (Some(ports), Some(other_ports)) if proto.name() == other.name() => {
+                ports_cover(ports, other_ports)
+            }
+            _ => false,
+        },
+    }
+}
+
+/// The packets matched by a [`ResolvedRule`], normalized for comparisons.
+struct RuleMatchSet<'a> {
+    rule: &'a ResolvedRule,
+    src: Option<AddressSet>,
+    dst: Option<AddressSet>,
+}
+
+impl<'a> RuleMatchSet<'a> {
+    fn new(rule: &'a ResolvedRule) -> Self {
+        Self {
+            rule,
+            src: address_set(rule.src()),
+            dst: address_set(rule.dst()),
+        }
+    }
+
+    /// Returns whether this rule matches all packets matched by `other`.
+    ///
+    /// Rules with a rate or connection limit stop matching once the limit is reached, so they
+    /// never cover another rule.
+    fn covers(&self, other: &RuleMatchSet) -> bool {
+        !self.rule.is_limited()
+            && self.rule.direction() == other.rule.direction()
+            && self
+                .rule
+                .iface()
+                .is_none_or(|iface| other.rule.iface() == Some(iface))
+            && self
+                .rule
+                .smac()
+                .is_none_or(|smac| other.rule.smac() == Some(smac))
+            && self
+                .rule
+                .vlan()
+                .is_none_or(|vlan| other.rule.vlan() == Some(vlan))
+            && address_covers(self.src.as_ref(), other.src.as_ref())
+            && address_covers(self.dst.as_ref(), other.dst.as_ref())
+            && protocol_covers(self.rule.proto(), other.rule.proto())
+            && self.rule.ct_state().is_none_or(|states| {
+                other
+                    .rule
+                    .ct_state()
+                    .is_some_and(|other| other.is_subset(states))
+            })
+            && self.rule.tcp_flags().is_none_or(|flags| {
+                other
+                    .rule
+                    .tcp_flags()
+                    .is_some_and(|other| flags.covers(other))
+            })
+    }
+}
+
+/// Finds rules that never match because an earlier rule matches all of their packets.
+///
+/// The rules are expected in the order they are evaluated, as returned by
+/// [`RuleResolver`](crate::firewall::resolve::RuleResolver). Macros resolve to multiple rules
+/// with the same location, such a rule is only reported if all of its parts are covered.
+pub fn lint(rules: &[ResolvedRule]) -> Vec<Finding> {
+    // rules that only set a mark or DSCP value neither end the evaluation nor are skipped
+    let match_sets: Vec<RuleMatchSet> = rules
+        .iter()
+        .filter(|rule| rule.action().is_terminal())
+        .map(RuleMatchSet::new)
+        .collect();
+
+    // consecutive rules with the same location have been created from the same rule
+    let mut groups: Vec<&[RuleMatchSet]> = Vec::new();
+    let mut rest = match_sets.as_slice();
+
+    while let Some(first) = rest.first() {
+        let len = rest
+            .iter()
+            .take_while(|rule| rule.rule.location() == first.rule.location())
+            .count();
+
+        let (group, remaining) = rest.split_at(len);
+        groups.push(group);
+        rest = remaining;
+    }
+
+    let mut findings = Vec::new();
+
+    for (index, group) in groups.iter().enumerate() {
+        let earlier = &groups[..index];
+
+        let covering: Option<Vec<&RuleMatchSet>> = group
+            .iter()
+            .map(|part| {
+                earlier
+                    .iter()
+                    .flat_map(|earlier| earlier.iter())
+                    .find(|earlier| earlier.covers(part))
+            })
+            .collect();
+
+        let Some(covering) = covering else {
+            continue;
+        };
+
+        let rule = group[0].rule;
+        let other = covering[0].rule;
+
+        let is_duplicate = covering.iter().zip(group.iter()).all(|(earlier, part)| {
+            earlier.rule.location() == other.location()
+                && earlier.rule.verdict() == part.rule.verdict()
+                && part.covers(earlier)
+        }) && earlier
+            .iter()
+            .find(|earlier| earlier[0].rule.location() == other.location())
+            .is_some_and(|earlier| earlier.len() == group.len());
+
+        let is_contradicted = rule.verdict() != Some(Verdict::Accept)
+            && covering
+                .iter()
+                .all(|earlier| earlier.rule.verdict() == Some(Verdict::Accept));
+
+        findings.push(if is_duplicate {
+            Finding {
+                kind: FindingKind::Duplicate,
+                rule: rule.location().clone(),
+                other: Some(other.location().clone()),
+            }
+        } else if is_contradicted {
+            Finding {
+                kind: FindingKind::Contradicted,
+                rule: other.location().clone(),
+                other: Some(rule.location().clone()),
+            }
+        } else {
+            Finding {
+                kind: FindingKind::Shadowed,
+                rule: rule.location().clone(),
+                other: Some(other.location().clone()),
+            }
+        });
+    }
+
+    findings
+}
+
+/// Finds rules of a guest matching on a VLAN whose packets never reach the guest.
+///
+/// The rules are expected as returned by
+/// [`RuleResolver::resolve_guest`](crate::firewall::resolve::RuleResolver::resolve_guest) for
+/// the guest. Packets of a VLAN only reach network devices without VLAN tag, whose trunks
+/// contain the VLAN if they are set, see [`NetworkDevice::passes_vlan`].
+///
+/// [`NetworkDevice::passes_vlan`]: crate::guest::vm::NetworkDevice::passes_vlan
+pub fn lint_vlans(guest: &GuestConfig, rules: &[ResolvedRule]) -> Vec<Finding> {
+    let devices = guest.network_config().network_devices();
+    let mut findings: Vec<Finding> = Vec::new();
+
+    for rule in rules {
+        let Some(vlan) = rule.vlan() else {
+            continue;
+        };
+
+        let passes_vlan = match rule.iface() {
+            Some(iface) => NetworkConfig::index_from_net_key(iface)
+                .ok()
+                .and_then(|index| devices.get(&index))
+                .is_some_and(|device| device.passes_vlan(vlan)),
+            None => devices.values().any(|device| device.passes_vlan(vlan)),
+        };
+
+        // macros resolve to multiple rules with the same location
+        if passes_vlan
+            || findings
+                .last()
+                .is_some_and(|finding| finding.rule == *rule.location())
+        {
+            continue;
+        }
+
+        findings.push(Finding {
+            kind: FindingKind::VlanNotPassed(vlan),
+            rule: rule.location().clone(),
+            other: None,
+        });
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::firewall::cluster::Config as ClusterConfig;
+    use crate::firewall::host::Config as HostConfig;
+    use crate::firewall::resolve::RuleResolver;
+    use crate::guest::types::Vmid;
+
+    use super::*;
+
+    const CLUSTER_CONFIG: &str = r#"
+[ALIASES]
+
+updates repo.example.com
+
+[IPSET management]
+
+10.0.0.0/24
+!10.0.0.128/25
+
+[RULES]
+
+IN ACCEPT -source 10.0.0.0/16
+IN ACCEPT -p tcp -dport 22:1024
+IN ACCEPT -p tcp -dport 80,443
+IN SSH(ACCEPT)
+IN HTTP(ACCEPT) -i vmbr0
+IN ACCEPT -p tcp -dport 22:1024
+IN DROP -p tcp -dport 8006
+IN ACCEPT -source +management -p udp
+IN DROP -source 10.0.0.0/25 -p udp -dport 53
+IN DROP -source 10.0.0.0/24 -p udp -dport 53
+IN ACCEPT -p icmp
+IN ACCEPT -p icmp -icmp-type echo-request
+IN ACCEPT -p ipv6-icmp -icmp-type echo-request
+IN ACCEPT -p ipv6-icmp
+IN ACCEPT -p udp -dport 5000 -limit 10/second
+IN ACCEPT -p udp -dport 5000
+OUT ACCEPT -ct-state established,related
+OUT ACCEPT -ct-state established
+OUT DROP -p tcp -tcp-flags syn,!ack
+OUT DROP -p tcp
+IN MARK(0x10) -p tcp -dport 3260
+IN ACCEPT -p tcp -dport 3260
+OUT ACCEPT -dest 192.0.2.1 -p udp
+OUT DROP -dest updates -p udp
+OUT ACCEPT -p udp -dport 123
+OUT ACCEPT -dest updates -p udp -dport 123
+"#;
+
+    #[test]
+    fn test_lint() {
+        let cluster =
+            ClusterConfig::parse(CLUSTER_CONFIG.as_bytes()).expect("valid cluster config");
+        let rules = RuleResolver::new(&cluster)
+            .resolve_host(&HostConfig::new())
+            .expect("rules can be resolved");
+
+        let findings: Vec<String> = lint(&rules).iter().map(Finding::to_string).collect();
+
+        assert_eq!(
+            findings,
+            vec![
+                "cluster.fw [RULES] rule 3: never matches, shadowed by cluster.fw [RULES] rule 2",
+                "cluster.fw [RULES] rule 4: never matches, shadowed by cluster.fw [RULES] rule 2",
+                "cluster.fw [RULES] rule 5: never matches, shadowed by cluster.fw [RULES] rule 2",
+                "cluster.fw [RULES] rule 6: duplicate of cluster.fw [RULES] rule 2",
+                "cluster.fw [RULES] rule 8: never matches, shadowed by cluster.fw [RULES] rule 1",
+                "cluster.fw [RULES] rule 1: accepts packets that are dropped by later \
+                 cluster.fw [RULES] rule 9",
+                "cluster.fw [RULES] rule 1: accepts packets that are dropped by later \
+                 cluster.fw [RULES] rule 10",
+                "cluster.fw [RULES] rule 12: never matches, shadowed by cluster.fw [RULES] rule 11",
+                "cluster.fw [RULES] rule 18: never matches, shadowed by cluster.fw [RULES] rule 17",
+                "cluster.fw [RULES] rule 26: never matches, shadowed by cluster.fw [RULES] rule 25",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_vlans() {
+        const NETWORK_CONFIG: &str = r#"
+net0: virtio=BC:24:11:49:8D:75,bridge=vmbr0
+net1: virtio=BC:24:11:49:8D:76,bridge=vmbr1,trunks=100;200-300
+net2: virtio=BC:24:11:49:8D:77,bridge=vmbr1,tag=20
+"#;
+
+        const GUEST_CONFIG: &str = r#"
+[RULES]
+
+IN ACCEPT -vlan 100
+IN ACCEPT -i net0 -vlan 400
+IN ACCEPT -i net1 -vlan 250
+IN ACCEPT -i net1 -vlan 10
+IN ACCEPT -i net2 -vlan 20
+IN HTTP(ACCEPT) -i net1 -vlan 400
+"#;
+
+        let cluster = ClusterConfig::parse("".as_bytes()).expect("valid cluster config");
+        let guest = GuestConfig::parse(
+            &Vmid::new(100),
+            "tap",
+            GUEST_CONFIG.as_bytes(),
+            NETWORK_CONFIG.as_bytes(),
+        )
+        .expect("valid guest config");
+
+        let rules = RuleResolver::new(&cluster)
+            .resolve_guest(&guest)
+            .expect("rules can be resolved");
+
+        let findings: Vec<String> = lint_vlans(&guest, &rules)
+            .iter()
+            .map(|finding| {
+                assert!(finding.other().is_none());
+                finding.rule().to_string()
+            })
+            .collect();
+
+        assert_eq!(
+            findings,
+            vec![
+                "100.fw [RULES] rule 4",
+                "100.fw [RULES] rule 5",
+                "100.fw [RULES] rule 6",
+            ]
+        );
+    }
+}
@@ -1,11 +1,20 @@
 pub mod bridge;
 pub mod cluster;
 pub mod common;
+pub mod coverage;
 pub mod ct_helper;
+pub mod diff;
+pub mod fqdn;
 pub mod fw_macros;
 pub mod guest;
 pub mod host;
+pub mod ipset_format;
+pub mod lint;
+pub mod nftables;
+pub mod overlay;
 pub mod ports;
+pub mod resolve;
+pub mod trace;
 pub mod types;
 
 pub(crate) mod parse;
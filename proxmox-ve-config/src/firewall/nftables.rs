@@ -0,0 +1,1113 @@
+//! Generation of nftables rulesets from firewall configs.
+//!
+//! The [`RulesetBuilder`] renders the rules of the cluster, host, guest and bridge configs into a
+//! [`Ruleset`], whose [`Display`](fmt::Display) implementation produces a script that can be
+//! loaded with `nft -f`.
+//!
+//! The host rules are placed in the `inet` table [`HOST_TABLE`], the guest rules in the `bridge`
+//! table [`GUEST_TABLE`]. Every network device of a guest with enabled firewall gets its own pair
+//! of chains, and every ipset is added as a named set per address family. Rate and connection
+//! limits of rules apply to every source address separately and are tracked in a dynamic set per
+//! generated rule.
+
+use std::fmt;
+
+use anyhow::Error;
+
+use proxmox_network_types::ip_address::Family;
+
+use crate::firewall::bridge::Config as BridgeConfig;
+use crate::firewall::cluster::Config as ClusterConfig;
+use crate::firewall::ct_helper::get_cthelper;
+use crate::firewall::fqdn::FqdnCache;
+use crate::firewall::guest::Config as GuestConfig;
+use crate::firewall::host::Config as HostConfig;
+use crate::firewall::overlay::Overlay;
+use crate::firewall::resolve::{ResolvedAddress, ResolvedRule, RuleResolver};
+use crate::firewall::types::address::IpEntry;
+use crate::firewall::types::ipset::{IpsetName, IpsetScope};
+use crate::firewall::types::log::{LogLevel, LogRateLimit, LogRateLimitTimescale, RateLimit};
+use crate::firewall::types::rule::{Direction, RuleAction, Verdict};
+use crate::firewall::types::rule_match::{
+    IcmpType, Icmpv6Type, Ports, Protocol, TcpFlag, TcpFlags,
+};
+use crate::firewall::types::Ipset;
+use crate::guest::types::Vmid;
+
+/// Name of the `inet` table containing the rules of the host.
+pub const HOST_TABLE: &str = "proxmox-firewall";
+/// Name of the `bridge` table containing the rules of the guests.
+pub const GUEST_TABLE: &str = "proxmox-firewall-guests";
+
+/// ICMPv6 types required for neighbor discovery.
+const NDP_TYPES: &str =
+    "{ nd-router-solicit, nd-router-advert, nd-neighbor-solicit, nd-neighbor-advert, nd-redirect }";
+
+/// TCP flag combinations which are never valid, see [`HostConfig::block_invalid_tcp`].
+const INVALID_TCP_FLAGS: [&str; 7] = [
+    "tcp flags & (fin|syn|rst|psh|ack|urg) == fin|psh|urg",
+    "tcp flags & (fin|syn|rst|psh|ack|urg) == 0x0",
+    "tcp flags & (syn|rst) == syn|rst",
+    "tcp flags & (fin|syn) == fin|syn",
+    "tcp flags & (fin|rst) == fin|rst",
+    "tcp flags & (fin|ack) == fin",
+    "tcp flags & (ack|urg) == urg",
+];
+
+/// A named set of addresses of a single family.
+struct Set {
+    name: String,
+    family: Family,
+    elements: Vec<IpEntry>,
+}
+
+impl fmt::Display for Set {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ty = match self.family {
+            Family::V4 => "ipv4_addr",
+            Family::V6 => "ipv6_addr",
+        };
+
+        writeln!(f, "\tset {} {{", self.name)?;
+        writeln!(f, "\t\ttype {ty}")?;
+        writeln!(f, "\t\tflags interval")?;
+        writeln!(f, "\t\tauto-merge")?;
+
+        if !self.elements.is_empty() {
+            write!(f, "\t\telements = {{ ")?;
+
+            for (index, element) in self.elements.iter().enumerate() {
+                if index > 0 {
+                    f.write_str(", ")?;
+                }
+
+                element.fmt(f)?;
+            }
+
+            writeln!(f, " }}")?;
+        }
+
+        writeln!(f, "\t}}")
+    }
+}
+
+/// A set that is filled by the rules of a chain, see [`SourceLimit`].
+struct DynamicSet {
+    name: String,
+    family: Family,
+    timeout: Option<LogRateLimitTimescale>,
+}
+
+impl fmt::Display for DynamicSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ty = match self.family {
+            Family::V4 => "ipv4_addr",
+            Family::V6 => "ipv6_addr",
+        };
+
+        writeln!(f, "\tset {} {{", self.name)?;
+        writeln!(f, "\t\ttype {ty}")?;
+        writeln!(f, "\t\tsize 65535")?;
+
+        match self.timeout {
+            Some(timeout) => {
+                let timeout = match timeout {
+                    LogRateLimitTimescale::Second => "1s",
+                    LogRateLimitTimescale::Minute => "1m",
+                    LogRateLimitTimescale::Hour => "1h",
+                    LogRateLimitTimescale::Day => "1d",
+                };
+
+                writeln!(f, "\t\tflags dynamic,timeout")?;
+                writeln!(f, "\t\ttimeout {timeout}")?;
+            }
+            None => writeln!(f, "\t\tflags dynamic")?,
+        }
+
+        writeln!(f, "\t}}")
+    }
+}
+
+/// A limit of a rule that applies to every source address separately.
+#[derive(Clone)]
+enum SourceLimit {
+    /// The maximum number of connections, tracked by conntrack.
+    Connections(u32),
+    /// The rate of packets, entries expire once the source was idle for one unit of the rate.
+    Rate(RateLimit),
+}
+
+/// A conntrack helper object.
+struct CtHelper {
+    name: String,
+    helper: String,
+    protocol: &'static str,
+}
+
+impl fmt::Display for CtHelper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "\tct helper {} {{", self.name)?;
+        writeln!(f, "\t\ttype \"{}\" protocol {}", self.helper, self.protocol)?;
+        writeln!(f, "\t}}")
+    }
+}
+
+/// A chain, which is a base chain if it has a hook.
+///
+/// The dynamic sets used by the rules of the chain are declared in the table, before all chains.
+struct Chain {
+    name: String,
+    hook: Option<&'static str>,
+    rules: Vec<String>,
+    sets: Vec<DynamicSet>,
+}
+
+impl Chain {
+    fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            hook: None,
+            rules: Vec::new(),
+            sets: Vec::new(),
+        }
+    }
+
+    fn base(name: impl Into<String>, hook: &'static str) -> Self {
+        Self {
+            name: name.into(),
+            hook: Some(hook),
+            rules: Vec::new(),
+            sets: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, rule: impl Into<String>) {
+        self.rules.push(rule.into());
+    }
+
+    /// Returns the statement that applies a limit to the source address of packets for the next
+    /// rule of the chain.
+    ///
+    /// Every rule gets its own set, so a packet is not counted twice if it is logged.
+    fn source_limit(&mut self, family: Family, limit: &SourceLimit) -> String {
+        let (kind, timeout) = match limit {
+            SourceLimit::Connections(_) => ("connlimit", None),
+            SourceLimit::Rate(limit) => ("limit", Some(limit.per())),
+        };
+
+        let name = format!("{}-{}-{kind}", self.name, self.rules.len());
+        let prefix = family_prefix(family);
+
+        let statement = match limit {
+            SourceLimit::Connections(connlimit) => {
+                format!("add @{name} {{ {prefix} saddr ct count {connlimit} }}")
+            }
+            SourceLimit::Rate(limit) => format!(
+                "update @{name} {{ {prefix} saddr {} }}",
+                rate_limit(limit.rate(), limit.per(), limit.burst())
+            ),
+        };
+
+        self.sets.push(DynamicSet {
+            name,
+            family,
+            timeout,
+        });
+
+        statement
+    }
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "\tchain {} {{", self.name)?;
+
+        if let Some(hook) = self.hook {
+            writeln!(
+                f,
+                "\t\ttype filter hook {hook} priority filter; policy accept;"
+            )?;
+        }
+
+        for rule in &self.rules {
+            writeln!(f, "\t\t{rule}")?;
+        }
+
+        writeln!(f, "\t}}")
+    }
+}
+
+struct Table {
+    family: &'static str,
+    name: &'static str,
+    ct_helpers: Vec<CtHelper>,
+    sets: Vec<Set>,
+    chains: Vec<Chain>,
+}
+
+impl Table {
+    fn new(family: &'static str, name: &'static str) -> Self {
+        Self {
+            family,
+            name,
+            ct_helpers: Vec::new(),
+            sets: Vec::new(),
+            chains: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "table {} {} {{", self.family, self.name)?;
+
+        let mut first = true;
+
+        let items = self
+            .ct_helpers
+            .iter()
+            .map(|helper| helper as &dyn fmt::Display)
+            .chain(self.sets.iter().map(|set| set as &dyn fmt::Display))
+            .chain(
+                self.chains
+                    .iter()
+                    .flat_map(|chain| chain.sets.iter())
+                    .map(|set| set as &dyn fmt::Display),
+            )
+            .chain(self.chains.iter().map(|chain| chain as &dyn fmt::Display));
+
+        for item in items {
+            if !std::mem::take(&mut first) {
+                writeln!(f)?;
+            }
+
+            item.fmt(f)?;
+        }
+
+        writeln!(f, "}}")
+    }
+}
+
+/// An nftables ruleset generated by a [`RulesetBuilder`].
+pub struct Ruleset {
+    tables: Vec<Table>,
+}
+
+impl fmt::Display for Ruleset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "#!/usr/sbin/nft -f")?;
+        writeln!(f)?;
+
+        // creating the tables first makes deleting them work if they do not exist yet
+        for (family, name) in [("inet", HOST_TABLE), ("bridge", GUEST_TABLE)] {
+            writeln!(f, "table {family} {name}")?;
+            writeln!(f, "delete table {family} {name}")?;
+        }
+
+        for table in &self.tables {
+            writeln!(f)?;
+            table.fmt(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds the nftables [`Ruleset`] for a node from its firewall configs.
+///
+/// If the cluster firewall is disabled, the ruleset only removes the existing tables.
+pub struct RulesetBuilder<'a> {
+    cluster: &'a ClusterConfig,
+    host: Option<&'a HostConfig>,
+    guests: Vec<&'a GuestConfig>,
+    bridges: Vec<(&'a str, &'a BridgeConfig)>,
+    fqdns: Option<&'a FqdnCache>,
+    overlay: Option<&'a Overlay>,
+}
+
+impl<'a> RulesetBuilder<'a> {
+    pub fn new(cluster: &'a ClusterConfig) -> Self {
+        Self {
+            cluster,
+            host: None,
+            guests: Vec::new(),
+            bridges: Vec::new(),
+            fqdns: None,
+            overlay: None,
+        }
+    }
+
+    /// Sets the config of the host, the host table is only generated if there is one.
+    pub fn host(mut self, host: &'a HostConfig) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    pub fn guest(mut self, guest: &'a GuestConfig) -> Self {
+        self.guests.push(guest);
+        self
+    }
+
+    /// Adds the config of the bridge with the given name.
+    pub fn bridge(mut self, name: &'a str, bridge: &'a BridgeConfig) -> Self {
+        self.bridges.push((name, bridge));
+        self
+    }
+
+    /// Sets the addresses of the domain names in aliases and ipsets, see
+    /// [`RuleResolver::fqdns`].
+    pub fn fqdns(mut self, fqdns: &'a FqdnCache) -> Self {
+        self.fqdns = Some(fqdns);
+        self
+    }
+
+    /// Sets the user-defined macros and CT helpers, see [`RuleResolver::overlay`].
+    pub fn overlay(mut self, overlay: &'a Overlay) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    pub fn build(self) -> Result<Ruleset, Error> {
+        let mut tables = Vec::new();
+
+        if !self.cluster.is_enabled() {
+            return Ok(Ruleset { tables });
+        }
+
+        let mut resolver = RuleResolver::new(self.cluster);
+
+        if let Some(fqdns) = self.fqdns {
+            resolver = resolver.fqdns(fqdns);
+        }
+
+        if let Some(overlay) = self.overlay {
+            resolver = resolver.overlay(overlay);
+        }
+
+        if let Some(host) = self.host.filter(|host| host.is_enabled()) {
+            tables.push(self.host_table(&resolver, host)?);
+        }
+
+        let guests: Vec<&GuestConfig> = self
+            .guests
+            .iter()
+            .copied()
+            .filter(|guest| guest.is_enabled())
+            .collect();
+
+        if !guests.is_empty() {
+            tables.push(self.guest_table(&resolver, &guests)?);
+        }
+
+        Ok(Ruleset { tables })
+    }
+
+    fn rule_context(&self) -> RuleContext {
+        RuleContext {
+            log_ratelimit: self.cluster.log_ratelimit(),
+            vmid: None,
+            match_iface: true,
+            source_limits: Vec::new(),
+        }
+    }
+
+    fn host_table(&self, resolver: &RuleResolver, host: &HostConfig) -> Result<Table, Error> {
+        let mut table = Table::new("inet", HOST_TABLE);
+        let ctx = self.rule_context();
+
+        add_ipsets(&mut table, resolver, self.cluster.ipsets().values(), None)?;
+
+        if let Some(helpers) = host.conntrack_helpers() {
+            let mut chain = Chain::base("ct-helpers", "prerouting");
+
+            for name in helpers {
+                let helper = match self.overlay {
+                    Some(overlay) => overlay.cthelper(name),
+                    None => get_cthelper(name),
+                };
+
+                let Some(helper) = helper else {
+                    log::warn!("unknown conntrack helper {name}");
+                    continue;
+                };
+
+                let family = match helper.family() {
+                    Some(Family::V4) => "meta nfproto ipv4 ",
+                    Some(Family::V6) => "meta nfproto ipv6 ",
+                    None => "",
+                };
+
+                for (helper_name, proto, protocol) in [
+                    (helper.tcp_helper_name(), helper.tcp(), "tcp"),
+                    (helper.udp_helper_name(), helper.udp(), "udp"),
+                ] {
+                    let Some(proto) = proto else {
+                        continue;
+                    };
+
+                    chain.add(format!(
+                        "{family}{} ct helper set \"{helper_name}\"",
+                        protocol_matches(proto).join(" ")
+                    ));
+
+                    table.ct_helpers.push(CtHelper {
+                        name: helper_name,
+                        helper: helper.helper().to_string(),
+                        protocol,
+                    });
+                }
+            }
+
+            table.chains.push(chain);
+        }
+
+        let mut input = Chain::base("input", "input");
+        input.add("iifname \"lo\" accept");
+
+        let mut output = Chain::base("output", "output");
+        output.add("oifname \"lo\" accept");
+
+        let mut forward = Chain::base("forward", "forward");
+
+        for chain in [&mut input, &mut output, &mut forward] {
+            if host.block_invalid_conntrack() {
+                chain.add("ct state invalid drop");
+            }
+
+            chain.add("ct state established,related accept");
+        }
+
+        if host.allow_ndp() {
+            input.add(format!("icmpv6 type {NDP_TYPES} accept"));
+            output.add(format!("icmpv6 type {NDP_TYPES} accept"));
+        }
+
+        let mut protection_chains = Vec::new();
+
+        if host.block_smurfs() {
+            let mut chain = Chain::new("block-smurfs");
+            chain.add("ip saddr 0.0.0.0 return");
+            ctx.log_and_verdict(
+                &mut chain,
+                "fib saddr type broadcast",
+                "block-smurfs",
+                Verdict::Drop,
+                host.block_smurfs_log_level(),
+                None,
+            );
+
+            input.add("jump block-smurfs");
+            protection_chains.push(chain);
+        }
+
+        if host.block_invalid_tcp() {
+            let mut chain = Chain::new("block-invalid-tcp");
+
+            for flags in INVALID_TCP_FLAGS {
+                ctx.log_and_verdict(
+                    &mut chain,
+                    flags,
+                    "block-invalid-tcp",
+                    Verdict::Drop,
+                    host.block_invalid_tcp_log_level(),
+                    None,
+                );
+            }
+
+            input.add("meta l4proto tcp jump block-invalid-tcp");
+            protection_chains.push(chain);
+        }
+
+        if host.block_synflood() {
+            let mut chain = Chain::new("block-synflood");
+            chain.add(format!(
+                "tcp flags & (fin|syn|rst|ack) == syn limit rate over {}/second burst {} packets drop",
+                host.synflood_rate(),
+                host.synflood_burst(),
+            ));
+
+            input.add("meta l4proto tcp jump block-synflood");
+            protection_chains.push(chain);
+        }
+
+        let mut bridge_chains = Vec::new();
+
+        for (name, bridge) in &self.bridges {
+            if !bridge.enabled() {
+                continue;
+            }
+
+            let chain_name = format!("bridge-{name}-forward");
+            let mut chain = Chain::new(chain_name.as_str());
+
+            for rule in resolver.resolve_bridge(name, bridge)? {
+                ctx.add_rule(&mut chain, &rule)?;
+            }
+
+            ctx.log_and_verdict(
+                &mut chain,
+                "",
+                &chain_name,
+                bridge.policy_forward(),
+                bridge.log_level_forward(),
+                None,
+            );
+
+            forward.add(format!("iifname \"{name}\" jump {chain_name}"));
+            forward.add(format!("oifname \"{name}\" jump {chain_name}"));
+            bridge_chains.push(chain);
+        }
+
+        let mut rule_chains = [
+            (Direction::In, Chain::new("host-in"), &mut input),
+            (Direction::Out, Chain::new("host-out"), &mut output),
+            (Direction::Forward, Chain::new("host-forward"), &mut forward),
+        ];
+
+        let rules = resolver.resolve_host(host)?;
+
+        for (dir, chain, base) in &mut rule_chains {
+            for rule in rules.iter().filter(|rule| rule.direction() == *dir) {
+                ctx.add_rule(chain, rule)?;
+            }
+
+            base.add(format!("jump {}", chain.name));
+            ctx.log_and_verdict(
+                base,
+                "",
+                &format!("policy-{dir}"),
+                self.cluster.default_policy(*dir),
+                host.log_level(*dir),
+                None,
+            );
+        }
+
+        let [(_, host_in, _), (_, host_out, _), (_, host_forward, _)] = rule_chains;
+
+        table.chains.extend([input, output, forward]);
+        table.chains.extend(protection_chains);
+        table.chains.extend(bridge_chains);
+        table.chains.extend([host_in, host_out, host_forward]);
+
+        Ok(table)
+    }
+
+    fn guest_table(
+        &self,
+        resolver: &RuleResolver,
+        guests: &[&GuestConfig],
+    ) -> Result<Table, Error> {
+        let mut table = Table::new("bridge", GUEST_TABLE);
+
+        add_ipsets(&mut table, resolver, self.cluster.ipsets().values(), None)?;
+
+        let mut forward = Chain::base("forward", "forward");
+        let mut guest_chains = Vec::new();
+
+        for guest in guests {
+            add_ipsets(&mut table, resolver, guest.ipsets().values(), Some(guest))?;
+
+            let ctx = RuleContext {
+                vmid: Some(guest.vmid()),
+                match_iface: false,
+                ..self.rule_context()
+            };
+
+            let rules = resolver.resolve_guest(guest)?;
+
+            for (index, device) in guest.network_config().network_devices() {
+                if !device.has_firewall() {
+                    continue;
+                }
+
+                let key = format!("net{index}");
+                let iface = guest.iface_name_by_index(*index);
+
+                let mut chain_in = Chain::new(format!("guest-{}-{key}-in", guest.vmid()));
+                let mut chain_out = Chain::new(format!("guest-{}-{key}-out", guest.vmid()));
+
+                if guest.macfilter() {
+                    chain_out.add(format!("ether saddr != {} drop", device.mac_address()));
+                }
+
+                if guest.ipfilter() {
+                    add_ipfilter(&mut chain_out, resolver, guest, &key)?;
+                }
+
+                for chain in [&mut chain_in, &mut chain_out] {
+                    chain.add("ct state invalid drop");
+                    chain.add("ct state established,related accept");
+                }
+
+                if guest.allow_dhcp() {
+                    chain_in.add("udp sport 67 udp dport 68 accept");
+                    chain_in.add("udp sport 547 udp dport 546 accept");
+                    chain_out.add("udp sport 68 udp dport 67 accept");
+                    chain_out.add("udp sport 546 udp dport 547 accept");
+                }
+
+                if !guest.allow_ra() {
+                    chain_out.add("icmpv6 type nd-router-advert drop");
+                }
+
+                if guest.allow_ndp() {
+                    chain_in.add(format!("icmpv6 type {NDP_TYPES} accept"));
+                    chain_out.add(format!("icmpv6 type {NDP_TYPES} accept"));
+                }
+
+                for (dir, chain) in [
+                    (Direction::In, &mut chain_in),
+                    (Direction::Out, &mut chain_out),
+                ] {
+                    let iface_rules = rules.iter().filter(|rule| {
+                        rule.direction() == dir && rule.iface().is_none_or(|iface| iface == key)
+                    });
+
+                    for rule in iface_rules {
+                        ctx.add_rule(chain, rule)?;
+                    }
+
+                    let chain_name = chain.name.clone();
+                    ctx.log_and_verdict(
+                        chain,
+                        "",
+                        &chain_name,
+                        guest.default_policy(dir),
+                        guest.log_level(dir),
+                        None,
+                    );
+                }
+
+                forward.add(format!("oifname \"{iface}\" jump {}", chain_in.name));
+                forward.add(format!("iifname \"{iface}\" jump {}", chain_out.name));
+
+                guest_chains.push(chain_in);
+                guest_chains.push(chain_out);
+            }
+        }
+
+        table.chains.push(forward);
+        table.chains.extend(guest_chains);
+
+        Ok(table)
+    }
+}
+
+/// Returns the name of the set containing the addresses of the given family of an ipset.
+fn set_name(family: Family, ipset: &IpsetName, vmid: Option<Vmid>, nomatch: bool) -> String {
+    let family = match family {
+        Family::V4 => "v4",
+        Family::V6 => "v6",
+    };
+
+    let scope = match (ipset.scope(), vmid) {
+        (IpsetScope::Guest, Some(vmid)) => format!("guest-{vmid}"),
+        (scope, _) => scope.to_string(),
+    };
+
+    let suffix = if nomatch { "-nomatch" } else { "" };
+
+    format!("{family}-{scope}/{}{suffix}", ipset.name())
+}
+
+fn entries_of_family(entries: &[IpEntry], family: Family) -> Vec<IpEntry> {
+    entries
+        .iter()
+        .filter(|entry| entry.family() == family)
+        .cloned()
+        .collect()
+}
+
+fn add_ipsets<'b>(
+    table: &mut Table,
+    resolver: &RuleResolver,
+    ipsets: impl Iterator<Item = &'b Ipset>,
+    guest: Option<&GuestConfig>,
+) -> Result<(), Error> {
+    let vmid = guest.map(GuestConfig::vmid);
+
+    for ipset in ipsets {
+        let address = resolver.resolve_ipset(ipset, guest)?;
+
+        for family in [Family::V4, Family::V6] {
+            for (nomatch, entries) in [(false, address.entries()), (true, address.nomatch())] {
+                let elements = entries_of_family(entries, family);
+
+                if elements.is_empty() {
+                    continue;
+                }
+
+                table.sets.push(Set {
+                    name: set_name(family, ipset.name(), vmid, nomatch),
+                    family,
+                    elements,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Only allows the addresses in the `ipfilter-<key>` ipset of the guest as source addresses.
+///
+/// Address families that are not contained in the ipset are not filtered.
+fn add_ipfilter(
+    chain: &mut Chain,
+    resolver: &RuleResolver,
+    guest: &GuestConfig,
+    key: &str,
+) -> Result<(), Error> {
+    let Some(ipset) = guest.ipset(&format!("ipfilter-{key}")) else {
+        return Ok(());
+    };
+
+    let address = resolver.resolve_ipset(ipset, Some(guest))?;
+
+    for family in [Family::V4, Family::V6] {
+        let prefix = family_prefix(family);
+
+        if !entries_of_family(address.nomatch(), family).is_empty() {
+            let set = set_name(family, ipset.name(), Some(guest.vmid()), true);
+            chain.add(format!("{prefix} saddr @{set} drop"));
+        }
+
+        if !entries_of_family(address.entries(), family).is_empty() {
+            let set = set_name(family, ipset.name(), Some(guest.vmid()), false);
+            chain.add(format!("{prefix} saddr != @{set} drop"));
+        }
+    }
+
+    Ok(())
+}
+
+fn family_prefix(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "ip",
+        Family::V6 => "ip6",
+    }
+}
+
+/// Settings that influence how rules are rendered.
+#[derive(Clone)]
+struct RuleContext {
+    log_ratelimit: Option<LogRateLimit>,
+    /// The guest whose rules are rendered, used for naming guest ipsets.
+    vmid: Option<Vmid>,
+    /// Whether the interface of a rule needs to be matched or is implied by the chain.
+    match_iface: bool,
+    /// The limits of the rule that is rendered, they are applied after its matches.
+    source_limits: Vec<SourceLimit>,
+}
+
+impl RuleContext {
+    /// Adds the rules for logging (if enabled) and applying a verdict to packets matching
+    /// `matches` to a chain.
+    ///
+    /// Logging is done in a separate rule, so the rate limit does not affect the verdict. Setting
+    /// a DSCP value and the source limits require the address family of the packets.
+    fn log_and_verdict(
+        &self,
+        chain: &mut Chain,
+        matches: &str,
+        prefix: &str,
+        action: impl Into<RuleAction>,
+        log: LogLevel,
+        family: Option<Family>,
+    ) {
+        let action = action.into();
+
+        if log != LogLevel::Nolog {
+            let mut rule = self.source_limit_matches(chain, matches, family);
+
+            if let Some(limit) = &self.log_ratelimit {
+                push_statement(
+                    &mut rule,
+                    &rate_limit(limit.rate(), limit.per(), Some(limit.burst())),
+                );
+            }
+
+            push_statement(
+                &mut rule,
+                &format!("log prefix \"{prefix}: {action}: \" level {log}"),
+            );
+
+            chain.add(rule);
+        }
+
+        let mut rule = self.source_limit_matches(chain, matches, family);
+
+        let statement = match action {
+            RuleAction::Verdict(Verdict::Accept) => "accept".to_string(),
+            RuleAction::Verdict(Verdict::Drop) => "drop".to_string(),
+            RuleAction::Verdict(Verdict::Reject) => "reject".to_string(),
+            RuleAction::Mark(mark) => format!("meta mark set {mark:#x}"),
+            RuleAction::Dscp(dscp) => {
+                let Some(family) = family else {
+                    for family in [Family::V4, Family::V6] {
+                        self.log_and_verdict(
+                            chain,
+                            matches,
+                            prefix,
+                            action,
+                            LogLevel::Nolog,
+                            Some(family),
+                        );
+                    }
+
+                    return;
+                };
+
+                format!("{} dscp set {dscp}", family_prefix(family))
+            }
+        };
+
+        push_statement(&mut rule, &statement);
+
+        chain.add(rule);
+    }
+
+    fn add_rule(&self, chain: &mut Chain, rule: &ResolvedRule) -> Result<(), Error> {
+        for family in rule_families(rule) {
+            let mut matches = Vec::new();
+
+            if self.match_iface {
+                if let Some(iface) = rule.iface() {
+                    let keyword = match rule.direction() {
+                        Direction::Out => "oifname",
+                        Direction::In | Direction::Forward => "iifname",
+                    };
+
+                    matches.push(format!("{keyword} \"{iface}\""));
+                }
+            }
+
+            if let Some(smac) = rule.smac() {
+                matches.push(format!("ether saddr {smac}"));
+            }
+
+            if let Some(vlan) = rule.vlan() {
+                matches.push(format!("vlan id {vlan}"));
+            }
+
+            if let Some(family) = family {
+                for (keyword, address) in [("saddr", rule.src()), ("daddr", rule.dst())] {
+                    if let Some(address) = address {
+                        self.address_matches(&mut matches, family, keyword, address);
+                    }
+                }
+            }
+
+            if let Some(proto) = rule.proto() {
+                matches.extend(protocol_matches(proto));
+            }
+
+            if let Some(ct_state) = rule.ct_state() {
+                let states: Vec<String> = ct_state.iter().map(|state| state.to_string()).collect();
+                matches.push(format!("ct state {{ {} }}", states.join(", ")));
+            }
+
+            if let Some(tcp_flags) = rule.tcp_flags() {
+                matches.push(tcp_flags_match(tcp_flags));
+            }
+
+            let ctx = RuleContext {
+                source_limits: rule_source_limits(rule),
+                ..self.clone()
+            };
+
+            let prefix = chain.name.clone();
+
+            ctx.log_and_verdict(
+                chain,
+                &matches.join(" "),
+                &prefix,
+                rule.action(),
+                rule.log().unwrap_or_default(),
+                family,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Appends the source limits of the rendered rule to `matches`.
+    fn source_limit_matches(
+        &self,
+        chain: &mut Chain,
+        matches: &str,
+        family: Option<Family>,
+    ) -> String {
+        let mut rule = String::from(matches);
+
+        if let Some(family) = family {
+            for limit in &self.source_limits {
+                push_statement(&mut rule, &chain.source_limit(family, limit));
+            }
+        }
+
+        rule
+    }
+
+    fn address_matches(
+        &self,
+        matches: &mut Vec<String>,
+        family: Family,
+        keyword: &str,
+        address: &ResolvedAddress,
+    ) {
+        let prefix = family_prefix(family);
+
+        match address.ipset() {
+            Some(ipset) => {
+                let set = set_name(family, ipset, self.vmid, false);
+                matches.push(format!("{prefix} {keyword} @{set}"));
+
+                if !entries_of_family(address.nomatch(), family).is_empty() {
+                    let set = set_name(family, ipset, self.vmid, true);
+                    matches.push(format!("{prefix} {keyword} != @{set}"));
+                }
+            }
+            None => {
+                let entries = entries_of_family(address.entries(), family);
+
+                let entries = match entries.as_slice() {
+                    [entry] => entry.to_string(),
+                    entries => format!(
+                        "{{ {} }}",
+                        entries
+                            .iter()
+                            .map(IpEntry::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                };
+
+                matches.push(format!("{prefix} {keyword} {entries}"));
+            }
+        }
+    }
+}
+
+/// Matches if all flags that have to be set are set and all flags that have to be unset are not.
+fn tcp_flags_match(flags: &TcpFlags) -> String {
+    let join = |flags: &mut dyn Iterator<Item = TcpFlag>| {
+        flags
+            .map(|flag| flag.to_string())
+            .collect::<Vec<_>>()
+            .join(" | ")
+    };
+
+    let mask = join(&mut flags.set().chain(flags.unset()));
+
+    match join(&mut flags.set()) {
+        set if set.is_empty() => format!("tcp flags & ({mask}) == 0"),
+        set => format!("tcp flags & ({mask}) == {set}"),
+    }
+}
+
+fn rate_limit(rate: i64, per: LogRateLimitTimescale, burst: Option<i64>) -> String {
+    match burst {
+        Some(burst) => format!("limit rate {rate}/{per} burst {burst} packets"),
+        None => format!("limit rate {rate}/{per}"),
+    }
+}
+
+fn push_statement(rule: &mut String, statement: &str) {
+    if !rule.is_empty() {
+        rule.push(' ');
+    }
+
+    rule.push_str(statement);
+}
+
+fn rule_source_limits(rule: &ResolvedRule) -> Vec<SourceLimit> {
+    let connlimit = rule.connlimit().map(SourceLimit::Connections);
+    let limit = rule.limit().copied().map(SourceLimit::Rate);
+
+    connlimit.into_iter().chain(limit).collect()
+}
+
+/// Returns the address families a rule needs to be generated for.
+///
+/// `None` means that the rule does not depend on the address family. Rules whose source and
+/// destination have no family in common do not match anything and produce no rules at all.
+/// Rules with limits per source address always need a family.
+fn rule_families(rule: &ResolvedRule) -> Vec<Option<Family>> {
+    let proto_family = rule.proto().and_then(Protocol::family);
+
+    if rule.src().is_none() && rule.dst().is_none() {
+        if proto_family.is_none() && !rule_source_limits(rule).is_empty() {
+            return vec![Some(Family::V4), Some(Family::V6)];
+        }
+
+        return vec![proto_family];
+    }
+
+    [Family::V4, Family::V6]
+        .into_iter()
+        .filter(|family| proto_family.is_none_or(|proto_family| proto_family == *family))
+        .filter(|family| {
+            [rule.src(), rule.dst()]
+                .into_iter()
+                .flatten()
+                .all(|address| !entries_of_family(address.entries(), *family).is_empty())
+        })
+        .map(Some)
+        .collect()
+}
+
+fn ports_matches(name: &str, ports: &Ports) -> Vec<String> {
+    let mut matches = Vec::new();
+
+    if let Some(sport) = ports.sport() {
+        matches.push(format!("{name} sport {sport}"));
+    }
+
+    if let Some(dport) = ports.dport() {
+        matches.push(format!("{name} dport {dport}"));
+    }
+
+    if matches.is_empty() {
+        matches.push(format!("meta l4proto {name}"));
+    }
+
+    matches
+}
+
+fn protocol_matches(proto: &Protocol) -> Vec<String> {
+    match proto {
+        Protocol::Icmp(icmp) => {
+            let mut matches = vec!["meta l4proto icmp".to_string()];
+
+            if let Some(ty) = icmp.ty().filter(|ty| !matches!(ty, IcmpType::Any)) {
+                matches.push(format!("icmp type {ty}"));
+            }
+
+            if let Some(code) = icmp.code() {
+                matches.push(format!("icmp code {code}"));
+            }
+
+            matches
+        }
+        Protocol::Icmpv6(icmp) => {
+            let mut matches = vec!["meta l4proto icmpv6".to_string()];
+
+            if let Some(ty) = icmp.ty().filter(|ty| !matches!(ty, Icmpv6Type::Any)) {
+                matches.push(format!("icmpv6 type {ty}"));
+            }
+
+            if let Some(code) = icmp.code() {
+                matches.push(format!("icmpv6 code {code}"));
+            }
+
+            matches
+        }
+        Protocol::Named(name) => vec![format!("meta l4proto {name}")],
+        Protocol::Numeric(num) => vec![format!("meta l4proto {num}")],
+        proto => match proto.ports() {
+            Some(ports) => ports_matches(&proto.name(), ports),
+            None => vec![format!("meta l4proto {}", proto.name())],
+        },
+    }
+}
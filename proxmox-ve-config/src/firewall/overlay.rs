@@ -0,0 +1,226 @@
+//! Site-specific firewall macros and conntrack helpers.
+//!
+//! An [`Overlay`] extends the built-in macro and CT helper definitions. It has to be passed to
+//! the [`RuleResolver`] and the [`RulesetBuilder`] for its definitions to be used, rules
+//! referencing a macro that is neither built-in nor part of the overlay fail to resolve.
+//!
+//! [`RuleResolver`]: crate::firewall::resolve::RuleResolver
+//! [`RulesetBuilder`]: crate::firewall::nftables::RulesetBuilder
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use serde::Deserialize;
+
+use crate::firewall::ct_helper::{self, get_cthelper, CtHelperMacro, CtHelperMacroJson};
+use crate::firewall::fw_macros::{self, get_macro, MacroDefinition, UserMacro};
+use crate::firewall::types::rule_match::Protocol;
+
+/// User-defined macros and CT helpers, in the same format as the built-in definitions.
+///
+/// ```json
+/// {
+///   "macros": {
+///     "OurAppCluster": {
+///       "desc": "Our application cluster traffic",
+///       "code": [{ "proto": "tcp", "dport": "7000:7010" }]
+///     }
+///   },
+///   "ct-helpers": [{ "name": "ftp-alt", "helper": "ftp", "v4": true, "tcp": 2121 }]
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OverlayDefinitions {
+    #[serde(default)]
+    pub macros: HashMap<String, MacroDefinition>,
+    #[serde(default)]
+    pub ct_helpers: Vec<CtHelperMacroJson>,
+}
+
+/// The validated definitions of an [`OverlayDefinitions`].
+///
+/// Lookups fall back to the built-in definitions, so the default overlay contains exactly the
+/// built-in macros and CT helpers.
+#[derive(Clone, Debug, Default)]
+pub struct Overlay {
+    macros: HashMap<String, UserMacro>,
+    ct_helpers: HashMap<String, CtHelperMacro>,
+}
+
+impl Overlay {
+    /// Validates the definitions, which must not conflict with any built-in one.
+    pub fn new(definitions: OverlayDefinitions) -> Result<Self, Error> {
+        Ok(Self {
+            macros: fw_macros::parse_user_macros(definitions.macros)?,
+            ct_helpers: ct_helper::parse_user_cthelpers(definitions.ct_helpers)?,
+        })
+    }
+
+    pub fn parse(data: &str) -> Result<Self, Error> {
+        let definitions: OverlayDefinitions =
+            serde_json::from_str(data).context("could not parse firewall overlay")?;
+
+        Self::new(definitions)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+
+        Self::parse(&data)
+    }
+
+    /// Looks up a user-defined macro, built-in macros are not contained in the overlay.
+    pub fn user_macro(&self, name: &str) -> Option<&UserMacro> {
+        self.macros.get(name)
+    }
+
+    /// The protocol entries of a built-in or user-defined macro.
+    pub fn macro_code(&self, name: &str) -> Option<&[Protocol]> {
+        match get_macro(name) {
+            Some(fw_macro) => Some(&fw_macro.code),
+            None => self.user_macro(name).map(UserMacro::code),
+        }
+    }
+
+    /// Looks up a built-in or user-defined CT helper.
+    pub fn cthelper(&self, name: &str) -> Option<&CtHelperMacro> {
+        get_cthelper(name).or_else(|| self.ct_helpers.get(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::firewall::cluster::Config as ClusterConfig;
+    use crate::firewall::host::Config as HostConfig;
+    use crate::firewall::nftables::RulesetBuilder;
+    use crate::firewall::resolve::RuleResolver;
+    use crate::firewall::types::port::PortList;
+    use crate::firewall::types::rule_match::{Ports, Tcp};
+
+    use super::*;
+
+    const OVERLAY: &str = r#"{
+        "macros": {
+            "OurAppCluster": {
+                "desc": "Our application cluster",
+                "code": [
+                    { "proto": "tcp", "dport": "7000:7010" },
+                    { "proto": "udp", "dport": "7000" }
+                ]
+            }
+        },
+        "ct-helpers": [
+            { "name": "ftp-alt", "helper": "ftp", "v4": true, "tcp": 2121 }
+        ]
+    }"#;
+
+    #[test]
+    fn test_overlay() {
+        let overlay = Overlay::parse(OVERLAY).expect("valid overlay");
+
+        let fw_macro = overlay
+            .user_macro("OurAppCluster")
+            .expect("macro is defined");
+
+        assert_eq!(fw_macro.description(), "Our application cluster");
+        assert_eq!(
+            fw_macro.code()[0],
+            Protocol::from(Tcp::new(Ports::new(
+                None,
+                "7000:7010".parse::<PortList>().unwrap()
+            )))
+        );
+
+        assert!(overlay.user_macro("SSH").is_none());
+        assert!(overlay.macro_code("SSH").is_some());
+        assert!(overlay.macro_code("Unknown").is_none());
+
+        let helper = overlay.cthelper("ftp-alt").expect("helper is defined");
+        assert_eq!(helper.helper(), "ftp");
+        assert_eq!(helper.tcp_helper_name(), "helper-ftp-alt-tcp");
+
+        assert!(overlay.cthelper("ftp").is_some());
+        assert!(Overlay::default().cthelper("ftp-alt").is_none());
+
+        for (data, error) in [
+            (
+                r#"{ "macros": { "ssh": { "code": [{ "proto": "tcp", "dport": "2222" }] } } }"#,
+                "conflicts with built-in macro SSH",
+            ),
+            (
+                r#"{ "macros": { "Broken": { "code": [{ "proto": "tcp", "dport": "99999" }] } } }"#,
+                "invalid entry in macro Broken",
+            ),
+            (
+                r#"{ "macros": { "Mark": { "code": [{ "proto": "tcp" }] } } }"#,
+                "conflicts with action MARK",
+            ),
+            (
+                r#"{ "macros": { "Our App": { "code": [{ "proto": "tcp" }] } } }"#,
+                "invalid macro name",
+            ),
+            (
+                r#"{ "ct-helpers": [{ "name": "ftp", "v4": true, "tcp": 2121 }] }"#,
+                "conflicts with built-in CT helper",
+            ),
+            (
+                r#"{ "ct-helpers": [{ "name": "none", "v4": true }] }"#,
+                "Neither TCP nor UDP",
+            ),
+        ] {
+            let err = Overlay::parse(data).expect_err("invalid overlay");
+
+            assert!(
+                format!("{err:#}").contains(error),
+                "unexpected error for {data}: {err:#}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve_overlay() {
+        let overlay = Overlay::parse(OVERLAY).expect("valid overlay");
+        let cluster = ClusterConfig::parse("[OPTIONS]\n\nenable: 1\n".as_bytes())
+            .expect("valid cluster config");
+
+        let host = HostConfig::parse(
+            "[OPTIONS]\n\nnf_conntrack_helpers: ftp-alt\n\n[RULES]\n\nIN OurAppCluster(ACCEPT)\n"
+                .as_bytes(),
+        )
+        .expect("user-defined macros are only checked when resolving rules");
+
+        RuleResolver::new(&cluster)
+            .resolve_host(&host)
+            .expect_err("unknown macro without overlay");
+
+        let rules = RuleResolver::new(&cluster)
+            .overlay(&overlay)
+            .resolve_host(&host)
+            .expect("macro is defined in the overlay");
+
+        assert_eq!(rules.len(), 2);
+
+        let ruleset = RulesetBuilder::new(&cluster)
+            .host(&host)
+            .overlay(&overlay)
+            .build()
+            .expect("ruleset can be generated")
+            .to_string();
+
+        assert!(ruleset.contains("ct helper helper-ftp-alt-tcp {\n\t\ttype \"ftp\" protocol tcp"));
+
+        let host =
+            HostConfig::parse("[RULES]\n\nIN OurAppCluster(ACCEPT) -tcp-flags syn\n".as_bytes())
+                .expect("valid host config");
+
+        RuleResolver::new(&cluster)
+            .overlay(&overlay)
+            .resolve_host(&host)
+            .expect_err("macro contains udp");
+    }
+}
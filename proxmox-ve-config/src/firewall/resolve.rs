@@ -0,0 +1,1173 @@
+//! Resolution of the references contained in firewall rules.
+//!
+//! Rules can reference security groups, macros, aliases and ipsets. The [`RuleResolver`] replaces
+//! all of those with their contents, so the resulting [`ResolvedRule`]s can be used without
+//! having to look anything up in the firewall configs.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use anyhow::{bail, format_err, Error};
+
+use proxmox_network_types::ip_address::Family;
+use proxmox_network_types::mac_address::MacAddress;
+
+use crate::firewall::bridge::Config as BridgeConfig;
+use crate::firewall::cluster::Config as ClusterConfig;
+use crate::firewall::coverage::EffectiveAddresses;
+use crate::firewall::fqdn::FqdnCache;
+use crate::firewall::fw_macros::get_macro;
+use crate::firewall::guest::Config as GuestConfig;
+use crate::firewall::host::Config as HostConfig;
+use crate::firewall::overlay::Overlay;
+use crate::firewall::types::address::{Fqdn, IpEntry};
+use crate::firewall::types::alias::{AliasScope, FqdnAlias, RuleAliasName};
+use crate::firewall::types::ipset::{IpsetAddress, IpsetName, IpsetScope, RuleIpsetName};
+use crate::firewall::types::log::{LogLevel, RateLimit};
+use crate::firewall::types::rule::{Direction, Kind, RuleAction, RuleGroup, Verdict};
+use crate::firewall::types::rule_match::{
+    check_families, ConnectionStates, IpAddrMatch, Protocol, RuleMatch, TcpFlags,
+};
+use crate::firewall::types::{Alias, Ipset, Rule};
+use crate::guest::types::Vmid;
+
+/// The firewall config file a rule is defined in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RuleConfig {
+    Cluster,
+    Host,
+    Guest(Vmid),
+    Bridge(String),
+}
+
+impl fmt::Display for RuleConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Cluster => f.write_str("cluster.fw"),
+            Self::Host => f.write_str("host.fw"),
+            Self::Guest(vmid) => write!(f, "{vmid}.fw"),
+            Self::Bridge(name) => write!(f, "{name}.fw"),
+        }
+    }
+}
+
+/// The section of a firewall config file a rule is defined in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RuleSection {
+    Rules,
+    Group(String),
+}
+
+impl fmt::Display for RuleSection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Rules => f.write_str("[RULES]"),
+            Self::Group(name) => write!(f, "[group {name}]"),
+        }
+    }
+}
+
+/// Points to the rule in a firewall config file that a [`ResolvedRule`] was created from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RuleLocation {
+    config: RuleConfig,
+    section: RuleSection,
+    index: usize,
+    included_from: Option<Box<RuleLocation>>,
+}
+
+impl RuleLocation {
+    fn new(config: RuleConfig, section: RuleSection, index: usize) -> Self {
+        Self {
+            config,
+            section,
+            index,
+            included_from: None,
+        }
+    }
+
+    pub fn config(&self) -> &RuleConfig {
+        &self.config
+    }
+
+    pub fn section(&self) -> &RuleSection {
+        &self.section
+    }
+
+    /// The 0-based position of the rule in its section.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The location of the `GROUP` rule which included the group containing this rule.
+    pub fn included_from(&self) -> Option<&RuleLocation> {
+        self.included_from.as_deref()
+    }
+}
+
+impl fmt::Display for RuleLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} rule {}",
+            self.config,
+            self.section,
+            self.index + 1
+        )?;
+
+        if let Some(location) = &self.included_from {
+            write!(f, " (included from {location})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The addresses matched by a source or destination of a [`ResolvedRule`].
+///
+/// An address matches if it is contained in any of the entries in [`Self::entries`], but in
+/// none of the entries in [`Self::nomatch`].
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct ResolvedAddress {
+    entries: Vec<IpEntry>,
+    nomatch: Vec<IpEntry>,
+    ipset: Option<IpsetName>,
+    fqdns: Vec<Fqdn>,
+}
+
+impl ResolvedAddress {
+    pub fn entries(&self) -> &[IpEntry] {
+        &self.entries
+    }
+
+    pub fn nomatch(&self) -> &[IpEntry] {
+        &self.nomatch
+    }
+
+    /// The ipset the addresses were taken from, if the rule referenced one.
+    ///
+    /// Ipsets with [`IpsetScope::Guest`] always belong to the config of the resolved rule.
+    pub fn ipset(&self) -> Option<&IpsetName> {
+        self.ipset.as_ref()
+    }
+
+    /// The domain names whose addresses are contained in the entries.
+    ///
+    /// The addresses are the ones known when the rule was resolved, they can change whenever the
+    /// domain names are resolved again.
+    pub fn fqdns(&self) -> &[Fqdn] {
+        &self.fqdns
+    }
+
+    fn add(&mut self, addresses: impl IntoIterator<Item = IpEntry>, nomatch: bool) {
+        if nomatch {
+            self.nomatch.extend(addresses);
+        } else {
+            self.entries.extend(addresses);
+        }
+    }
+
+    /// Returns the address families of the addresses matched by this address.
+    pub fn families(&self) -> Vec<Family> {
+        let mut families = Vec::new();
+
+        for entry in &self.entries {
+            if !families.contains(&entry.family()) {
+                families.push(entry.family());
+            }
+        }
+
+        families
+    }
+
+    pub fn contains_address(&self, ip: &IpAddr) -> bool {
+        self.entries.iter().any(|entry| entry.contains_address(ip))
+            && !self.nomatch.iter().any(|entry| entry.contains_address(ip))
+    }
+
+    /// Returns the minimal list of CIDRs covering the matched addresses, with overlapping and
+    /// adjacent entries merged and the nomatch entries removed.
+    pub fn effective_addresses(&self) -> EffectiveAddresses {
+        EffectiveAddresses::new(&self.entries, &self.nomatch)
+    }
+}
+
+/// A firewall rule without any references to groups, macros, aliases or ipsets.
+///
+/// Interface names are kept as they are written in the firewall config.
+#[derive(Clone, Debug)]
+pub struct ResolvedRule {
+    pub(crate) dir: Direction,
+    pub(crate) action: RuleAction,
+    pub(crate) iface: Option<String>,
+    pub(crate) log: Option<LogLevel>,
+    pub(crate) src: Option<ResolvedAddress>,
+    pub(crate) dst: Option<ResolvedAddress>,
+    pub(crate) proto: Option<Protocol>,
+    pub(crate) limit: Option<RateLimit>,
+    pub(crate) connlimit: Option<u32>,
+    pub(crate) ct_state: Option<ConnectionStates>,
+    pub(crate) tcp_flags: Option<TcpFlags>,
+    pub(crate) smac: Option<MacAddress>,
+    pub(crate) vlan: Option<u16>,
+    pub(crate) location: RuleLocation,
+}
+
+impl ResolvedRule {
+    pub fn direction(&self) -> Direction {
+        self.dir
+    }
+
+    pub fn action(&self) -> RuleAction {
+        self.action
+    }
+
+    /// The verdict of the rule, rules that only modify the packet do not have one.
+    pub fn verdict(&self) -> Option<Verdict> {
+        self.action.verdict()
+    }
+
+    pub fn iface(&self) -> Option<&str> {
+        self.iface.as_deref()
+    }
+
+    pub fn log(&self) -> Option<LogLevel> {
+        self.log
+    }
+
+    pub fn src(&self) -> Option<&ResolvedAddress> {
+        self.src.as_ref()
+    }
+
+    pub fn dst(&self) -> Option<&ResolvedAddress> {
+        self.dst.as_ref()
+    }
+
+    pub fn proto(&self) -> Option<&Protocol> {
+        self.proto.as_ref()
+    }
+
+    pub fn limit(&self) -> Option<&RateLimit> {
+        self.limit.as_ref()
+    }
+
+    pub fn connlimit(&self) -> Option<u32> {
+        self.connlimit
+    }
+
+    pub fn ct_state(&self) -> Option<&ConnectionStates> {
+        self.ct_state.as_ref()
+    }
+
+    pub fn tcp_flags(&self) -> Option<&TcpFlags> {
+        self.tcp_flags.as_ref()
+    }
+
+    pub fn smac(&self) -> Option<MacAddress> {
+        self.smac
+    }
+
+    pub fn vlan(&self) -> Option<u16> {
+        self.vlan
+    }
+
+    /// Whether the rule only matches some of the packets it describes, depending on the rate or
+    /// number of connections.
+    pub fn is_limited(&self) -> bool {
+        self.limit.is_some() || self.connlimit.is_some()
+    }
+
+    /// The location of the rule this rule was created from.
+    pub fn location(&self) -> &RuleLocation {
+        &self.location
+    }
+
+    /// Restricts the rule to an interface, returns `None` if the rule can never match on it.
+    fn restrict_to_iface(mut self, iface: &str) -> Option<Self> {
+        match self.iface.as_deref() {
+            Some(rule_iface) if rule_iface != iface => None,
+            _ => {
+                self.iface = Some(iface.to_string());
+                Some(self)
+            }
+        }
+    }
+}
+
+/// An alias found by [`Scope::alias`].
+enum AliasRef<'a> {
+    Address(&'a Alias),
+    Fqdn(&'a FqdnAlias),
+}
+
+/// Determines where aliases and ipsets referenced in a rule are looked up.
+///
+/// Legacy names, which contain no scope, are looked up in the config containing the rule first
+/// and then in the cluster config.
+#[derive(Clone, Copy)]
+struct Scope<'a> {
+    cluster: &'a ClusterConfig,
+    fqdns: Option<&'a FqdnCache>,
+    overlay: Option<&'a Overlay>,
+    aliases: Option<&'a BTreeMap<String, Alias>>,
+    fqdn_aliases: Option<&'a BTreeMap<String, FqdnAlias>>,
+    ipsets: Option<&'a BTreeMap<String, Ipset>>,
+    /// The addresses passed for the parameters of the security group containing the rule.
+    params: Option<&'a BTreeMap<String, ResolvedAddress>>,
+}
+
+impl<'a> Scope<'a> {
+    /// The scope of the cluster config, with the same domain names as this scope.
+    fn cluster_scope(&self) -> Self {
+        Self {
+            aliases: None,
+            fqdn_aliases: None,
+            ipsets: None,
+            params: None,
+            ..*self
+        }
+    }
+
+    /// Adds the last known addresses of a domain name, see [`FqdnCache::addresses`].
+    fn add_fqdn(&self, resolved: &mut ResolvedAddress, name: &Fqdn, nomatch: bool) {
+        let addresses = self
+            .fqdns
+            .map(|fqdns| fqdns.addresses(name))
+            .unwrap_or_default();
+
+        resolved.add(addresses.iter().copied().map(IpEntry::Cidr), nomatch);
+
+        if !resolved.fqdns.contains(name) {
+            resolved.fqdns.push(name.clone());
+        }
+    }
+
+    /// The protocol entries of a built-in macro, or of a user-defined one from the overlay.
+    fn macro_code(&self, name: &str) -> Option<&'a [Protocol]> {
+        match self.overlay {
+            Some(overlay) => overlay.macro_code(name),
+            None => get_macro(name).map(|fw_macro| fw_macro.code.as_slice()),
+        }
+    }
+
+    /// Adds the addresses of an alias.
+    fn add_alias(
+        &self,
+        resolved: &mut ResolvedAddress,
+        name: &RuleAliasName,
+        nomatch: bool,
+    ) -> Result<(), Error> {
+        match self.alias(name)? {
+            AliasRef::Address(alias) => resolved.add([IpEntry::Cidr(*alias.address())], nomatch),
+            AliasRef::Fqdn(alias) => self.add_fqdn(resolved, alias.fqdn(), nomatch),
+        }
+
+        Ok(())
+    }
+
+    /// Looks up an alias in the config containing the rule.
+    fn local_alias(&self, name: &str) -> Option<AliasRef<'a>> {
+        if let Some(alias) = self.aliases.and_then(|aliases| aliases.get(name)) {
+            return Some(AliasRef::Address(alias));
+        }
+
+        let alias = self.fqdn_aliases.and_then(|aliases| aliases.get(name))?;
+        Some(AliasRef::Fqdn(alias))
+    }
+
+    /// Looks up an alias in the cluster config.
+    fn cluster_alias(&self, name: &str) -> Option<AliasRef<'a>> {
+        if let Some(alias) = self.cluster.alias(name) {
+            return Some(AliasRef::Address(alias));
+        }
+
+        let alias = self.cluster.fqdn_alias(name)?;
+        Some(AliasRef::Fqdn(alias))
+    }
+
+    fn alias(&self, name: &RuleAliasName) -> Result<AliasRef<'a>, Error> {
+        let alias = match name {
+            RuleAliasName::Scoped(name) => match name.scope() {
+                AliasScope::Datacenter => self.cluster_alias(name.name()),
+                AliasScope::Guest => self.local_alias(name.name()),
+            },
+            RuleAliasName::Legacy(name) => self
+                .local_alias(name.as_ref())
+                .or_else(|| self.cluster_alias(name.as_ref())),
+        };
+
+        alias.ok_or_else(|| format_err!("unknown alias {name}"))
+    }
+
+    fn ipset(&self, name: &RuleIpsetName) -> Result<(&'a Ipset, Scope<'a>), Error> {
+        let ipset = match name {
+            RuleIpsetName::Scoped(name) => match name.scope() {
+                IpsetScope::Datacenter => self
+                    .cluster
+                    .ipset(name.name())
+                    .map(|ipset| (ipset, self.cluster_scope())),
+                IpsetScope::Guest => self
+                    .ipsets
+                    .and_then(|ipsets| ipsets.get(name.name()))
+                    .map(|ipset| (ipset, *self)),
+                IpsetScope::Sdn => bail!("cannot resolve SDN ipset +{name}"),
+            },
+            RuleIpsetName::Legacy(name) => self
+                .ipsets
+                .and_then(|ipsets| ipsets.get(name.as_ref()))
+                .map(|ipset| (ipset, *self))
+                .or_else(|| {
+                    self.cluster
+                        .ipset(name.as_ref())
+                        .map(|ipset| (ipset, self.cluster_scope()))
+                }),
+        };
+
+        ipset.ok_or_else(|| format_err!("unknown ipset +{name}"))
+    }
+
+    fn resolve_address(&self, address: &IpAddrMatch) -> Result<ResolvedAddress, Error> {
+        match address {
+            IpAddrMatch::Ip(list) => Ok(ResolvedAddress {
+                entries: list.to_vec(),
+                ..Default::default()
+            }),
+            IpAddrMatch::Alias(name) => {
+                let mut resolved = ResolvedAddress::default();
+                self.add_alias(&mut resolved, name, false)?;
+                Ok(resolved)
+            }
+            IpAddrMatch::Set(name) => {
+                let (ipset, scope) = self.ipset(name)?;
+                scope.resolve_ipset(ipset)
+            }
+            IpAddrMatch::Param(name) => self
+                .params
+                .and_then(|params| params.get(name))
+                .cloned()
+                .ok_or_else(|| format_err!("unknown parameter ${name}")),
+        }
+    }
+
+    fn resolve_ipset(&self, ipset: &Ipset) -> Result<ResolvedAddress, Error> {
+        let mut resolved = ResolvedAddress {
+            ipset: Some(ipset.name().clone()),
+            ..Default::default()
+        };
+
+        for entry in ipset.iter() {
+            match &entry.address {
+                IpsetAddress::Alias(name) => self.add_alias(&mut resolved, name, entry.nomatch)?,
+                IpsetAddress::Cidr(cidr) => resolved.add([IpEntry::Cidr(*cidr)], entry.nomatch),
+                IpsetAddress::Range(range) => resolved.add([IpEntry::Range(*range)], entry.nomatch),
+            }
+        }
+
+        for entry in ipset.fqdns() {
+            self.add_fqdn(&mut resolved, &entry.fqdn, entry.nomatch);
+        }
+
+        Ok(resolved)
+    }
+}
+
+/// Resolves the rules of firewall configs into [`ResolvedRule`]s.
+///
+/// Disabled rules are skipped, security groups are inlined, macros are expanded into one rule
+/// per protocol entry of the macro and aliases as well as ipsets are replaced by the addresses
+/// they contain.
+pub struct RuleResolver<'a> {
+    cluster: &'a ClusterConfig,
+    fqdns: Option<&'a FqdnCache>,
+    overlay: Option<&'a Overlay>,
+}
+
+impl<'a> RuleResolver<'a> {
+    pub fn new(cluster: &'a ClusterConfig) -> Self {
+        Self {
+            cluster,
+            fqdns: None,
+            overlay: None,
+        }
+    }
+
+    /// Sets the addresses of the domain names in aliases and ipsets.
+    ///
+    /// Without a cache, or if a name is not contained in it, the name matches no addresses.
+    pub fn fqdns(mut self, fqdns: &'a FqdnCache) -> Self {
+        self.fqdns = Some(fqdns);
+        self
+    }
+
+    /// Sets the user-defined macros, without an overlay only the built-in macros are known.
+    pub fn overlay(mut self, overlay: &'a Overlay) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    fn cluster_scope(&self) -> Scope<'a> {
+        Scope {
+            cluster: self.cluster,
+            fqdns: self.fqdns,
+            overlay: self.overlay,
+            aliases: None,
+            fqdn_aliases: None,
+            ipsets: None,
+            params: None,
+        }
+    }
+
+    /// Resolves the rules of a host, which consist of the rules in the host config followed by
+    /// the rules in the cluster config.
+    pub fn resolve_host(&self, host: &HostConfig) -> Result<Vec<ResolvedRule>, Error> {
+        let scope = self.cluster_scope();
+
+        let mut resolved = Vec::new();
+        self.resolve_rules(scope, RuleConfig::Host, host.rules(), &mut resolved)?;
+        self.resolve_rules(
+            scope,
+            RuleConfig::Cluster,
+            self.cluster.rules(),
+            &mut resolved,
+        )?;
+
+        // the parser cannot check the rules of security groups, since they can be included anywhere
+        if let Some(rule) = resolved
+            .iter()
+            .find(|rule| rule.smac.is_some() || rule.vlan.is_some())
+        {
+            bail!(
+                "{}: smac and vlan can only be used in guest and bridge firewall configs",
+                rule.location()
+            );
+        }
+
+        Ok(resolved)
+    }
+
+    /// Resolves the rules in the config of a guest.
+    pub fn resolve_guest(&self, guest: &GuestConfig) -> Result<Vec<ResolvedRule>, Error> {
+        let scope = Scope {
+            cluster: self.cluster,
+            fqdns: self.fqdns,
+            overlay: self.overlay,
+            aliases: Some(&guest.config.aliases),
+            fqdn_aliases: Some(&guest.config.fqdn_aliases),
+            ipsets: Some(&guest.config.ipsets),
+            params: None,
+        };
+
+        let mut resolved = Vec::new();
+        self.resolve_rules(
+            scope,
+            RuleConfig::Guest(guest.vmid()),
+            guest.rules(),
+            &mut resolved,
+        )?;
+
+        Ok(resolved)
+    }
+
+    /// Resolves the rules in the config of a bridge with the given name.
+    pub fn resolve_bridge(
+        &self,
+        name: &str,
+        bridge: &BridgeConfig,
+    ) -> Result<Vec<ResolvedRule>, Error> {
+        let scope = Scope {
+            cluster: self.cluster,
+            fqdns: self.fqdns,
+            overlay: self.overlay,
+            aliases: Some(&bridge.config.aliases),
+            fqdn_aliases: Some(&bridge.config.fqdn_aliases),
+            ipsets: None,
+            params: None,
+        };
+
+        let mut resolved = Vec::new();
+        self.resolve_rules(
+            scope,
+            RuleConfig::Bridge(name.to_string()),
+            &bridge.config.rules,
+            &mut resolved,
+        )?;
+
+        Ok(resolved)
+    }
+
+    /// Resolves the aliases contained in an ipset of the cluster config or, if given, of a guest
+    /// config.
+    pub fn resolve_ipset(
+        &self,
+        ipset: &Ipset,
+        guest: Option<&GuestConfig>,
+    ) -> Result<ResolvedAddress, Error> {
+        let scope = match guest {
+            Some(guest) => Scope {
+                cluster: self.cluster,
+                fqdns: self.fqdns,
+                overlay: self.overlay,
+                aliases: Some(&guest.config.aliases),
+                fqdn_aliases: Some(&guest.config.fqdn_aliases),
+                ipsets: Some(&guest.config.ipsets),
+                params: None,
+            },
+            None => self.cluster_scope(),
+        };
+
+        scope
+            .resolve_ipset(ipset)
+            .map_err(|err| format_err!("ipset {}: {err}", ipset.name()))
+    }
+
+    fn resolve_rules(
+        &self,
+        scope: Scope<'_>,
+        config: RuleConfig,
+        rules: &[Rule],
+        resolved: &mut Vec<ResolvedRule>,
+    ) -> Result<(), Error> {
+        for (index, rule) in rules.iter().enumerate() {
+            let location = RuleLocation::new(config.clone(), RuleSection::Rules, index);
+            self.resolve_rule(scope, rule, location, &mut Vec::new(), resolved)?;
+        }
+
+        Ok(())
+    }
+
+    fn resolve_rule(
+        &self,
+        scope: Scope<'_>,
+        rule: &Rule,
+        location: RuleLocation,
+        groups: &mut Vec<String>,
+        resolved: &mut Vec<ResolvedRule>,
+    ) -> Result<(), Error> {
+        if rule.disabled() {
+            return Ok(());
+        }
+
+        match rule.kind() {
+            Kind::Group(group) => self.resolve_group(scope, group, location, groups, resolved),
+            Kind::Match(rule) => resolve_match(scope, rule, &location, resolved)
+                .map_err(|err| format_err!("{location}: {err}")),
+        }
+    }
+
+    fn resolve_group(
+        &self,
+        scope: Scope<'_>,
+        group_rule: &RuleGroup,
+        location: RuleLocation,
+        groups: &mut Vec<String>,
+        resolved: &mut Vec<ResolvedRule>,
+    ) -> Result<(), Error> {
+        let name = group_rule.group();
+
+        if groups.iter().any(|group| group == name) {
+            bail!("{location}: circular reference to security group {name}");
+        }
+
+        let group = self
+            .cluster
+            .groups()
+            .get(name)
+            .ok_or_else(|| format_err!("{location}: unknown security group {name}"))?;
+
+        group
+            .check_include(group_rule)
+            .map_err(|err| format_err!("{location}: {err}"))?;
+
+        // the parameters refer to the addresses of the config including the group
+        let params = group_rule
+            .params()
+            .iter()
+            .map(|(param, address)| Ok((param.clone(), scope.resolve_address(address)?)))
+            .collect::<Result<BTreeMap<_, _>, Error>>()
+            .map_err(|err| format_err!("{location}: {err}"))?;
+
+        // security groups are always defined in the cluster config
+        let scope = Scope {
+            params: Some(&params),
+            ..self.cluster_scope()
+        };
+
+        groups.push(name.to_string());
+
+        let mut group_rules = Vec::new();
+
+        for (index, rule) in group.rules().iter().enumerate() {
+            let mut rule_location = RuleLocation::new(
+                RuleConfig::Cluster,
+                RuleSection::Group(name.to_string()),
+                index,
+            );
+            rule_location.included_from = Some(Box::new(location.clone()));
+
+            self.resolve_rule(scope, rule, rule_location, groups, &mut group_rules)?;
+        }
+
+        groups.pop();
+
+        match group_rule.iface() {
+            // rules of a group included for a specific interface only apply to that interface
+            Some(iface) => resolved.extend(
+                group_rules
+                    .into_iter()
+                    .filter_map(|rule| rule.restrict_to_iface(iface)),
+            ),
+            None => resolved.extend(group_rules),
+        }
+
+        Ok(())
+    }
+}
+
+fn resolve_match(
+    scope: Scope<'_>,
+    rule: &RuleMatch,
+    location: &RuleLocation,
+    resolved: &mut Vec<ResolvedRule>,
+) -> Result<(), Error> {
+    let src = rule
+        .ip()
+        .and_then(|ip| ip.src())
+        .map(|src| scope.resolve_address(src))
+        .transpose()?;
+
+    let dst = rule
+        .ip()
+        .and_then(|ip| ip.dst())
+        .map(|dst| scope.resolve_address(dst))
+        .transpose()?;
+
+    let src_families = src.as_ref().map(ResolvedAddress::families);
+    let dst_families = dst.as_ref().map(ResolvedAddress::families);
+
+    let check = |proto: Option<&Protocol>| {
+        check_families(src_families.as_deref(), dst_families.as_deref(), proto)
+    };
+
+    let protocols: Vec<Option<Protocol>> = match rule.fw_macro() {
+        Some(name) => {
+            let code = scope
+                .macro_code(name)
+                .ok_or_else(|| format_err!("unknown macro {name}"))?;
+
+            // user-defined macros are unknown to the parser, so they are checked here
+            if rule.tcp_flags().is_some()
+                && !code.iter().all(|proto| matches!(proto, Protocol::Tcp(_)))
+            {
+                bail!("tcp-flags can only be used with protocol tcp");
+            }
+
+            // macros can contain protocols of both families, only the fitting ones are used
+            let protocols: Vec<_> = code
+                .iter()
+                .filter(|proto| check(Some(proto)).is_ok())
+                .cloned()
+                .map(Some)
+                .collect();
+
+            if protocols.is_empty() {
+                match code.first() {
+                    Some(proto) => check(Some(proto))?,
+                    None => check(None)?,
+                }
+            }
+
+            protocols
+        }
+        None => {
+            check(rule.proto())?;
+            vec![rule.proto().cloned()]
+        }
+    };
+
+    for proto in protocols {
+        resolved.push(ResolvedRule {
+            dir: rule.direction(),
+            action: rule.action(),
+            iface: rule.iface().map(str::to_string),
+            log: rule.log(),
+            src: src.clone(),
+            dst: dst.clone(),
+            proto,
+            limit: rule.limit().copied(),
+            connlimit: rule.connlimit(),
+            ct_state: rule.ct_state().copied(),
+            tcp_flags: rule.tcp_flags().copied(),
+            smac: rule.smac(),
+            vlan: rule.vlan(),
+            location: location.clone(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use proxmox_network_types::ip_address::Cidr;
+
+    use crate::firewall::fqdn::tests::StaticResolver;
+    use crate::firewall::types::Group;
+
+    use super::*;
+
+    const CLUSTER_CONFIG: &str = r#"
+[ALIASES]
+
+web 10.0.0.10
+
+[IPSET management]
+
+10.0.0.0/24
+!10.0.0.5
+dc/web
+
+[RULES]
+
+IN SSH(ACCEPT) -source +dc/management
+|IN ACCEPT -p tcp -dport 8006
+
+[group webserver]
+
+IN HTTP(ACCEPT) -dest web
+IN ACCEPT -p tcp -dport 443 -i net1
+IN ACCEPT -p tcp -dport 8443 -i net0
+
+[group invalid]
+
+IN ACCEPT -source unknown
+
+[group tagged]
+
+IN ACCEPT -vlan 100
+
+[group tenant-web]
+
+IN ACCEPT -source $clients -p tcp -dport 443
+
+[group tenant-app]
+
+GROUP tenant-web(clients=$clients)
+IN ACCEPT -source $clients -dest $backend -p tcp -dport 8080
+"#;
+
+    fn cidr(cidr: &str) -> IpEntry {
+        IpEntry::Cidr(cidr.parse::<Cidr>().expect("valid cidr"))
+    }
+
+    fn host_config(rules: &str) -> HostConfig {
+        HostConfig::parse(format!("[RULES]\n\n{rules}\n").as_bytes()).expect("valid host config")
+    }
+
+    #[test]
+    fn test_resolve_host() {
+        let cluster =
+            ClusterConfig::parse(CLUSTER_CONFIG.as_bytes()).expect("valid cluster config");
+        let resolver = RuleResolver::new(&cluster);
+
+        let host = host_config("GROUP webserver -i net0\nOUT DROP -dest +management");
+        let rules = resolver.resolve_host(&host).expect("rules can be resolved");
+
+        let locations: Vec<String> = rules
+            .iter()
+            .map(|rule| rule.location().to_string())
+            .collect();
+
+        assert_eq!(
+            locations,
+            vec![
+                "cluster.fw [group webserver] rule 1 (included from host.fw [RULES] rule 1)",
+                "cluster.fw [group webserver] rule 3 (included from host.fw [RULES] rule 1)",
+                "host.fw [RULES] rule 2",
+                "cluster.fw [RULES] rule 1",
+            ]
+        );
+
+        assert_eq!(rules[0].iface(), Some("net0"));
+        assert_eq!(rules[0].proto().map(Protocol::name).as_deref(), Some("tcp"));
+        assert_eq!(
+            rules[0].dst().map(ResolvedAddress::entries),
+            Some([cidr("10.0.0.10/32")].as_slice())
+        );
+
+        assert_eq!(rules[1].iface(), Some("net0"));
+
+        let management = ResolvedAddress {
+            entries: vec![cidr("10.0.0.0/24"), cidr("10.0.0.10/32")],
+            nomatch: vec![cidr("10.0.0.5/32")],
+            ipset: Some(IpsetName::new(IpsetScope::Datacenter, "management")),
+            ..Default::default()
+        };
+
+        assert_eq!(rules[2].dst(), Some(&management));
+        assert_eq!(rules[3].src(), Some(&management));
+        assert_eq!(rules[3].verdict(), Some(Verdict::Accept));
+    }
+
+    #[test]
+    fn test_resolve_fqdns() {
+        const CLUSTER_CONFIG: &str = r#"
+[ALIASES]
+
+updates repo.example.com
+
+[IPSET saas]
+
+fqdn:api.vendor.com
+fqdn:unknown.vendor.com
+!198.51.100.128/25
+
+[RULES]
+
+OUT ACCEPT -dest updates -p tcp -dport 443
+OUT ACCEPT -dest +saas -p tcp -dport 443
+"#;
+
+        let cluster =
+            ClusterConfig::parse(CLUSTER_CONFIG.as_bytes()).expect("valid cluster config");
+
+        let mut fqdns: Vec<String> = cluster.fqdns().iter().map(|f| f.to_string()).collect();
+        fqdns.sort();
+        assert_eq!(
+            fqdns,
+            ["api.vendor.com", "repo.example.com", "unknown.vendor.com"]
+        );
+
+        let resolver = StaticResolver::new(
+            &[
+                ("repo.example.com", &["203.0.113.10", "2001:db8::10"]),
+                ("api.vendor.com", &["198.51.100.0/24"]),
+            ],
+            300,
+        );
+
+        let mut cache = FqdnCache::new();
+        let errors = cache.refresh(&resolver, cluster.fqdns(), 0);
+        assert_eq!(errors.len(), 1);
+
+        let rules = RuleResolver::new(&cluster)
+            .fqdns(&cache)
+            .resolve_host(&HostConfig::new())
+            .expect("rules can be resolved");
+
+        assert_eq!(
+            rules[0].dst().map(ResolvedAddress::entries),
+            Some([cidr("203.0.113.10/32"), cidr("2001:db8::10/128")].as_slice())
+        );
+        assert_eq!(
+            rules[1].dst(),
+            Some(&ResolvedAddress {
+                entries: vec![cidr("198.51.100.0/24")],
+                nomatch: vec![cidr("198.51.100.128/25")],
+                ipset: Some(IpsetName::new(IpsetScope::Datacenter, "saas")),
+                fqdns: vec![
+                    "api.vendor.com".parse().unwrap(),
+                    "unknown.vendor.com".parse().unwrap(),
+                ],
+            })
+        );
+        assert_eq!(
+            rules[1]
+                .dst()
+                .map(|address| address.effective_addresses().cidrs().collect::<Vec<_>>()),
+            Some(vec!["198.51.100.0/25".parse().unwrap()])
+        );
+
+        // without resolved addresses, the names match nothing
+        let rules = RuleResolver::new(&cluster)
+            .resolve_host(&HostConfig::new())
+            .expect("rules can be resolved");
+
+        assert_eq!(
+            rules[0].dst().map(ResolvedAddress::entries),
+            Some([].as_slice())
+        );
+    }
+
+    #[test]
+    fn test_resolve_guest() {
+        const GUEST_CONFIG: &str = r#"
+[ALIASES]
+
+web 192.168.0.10
+
+[IPSET web]
+
+web
+dc/web
+
+[RULES]
+
+IN ACCEPT -source web
+IN ACCEPT -source dc/web
+IN ACCEPT -source +web
+IN ACCEPT -source +dc/management
+"#;
+
+        let cluster =
+            ClusterConfig::parse(CLUSTER_CONFIG.as_bytes()).expect("valid cluster config");
+        let guest = GuestConfig::parse(
+            &Vmid::new(100),
+            "tap",
+            GUEST_CONFIG.as_bytes(),
+            "".as_bytes(),
+        )
+        .expect("valid guest config");
+
+        let rules = RuleResolver::new(&cluster)
+            .resolve_guest(&guest)
+            .expect("rules can be resolved");
+
+        let sources: Vec<&[IpEntry]> = rules
+            .iter()
+            .map(|rule| rule.src().expect("source is set").entries())
+            .collect();
+
+        assert_eq!(
+            sources,
+            vec![
+                [cidr("192.168.0.10/32")].as_slice(),
+                &[cidr("10.0.0.10/32")],
+                &[cidr("192.168.0.10/32"), cidr("10.0.0.10/32")],
+                &[cidr("10.0.0.0/24"), cidr("10.0.0.10/32")],
+            ]
+        );
+
+        assert_eq!(rules[0].location().to_string(), "100.fw [RULES] rule 1");
+    }
+
+    #[test]
+    fn test_resolve_group_params() {
+        const GUEST_CONFIG: &str = r#"
+[IPSET clients]
+
+192.168.0.0/24
+
+[RULES]
+
+GROUP tenant-app(clients=+clients,backend=10.1.0.0/16)
+"#;
+
+        let cluster =
+            ClusterConfig::parse(CLUSTER_CONFIG.as_bytes()).expect("valid cluster config");
+        let guest = GuestConfig::parse(
+            &Vmid::new(100),
+            "tap",
+            GUEST_CONFIG.as_bytes(),
+            "".as_bytes(),
+        )
+        .expect("valid guest config");
+
+        let rules = RuleResolver::new(&cluster)
+            .resolve_guest(&guest)
+            .expect("rules can be resolved");
+
+        // the ipset passed as parameter is looked up in the guest config
+        let clients = ResolvedAddress {
+            entries: vec![cidr("192.168.0.0/24")],
+            ipset: Some(IpsetName::new(IpsetScope::Guest, "clients")),
+            ..Default::default()
+        };
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].src(), Some(&clients));
+        assert_eq!(rules[1].src(), Some(&clients));
+        assert_eq!(
+            rules[1].dst().map(ResolvedAddress::entries),
+            Some([cidr("10.1.0.0/16")].as_slice())
+        );
+        assert_eq!(
+            rules[0].location().to_string(),
+            "cluster.fw [group tenant-web] rule 1 (included from cluster.fw [group tenant-app] \
+             rule 1 (included from 100.fw [RULES] rule 1))"
+        );
+
+        let resolver = RuleResolver::new(&cluster);
+
+        let error = resolver
+            .resolve_host(&host_config("GROUP tenant-app(clients=10.0.0.0/8)"))
+            .expect_err("missing parameter");
+        assert_eq!(
+            error.to_string(),
+            "host.fw [RULES] rule 1: security group tenant-app requires parameter backend"
+        );
+
+        let error = resolver
+            .resolve_host(&host_config("GROUP webserver(clients=10.0.0.0/8)"))
+            .expect_err("unknown parameter");
+        assert_eq!(
+            error.to_string(),
+            "host.fw [RULES] rule 1: security group webserver has no parameter clients"
+        );
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let mut cluster =
+            ClusterConfig::parse(CLUSTER_CONFIG.as_bytes()).expect("valid cluster config");
+
+        // the parser rejects circular references, the resolver must not rely on it
+        for (name, include) in [("loop-a", "GROUP loop-b"), ("loop-b", "GROUP loop-a")] {
+            let mut group = Group::new();
+            group.add_rule(include.parse().expect("valid rule"));
+            cluster.config.groups.insert(name.to_string(), group);
+        }
+
+        let resolver = RuleResolver::new(&cluster);
+
+        let error = resolver
+            .resolve_host(&host_config("GROUP loop-a"))
+            .expect_err("circular group reference");
+        assert_eq!(
+            error.to_string(),
+            "cluster.fw [group loop-b] rule 1 (included from cluster.fw [group loop-a] rule 1 \
+             (included from host.fw [RULES] rule 1)): circular reference to security group loop-a"
+        );
+
+        let error = resolver
+            .resolve_host(&host_config("GROUP invalid"))
+            .expect_err("unknown alias");
+        assert_eq!(
+            error.to_string(),
+            "cluster.fw [group invalid] rule 1 (included from host.fw [RULES] rule 1): \
+             unknown alias unknown"
+        );
+
+        let error = resolver
+            .resolve_host(&host_config(
+                "IN ACCEPT -p ipv6-icmp -source +dc/management",
+            ))
+            .expect_err("incompatible address family");
+        assert_eq!(
+            error.to_string(),
+            "host.fw [RULES] rule 1: source address is IPv4, but protocol icmpv6 requires IPv6"
+        );
+
+        let error = resolver
+            .resolve_host(&host_config("GROUP tagged"))
+            .expect_err("vlan in host rules");
+        assert_eq!(
+            error.to_string(),
+            "cluster.fw [group tagged] rule 1 (included from host.fw [RULES] rule 1): \
+             smac and vlan can only be used in guest and bridge firewall configs"
+        );
+
+        for rules in [
+            "GROUP unknown",
+            "IN UNKNOWN(ACCEPT)",
+            "IN ACCEPT -dest +unknown",
+            "IN ACCEPT -dest +guest/management",
+            "IN ACCEPT -dest +sdn/management",
+        ] {
+            resolver
+                .resolve_host(&host_config(rules))
+                .expect_err("unknown reference");
+        }
+    }
+}
@@ -0,0 +1,544 @@
+//! Simulation of the firewall for synthetic packets.
+//!
+//! The [`PacketTracer`] evaluates the rules that apply to the host, a guest or a bridge against a
+//! [`Packet`] and reports which rule decides what happens to it. This answers the question why
+//! some traffic is (not) getting through without having to read the firewall configs by hand.
+//!
+//! Only the rules from the firewall configs are evaluated. Connection tracking and the rules that
+//! are generated from options (e.g. DHCP, NDP, the MAC and IP filters of guests or the host
+//! protections) are not simulated.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use anyhow::Error;
+
+use proxmox_network_types::mac_address::MacAddress;
+
+use crate::firewall::bridge::Config as BridgeConfig;
+use crate::firewall::cluster::Config as ClusterConfig;
+use crate::firewall::guest::Config as GuestConfig;
+use crate::firewall::host::Config as HostConfig;
+use crate::firewall::resolve::{ResolvedRule, RuleResolver};
+use crate::firewall::types::rule::{Direction, Verdict};
+use crate::firewall::types::rule_match::{
+    ConnectionState, IcmpCode, IcmpType, Icmpv6Code, Icmpv6Type, Ports, Protocol, TcpFlag,
+};
+
+/// The protocol of a [`Packet`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PacketProtocol {
+    Dccp {
+        sport: u16,
+        dport: u16,
+    },
+    Sctp {
+        sport: u16,
+        dport: u16,
+    },
+    Tcp {
+        sport: u16,
+        dport: u16,
+    },
+    Udp {
+        sport: u16,
+        dport: u16,
+    },
+    UdpLite {
+        sport: u16,
+        dport: u16,
+    },
+    Icmp {
+        ty: u8,
+        code: u8,
+    },
+    Icmpv6 {
+        ty: u8,
+        code: u8,
+    },
+    /// Any other protocol, matched by rules using the same protocol name.
+    Named(String),
+    /// Any other protocol, matched by rules using the same protocol number.
+    Numeric(u8),
+}
+
+impl PacketProtocol {
+    fn matches(&self, proto: &Protocol) -> bool {
+        match (proto, self) {
+            (Protocol::Dccp(ports), Self::Dccp { sport, dport })
+            | (Protocol::UdpLite(ports), Self::UdpLite { sport, dport }) => {
+                ports_match(ports, *sport, *dport)
+            }
+            (Protocol::Sctp(sctp), Self::Sctp { sport, dport }) => {
+                ports_match(sctp.ports(), *sport, *dport)
+            }
+            (Protocol::Tcp(tcp), Self::Tcp { sport, dport }) => {
+                ports_match(tcp.ports(), *sport, *dport)
+            }
+            (Protocol::Udp(udp), Self::Udp { sport, dport }) => {
+                ports_match(udp.ports(), *sport, *dport)
+            }
+            (Protocol::Icmp(icmp), Self::Icmp { ty, code }) => {
+                icmp.ty()
+                    .and_then(IcmpType::number)
+                    .is_none_or(|rule_ty| rule_ty == *ty)
+                    && icmp
+                        .code()
+                        .and_then(IcmpCode::number)
+                        .is_none_or(|rule_code| rule_code == *code)
+            }
+            (Protocol::Icmpv6(icmp), Self::Icmpv6 { ty, code }) => {
+                icmp.ty()
+                    .and_then(Icmpv6Type::number)
+                    .is_none_or(|rule_ty| rule_ty == *ty)
+                    && icmp
+                        .code()
+                        .and_then(Icmpv6Code::number)
+                        .is_none_or(|rule_code| rule_code == *code)
+            }
+            (Protocol::Named(rule_name), Self::Named(name)) => rule_name == name,
+            (Protocol::Numeric(rule_num), Self::Numeric(num)) => rule_num == num,
+            _ => false,
+        }
+    }
+}
+
+fn ports_match(ports: &Ports, sport: u16, dport: u16) -> bool {
+    ports.sport().is_none_or(|ports| ports.contains(sport))
+        && ports.dport().is_none_or(|ports| ports.contains(dport))
+}
+
+/// A synthetic packet that is evaluated by a [`PacketTracer`].
+///
+/// By default, the packet is the first packet of a new connection, so TCP packets only have the
+/// SYN flag set.
+#[derive(Clone, Debug)]
+pub struct Packet {
+    dir: Direction,
+    iface: Option<String>,
+    src: IpAddr,
+    dst: IpAddr,
+    proto: PacketProtocol,
+    ct_state: ConnectionState,
+    tcp_flags: Vec<TcpFlag>,
+    smac: Option<MacAddress>,
+    vlan: Option<u16>,
+}
+
+impl Packet {
+    pub fn new(
+        dir: Direction,
+        src: impl Into<IpAddr>,
+        dst: impl Into<IpAddr>,
+        proto: PacketProtocol,
+    ) -> Self {
+        let tcp_flags = match proto {
+            PacketProtocol::Tcp { .. } => vec![TcpFlag::Syn],
+            _ => Vec::new(),
+        };
+
+        Self {
+            dir,
+            iface: None,
+            src: src.into(),
+            dst: dst.into(),
+            proto,
+            ct_state: ConnectionState::New,
+            tcp_flags,
+            smac: None,
+            vlan: None,
+        }
+    }
+
+    /// Sets the interface the packet is received or sent on.
+    ///
+    /// For guests this is the name of the network device (e.g. `net0`), otherwise it is the name
+    /// of the interface on the host. Rules restricted to an interface never match packets without
+    /// one.
+    pub fn with_iface(mut self, iface: impl Into<String>) -> Self {
+        self.iface = Some(iface.into());
+        self
+    }
+
+    pub fn with_ct_state(mut self, ct_state: ConnectionState) -> Self {
+        self.ct_state = ct_state;
+        self
+    }
+
+    /// Sets the TCP flags of the packet, which are ignored for other protocols.
+    pub fn with_tcp_flags(mut self, tcp_flags: impl IntoIterator<Item = TcpFlag>) -> Self {
+        self.tcp_flags = tcp_flags.into_iter().collect();
+        self
+    }
+
+    /// Sets the source MAC address of the packet, rules matching on one never match packets
+    /// without one.
+    pub fn with_smac(mut self, smac: MacAddress) -> Self {
+        self.smac = Some(smac);
+        self
+    }
+
+    /// Sets the VLAN ID of a tagged packet, rules matching on one never match untagged packets.
+    pub fn with_vlan(mut self, vlan: u16) -> Self {
+        self.vlan = Some(vlan);
+        self
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.dir
+    }
+
+    pub fn iface(&self) -> Option<&str> {
+        self.iface.as_deref()
+    }
+
+    pub fn src(&self) -> &IpAddr {
+        &self.src
+    }
+
+    pub fn dst(&self) -> &IpAddr {
+        &self.dst
+    }
+
+    pub fn proto(&self) -> &PacketProtocol {
+        &self.proto
+    }
+
+    pub fn ct_state(&self) -> ConnectionState {
+        self.ct_state
+    }
+
+    pub fn tcp_flags(&self) -> &[TcpFlag] {
+        &self.tcp_flags
+    }
+
+    pub fn smac(&self) -> Option<MacAddress> {
+        self.smac
+    }
+
+    pub fn vlan(&self) -> Option<u16> {
+        self.vlan
+    }
+
+    fn matches(&self, rule: &ResolvedRule) -> bool {
+        rule.direction() == self.dir
+            && rule
+                .iface()
+                .is_none_or(|iface| self.iface.as_deref() == Some(iface))
+            && rule.src().is_none_or(|src| src.contains_address(&self.src))
+            && rule.dst().is_none_or(|dst| dst.contains_address(&self.dst))
+            && rule.proto().is_none_or(|proto| self.proto.matches(proto))
+            && rule
+                .ct_state()
+                .is_none_or(|states| states.contains(self.ct_state))
+            && rule
+                .tcp_flags()
+                .is_none_or(|flags| flags.matches(&self.tcp_flags))
+            && rule.smac().is_none_or(|smac| self.smac == Some(smac))
+            && rule.vlan().is_none_or(|vlan| self.vlan == Some(vlan))
+    }
+}
+
+/// What decided the verdict for a [`Packet`].
+#[derive(Clone, Copy, Debug)]
+pub enum TraceResult<'a> {
+    /// The first rule with a verdict that matched the packet.
+    Rule(&'a ResolvedRule, Verdict),
+    /// No rule matched, so the default policy for the direction of the packet applies.
+    DefaultPolicy(Verdict),
+}
+
+impl TraceResult<'_> {
+    pub fn verdict(&self) -> Verdict {
+        match self {
+            Self::Rule(_, verdict) | Self::DefaultPolicy(verdict) => *verdict,
+        }
+    }
+}
+
+impl fmt::Display for TraceResult<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Rule(rule, verdict) => write!(f, "{verdict} by {}", rule.location()),
+            Self::DefaultPolicy(verdict) => write!(f, "{verdict} by default policy"),
+        }
+    }
+}
+
+/// Evaluates the firewall rules of the host, a guest or a bridge for [`Packet`]s.
+///
+/// The rules are walked in the order the firewall evaluates them, with security groups being
+/// expanded in place.
+pub struct PacketTracer {
+    rules: Vec<ResolvedRule>,
+    policy_in: Verdict,
+    policy_out: Verdict,
+    policy_forward: Verdict,
+}
+
+impl PacketTracer {
+    /// Creates a tracer for the host, which evaluates the rules of the host config followed by
+    /// the rules of the cluster config.
+    pub fn host(cluster: &ClusterConfig, host: &HostConfig) -> Result<Self, Error> {
+        Ok(Self {
+            rules: RuleResolver::new(cluster).resolve_host(host)?,
+            policy_in: cluster.default_policy(Direction::In),
+            policy_out: cluster.default_policy(Direction::Out),
+            policy_forward: cluster.default_policy(Direction::Forward),
+        })
+    }
+
+    pub fn guest(cluster: &ClusterConfig, guest: &GuestConfig) -> Result<Self, Error> {
+        Ok(Self {
+            rules: RuleResolver::new(cluster).resolve_guest(guest)?,
+            policy_in: guest.default_policy(Direction::In),
+            policy_out: guest.default_policy(Direction::Out),
+            policy_forward: guest.default_policy(Direction::Forward),
+        })
+    }
+
+    /// Creates a tracer for the bridge with the given name.
+    ///
+    /// Bridges only filter forwarded packets, all other packets are accepted.
+    pub fn bridge(
+        cluster: &ClusterConfig,
+        name: &str,
+        bridge: &BridgeConfig,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            rules: RuleResolver::new(cluster).resolve_bridge(name, bridge)?,
+            policy_in: Verdict::Accept,
+            policy_out: Verdict::Accept,
+            policy_forward: bridge.policy_forward(),
+        })
+    }
+
+    pub fn default_policy(&self, dir: Direction) -> Verdict {
+        match dir {
+            Direction::In => self.policy_in,
+            Direction::Out => self.policy_out,
+            Direction::Forward => self.policy_forward,
+        }
+    }
+
+    /// Returns the first rule matching the packet or the default policy if there is none.
+    ///
+    /// Rules that set a mark or DSCP value do not decide the verdict and are skipped.
+    pub fn trace(&self, packet: &Packet) -> TraceResult<'_> {
+        self.rules
+            .iter()
+            .filter(|rule| packet.matches(rule))
+            .find_map(|rule| Some(TraceResult::Rule(rule, rule.verdict()?)))
+            .unwrap_or_else(|| TraceResult::DefaultPolicy(self.default_policy(packet.dir)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use crate::guest::types::Vmid;
+
+    use super::*;
+
+    const CLUSTER_CONFIG: &str = r#"
+[OPTIONS]
+
+policy_in: DROP
+
+[IPSET management]
+
+10.0.0.0/24
+!10.0.0.5
+
+[RULES]
+
+IN ACCEPT -p tcp -dport 8006 -source +management
+
+[group webserver]
+
+IN HTTP(ACCEPT)
+IN ACCEPT -p tcp -dport 443 -i net1
+"#;
+
+    const GUEST_CONFIG: &str = r#"
+[OPTIONS]
+
+policy_in: REJECT
+
+[RULES]
+
+GROUP webserver -i net0
+IN ACCEPT -p icmp -icmp-type echo-request
+IN ACCEPT -p ipv6-icmp -icmp-type port-unreachable
+IN DROP -p tcp -sport 1024:65535 -source 192.168.0.0/16
+OUT ACCEPT -ct-state established,related
+OUT DROP -p tcp -tcp-flags syn,!ack
+IN ACCEPT -vlan 100 -p tcp -dport 22
+"#;
+
+    fn tcp(sport: u16, dport: u16) -> PacketProtocol {
+        PacketProtocol::Tcp { sport, dport }
+    }
+
+    #[test]
+    fn test_trace_host() {
+        let cluster =
+            ClusterConfig::parse(CLUSTER_CONFIG.as_bytes()).expect("valid cluster config");
+        let host = HostConfig::parse("[RULES]\n\nIN REJECT -i vmbr0 -p udp\n".as_bytes())
+            .expect("valid host config");
+
+        let tracer = PacketTracer::host(&cluster, &host).expect("rules can be resolved");
+
+        let packet = Packet::new(
+            Direction::In,
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            tcp(40000, 8006),
+        );
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "ACCEPT by cluster.fw [RULES] rule 1"
+        );
+
+        // nomatch entries of the ipset are excluded
+        let packet = Packet::new(
+            Direction::In,
+            Ipv4Addr::new(10, 0, 0, 5),
+            Ipv4Addr::new(10, 0, 0, 2),
+            tcp(40000, 8006),
+        );
+        assert_eq!(tracer.trace(&packet).to_string(), "DROP by default policy");
+
+        let packet = Packet::new(
+            Direction::In,
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            PacketProtocol::Udp {
+                sport: 53,
+                dport: 53,
+            },
+        );
+        assert_eq!(tracer.trace(&packet).verdict(), Verdict::Drop);
+        assert_eq!(
+            tracer.trace(&packet.with_iface("vmbr0")).to_string(),
+            "REJECT by host.fw [RULES] rule 1"
+        );
+
+        let packet = Packet::new(
+            Direction::Out,
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 1),
+            tcp(8006, 40000),
+        );
+        assert!(matches!(
+            tracer.trace(&packet),
+            TraceResult::DefaultPolicy(Verdict::Accept)
+        ));
+    }
+
+    #[test]
+    fn test_trace_guest() {
+        let cluster =
+            ClusterConfig::parse(CLUSTER_CONFIG.as_bytes()).expect("valid cluster config");
+        let guest = GuestConfig::parse(
+            &Vmid::new(100),
+            "tap",
+            GUEST_CONFIG.as_bytes(),
+            "net0: virtio=BC:24:11:49:8D:75,bridge=vmbr0,trunks=100\n".as_bytes(),
+        )
+        .expect("valid guest config");
+
+        let tracer = PacketTracer::guest(&cluster, &guest).expect("rules can be resolved");
+
+        let src = Ipv4Addr::new(192, 168, 0, 1);
+        let dst = Ipv4Addr::new(10, 0, 0, 100);
+
+        let packet = Packet::new(Direction::In, src, dst, tcp(40000, 80)).with_iface("net0");
+        let TraceResult::Rule(rule, _) = tracer.trace(&packet) else {
+            panic!("packet matches a rule");
+        };
+        assert_eq!(
+            rule.location().to_string(),
+            "cluster.fw [group webserver] rule 1 (included from 100.fw [RULES] rule 1)"
+        );
+        assert_eq!(rule.verdict(), Some(Verdict::Accept));
+
+        // the group is only included for net0
+        let packet = Packet::new(Direction::In, src, dst, tcp(40000, 80)).with_iface("net1");
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "DROP by 100.fw [RULES] rule 4"
+        );
+
+        let packet = Packet::new(Direction::In, src, dst, tcp(22, 443)).with_iface("net1");
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "REJECT by default policy"
+        );
+
+        let packet = Packet::new(
+            Direction::In,
+            src,
+            dst,
+            PacketProtocol::Icmp { ty: 8, code: 0 },
+        );
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "ACCEPT by 100.fw [RULES] rule 2"
+        );
+
+        let packet = Packet::new(
+            Direction::In,
+            src,
+            dst,
+            PacketProtocol::Icmp { ty: 0, code: 0 },
+        );
+        assert_eq!(tracer.trace(&packet).verdict(), Verdict::Reject);
+
+        let packet = Packet::new(
+            Direction::In,
+            Ipv6Addr::LOCALHOST,
+            Ipv6Addr::LOCALHOST,
+            PacketProtocol::Icmpv6 { ty: 1, code: 4 },
+        );
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "ACCEPT by 100.fw [RULES] rule 3"
+        );
+
+        let packet = Packet::new(Direction::Out, dst, src, tcp(40000, 443));
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "DROP by 100.fw [RULES] rule 6"
+        );
+
+        let packet = packet.with_tcp_flags([TcpFlag::Syn, TcpFlag::Ack]);
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "ACCEPT by default policy"
+        );
+
+        let packet = packet
+            .with_ct_state(ConnectionState::Established)
+            .with_tcp_flags([TcpFlag::Ack]);
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "ACCEPT by 100.fw [RULES] rule 5"
+        );
+
+        let src = Ipv4Addr::new(10, 1, 0, 1);
+
+        let packet = Packet::new(Direction::In, src, dst, tcp(40000, 22));
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "REJECT by default policy"
+        );
+
+        let packet = packet.with_vlan(100);
+        assert_eq!(
+            tracer.trace(&packet).to_string(),
+            "ACCEPT by 100.fw [RULES] rule 7"
+        );
+    }
+}
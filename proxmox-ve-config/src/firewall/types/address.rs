@@ -1,4 +1,5 @@
 use std::fmt;
+use std::net::IpAddr;
 use std::ops::Deref;
 
 use anyhow::{bail, Error};
@@ -37,12 +38,25 @@ impl fmt::Display for IpEntry {
 }
 
 impl IpEntry {
-    fn family(&self) -> Family {
+    pub(crate) fn family(&self) -> Family {
         match self {
             Self::Cidr(cidr) => cidr.family(),
             Self::Range(range) => range.family(),
         }
     }
+
+    pub fn contains_address(&self, ip: &IpAddr) -> bool {
+        match (self, ip) {
+            (Self::Cidr(cidr), ip) => cidr.contains_address(ip),
+            (Self::Range(IpRange::V4(range)), IpAddr::V4(ip)) => {
+                range.start() <= ip && ip <= range.last()
+            }
+            (Self::Range(IpRange::V6(range)), IpAddr::V6(ip)) => {
+                range.start() <= ip && ip <= range.last()
+            }
+            _ => false,
+        }
+    }
 }
 
 impl From<Cidr> for IpEntry {
@@ -86,6 +100,20 @@ impl<T: Into<IpEntry>> From<T> for IpList {
     }
 }
 
+impl fmt::Display for IpList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, entry) in self.entries.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+
+            entry.fmt(f)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl std::str::FromStr for IpList {
     type Err = Error;
 
@@ -148,6 +176,72 @@ impl IpList {
     }
 }
 
+/// A fully qualified domain name, which resolves to a set of addresses.
+///
+/// Names are stored in lowercase and without a trailing dot. The last label must not be numeric,
+/// so IPv4 addresses are never mistaken for a name.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Fqdn(String);
+
+proxmox_serde::forward_deserialize_to_from_str!(Fqdn);
+proxmox_serde::forward_serialize_to_display!(Fqdn);
+
+impl Fqdn {
+    /// Maximum length of a name, without the trailing dot.
+    pub const MAX_LENGTH: usize = 253;
+    /// Maximum length of a single label of a name.
+    pub const MAX_LABEL_LENGTH: usize = 63;
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Fqdn {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for Fqdn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let name = s.strip_suffix('.').unwrap_or(s);
+
+        if name.is_empty() || name.len() > Self::MAX_LENGTH {
+            bail!("invalid domain name length: {s}");
+        }
+
+        for label in name.split('.') {
+            if label.is_empty()
+                || label.len() > Self::MAX_LABEL_LENGTH
+                || label.starts_with('-')
+                || label.ends_with('-')
+                || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                bail!("invalid domain name: {s}");
+            }
+        }
+
+        if name
+            .rsplit('.')
+            .next()
+            .is_some_and(|label| label.chars().all(|c| c.is_ascii_digit()))
+        {
+            bail!("invalid domain name, the last label is numeric: {s}");
+        }
+
+        Ok(Self(name.to_ascii_lowercase()))
+    }
+}
+
+impl fmt::Display for Fqdn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -274,4 +368,33 @@ mod tests {
         ])
         .expect_err("cannot mix ip families in ip list");
     }
+
+    #[test]
+    fn test_parse_fqdn() {
+        for (input, name) in [
+            ("repo.example.com", "repo.example.com"),
+            ("API.Vendor.com.", "api.vendor.com"),
+            ("localhost", "localhost"),
+            ("xn--bcher-kva.example", "xn--bcher-kva.example"),
+            ("1password.com", "1password.com"),
+        ] {
+            let fqdn: Fqdn = input.parse().expect("valid domain name");
+            assert_eq!(fqdn.as_str(), name);
+        }
+
+        for input in [
+            "",
+            ".",
+            "repo..example.com",
+            "-repo.example.com",
+            "repo-.example.com",
+            "repo_1.example.com",
+            "10.0.0.1",
+            "10.0.0.300",
+            "10.0.0.0/8",
+            &format!("{}.com", "a".repeat(64)),
+        ] {
+            input.parse::<Fqdn>().expect_err("invalid domain name");
+        }
+    }
 }
@@ -5,6 +5,7 @@ use anyhow::{bail, format_err, Error};
 use proxmox_network_types::ip_address::Cidr;
 
 use crate::firewall::parse::{match_name, match_non_whitespace};
+use crate::firewall::types::address::Fqdn;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum AliasScope {
@@ -211,27 +212,110 @@ impl Alias {
     }
 }
 
+/// Splits a line of the ALIASES section into the name, the value and the comment of the alias.
+fn split_alias_line(s: &str) -> Result<(&str, &str, Option<String>), Error> {
+    let (name, line) =
+        match_name(s.trim_start()).ok_or_else(|| format_err!("expected an alias name"))?;
+
+    let (address, line) = match_non_whitespace(line.trim_start())
+        .ok_or_else(|| format_err!("expected a value for alias {name:?}"))?;
+
+    let line = line.trim_start();
+
+    let comment = match line.strip_prefix('#') {
+        Some(comment) => Some(comment.trim().to_string()),
+        None if !line.is_empty() => bail!("trailing characters in alias: {line:?}"),
+        None => None,
+    };
+
+    Ok((name, address, comment))
+}
+
+fn write_alias_line(
+    f: &mut std::fmt::Formatter<'_>,
+    name: &str,
+    address: &dyn Display,
+    comment: Option<&str>,
+) -> std::fmt::Result {
+    write!(f, "{name} {address}")?;
+
+    if let Some(comment) = comment {
+        write!(f, " # {comment}")?;
+    }
+
+    Ok(())
+}
+
 impl FromStr for Alias {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (name, line) =
-            match_name(s.trim_start()).ok_or_else(|| format_err!("expected an alias name"))?;
+        let (name, address, comment) = split_alias_line(s)?;
+        let address: Cidr = address.parse()?;
 
-        let (address, line) = match_non_whitespace(line.trim_start())
-            .ok_or_else(|| format_err!("expected a value for alias {name:?}"))?;
+        Ok(Alias::new(name, address, comment))
+    }
+}
 
-        let address: Cidr = address.parse()?;
+impl Display for Alias {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_alias_line(f, &self.name, &self.address, self.comment())
+    }
+}
 
-        let line = line.trim_start();
+/// An alias for a domain name, stored in the ALIASES section of the firewall configuration
+/// together with the [`Alias`]es of addresses.
+///
+/// The addresses of the domain name are looked up by an
+/// [`FqdnResolver`](crate::firewall::fqdn::FqdnResolver) and can change over time.
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct FqdnAlias {
+    name: String,
+    fqdn: Fqdn,
+    comment: Option<String>,
+}
 
-        let comment = match line.strip_prefix('#') {
-            Some(comment) => Some(comment.trim().to_string()),
-            None if !line.is_empty() => bail!("trailing characters in alias: {line:?}"),
-            None => None,
-        };
+impl FqdnAlias {
+    /// Creates a new [`FqdnAlias`], see [`Alias::new`].
+    pub fn new(name: impl Into<String>, fqdn: Fqdn, comment: impl Into<Option<String>>) -> Self {
+        let mut lowercase_name = name.into();
+        lowercase_name.make_ascii_lowercase();
 
-        Ok(Alias::new(name, address, comment))
+        Self {
+            name: lowercase_name,
+            fqdn,
+            comment: comment.into(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn fqdn(&self) -> &Fqdn {
+        &self.fqdn
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+}
+
+impl FromStr for FqdnAlias {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, fqdn, comment) = split_alias_line(s)?;
+        let fqdn: Fqdn = fqdn.parse()?;
+
+        Ok(FqdnAlias::new(name, fqdn, comment))
+    }
+}
+
+impl Display for FqdnAlias {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_alias_line(f, &self.name, &self.fqdn, self.comment())
     }
 }
 
@@ -261,6 +345,33 @@ mod tests {
             &Cidr::new_v4([10, 0, 0, 0], 32).expect("valid CIDR")
         );
         assert_eq!(alias.comment(), Some("a comment"));
+
+        "updates repo.example.com"
+            .parse::<Alias>()
+            .expect_err("domain names are no alias addresses");
+    }
+
+    #[test]
+    fn test_parse_fqdn_alias() {
+        let alias = "Updates Repo.Example.com. # mirror"
+            .parse::<FqdnAlias>()
+            .expect("valid alias");
+        assert_eq!(alias.name(), "updates");
+        assert_eq!(
+            alias.fqdn(),
+            &"repo.example.com"
+                .parse::<Fqdn>()
+                .expect("valid domain name")
+        );
+        assert_eq!(alias.to_string(), "updates repo.example.com # mirror");
+
+        for alias in [
+            "updates 10.0.0.1",
+            "updates repo_1.example.com",
+            "updates repo.example.com trailing",
+        ] {
+            alias.parse::<FqdnAlias>().expect_err("invalid alias");
+        }
     }
 
     #[test]
@@ -1,5 +1,8 @@
-use anyhow::Error;
+use std::collections::BTreeSet;
 
+use anyhow::{bail, Error};
+
+use crate::firewall::types::rule::{Kind, RuleGroup};
 use crate::firewall::types::Rule;
 
 #[derive(Debug)]
@@ -27,6 +30,48 @@ impl Group {
         &self.rules
     }
 
+    /// Returns the names of the parameters of this group, which are all parameters used in its
+    /// rules. Every rule including the group has to pass an address for each of them.
+    pub fn params(&self) -> BTreeSet<&str> {
+        self.rules.iter().flat_map(Rule::params).collect()
+    }
+
+    /// Returns the rules including other security groups.
+    pub fn includes(&self) -> impl Iterator<Item = &RuleGroup> + '_ {
+        self.rules.iter().filter_map(|rule| match rule.kind() {
+            Kind::Group(group) => Some(group),
+            Kind::Match(_) => None,
+        })
+    }
+
+    /// Checks that a rule including this group passes exactly the parameters of this group.
+    pub(crate) fn check_include(&self, include: &RuleGroup) -> Result<(), Error> {
+        let params = self.params();
+
+        if let Some(param) = params
+            .iter()
+            .find(|param| !include.params().contains_key(**param))
+        {
+            bail!(
+                "security group {} requires parameter {param}",
+                include.group()
+            );
+        }
+
+        if let Some(param) = include
+            .params()
+            .keys()
+            .find(|param| !params.contains(param.as_str()))
+        {
+            bail!(
+                "security group {} has no parameter {param}",
+                include.group()
+            );
+        }
+
+        Ok(())
+    }
+
     pub fn comment(&self) -> Option<&str> {
         self.comment.as_deref()
     }
@@ -35,8 +80,7 @@ impl Group {
         self.comment = comment;
     }
 
-    pub(crate) fn parse_entry(&mut self, line: &str) -> Result<(), Error> {
-        self.rules.push(line.parse()?);
-        Ok(())
+    pub(crate) fn add_rule(&mut self, rule: Rule) {
+        self.rules.push(rule);
     }
 }
@@ -1,13 +1,18 @@
 use core::fmt::Display;
+use std::io;
 use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
 
 use anyhow::{bail, format_err, Error};
 use proxmox_network_types::ip_address::{Cidr, IpRange};
 
+use crate::firewall::common::ParseErrors;
+use crate::firewall::coverage::EffectiveAddresses;
+use crate::firewall::ipset_format::IpsetFormat;
 use crate::firewall::parse::{match_name, match_non_whitespace};
 use crate::guest::vm::NetworkConfig;
 
+use super::address::{Fqdn, IpEntry};
 use super::alias::RuleAliasName;
 
 /// The scope of an ipset.
@@ -179,6 +184,10 @@ impl FromStr for IpsetAddress {
             return Ok(IpsetAddress::Cidr(cidr));
         }
 
+        if let Ok(range) = s.parse() {
+            return Ok(IpsetAddress::Range(range));
+        }
+
         if let Ok(name) = s.parse() {
             return Ok(IpsetAddress::Alias(name));
         }
@@ -187,6 +196,16 @@ impl FromStr for IpsetAddress {
     }
 }
 
+impl Display for IpsetAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Alias(name) => name.fmt(f),
+            Self::Cidr(cidr) => cidr.fmt(f),
+            Self::Range(range) => range.fmt(f),
+        }
+    }
+}
+
 impl From<Cidr> for IpsetAddress {
     fn from(cidr: Cidr) -> Self {
         IpsetAddress::Cidr(cidr)
@@ -231,37 +250,133 @@ impl<T: Into<IpsetAddress>> From<T> for IpsetEntry {
     }
 }
 
+/// Splits a line of an IPSET section into the nomatch flag, the value and the comment.
+fn split_entry_line(line: &str) -> Result<(bool, &str, Option<String>), Error> {
+    let line = line.trim_start();
+
+    let (nomatch, line) = match line.strip_prefix('!') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+
+    let (value, line) =
+        match_non_whitespace(line.trim_start()).ok_or_else(|| format_err!("missing value"))?;
+
+    let line = line.trim_start();
+
+    let comment = match line.strip_prefix('#') {
+        Some(comment) => Some(comment.trim().to_string()),
+        None if !line.is_empty() => bail!("trailing characters in ipset entry: {line:?}"),
+        None => None,
+    };
+
+    Ok((nomatch, value, comment))
+}
+
+fn write_entry_line(
+    f: &mut std::fmt::Formatter<'_>,
+    nomatch: bool,
+    value: &dyn Display,
+    comment: Option<&str>,
+) -> std::fmt::Result {
+    if nomatch {
+        f.write_str("!")?;
+    }
+
+    value.fmt(f)?;
+
+    if let Some(comment) = comment {
+        write!(f, " # {comment}")?;
+    }
+
+    Ok(())
+}
+
 impl FromStr for IpsetEntry {
     type Err = Error;
 
     fn from_str(line: &str) -> Result<Self, Error> {
-        let line = line.trim_start();
+        let (nomatch, address, comment) = split_entry_line(line)?;
 
-        let (nomatch, line) = match line.strip_prefix('!') {
-            Some(line) => (true, line),
-            None => (false, line),
-        };
+        Ok(Self {
+            nomatch,
+            address: address.parse()?,
+            comment,
+        })
+    }
+}
 
-        let (address, line) =
-            match_non_whitespace(line.trim_start()).ok_or_else(|| format_err!("missing value"))?;
+impl Display for IpsetEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_entry_line(f, self.nomatch, &self.address, self.comment.as_deref())
+    }
+}
+
+/// An entry of an ipset containing a domain name, written as `fqdn:<name>` to distinguish it
+/// from alias names.
+///
+/// The addresses of the domain name are looked up by an
+/// [`FqdnResolver`](crate::firewall::fqdn::FqdnResolver) and can change over time.
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct IpsetFqdnEntry {
+    pub nomatch: bool,
+    pub fqdn: Fqdn,
+    pub comment: Option<String>,
+}
+
+impl IpsetFqdnEntry {
+    pub const PREFIX: &'static str = "fqdn:";
+
+    pub fn new(fqdn: Fqdn, nomatch: bool, comment: impl Into<Option<String>>) -> Self {
+        Self {
+            nomatch,
+            fqdn,
+            comment: comment.into(),
+        }
+    }
 
-        let address: IpsetAddress = address.parse()?;
+    /// Returns whether a line of an IPSET section contains a domain name.
+    fn is_fqdn_entry(line: &str) -> bool {
         let line = line.trim_start();
+        let line = line.strip_prefix('!').unwrap_or(line);
 
-        let comment = match line.strip_prefix('#') {
-            Some(comment) => Some(comment.trim().to_string()),
-            None if !line.is_empty() => bail!("trailing characters in ipset entry: {line:?}"),
-            None => None,
-        };
+        line.trim_start().starts_with(Self::PREFIX)
+    }
+}
+
+impl From<Fqdn> for IpsetFqdnEntry {
+    fn from(fqdn: Fqdn) -> Self {
+        Self::new(fqdn, false, None)
+    }
+}
+
+impl FromStr for IpsetFqdnEntry {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self, Error> {
+        let (nomatch, value, comment) = split_entry_line(line)?;
+
+        let fqdn = value
+            .strip_prefix(Self::PREFIX)
+            .ok_or_else(|| format_err!("expected a domain name prefixed with {:?}", Self::PREFIX))?
+            .parse()?;
 
         Ok(Self {
             nomatch,
-            address,
+            fqdn,
             comment,
         })
     }
 }
 
+impl Display for IpsetFqdnEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = format!("{}{}", Self::PREFIX, self.fqdn);
+        write_entry_line(f, self.nomatch, &value, self.comment.as_deref())
+    }
+}
+
 #[derive(Debug)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct Ipfilter<'a> {
@@ -288,6 +403,9 @@ impl Ipfilter<'_> {
 pub struct Ipset {
     pub name: IpsetName,
     set: Vec<IpsetEntry>,
+    /// The entries containing domain names, together with the number of other entries preceding
+    /// them, to keep their position when writing the ipset.
+    fqdns: Vec<(usize, IpsetFqdnEntry)>,
     pub comment: Option<String>,
 }
 
@@ -296,6 +414,7 @@ impl Ipset {
         Self {
             name,
             set: Vec::new(),
+            fqdns: Vec::new(),
             comment: None,
         }
     }
@@ -309,10 +428,44 @@ impl Ipset {
     }
 
     pub(crate) fn parse_entry(&mut self, line: &str) -> Result<(), Error> {
-        self.set.push(line.parse()?);
+        if IpsetFqdnEntry::is_fqdn_entry(line) {
+            self.fqdns.push((self.set.len(), line.parse()?));
+        } else {
+            self.set.push(line.parse()?);
+        }
+
         Ok(())
     }
 
+    /// The entries containing domain names, which are kept apart from the other entries.
+    pub fn fqdns(&self) -> impl ExactSizeIterator<Item = &IpsetFqdnEntry> + '_ {
+        self.fqdns.iter().map(|(_, entry)| entry)
+    }
+
+    /// Appends an entry containing a domain name after the current entries.
+    pub fn add_fqdn(&mut self, entry: impl Into<IpsetFqdnEntry>) {
+        self.fqdns.push((self.set.len(), entry.into()));
+    }
+
+    /// The lines of all entries, with the entries containing domain names at their original
+    /// position among the other entries.
+    pub(crate) fn lines(&self) -> Vec<String> {
+        let mut fqdns = self.fqdns.iter().peekable();
+        let mut lines = Vec::with_capacity(self.set.len() + self.fqdns.len());
+
+        for (index, entry) in self.set.iter().enumerate() {
+            while let Some((_, fqdn)) = fqdns.next_if(|(position, _)| *position <= index) {
+                lines.push(fqdn.to_string());
+            }
+
+            lines.push(entry.to_string());
+        }
+
+        // entries after the last other entry, or positioned after entries that have been removed
+        lines.extend(fqdns.map(|(_, fqdn)| fqdn.to_string()));
+        lines
+    }
+
     pub fn ipfilter(&self) -> Option<Ipfilter<'_>> {
         if self.name.scope() != IpsetScope::Guest {
             return None;
@@ -333,6 +486,73 @@ impl Ipset {
 
         None
     }
+
+    /// Appends the entries of an address list in an external format.
+    ///
+    /// Nothing is added if any line of the list is invalid, all invalid lines are reported.
+    pub fn import(&mut self, format: IpsetFormat, data: &str) -> Result<(), ParseErrors> {
+        let entries = format.parse(data)?;
+        self.set.extend(entries);
+        Ok(())
+    }
+
+    /// Writes the entries as an address list in an external format, see [`IpsetFormat::write`].
+    pub fn export<W: io::Write>(&self, format: IpsetFormat, output: W) -> Result<(), Error> {
+        if let Some(entry) = self.fqdns().next() {
+            bail!(
+                "ipset {}: cannot export {entry} in {format} format",
+                self.name
+            );
+        }
+
+        format
+            .write(&self.set, output)
+            .map_err(|err| format_err!("ipset {}: {err}", self.name))
+    }
+
+    /// Returns an ipset with the same name and comment, containing the minimal list of CIDRs
+    /// that covers the same addresses as this ipset.
+    ///
+    /// Overlapping and adjacent entries are merged and nomatch entries are removed together with
+    /// the addresses they exclude. The comments of the entries are dropped. Aliases and domain
+    /// names have to be resolved first, see [`RuleResolver::resolve_ipset`].
+    ///
+    /// [`RuleResolver::resolve_ipset`]: crate::firewall::resolve::RuleResolver::resolve_ipset
+    pub fn optimized(&self) -> Result<Ipset, Error> {
+        if let Some(entry) = self.fqdns().next() {
+            bail!("cannot optimize ipset {}, it contains {entry}", self.name);
+        }
+
+        let mut entries = Vec::new();
+        let mut nomatch = Vec::new();
+
+        for entry in &self.set {
+            let address = match &entry.address {
+                IpsetAddress::Cidr(cidr) => IpEntry::Cidr(*cidr),
+                IpsetAddress::Range(range) => IpEntry::Range(*range),
+                address => bail!(
+                    "cannot optimize ipset {}, it references {address}",
+                    self.name
+                ),
+            };
+
+            if entry.nomatch {
+                nomatch.push(address);
+            } else {
+                entries.push(address);
+            }
+        }
+
+        let mut optimized = Ipset::new(self.name.clone());
+        optimized.comment.clone_from(&self.comment);
+        optimized.extend(
+            EffectiveAddresses::new(&entries, &nomatch)
+                .cidrs()
+                .map(IpsetEntry::from),
+        );
+
+        Ok(optimized)
+    }
 }
 
 impl Deref for Ipset {
@@ -418,6 +638,64 @@ mod tests {
             .parse::<IpsetAddress>()
             .expect("valid ipset address");
         assert!(matches!(ipset_address, IpsetAddress::Alias(..)));
+
+        ipset_address = "10.0.0.1-10.0.0.9"
+            .parse::<IpsetAddress>()
+            .expect("valid ipset address");
+        assert!(matches!(ipset_address, IpsetAddress::Range(..)));
+        assert_eq!(ipset_address.to_string(), "10.0.0.1-10.0.0.9");
+
+        "fqdn:api.vendor.com"
+            .parse::<IpsetAddress>()
+            .expect_err("domain names are no ipset addresses");
+    }
+
+    #[test]
+    fn test_parse_ipset_fqdn_entry() {
+        let entry = " ! fqdn:API.vendor.com # saas"
+            .parse::<IpsetFqdnEntry>()
+            .expect("valid ipset entry");
+
+        assert_eq!(
+            entry,
+            IpsetFqdnEntry {
+                nomatch: true,
+                fqdn: "api.vendor.com".parse().unwrap(),
+                comment: Some("saas".to_string()),
+            }
+        );
+        assert_eq!(entry.to_string(), "!fqdn:api.vendor.com # saas");
+
+        for entry in [
+            "fqdn:",
+            "fqdn:10.0.0.1",
+            "fqdn:api..vendor.com",
+            "api.vendor.com",
+        ] {
+            entry
+                .parse::<IpsetFqdnEntry>()
+                .expect_err("invalid ipset entry");
+        }
+
+        let mut ipset = Ipset::from_parts(IpsetScope::Datacenter, "saas");
+        ipset.parse_entry("10.0.0.1").expect("valid ipset entry");
+        ipset
+            .parse_entry("!fqdn:api.vendor.com # saas")
+            .expect("valid ipset entry");
+        ipset
+            .parse_entry("fqdn:api..vendor.com")
+            .expect_err("invalid ipset entry");
+        ipset.parse_entry("10.0.0.2").expect("valid ipset entry");
+
+        assert_eq!(ipset.len(), 2);
+        assert_eq!(ipset.fqdns().collect::<Vec<_>>(), [&entry]);
+        assert_eq!(
+            ipset.lines(),
+            ["10.0.0.1/32", "!fqdn:api.vendor.com # saas", "10.0.0.2/32"]
+        );
+        ipset
+            .optimized()
+            .expect_err("domain names cannot be optimized");
     }
 
     #[test]
@@ -465,4 +743,37 @@ mod tests {
             }
         )
     }
+
+    #[test]
+    fn test_optimized_ipset() {
+        let mut ipset = Ipset::from_parts(IpsetScope::Datacenter, "blocklist");
+        ipset.comment = Some("known bad networks".to_string());
+
+        for entry in [
+            "198.51.100.0/25 # first half",
+            "198.51.100.128/25",
+            "198.51.100.7",
+            "203.0.113.0/24",
+            "!203.0.113.0/25",
+            "2001:db8::/33",
+            "2001:db8:8000::/33",
+        ] {
+            ipset.parse_entry(entry).expect("valid ipset entry");
+        }
+
+        let optimized = ipset.optimized().expect("ipset can be optimized");
+
+        assert_eq!(optimized.name(), ipset.name());
+        assert_eq!(optimized.comment.as_deref(), Some("known bad networks"));
+        assert_eq!(
+            optimized
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect::<Vec<_>>(),
+            ["198.51.100.0/24", "203.0.113.128/25", "2001:db8::/32"]
+        );
+
+        ipset.parse_entry("dc/servers").expect("valid ipset entry");
+        ipset.optimized().expect_err("aliases cannot be optimized");
+    }
 }
@@ -2,11 +2,10 @@ use std::fmt;
 use std::str::FromStr;
 
 use crate::firewall::parse::parse_bool;
-use anyhow::{bail, Error};
+use anyhow::{bail, format_err, Error};
 use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, Default)]
-#[cfg_attr(test, derive(Eq, PartialEq))]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, Default, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum LogRateLimitTimescale {
     #[default]
@@ -30,8 +29,35 @@ impl FromStr for LogRateLimitTimescale {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-#[cfg_attr(test, derive(Eq, PartialEq))]
+impl fmt::Display for LogRateLimitTimescale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogRateLimitTimescale::Second => "second",
+            LogRateLimitTimescale::Minute => "minute",
+            LogRateLimitTimescale::Hour => "hour",
+            LogRateLimitTimescale::Day => "day",
+        })
+    }
+}
+
+/// Parses a rate of the form `<packets>[/<timescale>]`.
+fn parse_rate(value: &str) -> Result<(i64, Option<LogRateLimitTimescale>), Error> {
+    match value.split_once('/') {
+        None => Ok((i64::from_str(value)?, None)),
+        Some((rate, unit)) => {
+            if unit.is_empty() {
+                bail!("empty unit specification")
+            }
+
+            Ok((
+                i64::from_str(rate)?,
+                Some(LogRateLimitTimescale::from_str(unit)?),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Eq, PartialEq)]
 pub struct LogRateLimit {
     enabled: bool,
     rate: i64, // in packets
@@ -91,19 +117,15 @@ impl FromStr for LogRateLimit {
                 Some((key, value)) if !key.is_empty() && !value.is_empty() => match key {
                     "enable" => limit.enabled = parse_bool(value)?,
                     "burst" => limit.burst = i64::from_str(value)?,
-                    "rate" => match value.split_once('/') {
-                        None => {
-                            limit.rate = i64::from_str(value)?;
-                        }
-                        Some((rate, unit)) => {
-                            if unit.is_empty() {
-                                bail!("empty unit specification")
-                            }
+                    "rate" => {
+                        let (rate, per) = parse_rate(value)?;
 
-                            limit.rate = i64::from_str(rate)?;
-                            limit.per = LogRateLimitTimescale::from_str(unit)?;
+                        limit.rate = rate;
+
+                        if let Some(per) = per {
+                            limit.per = per;
                         }
-                    },
+                    }
                     _ => bail!("Invalid value for Key found in log_ratelimit!"),
                 },
                 _ => bail!("invalid value in log_ratelimit"),
@@ -114,6 +136,86 @@ impl FromStr for LogRateLimit {
     }
 }
 
+/// A rate limit of a rule, e.g. `10/second,burst=20`.
+///
+/// Uses the same format as the `rate` and `burst` keys of [`LogRateLimit`], the rate can also be
+/// given without a key.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct RateLimit {
+    rate: i64, // in packets
+    per: LogRateLimitTimescale,
+    burst: Option<i64>, // in packets
+}
+
+impl RateLimit {
+    pub fn new(
+        rate: i64,
+        per: LogRateLimitTimescale,
+        burst: impl Into<Option<i64>>,
+    ) -> Result<Self, Error> {
+        let burst = burst.into();
+
+        if rate < 1 {
+            bail!("rate must be at least 1");
+        }
+
+        if burst.is_some_and(|burst| burst < 1) {
+            bail!("burst must be at least 1");
+        }
+
+        Ok(Self { rate, per, burst })
+    }
+
+    pub fn rate(&self) -> i64 {
+        self.rate
+    }
+
+    pub fn per(&self) -> LogRateLimitTimescale {
+        self.per
+    }
+
+    pub fn burst(&self) -> Option<i64> {
+        self.burst
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(str: &str) -> Result<Self, Error> {
+        let mut rate = None;
+        let mut burst = None;
+
+        for element in str.split(',') {
+            match element.split_once('=') {
+                None if rate.is_none() && !element.is_empty() => rate = Some(parse_rate(element)?),
+                Some(("rate", value)) if rate.is_none() => rate = Some(parse_rate(value)?),
+                Some(("burst", value)) if burst.is_none() => burst = Some(i64::from_str(value)?),
+                _ => bail!("invalid value {element:?} in rate limit"),
+            }
+        }
+
+        let (rate, per) = rate.ok_or_else(|| format_err!("missing rate in rate limit"))?;
+
+        Self::new(rate, per.unwrap_or_default(), burst)
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.rate, self.per)?;
+
+        if let Some(burst) = self.burst {
+            write!(f, ",burst={burst}")?;
+        }
+
+        Ok(())
+    }
+}
+
+proxmox_serde::forward_deserialize_to_from_str!(RateLimit);
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Default)]
 pub enum LogLevel {
     #[default]
@@ -219,4 +321,36 @@ mod tests {
             .parse::<LogRateLimit>()
             .expect_err("invalid unit for rate");
     }
+
+    #[test]
+    fn test_parse_rule_rate_limit() {
+        for (input, expected) in [
+            (
+                "10/second,burst=20",
+                RateLimit::new(10, LogRateLimitTimescale::Second, 20),
+            ),
+            ("5", RateLimit::new(5, LogRateLimitTimescale::Second, None)),
+            (
+                "burst=3,rate=1/minute",
+                RateLimit::new(1, LogRateLimitTimescale::Minute, 3),
+            ),
+        ] {
+            let limit = input.parse::<RateLimit>().expect("valid rate limit");
+            assert_eq!(limit, expected.unwrap());
+            assert_eq!(limit, limit.to_string().parse().unwrap());
+        }
+
+        for input in [
+            "",
+            "burst=20",
+            "0/second",
+            "10/second,burst=0",
+            "10/fortnight",
+            "10,20",
+            "10,enable=1",
+            "10/second,",
+        ] {
+            input.parse::<RateLimit>().expect_err("invalid rate limit");
+        }
+    }
 }
@@ -90,6 +90,29 @@ impl Deref for PortList {
     }
 }
 
+impl PortList {
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|entry| match entry {
+            PortEntry::Port(entry) => *entry == port,
+            PortEntry::Range(beg, end) => (*beg..=*end).contains(&port),
+        })
+    }
+
+    /// Formats the port list the way it is written in the firewall config files.
+    ///
+    /// The [`Display`](fmt::Display) implementation produces the nftables notation instead.
+    pub fn to_config_string(&self) -> String {
+        self.0
+            .iter()
+            .map(|entry| match entry {
+                PortEntry::Port(port) => port.to_string(),
+                PortEntry::Range(beg, end) => format!("{beg}:{end}"),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
 impl std::str::FromStr for PortList {
     type Err = Error;
 
@@ -173,6 +196,8 @@ mod tests {
             ])
         );
 
+        assert_eq!(port_list.to_config_string(), "12345,0:65535,1337,22:80,443");
+
         "0::1337".parse::<PortList>().unwrap_err();
         "0:1337,".parse::<PortList>().unwrap_err();
         "70000".parse::<PortList>().unwrap_err();
@@ -1,12 +1,19 @@
 use core::fmt::Display;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::str::FromStr;
 
 use anyhow::{bail, ensure, format_err, Error};
+use proxmox_network_types::mac_address::MacAddress;
+use proxmox_sortable_macro::sortable;
 
 use crate::firewall::parse::match_name;
-use crate::firewall::types::rule_match::RuleMatch;
+use crate::firewall::types::log::{LogLevel, RateLimit};
+use crate::firewall::types::port::PortList;
 use crate::firewall::types::rule_match::RuleOptions;
+use crate::firewall::types::rule_match::{
+    ConnectionStates, IpAddrMatch, Protocol, RuleMatch, TcpFlags,
+};
 
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum Direction {
@@ -85,6 +92,127 @@ impl Display for Verdict {
 
 proxmox_serde::forward_deserialize_to_from_str!(Verdict);
 
+/// Names of the actions that take a value, written as e.g. `MARK(0x10)`.
+pub(crate) const ACTIONS_WITH_VALUE: [&str; 2] = ["MARK", "DSCP"];
+
+pub const DSCP_MAX: u8 = 63;
+
+#[sortable]
+const DSCP_CLASSES: [(&str, u8); 22] = sorted!([
+    ("af11", 10),
+    ("af12", 12),
+    ("af13", 14),
+    ("af21", 18),
+    ("af22", 20),
+    ("af23", 22),
+    ("af31", 26),
+    ("af32", 28),
+    ("af33", 30),
+    ("af41", 34),
+    ("af42", 36),
+    ("af43", 38),
+    ("cs0", 0),
+    ("cs1", 8),
+    ("cs2", 16),
+    ("cs3", 24),
+    ("cs4", 32),
+    ("cs5", 40),
+    ("cs6", 48),
+    ("cs7", 56),
+    ("ef", 46),
+    ("va", 44),
+]);
+
+/// The action of a rule matching a packet.
+///
+/// Rules with a [`Verdict`] end the evaluation of the rules, all other actions modify the packet
+/// and continue with the next rule.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuleAction {
+    Verdict(Verdict),
+    /// Sets the firewall mark of the packet.
+    Mark(u32),
+    /// Sets the DSCP value of the packet.
+    Dscp(u8),
+}
+
+impl Default for RuleAction {
+    fn default() -> Self {
+        Self::Verdict(Verdict::default())
+    }
+}
+
+impl From<Verdict> for RuleAction {
+    fn from(verdict: Verdict) -> Self {
+        Self::Verdict(verdict)
+    }
+}
+
+impl RuleAction {
+    /// The verdict of the action, if the evaluation of rules stops at a rule with this action.
+    pub fn verdict(self) -> Option<Verdict> {
+        match self {
+            RuleAction::Verdict(verdict) => Some(verdict),
+            RuleAction::Mark(_) | RuleAction::Dscp(_) => None,
+        }
+    }
+
+    /// Whether the evaluation of rules stops at a rule with this action.
+    pub fn is_terminal(self) -> bool {
+        self.verdict().is_some()
+    }
+
+    /// Parses an action that takes a value, e.g. `MARK` with `0x10`.
+    ///
+    /// Marks can be given in decimal or hexadecimal notation, DSCP values either as number
+    /// between 0 and 63 or as the name of a class like `af41` or `ef`.
+    pub(crate) fn parse_with_value(name: &str, value: &str) -> Result<Self, Error> {
+        if name.eq_ignore_ascii_case("MARK") {
+            let mark = match value
+                .strip_prefix("0x")
+                .or_else(|| value.strip_prefix("0X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => value.parse(),
+            };
+
+            return mark
+                .map(RuleAction::Mark)
+                .map_err(|_| format_err!("invalid mark {value:?}, expected a 32 bit number"));
+        }
+
+        if name.eq_ignore_ascii_case("DSCP") {
+            let class = value.to_ascii_lowercase();
+
+            if let Ok(index) = DSCP_CLASSES.binary_search_by(|(name, _)| (*name).cmp(&class)) {
+                return Ok(RuleAction::Dscp(DSCP_CLASSES[index].1));
+            }
+
+            return match value.parse::<u8>() {
+                Ok(dscp) if dscp <= DSCP_MAX => Ok(RuleAction::Dscp(dscp)),
+                _ => {
+                    bail!("invalid DSCP value {value:?}, expected a class name or 0 to {DSCP_MAX}")
+                }
+            };
+        }
+
+        bail!("invalid action {name:?}, expected one of 'MARK' or 'DSCP'");
+    }
+}
+
+impl Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleAction::Verdict(verdict) => verdict.fmt(f),
+            RuleAction::Mark(mark) => write!(f, "MARK({mark:#x})"),
+            RuleAction::Dscp(dscp) => match DSCP_CLASSES.iter().find(|class| class.1 == *dscp) {
+                Some((class, _)) => write!(f, "DSCP({class})"),
+                None => write!(f, "DSCP({dscp})"),
+            },
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct Rule {
@@ -140,6 +268,25 @@ impl FromStr for Rule {
     }
 }
 
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.disabled {
+            f.write_str("|")?;
+        }
+
+        match &self.kind {
+            Kind::Group(group) => group.fmt(f)?,
+            Kind::Match(rule) => rule.fmt(f)?,
+        }
+
+        if let Some(comment) = &self.comment {
+            write!(f, " # {comment}")?;
+        }
+
+        Ok(())
+    }
+}
+
 impl Rule {
     pub fn iface(&self) -> Option<&str> {
         match &self.kind {
@@ -159,6 +306,30 @@ impl Rule {
     pub fn comment(&self) -> Option<&str> {
         self.comment.as_deref()
     }
+
+    /// Checks that the rule can be written to a firewall config.
+    pub(crate) fn check_representable(&self) -> Result<(), Error> {
+        match &self.kind {
+            Kind::Group(_) => Ok(()),
+            Kind::Match(rule) => rule.check_representable(),
+        }
+    }
+
+    /// Returns the names of the security group parameters used by this rule.
+    pub fn params(&self) -> Vec<&str> {
+        let addresses: Vec<&IpAddrMatch> = match &self.kind {
+            Kind::Group(group) => group.params.values().collect(),
+            Kind::Match(rule) => rule
+                .ip()
+                .map(|ip| ip.src().into_iter().chain(ip.dst()).collect())
+                .unwrap_or_default(),
+        };
+
+        addresses
+            .into_iter()
+            .filter_map(IpAddrMatch::param)
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -195,10 +366,67 @@ impl From<RuleMatch> for Kind {
 pub struct RuleGroup {
     pub(crate) group: String,
     pub(crate) iface: Option<String>,
+    pub(crate) params: BTreeMap<String, IpAddrMatch>,
+}
+
+/// Parses the parameters passed to a security group, e.g. `src=+dc/tenant-a,dst=$dst`.
+///
+/// Since IP lists are separated by commas as well, everything up to the next `name=` belongs to
+/// the value of the previous parameter.
+fn parse_group_params(input: &str) -> Result<BTreeMap<String, IpAddrMatch>, Error> {
+    let mut params = BTreeMap::new();
+    let mut current: Option<(&str, String)> = None;
+
+    let mut insert = |name: &str, value: String| -> Result<(), Error> {
+        let value = value
+            .parse()
+            .map_err(|err| format_err!("invalid value for parameter {name}: {err}"))?;
+
+        if params.insert(name.to_string(), value).is_some() {
+            bail!("duplicate parameter {name}");
+        }
+
+        Ok(())
+    };
+
+    for part in input.split(',').map(str::trim) {
+        match part.split_once('=') {
+            Some((name, value)) => {
+                if let Some((name, value)) = current.take() {
+                    insert(name, value)?;
+                }
+
+                let name = name.trim();
+
+                if !matches!(match_name(name), Some((_, ""))) {
+                    bail!("invalid parameter name {name:?}");
+                }
+
+                current = Some((name, value.trim().to_string()));
+            }
+            None => match &mut current {
+                Some((_, value)) => {
+                    value.push(',');
+                    value.push_str(part);
+                }
+                None => bail!("expected a parameter name before {part:?}"),
+            },
+        }
+    }
+
+    if let Some((name, value)) = current {
+        insert(name, value)?;
+    }
+
+    Ok(params)
 }
 
 impl RuleGroup {
-    pub(crate) fn from_options(group: String, options: RuleOptions) -> Result<Self, Error> {
+    pub(crate) fn from_options(
+        group: String,
+        params: BTreeMap<String, IpAddrMatch>,
+        options: RuleOptions,
+    ) -> Result<Self, Error> {
         ensure!(
             options.proto.is_none()
                 && options.dport.is_none()
@@ -206,13 +434,20 @@ impl RuleGroup {
                 && options.dest.is_none()
                 && options.source.is_none()
                 && options.log.is_none()
-                && options.icmp_type.is_none(),
+                && options.icmp_type.is_none()
+                && options.limit.is_none()
+                && options.connlimit.is_none()
+                && options.ct_state.is_none()
+                && options.tcp_flags.is_none()
+                && options.smac.is_none()
+                && options.vlan.is_none(),
             "only interface parameter is permitted for group rules"
         );
 
         Ok(Self {
             group,
             iface: options.iface,
+            params,
         })
     }
 
@@ -223,6 +458,11 @@ impl RuleGroup {
     pub fn iface(&self) -> Option<&str> {
         self.iface.as_deref()
     }
+
+    /// The addresses passed for the parameters of the security group.
+    pub fn params(&self) -> &BTreeMap<String, IpAddrMatch> {
+        &self.params
+    }
 }
 
 impl FromStr for RuleGroup {
@@ -239,22 +479,279 @@ impl FromStr for RuleGroup {
         let (name, rest) =
             match_name(rest.trim()).ok_or_else(|| format_err!("expected a name for rule group"))?;
 
+        let (params, rest) = match rest.strip_prefix('(') {
+            Some(rest) => {
+                let (params, rest) = rest
+                    .split_once(')')
+                    .ok_or_else(|| format_err!("missing ')' after group parameters"))?;
+
+                (parse_group_params(params)?, rest)
+            }
+            None => (BTreeMap::new(), rest),
+        };
+
         let options = rest.trim_start().parse()?;
 
-        Self::from_options(name.to_string(), options)
+        Self::from_options(name.to_string(), params, options)
+    }
+}
+
+impl fmt::Display for RuleGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GROUP {}", self.group)?;
+
+        if !self.params.is_empty() {
+            let params: Vec<String> = self
+                .params
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect();
+
+            write!(f, "({})", params.join(","))?;
+        }
+
+        if let Some(iface) = &self.iface {
+            write!(f, " -i {iface}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a [`Rule`] from typed values instead of its textual representation.
+///
+/// The rule is validated the same way as a parsed rule when calling [`RuleBuilder::build`], so
+/// every built rule can be written to a firewall config and parsed again.
+#[derive(Clone, Debug)]
+pub struct RuleBuilder {
+    group: Option<String>,
+    dir: Direction,
+    action: RuleAction,
+    fw_macro: Option<String>,
+    source: Option<IpAddrMatch>,
+    dest: Option<IpAddrMatch>,
+    proto: Option<Protocol>,
+    iface: Option<String>,
+    log: Option<LogLevel>,
+    limit: Option<RateLimit>,
+    connlimit: Option<u32>,
+    ct_state: Option<ConnectionStates>,
+    tcp_flags: Option<TcpFlags>,
+    smac: Option<MacAddress>,
+    vlan: Option<u16>,
+    params: BTreeMap<String, IpAddrMatch>,
+    comment: Option<String>,
+    disabled: bool,
+}
+
+impl RuleBuilder {
+    /// Creates a builder for a rule matching packets.
+    pub fn new(dir: Direction, action: impl Into<RuleAction>) -> Self {
+        Self {
+            group: None,
+            dir,
+            action: action.into(),
+            fw_macro: None,
+            source: None,
+            dest: None,
+            proto: None,
+            iface: None,
+            log: None,
+            limit: None,
+            connlimit: None,
+            ct_state: None,
+            tcp_flags: None,
+            smac: None,
+            vlan: None,
+            params: BTreeMap::new(),
+            comment: None,
+            disabled: false,
+        }
+    }
+
+    /// Creates a builder for a rule including the security group with the given name.
+    ///
+    /// Group rules only support an interface, parameters, a comment and being disabled.
+    pub fn group(name: impl Into<String>) -> Self {
+        Self {
+            group: Some(name.into()),
+            ..Self::new(Direction::default(), Verdict::default())
+        }
+    }
+
+    pub fn fw_macro(mut self, name: impl Into<String>) -> Self {
+        self.fw_macro = Some(name.into());
+        self
+    }
+
+    pub fn iface(mut self, iface: impl Into<String>) -> Self {
+        self.iface = Some(iface.into());
+        self
+    }
+
+    pub fn source(mut self, source: impl Into<IpAddrMatch>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn dest(mut self, dest: impl Into<IpAddrMatch>) -> Self {
+        self.dest = Some(dest.into());
+        self
+    }
+
+    pub fn proto(mut self, proto: impl Into<Protocol>) -> Self {
+        self.proto = Some(proto.into());
+        self
+    }
+
+    pub fn log(mut self, log: LogLevel) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    pub fn limit(mut self, limit: RateLimit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn connlimit(mut self, connlimit: u32) -> Self {
+        self.connlimit = Some(connlimit);
+        self
+    }
+
+    pub fn ct_state(mut self, ct_state: ConnectionStates) -> Self {
+        self.ct_state = Some(ct_state);
+        self
+    }
+
+    pub fn tcp_flags(mut self, tcp_flags: TcpFlags) -> Self {
+        self.tcp_flags = Some(tcp_flags);
+        self
+    }
+
+    pub fn smac(mut self, smac: MacAddress) -> Self {
+        self.smac = Some(smac);
+        self
+    }
+
+    pub fn vlan(mut self, vlan: u16) -> Self {
+        self.vlan = Some(vlan);
+        self
+    }
+
+    /// Passes an address for a parameter of the included security group.
+    pub fn param(mut self, name: impl Into<String>, value: impl Into<IpAddrMatch>) -> Self {
+        self.params.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn disabled(mut self, disabled: bool) -> Self {
+        self.disabled = disabled;
+        self
+    }
+
+    /// Converts the values into the options of a rule as they are written in a config file.
+    fn options(&self) -> Result<RuleOptions, Error> {
+        let mut options = RuleOptions {
+            iface: self.iface.clone(),
+            source: self.source.as_ref().map(IpAddrMatch::to_string),
+            dest: self.dest.as_ref().map(IpAddrMatch::to_string),
+            log: self.log,
+            limit: self.limit,
+            connlimit: self.connlimit.map(|connlimit| connlimit.to_string()),
+            ct_state: self.ct_state.as_ref().map(ConnectionStates::to_string),
+            tcp_flags: self.tcp_flags.as_ref().map(TcpFlags::to_string),
+            smac: self.smac.map(|smac| smac.to_string()),
+            vlan: self.vlan.map(|vlan| vlan.to_string()),
+            ..Default::default()
+        };
+
+        if let Some(proto) = &self.proto {
+            options.proto = Some(proto.name());
+
+            if let Some(ports) = proto.ports() {
+                options.sport = ports.sport().map(PortList::to_config_string);
+                options.dport = ports.dport().map(PortList::to_config_string);
+            }
+
+            options.icmp_type = match proto {
+                Protocol::Icmp(icmp) => {
+                    icmp.check_representable()?;
+                    icmp.ty().map(|_| icmp.to_string())
+                }
+                Protocol::Icmpv6(icmp) => {
+                    icmp.check_representable()?;
+                    icmp.ty().map(|_| icmp.to_string())
+                }
+                _ => None,
+            };
+        }
+
+        Ok(options)
+    }
+
+    pub fn build(self) -> Result<Rule, Error> {
+        if let Some(comment) = &self.comment {
+            ensure!(
+                !comment.contains(['\n', '\r']),
+                "comment must not contain any newlines"
+            );
+        }
+
+        ensure!(
+            self.group.is_some() || self.params.is_empty(),
+            "parameters can only be passed to security groups"
+        );
+
+        for name in [&self.group, &self.fw_macro]
+            .into_iter()
+            .flatten()
+            .chain(self.params.keys())
+        {
+            ensure!(
+                match_name(name) == Some((name.as_str(), "")),
+                "invalid name {name:?}"
+            );
+        }
+
+        let options = self.options()?;
+
+        let kind = match self.group {
+            Some(group) => Kind::from(RuleGroup::from_options(group, self.params, options)?),
+            None => Kind::from(RuleMatch::from_options(
+                self.dir,
+                self.action,
+                self.fw_macro,
+                options,
+            )?),
+        };
+
+        Ok(Rule {
+            disabled: self.disabled,
+            kind,
+            comment: self.comment,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::num::NonZeroU16;
+
     use proxmox_network_types::ip_address::{Cidr, IpRange};
 
     use crate::firewall::types::{
         address::{IpEntry, IpList},
         alias::{AliasName, AliasScope, RuleAliasName},
         ipset::{IpsetName, IpsetScope, RuleIpsetName},
-        log::LogLevel,
-        rule_match::{Icmp, IcmpCode, IcmpType, IpAddrMatch, IpMatch, Ports, Protocol, Udp},
+        log::{LogLevel, LogRateLimitTimescale},
+        port::PortEntry,
+        rule_match::{Icmp, IcmpCode, IcmpType, IpAddrMatch, IpMatch, Ports, Protocol, Tcp, Udp},
     };
 
     use super::*;
@@ -273,6 +770,7 @@ mod tests {
                 kind: Kind::Group(RuleGroup {
                     group: "tgr".to_string(),
                     iface: Some("eth0".to_string()),
+                    params: Default::default(),
                 }),
             },
         );
@@ -288,7 +786,7 @@ mod tests {
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::In,
-                    verdict: Verdict::Accept,
+                    action: Verdict::Accept.into(),
                     proto: Some(Udp::new(Ports::from_u16(22, 33)).into()),
                     log: Some(LogLevel::Warning),
                     ..Default::default()
@@ -305,7 +803,7 @@ mod tests {
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::In,
-                    verdict: Verdict::Accept,
+                    action: Verdict::Accept.into(),
                     proto: Some(Udp::new(Ports::new(None, None)).into()),
                     iface: Some("eth0".to_string()),
                     ..Default::default()
@@ -326,7 +824,7 @@ mod tests {
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::Out,
-                    verdict: Verdict::Drop,
+                    action: Verdict::Drop.into(),
                     ip: IpMatch::new(
                         IpAddrMatch::Ip(IpList::from(Cidr::new_v4([10, 0, 0, 0], 24).unwrap())),
                         IpAddrMatch::Ip(
@@ -361,7 +859,7 @@ mod tests {
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::In,
-                    verdict: Verdict::Accept,
+                    action: Verdict::Accept.into(),
                     log: Some(LogLevel::Critical),
                     fw_macro: Some("BGP".to_string()),
                     iface: Some("eth0".to_string()),
@@ -381,7 +879,7 @@ mod tests {
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::In,
-                    verdict: Verdict::Accept,
+                    action: Verdict::Accept.into(),
                     ip: Some(
                         IpMatch::new(
                             IpAddrMatch::Alias(RuleAliasName::Scoped(AliasName::new(
@@ -409,7 +907,7 @@ mod tests {
                 comment: None,
                 kind: Kind::Match(RuleMatch {
                     dir: Direction::In,
-                    verdict: Verdict::Reject,
+                    action: Verdict::Reject.into(),
                     ..Default::default()
                 }),
             }
@@ -427,4 +925,287 @@ mod tests {
             .parse::<Rule>()
             .expect_err("no value for option");
     }
+
+    #[test]
+    fn test_parse_action_with_value() {
+        for (input, action, output) in [
+            (
+                "IN MARK(0x10) -p tcp",
+                RuleAction::Mark(0x10),
+                "IN MARK(0x10) -p tcp",
+            ),
+            ("IN mark(16)", RuleAction::Mark(0x10), "IN MARK(0x10)"),
+            ("IN MARK(0X1F)", RuleAction::Mark(0x1f), "IN MARK(0x1f)"),
+            (
+                "OUT MARK(0xffffffff)",
+                RuleAction::Mark(u32::MAX),
+                "OUT MARK(0xffffffff)",
+            ),
+            (
+                "OUT DSCP(af41) -dest 10.0.0.1",
+                RuleAction::Dscp(34),
+                "OUT DSCP(af41) -dest 10.0.0.1/32",
+            ),
+            ("OUT DSCP(AF41)", RuleAction::Dscp(34), "OUT DSCP(af41)"),
+            ("OUT DSCP(EF)", RuleAction::Dscp(46), "OUT DSCP(ef)"),
+            ("OUT DSCP(46)", RuleAction::Dscp(46), "OUT DSCP(ef)"),
+            ("OUT DSCP(63)", RuleAction::Dscp(63), "OUT DSCP(63)"),
+        ] {
+            let rule: Rule = input.parse().expect("valid rule");
+
+            let Kind::Match(rule_match) = rule.kind() else {
+                panic!("expected a match rule");
+            };
+
+            assert_eq!(rule_match.action(), action);
+            assert_eq!(rule_match.verdict(), None);
+            assert!(!action.is_terminal());
+            assert_eq!(rule.to_string(), output);
+        }
+
+        for input in [
+            "IN MARK(0x100000000)",
+            "IN MARK(-1)",
+            "IN MARK()",
+            "IN MARK(0x10",
+            "IN DSCP(64)",
+            "IN DSCP(af44)",
+            "IN SSH(MARK(1))",
+        ] {
+            input.parse::<Rule>().expect_err("invalid action");
+        }
+
+        "MARK".parse::<Verdict>().expect_err("not a valid policy");
+
+        RuleBuilder::new(Direction::In, RuleAction::Mark(1))
+            .fw_macro("SSH")
+            .build()
+            .expect_err("macros require a verdict");
+    }
+
+    #[test]
+    fn test_parse_rule_limits() {
+        let rule: Rule = "IN SSH(ACCEPT) -i net0 -limit 3/minute,burst=5 -connlimit 10"
+            .parse()
+            .expect("valid rule");
+
+        assert_eq!(
+            rule,
+            Rule {
+                disabled: false,
+                comment: None,
+                kind: Kind::Match(RuleMatch {
+                    dir: Direction::In,
+                    action: Verdict::Accept.into(),
+                    fw_macro: Some("SSH".to_string()),
+                    iface: Some("net0".to_string()),
+                    limit: Some(RateLimit::new(3, LogRateLimitTimescale::Minute, 5).unwrap()),
+                    connlimit: Some(10),
+                    ..Default::default()
+                }),
+            }
+        );
+
+        assert_eq!(
+            rule.to_string(),
+            "IN SSH(ACCEPT) -i net0 -limit 3/minute,burst=5 -connlimit 10"
+        );
+
+        let rule: Rule = "IN ACCEPT -p tcp -limit 10".parse().expect("valid rule");
+        assert_eq!(rule.to_string(), "IN ACCEPT -p tcp -limit 10/second");
+
+        for rule in [
+            "IN ACCEPT -limit 0/second",
+            "IN ACCEPT -limit 10/second,burst=-1",
+            "IN ACCEPT -limit enable=1",
+            "IN ACCEPT -connlimit 0",
+            "IN ACCEPT -connlimit many",
+            "GROUP tgr -limit 10/second",
+            "GROUP tgr -connlimit 10",
+        ] {
+            rule.parse::<Rule>().expect_err("invalid limit");
+        }
+    }
+
+    #[test]
+    fn test_parse_rule_smac_vlan() {
+        let rule: Rule = "IN ACCEPT -i net0 -smac bc:24:11:49:8d:75 -vlan 100"
+            .parse()
+            .expect("valid rule");
+
+        assert_eq!(
+            rule,
+            Rule {
+                disabled: false,
+                comment: None,
+                kind: Kind::Match(RuleMatch {
+                    dir: Direction::In,
+                    action: Verdict::Accept.into(),
+                    iface: Some("net0".to_string()),
+                    smac: Some(MacAddress::new([0xBC, 0x24, 0x11, 0x49, 0x8D, 0x75])),
+                    vlan: NonZeroU16::new(100),
+                    ..Default::default()
+                }),
+            }
+        );
+
+        let rule = RuleBuilder::new(Direction::Out, Verdict::Drop)
+            .vlan(4094)
+            .build()
+            .expect("valid rule");
+        assert_eq!(rule.to_string(), "OUT DROP -vlan 4094");
+
+        for rule in [
+            "IN ACCEPT -smac bc:24:11:49:8d",
+            "IN ACCEPT -smac 10.0.0.1",
+            "IN ACCEPT -vlan 0",
+            "IN ACCEPT -vlan 4095",
+            "IN ACCEPT -vlan trunk",
+            "GROUP tgr -smac bc:24:11:49:8d:75",
+            "GROUP tgr -vlan 100",
+        ] {
+            rule.parse::<Rule>().expect_err("invalid smac or vlan");
+        }
+    }
+
+    #[test]
+    fn test_parse_group_params() {
+        let rule: Rule = "GROUP webserver(src=10.0.0.0/8, 192.168.0.0/16,dst=$backend) -i net0"
+            .parse()
+            .expect("valid rule");
+
+        let Kind::Group(group) = rule.kind() else {
+            panic!("expected a group rule");
+        };
+
+        assert_eq!(group.group(), "webserver");
+        assert_eq!(group.iface(), Some("net0"));
+        assert_eq!(
+            group.params().get("src"),
+            Some(&"10.0.0.0/8,192.168.0.0/16".parse().unwrap())
+        );
+        assert_eq!(rule.params(), ["backend"]);
+        assert_eq!(
+            rule.to_string(),
+            "GROUP webserver(dst=$backend,src=10.0.0.0/8,192.168.0.0/16) -i net0"
+        );
+
+        let rule: Rule = "IN ACCEPT -source $src -dest 10.0.0.1 -p tcp -dport 443"
+            .parse()
+            .expect("valid rule");
+        assert_eq!(rule.params(), ["src"]);
+
+        for input in [
+            "GROUP webserver(src=10.0.0.0/8",
+            "GROUP webserver(10.0.0.0/8)",
+            "GROUP webserver(src=10.0.0.0/8,src=10.0.0.1)",
+            "GROUP webserver(src=)",
+            "GROUP webserver(a b=10.0.0.1)",
+        ] {
+            input.parse::<Rule>().expect_err("invalid group parameters");
+        }
+    }
+
+    #[test]
+    fn test_rule_builder() {
+        let rule = RuleBuilder::new(Direction::In, Verdict::Accept)
+            .iface("net0")
+            .source(Cidr::new_v4([10, 0, 0, 0], 8).unwrap())
+            .dest(RuleIpsetName::Scoped(IpsetName::new(
+                IpsetScope::Datacenter,
+                "web",
+            )))
+            .proto(Tcp::new(Ports::new(
+                None,
+                PortList::from_iter([PortEntry::Port(80), PortEntry::Range(8000, 8080)]),
+            )))
+            .log(LogLevel::Info)
+            .comment("web access")
+            .build()
+            .expect("valid rule");
+
+        assert_eq!(
+            rule.to_string(),
+            "IN ACCEPT -i net0 -source 10.0.0.0/8 -dest +dc/web -p tcp -dport 80,8000:8080 \
+             -log info # web access"
+        );
+        assert_eq!(rule, rule.to_string().parse().expect("valid rule"));
+
+        let rule = RuleBuilder::new(Direction::Out, Verdict::Drop)
+            .fw_macro("SSH")
+            .dest(RuleAliasName::Scoped(AliasName::new(
+                AliasScope::Guest,
+                "alias",
+            )))
+            .disabled(true)
+            .build()
+            .expect("valid rule");
+
+        assert_eq!(rule.to_string(), "|OUT SSH(DROP) -dest guest/alias");
+
+        let rule = RuleBuilder::new(Direction::In, Verdict::Reject)
+            .source(IpRange::new_v4([10, 0, 0, 1], [10, 0, 0, 10]).unwrap())
+            .proto(Icmp::new_ty_and_code(
+                IcmpType::Numeric(3),
+                IcmpCode::Numeric(3),
+            ))
+            .build()
+            .expect("valid rule");
+
+        assert_eq!(
+            rule.to_string(),
+            "IN REJECT -source 10.0.0.1-10.0.0.10 -p icmp -icmp-type port-unreachable"
+        );
+
+        let rule = RuleBuilder::group("webserver")
+            .iface("net1")
+            .build()
+            .expect("valid rule");
+
+        assert_eq!(rule.to_string(), "GROUP webserver -i net1");
+
+        RuleBuilder::group("webserver")
+            .proto(Tcp::new(Ports::from_u16(None, 80)))
+            .build()
+            .expect_err("group rules cannot match on protocols");
+
+        let rule = RuleBuilder::group("webserver")
+            .param(
+                "src",
+                RuleIpsetName::Scoped(IpsetName::new(IpsetScope::Datacenter, "tenant-a")),
+            )
+            .build()
+            .expect("valid rule");
+
+        assert_eq!(rule.to_string(), "GROUP webserver(src=+dc/tenant-a)");
+
+        RuleBuilder::new(Direction::In, Verdict::Accept)
+            .param("src", Cidr::new_v4([10, 0, 0, 0], 8).unwrap())
+            .build()
+            .expect_err("only groups take parameters");
+
+        RuleBuilder::new(Direction::In, Verdict::Accept)
+            .source(Cidr::new_v6([0xFD00, 0, 0, 0, 0, 0, 0, 0], 64).unwrap())
+            .proto(Icmp::new_ty(IcmpType::Named("echo-request")))
+            .build()
+            .expect_err("icmp cannot match IPv6 addresses");
+
+        RuleBuilder::new(Direction::In, Verdict::Accept)
+            .proto(Icmp::new_ty_and_code(
+                IcmpType::Numeric(3),
+                IcmpCode::Numeric(200),
+            ))
+            .build()
+            .expect_err("icmp type cannot be represented");
+
+        RuleBuilder::new(Direction::In, Verdict::Accept)
+            .comment("multi\nline")
+            .build()
+            .expect_err("comments cannot contain newlines");
+
+        RuleBuilder::new(Direction::In, Verdict::Accept)
+            .fw_macro("SSH -p tcp")
+            .build()
+            .expect_err("invalid macro name");
+    }
 }
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::num::NonZeroU16;
 use std::str::FromStr;
 
 use serde::Deserialize;
@@ -7,16 +8,23 @@ use serde::Deserialize;
 use anyhow::{bail, format_err, Error};
 use serde::de::IntoDeserializer;
 
-use proxmox_network_types::ip_address::Family;
+use proxmox_network_types::ip_address::{Cidr, Family, IpRange};
+use proxmox_network_types::mac_address::MacAddress;
 use proxmox_sortable_macro::sortable;
 
+use crate::firewall::fw_macros::get_macro;
 use crate::firewall::parse::{match_name, match_non_whitespace, SomeStr};
 use crate::firewall::types::address::IpList;
 use crate::firewall::types::alias::RuleAliasName;
 use crate::firewall::types::ipset::RuleIpsetName;
-use crate::firewall::types::log::LogLevel;
+use crate::firewall::types::log::{LogLevel, RateLimit};
 use crate::firewall::types::port::PortList;
-use crate::firewall::types::rule::{Direction, Verdict};
+use crate::firewall::types::rule::{Direction, RuleAction, Verdict, ACTIONS_WITH_VALUE};
+
+/// Smallest VLAN ID that can be matched, 0 only carries the priority of a packet.
+pub const VLAN_ID_MIN: u16 = 1;
+/// Largest VLAN ID that can be matched, 4095 is reserved.
+pub const VLAN_ID_MAX: u16 = 4094;
 
 #[derive(Clone, Debug, Default, Deserialize)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
@@ -36,6 +44,15 @@ pub(crate) struct RuleOptions {
 
     pub(crate) log: Option<LogLevel>,
     pub(crate) icmp_type: Option<String>,
+
+    pub(crate) limit: Option<RateLimit>,
+    pub(crate) connlimit: Option<String>,
+
+    pub(crate) ct_state: Option<String>,
+    pub(crate) tcp_flags: Option<String>,
+
+    pub(crate) smac: Option<String>,
+    pub(crate) vlan: Option<String>,
 }
 
 impl FromStr for RuleOptions {
@@ -84,39 +101,138 @@ impl FromStr for RuleOptions {
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct RuleMatch {
     pub(crate) dir: Direction,
-    pub(crate) verdict: Verdict,
+    pub(crate) action: RuleAction,
     pub(crate) fw_macro: Option<String>,
 
     pub(crate) iface: Option<String>,
     pub(crate) log: Option<LogLevel>,
     pub(crate) ip: Option<IpMatch>,
     pub(crate) proto: Option<Protocol>,
+
+    pub(crate) limit: Option<RateLimit>,
+    pub(crate) connlimit: Option<u32>,
+
+    pub(crate) ct_state: Option<ConnectionStates>,
+    pub(crate) tcp_flags: Option<TcpFlags>,
+
+    pub(crate) smac: Option<MacAddress>,
+    // VLAN IDs are never zero, storing them as NonZeroU16 keeps the rule small
+    pub(crate) vlan: Option<NonZeroU16>,
 }
 
 impl RuleMatch {
     pub(crate) fn from_options(
         dir: Direction,
-        verdict: Verdict,
+        action: impl Into<RuleAction>,
         fw_macro: impl Into<Option<String>>,
         options: RuleOptions,
     ) -> Result<Self, Error> {
+        let action = action.into();
+
         if options.dport.is_some() && options.icmp_type.is_some() {
             bail!("dport and icmp-type are mutually exclusive");
         }
 
+        let fw_macro = fw_macro.into();
+
+        if fw_macro.is_some() && !action.is_terminal() {
+            bail!("macros can only be used with ACCEPT, REJECT or DROP");
+        }
+
         let ip = IpMatch::from_options(&options)?;
         let proto = Protocol::from_options(&options)?;
 
-        // todo: check protocol & IP Version compatibility
+        let connlimit = options
+            .connlimit
+            .as_deref()
+            .map(|connlimit| match connlimit.parse::<u32>() {
+                Ok(connlimit) if connlimit > 0 => Ok(connlimit),
+                _ => bail!("invalid connlimit {connlimit:?}, expected a positive number"),
+            })
+            .transpose()?;
+
+        let ct_state = options
+            .ct_state
+            .as_deref()
+            .map(ConnectionStates::from_str)
+            .transpose()?;
+
+        let tcp_flags = options
+            .tcp_flags
+            .as_deref()
+            .map(TcpFlags::from_str)
+            .transpose()?;
+
+        let smac = options
+            .smac
+            .as_deref()
+            .map(|smac| {
+                smac.parse::<MacAddress>()
+                    .map_err(|_| format_err!("invalid smac {smac:?}, expected a MAC address"))
+            })
+            .transpose()?;
+
+        let vlan = options
+            .vlan
+            .as_deref()
+            .map(|vlan| match vlan.parse::<u16>() {
+                Ok(vlan) if (VLAN_ID_MIN..=VLAN_ID_MAX).contains(&vlan) => {
+                    Ok(NonZeroU16::new(vlan).expect("VLAN ID is not zero"))
+                }
+                _ => bail!(
+                    "invalid vlan {vlan:?}, expected a VLAN ID between {VLAN_ID_MIN} and {VLAN_ID_MAX}"
+                ),
+            })
+            .transpose()?;
+
+        if tcp_flags.is_some() {
+            // macros are checked here as well, since their protocols cannot be overridden, unknown
+            // macros might be user-defined ones and are checked when resolving the rule
+            let is_tcp = |proto: &Protocol| matches!(proto, Protocol::Tcp(_));
+
+            let only_tcp = match (&fw_macro, &proto) {
+                (Some(name), _) => match get_macro(name) {
+                    Some(fw_macro) => !fw_macro.code.is_empty() && fw_macro.code.iter().all(is_tcp),
+                    None => true,
+                },
+                (None, Some(proto)) => is_tcp(proto),
+                (None, None) => false,
+            };
+
+            if !only_tcp {
+                bail!("tcp-flags can only be used with protocol tcp");
+            }
+        }
+
+        let src = ip
+            .as_ref()
+            .and_then(IpMatch::src)
+            .and_then(IpAddrMatch::family);
+        let dst = ip
+            .as_ref()
+            .and_then(IpMatch::dst)
+            .and_then(IpAddrMatch::family);
+
+        check_families(
+            src.as_ref().map(std::slice::from_ref),
+            dst.as_ref().map(std::slice::from_ref),
+            proto.as_ref(),
+        )?;
 
         Ok(Self {
             dir,
-            verdict,
-            fw_macro: fw_macro.into(),
+            action,
+            fw_macro,
             iface: options.iface,
             log: options.log,
             ip,
             proto,
+            limit: options.limit,
+            connlimit,
+            ct_state,
+            tcp_flags,
+            smac,
+            vlan,
         })
     }
 
@@ -128,8 +244,13 @@ impl RuleMatch {
         self.iface.as_deref()
     }
 
-    pub fn verdict(&self) -> Verdict {
-        self.verdict
+    pub fn action(&self) -> RuleAction {
+        self.action
+    }
+
+    /// The verdict of the rule, rules that only modify the packet do not have one.
+    pub fn verdict(&self) -> Option<Verdict> {
+        self.action.verdict()
     }
 
     pub fn fw_macro(&self) -> Option<&str> {
@@ -147,14 +268,117 @@ impl RuleMatch {
     pub fn proto(&self) -> Option<&Protocol> {
         self.proto.as_ref()
     }
+
+    /// Checks that the rule can be written to a firewall config, see
+    /// [`Icmp::check_representable`].
+    pub(crate) fn check_representable(&self) -> Result<(), Error> {
+        match &self.proto {
+            Some(Protocol::Icmp(icmp)) => icmp.check_representable(),
+            Some(Protocol::Icmpv6(icmp)) => icmp.check_representable(),
+            _ => Ok(()),
+        }
+    }
+
+    /// The rate limit of packets matching this rule, which applies to every source address
+    /// separately.
+    pub fn limit(&self) -> Option<&RateLimit> {
+        self.limit.as_ref()
+    }
+
+    /// The maximum number of concurrent connections matching this rule per source address.
+    pub fn connlimit(&self) -> Option<u32> {
+        self.connlimit
+    }
+
+    /// The conntrack states of packets matching this rule.
+    pub fn ct_state(&self) -> Option<&ConnectionStates> {
+        self.ct_state.as_ref()
+    }
+
+    pub fn tcp_flags(&self) -> Option<&TcpFlags> {
+        self.tcp_flags.as_ref()
+    }
+
+    /// The source MAC address of packets matching this rule.
+    pub fn smac(&self) -> Option<MacAddress> {
+        self.smac
+    }
+
+    /// The 802.1Q VLAN ID of packets matching this rule.
+    pub fn vlan(&self) -> Option<u16> {
+        self.vlan.map(NonZeroU16::get)
+    }
+}
+
+fn family_name(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "IPv4",
+        Family::V6 => "IPv6",
+    }
+}
+
+/// Checks that a rule with the given source, destination and protocol can match packets of at
+/// least one address family.
+///
+/// Source and destination are given as the families of the addresses they contain. `None` or an
+/// empty slice means that the rule does not match on them or that their family is not known.
+pub(crate) fn check_families(
+    src: Option<&[Family]>,
+    dst: Option<&[Family]>,
+    proto: Option<&Protocol>,
+) -> Result<(), Error> {
+    let src = src.filter(|families| !families.is_empty());
+    let dst = dst.filter(|families| !families.is_empty());
+
+    if let Some(proto) = proto {
+        if let Some(proto_family) = proto.family() {
+            for (name, families) in [("source", src), ("destination", dst)] {
+                if let Some(families) = families.filter(|f| !f.contains(&proto_family)) {
+                    bail!(
+                        "{name} address is {}, but protocol {} requires {}",
+                        family_name(families[0]),
+                        proto.name(),
+                        family_name(proto_family),
+                    );
+                }
+            }
+        }
+    }
+
+    if let (Some(src), Some(dst)) = (src, dst) {
+        if !src.iter().any(|family| dst.contains(family)) {
+            bail!(
+                "source address is {}, but destination address is {}",
+                family_name(src[0]),
+                family_name(dst[0]),
+            );
+        }
+    }
+
+    Ok(())
 }
 
-/// Returns `(Macro name, Verdict, RestOfTheLine)`.
-fn parse_action(line: &str) -> Result<(Option<&str>, Verdict, &str), Error> {
+/// Returns `(Macro name, RuleAction, RestOfTheLine)`.
+pub(crate) fn parse_action(line: &str) -> Result<(Option<&str>, RuleAction, &str), Error> {
     let (verdict, line) =
         match_name(line).ok_or_else(|| format_err!("expected a verdict or macro name"))?;
 
     Ok(if let Some(line) = line.strip_prefix('(') {
+        if ACTIONS_WITH_VALUE
+            .iter()
+            .any(|name| verdict.eq_ignore_ascii_case(name))
+        {
+            // <action>(<value>)
+
+            let (value, line) = line
+                .split_once(')')
+                .ok_or_else(|| format_err!("expected closing ')' after {verdict} value"))?;
+
+            let action = RuleAction::parse_with_value(verdict, value.trim())?;
+
+            return Ok((None, action, line.trim_start()));
+        }
+
         // <macro>(<verdict>)
 
         let macro_name = verdict;
@@ -165,9 +389,9 @@ fn parse_action(line: &str) -> Result<(Option<&str>, Verdict, &str), Error> {
 
         let verdict: Verdict = verdict.parse()?;
 
-        (Some(macro_name), verdict, line.trim_start())
+        (Some(macro_name), verdict.into(), line.trim_start())
     } else {
-        (None, verdict.parse()?, line.trim_start())
+        (None, verdict.parse::<Verdict>()?.into(), line.trim_start())
     })
 }
 
@@ -179,11 +403,90 @@ impl FromStr for RuleMatch {
 
         let direction: Direction = dir.parse()?;
 
-        let (fw_macro, verdict, rest) = parse_action(rest.trim_start())?;
+        let (fw_macro, action, rest) = parse_action(rest.trim_start())?;
 
         let options: RuleOptions = rest.trim_start().parse()?;
 
-        Self::from_options(direction, verdict, fw_macro.map(str::to_string), options)
+        Self::from_options(direction, action, fw_macro.map(str::to_string), options)
+    }
+}
+
+impl fmt::Display for RuleMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.dir {
+            Direction::In => "IN",
+            Direction::Out => "OUT",
+            Direction::Forward => "FORWARD",
+        })?;
+
+        match &self.fw_macro {
+            Some(fw_macro) => write!(f, " {fw_macro}({})", self.action)?,
+            None => write!(f, " {}", self.action)?,
+        }
+
+        if let Some(iface) = &self.iface {
+            write!(f, " -i {iface}")?;
+        }
+
+        if let Some(ip) = &self.ip {
+            if let Some(src) = &ip.src {
+                write!(f, " -source {src}")?;
+            }
+
+            if let Some(dst) = &ip.dst {
+                write!(f, " -dest {dst}")?;
+            }
+        }
+
+        if let Some(proto) = &self.proto {
+            write!(f, " -p {}", proto.name())?;
+
+            if let Some(ports) = proto.ports() {
+                if let Some(dport) = ports.dport() {
+                    write!(f, " -dport {}", dport.to_config_string())?;
+                }
+
+                if let Some(sport) = ports.sport() {
+                    write!(f, " -sport {}", sport.to_config_string())?;
+                }
+            }
+
+            match proto {
+                Protocol::Icmp(icmp) if icmp.ty().is_some() => write!(f, " -icmp-type {icmp}")?,
+                Protocol::Icmpv6(icmp) if icmp.ty().is_some() => write!(f, " -icmp-type {icmp}")?,
+                _ => (),
+            }
+        }
+
+        if let Some(ct_state) = &self.ct_state {
+            write!(f, " -ct-state {ct_state}")?;
+        }
+
+        if let Some(tcp_flags) = &self.tcp_flags {
+            write!(f, " -tcp-flags {tcp_flags}")?;
+        }
+
+        if let Some(smac) = self.smac {
+            write!(f, " -smac {smac}")?;
+        }
+
+        if let Some(vlan) = self.vlan {
+            write!(f, " -vlan {vlan}")?;
+        }
+
+        if let Some(limit) = &self.limit {
+            write!(f, " -limit {limit}")?;
+        }
+
+        if let Some(connlimit) = self.connlimit {
+            write!(f, " -connlimit {connlimit}")?;
+        }
+
+        if let Some(log) = self.log {
+            write!(f, " -log {log}")?;
+        }
+
+        Ok(())
     }
 }
 
@@ -255,6 +558,9 @@ pub enum IpAddrMatch {
     Ip(IpList),
     Set(RuleIpsetName),
     Alias(RuleAliasName),
+    /// A parameter of a security group, written as `$name`, which is replaced by the address
+    /// passed when including the group.
+    Param(String),
 }
 
 impl IpAddrMatch {
@@ -265,6 +571,55 @@ impl IpAddrMatch {
 
         None
     }
+
+    /// Returns the name of the security group parameter, if this is one.
+    pub fn param(&self) -> Option<&str> {
+        match self {
+            IpAddrMatch::Param(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl From<IpList> for IpAddrMatch {
+    fn from(value: IpList) -> Self {
+        IpAddrMatch::Ip(value)
+    }
+}
+
+impl From<Cidr> for IpAddrMatch {
+    fn from(value: Cidr) -> Self {
+        IpAddrMatch::Ip(value.into())
+    }
+}
+
+impl From<IpRange> for IpAddrMatch {
+    fn from(value: IpRange) -> Self {
+        IpAddrMatch::Ip(value.into())
+    }
+}
+
+impl From<RuleIpsetName> for IpAddrMatch {
+    fn from(value: RuleIpsetName) -> Self {
+        IpAddrMatch::Set(value)
+    }
+}
+
+impl From<RuleAliasName> for IpAddrMatch {
+    fn from(value: RuleAliasName) -> Self {
+        IpAddrMatch::Alias(value)
+    }
+}
+
+impl fmt::Display for IpAddrMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpAddrMatch::Ip(list) => list.fmt(f),
+            IpAddrMatch::Set(name) => write!(f, "+{name}"),
+            IpAddrMatch::Alias(name) => name.fmt(f),
+            IpAddrMatch::Param(name) => write!(f, "${name}"),
+        }
+    }
 }
 
 impl FromStr for IpAddrMatch {
@@ -275,6 +630,14 @@ impl FromStr for IpAddrMatch {
             bail!("empty IP specification");
         }
 
+        if let Some(name) = value.strip_prefix('$') {
+            if !matches!(match_name(name), Some((_, ""))) {
+                bail!("invalid parameter name: {value}");
+            }
+
+            return Ok(IpAddrMatch::Param(name.to_string()));
+        }
+
         if let Ok(ip_list) = value.parse() {
             return Ok(IpAddrMatch::Ip(ip_list));
         }
@@ -334,6 +697,32 @@ impl Protocol {
             _ => None,
         }
     }
+
+    /// Returns the name of the protocol as it is written in the firewall config files.
+    pub fn name(&self) -> String {
+        match self {
+            Self::Dccp(_) => "dccp".to_string(),
+            Self::Sctp(_) => "sctp".to_string(),
+            Self::Tcp(_) => "tcp".to_string(),
+            Self::Udp(_) => "udp".to_string(),
+            Self::UdpLite(_) => "udplite".to_string(),
+            Self::Icmp(_) => "icmp".to_string(),
+            Self::Icmpv6(_) => "icmpv6".to_string(),
+            Self::Named(name) => name.clone(),
+            Self::Numeric(num) => num.to_string(),
+        }
+    }
+
+    /// Returns the ports of the protocol, if it is a protocol that supports matching on ports.
+    pub fn ports(&self) -> Option<&Ports> {
+        match self {
+            Self::Dccp(ports) | Self::UdpLite(ports) => Some(ports),
+            Self::Sctp(sctp) => Some(sctp.ports()),
+            Self::Tcp(tcp) => Some(tcp.ports()),
+            Self::Udp(udp) => Some(udp.ports()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -485,6 +874,35 @@ impl Icmp {
     pub fn code(&self) -> Option<&IcmpCode> {
         self.code.as_ref()
     }
+
+    /// Returns the iptables name of the type and code combination, if there is one.
+    fn combination_name(&self) -> Option<&'static str> {
+        let combination = (self.ty.as_ref()?.number()?, self.code.as_ref()?.number()?);
+
+        IPTABLES_ICMP_TYPES_MAPPING
+            .iter()
+            .find(|(_, mapping)| {
+                matches!(mapping, IcmpTypeMap::Custom(custom) if *custom == combination)
+            })
+            .map(|(name, _)| *name)
+    }
+
+    /// Checks that the type and code can be written to the `icmp-type` option of a rule.
+    ///
+    /// A code can only be written together with a type, if the combination has an iptables name.
+    pub fn check_representable(&self) -> Result<(), Error> {
+        if self.code.is_some() && self.combination_name().is_none() {
+            bail!("icmp type and code {self} cannot be represented in a firewall config");
+        }
+
+        Ok(())
+    }
+}
+
+impl From<Icmp> for Protocol {
+    fn from(value: Icmp) -> Self {
+        Protocol::Icmp(value)
+    }
 }
 
 /// Some icmp_types are not supported by nftables. See:
@@ -549,6 +967,30 @@ impl FromStr for Icmp {
     }
 }
 
+/// Formats the value of the `icmp-type` option.
+///
+/// Type and code combinations are written using their iptables name. Combinations without such a
+/// name cannot be represented in the firewall config and are written as `<type>/<code>`, see
+/// [`Icmp::check_representable`].
+impl fmt::Display for Icmp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.combination_name() {
+            return f.write_str(name);
+        }
+
+        match &self.ty {
+            Some(ty) => ty.fmt(f)?,
+            None => f.write_str("any")?,
+        }
+
+        if let Some(code) = &self.code {
+            write!(f, "/{code}")?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub enum IcmpType {
@@ -606,6 +1048,20 @@ impl fmt::Display for IcmpType {
     }
 }
 
+impl IcmpType {
+    /// Returns the numeric value of the type, or `None` for [`IcmpType::Any`].
+    pub fn number(&self) -> Option<u8> {
+        match self {
+            Self::Numeric(ty) => Some(*ty),
+            Self::Named(name) => ICMP_TYPES
+                .binary_search_by(|v| v.0.cmp(name))
+                .ok()
+                .map(|index| ICMP_TYPES[index].1),
+            Self::Any => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub enum IcmpCode {
@@ -649,6 +1105,19 @@ impl fmt::Display for IcmpCode {
     }
 }
 
+impl IcmpCode {
+    /// Returns the numeric value of the code.
+    pub fn number(&self) -> Option<u8> {
+        match self {
+            Self::Numeric(code) => Some(*code),
+            Self::Named(name) => ICMP_CODES
+                .binary_search_by(|v| v.0.cmp(name))
+                .ok()
+                .map(|index| ICMP_CODES[index].1),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct Icmpv6 {
@@ -686,6 +1155,35 @@ impl Icmpv6 {
     pub fn code(&self) -> Option<&Icmpv6Code> {
         self.code.as_ref()
     }
+
+    /// Returns the iptables name of the type and code combination, if there is one.
+    fn combination_name(&self) -> Option<&'static str> {
+        let combination = (self.ty.as_ref()?.number()?, self.code.as_ref()?.number()?);
+
+        IPTABLES_ICMPV6_TYPES_MAPPING
+            .iter()
+            .find(|(_, mapping)| {
+                matches!(mapping, IcmpTypeMap::Custom(custom) if *custom == combination)
+            })
+            .map(|(name, _)| *name)
+    }
+
+    /// Checks that the type and code can be written to the `icmp-type` option of a rule.
+    ///
+    /// A code can only be written together with a type, if the combination has an iptables name.
+    pub fn check_representable(&self) -> Result<(), Error> {
+        if self.code.is_some() && self.combination_name().is_none() {
+            bail!("icmpv6 type and code {self} cannot be represented in a firewall config");
+        }
+
+        Ok(())
+    }
+}
+
+impl From<Icmpv6> for Protocol {
+    fn from(value: Icmpv6) -> Self {
+        Protocol::Icmpv6(value)
+    }
 }
 
 enum IcmpTypeMap {
@@ -765,6 +1263,30 @@ impl FromStr for Icmpv6 {
     }
 }
 
+/// Formats the value of the `icmp-type` option.
+///
+/// Type and code combinations are written using their iptables name. Combinations without such a
+/// name cannot be represented in the firewall config and are written as `<type>/<code>`, see
+/// [`Icmpv6::check_representable`].
+impl fmt::Display for Icmpv6 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.combination_name() {
+            return f.write_str(name);
+        }
+
+        match &self.ty {
+            Some(ty) => ty.fmt(f)?,
+            None => f.write_str("any")?,
+        }
+
+        if let Some(code) = &self.code {
+            write!(f, "/{code}")?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub enum Icmpv6Type {
@@ -826,6 +1348,20 @@ impl fmt::Display for Icmpv6Type {
     }
 }
 
+impl Icmpv6Type {
+    /// Returns the numeric value of the type, or `None` for [`Icmpv6Type::Any`].
+    pub fn number(&self) -> Option<u8> {
+        match self {
+            Self::Numeric(ty) => Some(*ty),
+            Self::Named(name) => ICMPV6_TYPES
+                .binary_search_by(|v| v.0.cmp(name))
+                .ok()
+                .map(|index| ICMPV6_TYPES[index].1),
+            Self::Any => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub enum Icmpv6Code {
@@ -868,20 +1404,295 @@ impl fmt::Display for Icmpv6Code {
     }
 }
 
+impl Icmpv6Code {
+    /// Returns the numeric value of the code.
+    pub fn number(&self) -> Option<u8> {
+        match self {
+            Self::Numeric(code) => Some(*code),
+            Self::Named(name) => ICMPV6_CODES
+                .binary_search_by(|v| v.0.cmp(name))
+                .ok()
+                .map(|index| ICMPV6_CODES[index].1),
+        }
+    }
+}
+
+/// A conntrack state of a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    New,
+    Established,
+    Related,
+    Invalid,
+}
+
+impl ConnectionState {
+    const ALL: [ConnectionState; 4] = [
+        ConnectionState::New,
+        ConnectionState::Established,
+        ConnectionState::Related,
+        ConnectionState::Invalid,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl FromStr for ConnectionState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "new" => Self::New,
+            "established" => Self::Established,
+            "related" => Self::Related,
+            "invalid" => Self::Invalid,
+            _ => bail!("invalid connection state {s:?}"),
+        })
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::New => "new",
+            Self::Established => "established",
+            Self::Related => "related",
+            Self::Invalid => "invalid",
+        })
+    }
+}
+
+/// A set of conntrack states, written as a comma-separated list, e.g. `established,related`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct ConnectionStates(u8);
+
+impl ConnectionStates {
+    pub fn new(states: impl IntoIterator<Item = ConnectionState>) -> Result<Self, Error> {
+        let states = states.into_iter().fold(0, |bits, state| bits | state.bit());
+
+        if states == 0 {
+            bail!("empty list of connection states");
+        }
+
+        Ok(Self(states))
+    }
+
+    pub fn contains(&self, state: ConnectionState) -> bool {
+        self.0 & state.bit() != 0
+    }
+
+    pub fn is_subset(&self, other: &ConnectionStates) -> bool {
+        self.0 & !other.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ConnectionState> + '_ {
+        ConnectionState::ALL
+            .into_iter()
+            .filter(|state| self.contains(*state))
+    }
+}
+
+impl FromStr for ConnectionStates {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Self::new(
+            s.split(',')
+                .map(ConnectionState::from_str)
+                .collect::<Result<Vec<_>, Error>>()?,
+        )
+    }
+}
+
+impl fmt::Display for ConnectionStates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, state) in self.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+
+            state.fmt(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpFlag {
+    Fin,
+    Syn,
+    Rst,
+    Psh,
+    Ack,
+    Urg,
+    Ecn,
+    Cwr,
+}
+
+impl TcpFlag {
+    const ALL: [TcpFlag; 8] = [
+        TcpFlag::Fin,
+        TcpFlag::Syn,
+        TcpFlag::Rst,
+        TcpFlag::Psh,
+        TcpFlag::Ack,
+        TcpFlag::Urg,
+        TcpFlag::Ecn,
+        TcpFlag::Cwr,
+    ];
+
+    /// The bit of the flag in the TCP header.
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl FromStr for TcpFlag {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "fin" => Self::Fin,
+            "syn" => Self::Syn,
+            "rst" => Self::Rst,
+            "psh" => Self::Psh,
+            "ack" => Self::Ack,
+            "urg" => Self::Urg,
+            "ecn" => Self::Ecn,
+            "cwr" => Self::Cwr,
+            _ => bail!("invalid tcp flag {s:?}"),
+        })
+    }
+}
+
+impl fmt::Display for TcpFlag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fin => "fin",
+            Self::Syn => "syn",
+            Self::Rst => "rst",
+            Self::Psh => "psh",
+            Self::Ack => "ack",
+            Self::Urg => "urg",
+            Self::Ecn => "ecn",
+            Self::Cwr => "cwr",
+        })
+    }
+}
+
+/// TCP flags that have to be set or unset, written as a comma-separated list where unset flags
+/// are prefixed with `!`, e.g. `syn,!ack`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct TcpFlags {
+    set: u8,
+    unset: u8,
+}
+
+impl TcpFlags {
+    pub fn new(
+        set: impl IntoIterator<Item = TcpFlag>,
+        unset: impl IntoIterator<Item = TcpFlag>,
+    ) -> Result<Self, Error> {
+        let set = set.into_iter().fold(0, |bits, flag| bits | flag.bit());
+        let unset = unset.into_iter().fold(0, |bits, flag| bits | flag.bit());
+
+        if set == 0 && unset == 0 {
+            bail!("empty list of tcp flags");
+        }
+
+        let flags = Self { set, unset };
+
+        if let Some(flag) = flags.set().find(|flag| flag.bit() & unset != 0) {
+            bail!("tcp flag {flag} must not be both set and unset");
+        }
+
+        Ok(flags)
+    }
+
+    /// The flags that have to be set.
+    pub fn set(&self) -> impl Iterator<Item = TcpFlag> + '_ {
+        TcpFlag::ALL
+            .into_iter()
+            .filter(|flag| flag.bit() & self.set != 0)
+    }
+
+    /// The flags that have to be unset.
+    pub fn unset(&self) -> impl Iterator<Item = TcpFlag> + '_ {
+        TcpFlag::ALL
+            .into_iter()
+            .filter(|flag| flag.bit() & self.unset != 0)
+    }
+
+    /// Returns whether all packets matched by `other` are matched as well.
+    pub fn covers(&self, other: &TcpFlags) -> bool {
+        self.set & !other.set == 0 && self.unset & !other.unset == 0
+    }
+
+    /// Returns whether a packet with the given flags set matches.
+    pub fn matches(&self, flags: &[TcpFlag]) -> bool {
+        let flags = flags.iter().fold(0, |bits, flag| bits | flag.bit());
+        flags & self.set == self.set && flags & self.unset == 0
+    }
+}
+
+impl FromStr for TcpFlags {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut set = Vec::new();
+        let mut unset = Vec::new();
+
+        for flag in s.split(',') {
+            match flag.strip_prefix('!') {
+                Some(flag) => unset.push(flag.parse()?),
+                None => set.push(flag.parse()?),
+            }
+        }
+
+        Self::new(set, unset)
+    }
+}
+
+impl fmt::Display for TcpFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = self
+            .set()
+            .map(|flag| (flag, ""))
+            .chain(self.unset().map(|flag| (flag, "!")));
+
+        for (index, (flag, prefix)) in flags.enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+
+            write!(f, "{prefix}{flag}")?;
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::firewall::types::alias::{AliasName, AliasScope::Guest};
-    use proxmox_network_types::ip_address::Cidr;
 
     use super::*;
 
     #[test]
     fn test_parse_action() {
-        assert_eq!(parse_action("REJECT").unwrap(), (None, Verdict::Reject, ""));
+        assert_eq!(
+            parse_action("REJECT").unwrap(),
+            (None, Verdict::Reject.into(), "")
+        );
 
         assert_eq!(
             parse_action("SSH(ACCEPT) qweasd").unwrap(),
-            (Some("SSH"), Verdict::Accept, "qweasd")
+            (Some("SSH"), Verdict::Accept.into(), "qweasd")
         );
     }
 
@@ -892,15 +1703,23 @@ mod tests {
             "10.0.0.0/8,192.168.0.0-192.168.255.255,172.16.0.1",
             "dc/test",
             "+guest/proxmox",
+            "$tenant",
         ] {
             input.parse::<IpAddrMatch>().expect("valid ip match");
         }
 
+        assert_eq!(
+            "$tenant".parse::<IpAddrMatch>().unwrap().param(),
+            Some("tenant")
+        );
+
         for input in [
             "10.0.0.0/",
             "10.0.0.0/8,192.168.256.0-192.168.255.255,172.16.0.1",
             "dcc/test",
             "+guest/",
+            "$",
+            "$dc/tenant",
             "",
         ] {
             input.parse::<IpAddrMatch>().expect_err("invalid ip match");
@@ -925,6 +1744,12 @@ mod tests {
                 iface: Some("ens1".to_string()),
                 log: Some(LogLevel::Critical),
                 icmp_type: None,
+                limit: None,
+                connlimit: None,
+                ct_state: None,
+                tcp_flags: None,
+                smac: None,
+                vlan: None,
             }
         );
 
@@ -1010,6 +1835,116 @@ mod tests {
             .expect_err("cannot mix dport and icmp-type");
     }
 
+    #[test]
+    fn test_from_options_families() {
+        for (proto, source, dest) in [
+            ("icmp", Some("10.0.0.0/8"), None),
+            ("ipv6-icmp", None, Some("fd00::/64")),
+            ("tcp", Some("10.0.0.0/8"), Some("dc/test")),
+            ("icmp", Some("+dc/test"), Some("10.0.0.1")),
+        ] {
+            let options = RuleOptions {
+                proto: Some(proto.to_string()),
+                source: source.map(str::to_string),
+                dest: dest.map(str::to_string),
+                ..Default::default()
+            };
+
+            RuleMatch::from_options(Direction::In, Verdict::Accept, None, options)
+                .expect("valid rule");
+        }
+
+        let options = RuleOptions {
+            proto: Some("icmpv6".to_string()),
+            source: Some("10.0.0.0/8".to_string()),
+            ..Default::default()
+        };
+
+        let error = RuleMatch::from_options(Direction::In, Verdict::Accept, None, options)
+            .expect_err("icmpv6 cannot match IPv4 addresses");
+        assert_eq!(
+            error.to_string(),
+            "source address is IPv4, but protocol icmpv6 requires IPv6"
+        );
+
+        let options = RuleOptions {
+            proto: Some("icmp".to_string()),
+            dest: Some("fd00::1".to_string()),
+            ..Default::default()
+        };
+
+        let error = RuleMatch::from_options(Direction::In, Verdict::Accept, None, options)
+            .expect_err("icmp cannot match IPv6 addresses");
+        assert_eq!(
+            error.to_string(),
+            "destination address is IPv6, but protocol icmp requires IPv4"
+        );
+
+        check_families(
+            Some(&[Family::V4, Family::V6]),
+            Some(&[Family::V6]),
+            Some(&Protocol::Icmpv6(Icmpv6::default())),
+        )
+        .expect("source contains IPv6 addresses");
+
+        let error = check_families(Some(&[Family::V4]), Some(&[Family::V6]), None)
+            .expect_err("source and destination have no family in common");
+        assert_eq!(
+            error.to_string(),
+            "source address is IPv4, but destination address is IPv6"
+        );
+    }
+
+    #[test]
+    fn test_parse_state_and_flags() {
+        let rule: RuleMatch = "OUT ACCEPT -p tcp -ct-state related,established -tcp-flags ack,!syn"
+            .parse()
+            .expect("valid rule");
+
+        assert_eq!(
+            rule.ct_state(),
+            Some(
+                &ConnectionStates::new([ConnectionState::Established, ConnectionState::Related])
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            rule.tcp_flags(),
+            Some(&TcpFlags::new([TcpFlag::Ack], [TcpFlag::Syn]).unwrap())
+        );
+        assert_eq!(
+            rule.to_string(),
+            "OUT ACCEPT -p tcp -ct-state established,related -tcp-flags ack,!syn"
+        );
+
+        let flags = rule.tcp_flags().unwrap();
+        assert!(flags.matches(&[TcpFlag::Ack, TcpFlag::Psh]));
+        assert!(!flags.matches(&[TcpFlag::Syn, TcpFlag::Ack]));
+        assert!(!flags.matches(&[]));
+
+        "OUT DROP -ct-state new"
+            .parse::<RuleMatch>()
+            .expect("ct-state works without protocol");
+
+        "IN SSH(ACCEPT) -tcp-flags syn"
+            .parse::<RuleMatch>()
+            .expect("SSH macro only contains tcp");
+
+        for rule in [
+            "OUT DROP -ct-state",
+            "OUT DROP -ct-state new,closed",
+            "OUT DROP -ct-state new,",
+            "OUT DROP -tcp-flags syn",
+            "OUT DROP -p udp -tcp-flags syn",
+            "IN DNS(ACCEPT) -tcp-flags syn",
+            "OUT DROP -p tcp -tcp-flags syn,!syn",
+            "OUT DROP -p tcp -tcp-flags ack,!",
+            "OUT DROP -p tcp -tcp-flags xmas",
+        ] {
+            rule.parse::<RuleMatch>().expect_err("invalid rule");
+        }
+    }
+
     #[test]
     fn test_parse_icmp() {
         let mut icmp: Icmp = "info-request".parse().expect("valid icmp type");
@@ -1041,6 +1976,26 @@ mod tests {
                 code: Some(IcmpCode::Numeric(3))
             }
         );
+        assert_eq!(icmp.to_string(), "port-unreachable");
+
+        icmp = Icmp::new_ty_and_code(
+            IcmpType::Named("destination-unreachable"),
+            IcmpCode::Named("host-unreachable"),
+        );
+        assert_eq!(icmp.to_string(), "host-unreachable");
+        icmp.check_representable().expect("combination has a name");
+
+        icmp = Icmp::new_ty_and_code(IcmpType::Numeric(3), IcmpCode::Numeric(99));
+        assert_eq!(icmp.to_string(), "3/99");
+        icmp.check_representable()
+            .expect_err("combination has no name");
+
+        icmp = Icmp {
+            ty: None,
+            code: Some(IcmpCode::Numeric(3)),
+        };
+        assert_eq!(icmp.to_string(), "any/3");
+        icmp.check_representable().expect_err("code without type");
     }
 
     #[test]
@@ -1074,5 +2029,14 @@ mod tests {
                 code: Some(Icmpv6Code::Numeric(1))
             }
         );
+        assert_eq!(icmp.to_string(), "unknown-header-type");
+
+        icmp = Icmpv6::new_ty_and_code(Icmpv6Type::Numeric(1), Icmpv6Code::Named("policy-fail"));
+        assert_eq!(icmp.to_string(), "failed-policy");
+
+        icmp = Icmpv6::new_ty_and_code(Icmpv6Type::Any, Icmpv6Code::Numeric(0));
+        assert_eq!(icmp.to_string(), "any/0");
+        icmp.check_representable()
+            .expect_err("combination has no name");
     }
 }
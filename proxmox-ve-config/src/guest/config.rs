@@ -0,0 +1,421 @@
+//! Reader for complete guest config files.
+//!
+//! Besides the current configuration, a guest config file contains a section for every snapshot
+//! and a `[PENDING]` section with changes that are applied on the next reboot of the guest:
+//!
+//! ```text
+//! net0: virtio=BC:24:11:12:34:56,bridge=vmbr0,firewall=1
+//!
+//! [PENDING]
+//! delete: net1,ipconfig0
+//! net0: virtio=BC:24:11:12:34:56,bridge=vmbr0,firewall=0
+//!
+//! [before-upgrade]
+//! net0: virtio=BC:24:11:12:34:56,bridge=vmbr0
+//! snaptime: 1700143513
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use anyhow::{bail, Context, Error};
+
+use proxmox_network_types::ip_address::{Ipv4Cidr, Ipv6Cidr};
+
+use crate::guest::vm::{NetworkConfig, NetworkDevice, QemuIpConfig};
+
+const PENDING_SECTION: &str = "PENDING";
+
+/// Sections with this prefix contain internal data, like the cloud-init state of a VM.
+const SPECIAL_SECTION_PREFIX: &str = "special:";
+
+/// The network devices of a guest config file, split by the sections of the file.
+#[derive(Debug, Default)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct GuestConfig {
+    current: NetworkConfig,
+    pending: NetworkConfig,
+    pending_delete: BTreeSet<i64>,
+    pending_ip_config_delete: BTreeSet<i64>,
+    snapshots: BTreeMap<String, NetworkConfig>,
+}
+
+enum Section {
+    Current,
+    Pending,
+    Snapshot(String),
+    Special,
+}
+
+impl GuestConfig {
+    pub fn parse<R: io::BufRead>(input: R) -> Result<Self, Error> {
+        let mut config = Self::default();
+        let mut section = Section::Current;
+
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[') {
+                let Some(name) = name.strip_suffix(']') else {
+                    bail!("invalid section header: {line}");
+                };
+
+                section = if name == PENDING_SECTION {
+                    Section::Pending
+                } else if name.starts_with(SPECIAL_SECTION_PREFIX) {
+                    Section::Special
+                } else {
+                    if config.snapshots.contains_key(name) {
+                        bail!("duplicate snapshot {name}");
+                    }
+
+                    config
+                        .snapshots
+                        .insert(name.to_string(), NetworkConfig::new());
+
+                    Section::Snapshot(name.to_string())
+                };
+
+                continue;
+            }
+
+            match &section {
+                Section::Current => config.current.parse_line(line)?,
+                Section::Pending => config.parse_pending_line(line)?,
+                Section::Snapshot(name) => config
+                    .snapshots
+                    .get_mut(name)
+                    .expect("snapshot has been inserted")
+                    .parse_line(line)
+                    .with_context(|| format!("invalid config of snapshot {name}"))?,
+                Section::Special => (),
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn parse_pending_line(&mut self, line: &str) -> Result<(), Error> {
+        if let Some(keys) = line.strip_prefix("delete:") {
+            for key in keys.split(',') {
+                // a leading `!` forces the deletion
+                let key = key.trim().trim_start_matches('!');
+
+                if let Ok(index) = NetworkConfig::index_from_net_key(key) {
+                    self.pending_delete.insert(index);
+                } else if let Ok(index) = NetworkConfig::index_from_ipconfig_key(key) {
+                    self.pending_ip_config_delete.insert(index);
+                }
+            }
+
+            return Ok(());
+        }
+
+        self.pending
+            .parse_line(line)
+            .context("invalid pending config")
+    }
+
+    /// The network devices of the current configuration.
+    pub fn current(&self) -> &NetworkConfig {
+        &self.current
+    }
+
+    /// The network devices that are added or changed by the pending configuration.
+    pub fn pending(&self) -> &NetworkConfig {
+        &self.pending
+    }
+
+    /// The indices of the network devices that are removed by the pending configuration.
+    pub fn pending_delete(&self) -> &BTreeSet<i64> {
+        &self.pending_delete
+    }
+
+    /// The indices of the IP configs that are removed by the pending configuration.
+    pub fn pending_ip_config_delete(&self) -> &BTreeSet<i64> {
+        &self.pending_ip_config_delete
+    }
+
+    pub fn snapshots(&self) -> &BTreeMap<String, NetworkConfig> {
+        &self.snapshots
+    }
+
+    pub fn snapshot(&self, name: &str) -> Option<&NetworkConfig> {
+        self.snapshots.get(name)
+    }
+
+    /// Returns the network devices that are about to change in a way that is relevant for the
+    /// firewall, ordered by their index.
+    ///
+    /// A device is changed if its MAC address, firewall flag or IP addresses differ between the
+    /// current and the pending configuration. Other properties, like the bridge, are ignored. The
+    /// IP addresses of QEMU guests are taken from the `ipconfigN` entry of the device.
+    pub fn pending_changes(&self) -> Vec<NetworkDeviceChange<'_>> {
+        let current = self.current.network_devices();
+        let pending = self.pending.network_devices();
+
+        let indices: BTreeSet<i64> = current
+            .keys()
+            .chain(pending.keys())
+            .chain(self.pending_delete.iter())
+            .copied()
+            .collect();
+
+        let mut changes = Vec::new();
+
+        for index in indices {
+            let old = current.get(&index);
+
+            let new = match pending.get(&index) {
+                Some(device) => Some(device),
+                None if self.pending_delete.contains(&index) => None,
+                None => old,
+            };
+
+            let old_ip_config = self.current.ip_configs().get(&index);
+
+            let new_ip_config = match self.pending.ip_configs().get(&index) {
+                Some(ip_config) => Some(ip_config),
+                None if self.pending_ip_config_delete.contains(&index) => None,
+                None => old_ip_config,
+            };
+
+            let change = match (old, new) {
+                (None, Some(device)) => NetworkDeviceChange::Added { index, device },
+                (Some(device), None) => NetworkDeviceChange::Removed { index, device },
+                (Some(old), Some(new))
+                    if !firewall_equal((old, old_ip_config), (new, new_ip_config)) =>
+                {
+                    NetworkDeviceChange::Changed { index, old, new }
+                }
+                _ => continue,
+            };
+
+            changes.push(change);
+        }
+
+        changes
+    }
+}
+
+/// Returns whether two network devices, together with their IP configs, are the same from the
+/// view of the firewall.
+fn firewall_equal(
+    (device, ip_config): (&NetworkDevice, Option<&QemuIpConfig>),
+    (other, other_ip_config): (&NetworkDevice, Option<&QemuIpConfig>),
+) -> bool {
+    device.mac_address() == other.mac_address()
+        && device.has_firewall() == other.has_firewall()
+        && addresses(device, ip_config) == addresses(other, other_ip_config)
+}
+
+/// The static IP addresses of a network device.
+///
+/// LXC devices carry their addresses themselves, while QEMU devices get them from the IP config
+/// with the same index.
+fn addresses(
+    device: &NetworkDevice,
+    ip_config: Option<&QemuIpConfig>,
+) -> (Option<Ipv4Cidr>, Option<Ipv6Cidr>) {
+    match device {
+        NetworkDevice::Qemu(_) => (
+            ip_config.and_then(|config| config.ip()?.cidr()),
+            ip_config.and_then(|config| config.ip6()?.cidr()),
+        ),
+        NetworkDevice::Lxc(_) => (device.ip(), device.ip6()),
+    }
+}
+
+/// A firewall relevant change of a network device, see [`GuestConfig::pending_changes`].
+#[derive(Debug)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub enum NetworkDeviceChange<'a> {
+    Added {
+        index: i64,
+        device: &'a NetworkDevice,
+    },
+    Removed {
+        index: i64,
+        device: &'a NetworkDevice,
+    },
+    Changed {
+        index: i64,
+        old: &'a NetworkDevice,
+        new: &'a NetworkDevice,
+    },
+}
+
+impl NetworkDeviceChange<'_> {
+    pub fn index(&self) -> i64 {
+        match self {
+            NetworkDeviceChange::Added { index, .. }
+            | NetworkDeviceChange::Removed { index, .. }
+            | NetworkDeviceChange::Changed { index, .. } => *index,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QEMU_CONFIG: &str = "\
+boot: order=scsi0;net0
+memory: 8192
+net0: virtio=BC:24:11:00:00:01,bridge=vmbr0,firewall=1
+net1: virtio=BC:24:11:00:00:02,bridge=vmbr0
+net2: virtio=BC:24:11:00:00:03,bridge=vmbr0,firewall=1
+scsi0: local-lvm:vm-100-disk-0,size=32G
+
+[PENDING]
+delete: net1,!net4
+net0: virtio=BC:24:11:00:00:01,bridge=vmbr1,firewall=1
+net2: virtio=BC:24:11:00:00:03,bridge=vmbr0,firewall=0
+net3: e1000=BC:24:11:00:00:04,bridge=vmbr0
+
+[pre-upgrade]
+memory: 4096
+net0: virtio=BC:24:11:00:00:10,bridge=vmbr0
+parent: base
+snaptime: 1700143513
+
+[base]
+memory: 4096
+snaptime: 1700000000
+
+[special:cloudinit]
+net0: virtio=BC:24:11:00:00:20,bridge=vmbr0
+";
+
+    #[test]
+    fn test_parse_guest_config() {
+        let config = GuestConfig::parse(QEMU_CONFIG.as_bytes()).expect("valid guest config");
+
+        assert_eq!(config.current().network_devices().len(), 3);
+        assert_eq!(config.pending().network_devices().len(), 3);
+        assert_eq!(config.pending_delete(), &BTreeSet::from([1, 4]));
+
+        assert_eq!(
+            config.snapshots().keys().collect::<Vec<_>>(),
+            ["base", "pre-upgrade"]
+        );
+        assert!(config
+            .snapshot("base")
+            .unwrap()
+            .network_devices()
+            .is_empty());
+        assert_eq!(
+            config.snapshot("pre-upgrade").unwrap().network_devices()[&0]
+                .mac_address()
+                .to_string(),
+            "BC:24:11:00:00:10"
+        );
+
+        let changes = config.pending_changes();
+
+        // net0 only moves to another bridge, which is not relevant for the firewall
+        assert_eq!(
+            changes
+                .iter()
+                .map(NetworkDeviceChange::index)
+                .collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+
+        let devices = config.current().network_devices();
+        let pending = config.pending().network_devices();
+
+        assert_eq!(
+            changes,
+            [
+                NetworkDeviceChange::Removed {
+                    index: 1,
+                    device: &devices[&1],
+                },
+                NetworkDeviceChange::Changed {
+                    index: 2,
+                    old: &devices[&2],
+                    new: &pending[&2],
+                },
+                NetworkDeviceChange::Added {
+                    index: 3,
+                    device: &pending[&3],
+                },
+            ]
+        );
+
+        GuestConfig::parse("net0: virtio=BC:24:11:00:00:01\n[PENDING]\nnet0: virtio".as_bytes())
+            .expect_err("invalid pending network device");
+
+        GuestConfig::parse("[snap]\n[snap]\n".as_bytes()).expect_err("duplicate snapshot");
+
+        GuestConfig::parse("[PENDING\n".as_bytes()).expect_err("invalid section header");
+    }
+
+    #[test]
+    fn test_pending_ip_config() {
+        let config = GuestConfig::parse(
+            "\
+ipconfig0: ip=10.0.0.10/24,gw=10.0.0.1
+ipconfig1: ip=dhcp,ip6=fd00::10/64
+ipconfig2: ip=10.0.2.10/24
+net0: virtio=BC:24:11:00:00:01,bridge=vmbr0,firewall=1
+net1: virtio=BC:24:11:00:00:02,bridge=vmbr0,firewall=1
+net2: virtio=BC:24:11:00:00:03,bridge=vmbr0,firewall=1
+
+[PENDING]
+delete: ipconfig1
+ipconfig0: ip=10.0.0.20/24,gw=10.0.0.1
+ipconfig2: ip=10.0.2.10/24,gw=10.0.2.1
+
+[snap]
+ipconfig0: ip=10.0.0.5/24
+net0: virtio=BC:24:11:00:00:01,bridge=vmbr0,firewall=1
+"
+            .as_bytes(),
+        )
+        .expect("valid guest config");
+
+        assert_eq!(config.current().ip_configs().len(), 3);
+        assert_eq!(config.pending().ip_configs().len(), 2);
+        assert!(config.pending_delete().is_empty());
+        assert_eq!(config.pending_ip_config_delete(), &BTreeSet::from([1]));
+        assert_eq!(
+            config.snapshot("snap").unwrap().ip_configs()[&0]
+                .ip()
+                .and_then(|ip| ip.cidr())
+                .map(|cidr| cidr.to_string()),
+            Some("10.0.0.5/24".to_string())
+        );
+
+        let devices = config.current().network_devices();
+
+        // net0 gets a new address and net1 loses its IPv6 address, only the gateway of net2
+        // changes, which is not relevant for the firewall
+        assert_eq!(
+            config.pending_changes(),
+            [
+                NetworkDeviceChange::Changed {
+                    index: 0,
+                    old: &devices[&0],
+                    new: &devices[&0],
+                },
+                NetworkDeviceChange::Changed {
+                    index: 1,
+                    old: &devices[&1],
+                    new: &devices[&1],
+                },
+            ]
+        );
+
+        GuestConfig::parse("ipconfig0: ip=10.0.0.300/24\n".as_bytes())
+            .expect_err("invalid ip config");
+
+        GuestConfig::parse("ipconfig31: ip=dhcp\n".as_bytes()).expect_err("invalid ipconfig key");
+    }
+}
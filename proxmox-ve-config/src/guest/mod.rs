@@ -7,6 +7,7 @@ use serde::Deserialize;
 use proxmox_sys::nodename;
 use types::Vmid;
 
+pub mod config;
 pub mod types;
 pub mod vm;
 
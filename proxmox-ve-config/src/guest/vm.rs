@@ -1,17 +1,22 @@
 use std::collections::BTreeMap;
+use std::fmt;
 use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 
-use anyhow::{bail, Error};
+use anyhow::{bail, format_err, Error};
 use serde::Deserialize;
 
 use proxmox_network_types::ip_address::{Ipv4Cidr, Ipv6Cidr};
 use proxmox_network_types::mac_address::MacAddress;
 use proxmox_schema::property_string::PropertyString;
-use proxmox_schema::{ApiType, BooleanSchema, KeyAliasInfo, ObjectSchema, StringSchema};
+use proxmox_schema::{
+    ApiType, BooleanSchema, IntegerSchema, KeyAliasInfo, ObjectSchema, StringSchema,
+};
 use proxmox_sortable_macro::sortable;
 
 use crate::firewall::parse::match_digits;
+use crate::firewall::types::rule_match::{VLAN_ID_MAX, VLAN_ID_MIN};
 
 /// All possible models of network devices for both QEMU and LXC guests.
 #[derive(Debug, Clone, Copy)]
@@ -20,12 +25,44 @@ pub enum NetworkDeviceModel {
     VirtIO,
     Veth,
     E1000,
+    E1000_82540em,
+    E1000_82544gc,
+    E1000_82545em,
+    E1000e,
+    I82551,
+    I82557b,
+    I82559er,
+    Ne2kIsa,
+    Ne2kPci,
+    Pcnet,
     Vmxnet3,
     RTL8139,
 }
 
 proxmox_serde::forward_deserialize_to_from_str!(NetworkDeviceModel);
 
+impl NetworkDeviceModel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NetworkDeviceModel::VirtIO => "virtio",
+            NetworkDeviceModel::Veth => "veth",
+            NetworkDeviceModel::E1000 => "e1000",
+            NetworkDeviceModel::E1000_82540em => "e1000-82540em",
+            NetworkDeviceModel::E1000_82544gc => "e1000-82544gc",
+            NetworkDeviceModel::E1000_82545em => "e1000-82545em",
+            NetworkDeviceModel::E1000e => "e1000e",
+            NetworkDeviceModel::I82551 => "i82551",
+            NetworkDeviceModel::I82557b => "i82557b",
+            NetworkDeviceModel::I82559er => "i82559er",
+            NetworkDeviceModel::Ne2kIsa => "ne2k_isa",
+            NetworkDeviceModel::Ne2kPci => "ne2k_pci",
+            NetworkDeviceModel::Pcnet => "pcnet",
+            NetworkDeviceModel::Vmxnet3 => "vmxnet3",
+            NetworkDeviceModel::RTL8139 => "rtl8139",
+        }
+    }
+}
+
 impl FromStr for NetworkDeviceModel {
     type Err = Error;
 
@@ -33,6 +70,16 @@ impl FromStr for NetworkDeviceModel {
         match s {
             "virtio" => Ok(NetworkDeviceModel::VirtIO),
             "e1000" => Ok(NetworkDeviceModel::E1000),
+            "e1000-82540em" => Ok(NetworkDeviceModel::E1000_82540em),
+            "e1000-82544gc" => Ok(NetworkDeviceModel::E1000_82544gc),
+            "e1000-82545em" => Ok(NetworkDeviceModel::E1000_82545em),
+            "e1000e" => Ok(NetworkDeviceModel::E1000e),
+            "i82551" => Ok(NetworkDeviceModel::I82551),
+            "i82557b" => Ok(NetworkDeviceModel::I82557b),
+            "i82559er" => Ok(NetworkDeviceModel::I82559er),
+            "ne2k_isa" => Ok(NetworkDeviceModel::Ne2kIsa),
+            "ne2k_pci" => Ok(NetworkDeviceModel::Ne2kPci),
+            "pcnet" => Ok(NetworkDeviceModel::Pcnet),
             "rtl8139" => Ok(NetworkDeviceModel::RTL8139),
             "vmxnet3" => Ok(NetworkDeviceModel::Vmxnet3),
             "veth" => Ok(NetworkDeviceModel::Veth),
@@ -41,17 +88,157 @@ impl FromStr for NetworkDeviceModel {
     }
 }
 
+impl fmt::Display for NetworkDeviceModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A bandwidth limit of a network device, in MB/s.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct NetworkRate(f64);
+
+// the rate is always a finite number, so equality is total
+impl Eq for NetworkRate {}
+
+proxmox_serde::forward_deserialize_to_from_str!(NetworkRate);
+
+impl NetworkRate {
+    pub fn new(mbps: f64) -> Result<Self, Error> {
+        if !mbps.is_finite() || mbps < 0.0 {
+            bail!("invalid rate {mbps}, expected a positive number of MB/s");
+        }
+
+        Ok(Self(mbps))
+    }
+
+    pub fn mbps(&self) -> f64 {
+        self.0
+    }
+}
+
+impl FromStr for NetworkRate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mbps = s
+            .parse()
+            .map_err(|_| format_err!("invalid rate {s:?}, expected a number of MB/s"))?;
+
+        Self::new(mbps)
+    }
+}
+
+impl fmt::Display for NetworkRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The VLANs passed through a network device, written as `10;20-30` in the guest config.
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct VlanTrunks(Vec<(u16, u16)>);
+
+proxmox_serde::forward_deserialize_to_from_str!(VlanTrunks);
+
+impl VlanTrunks {
+    /// The inclusive ranges of VLAN IDs, in the order they are written in the config.
+    pub fn ranges(&self) -> &[(u16, u16)] {
+        &self.0
+    }
+
+    pub fn contains(&self, vlan: u16) -> bool {
+        self.0
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&vlan))
+    }
+}
+
+impl FromStr for VlanTrunks {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_id = |id: &str| match id.trim().parse::<u16>() {
+            Ok(id) if (VLAN_ID_MIN..=VLAN_ID_MAX).contains(&id) => Ok(id),
+            _ => bail!(
+                "invalid VLAN ID {id:?} in trunks, expected a number between {VLAN_ID_MIN} and \
+                 {VLAN_ID_MAX}"
+            ),
+        };
+
+        let ranges = s
+            .split(';')
+            .map(|range| {
+                let (first, last) = match range.split_once('-') {
+                    Some((first, last)) => (parse_id(first)?, parse_id(last)?),
+                    None => (parse_id(range)?, parse_id(range)?),
+                };
+
+                if first > last {
+                    bail!("invalid VLAN range {range:?} in trunks");
+                }
+
+                Ok((first, last))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self(ranges))
+    }
+}
+
+impl fmt::Display for VlanTrunks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, (first, last)) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(";")?;
+            }
+
+            match first == last {
+                true => write!(f, "{first}")?,
+                false => write!(f, "{first}-{last}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes an optional property of a property string.
+fn write_property(
+    f: &mut fmt::Formatter,
+    key: &str,
+    value: Option<impl fmt::Display>,
+) -> fmt::Result {
+    match value {
+        Some(value) => write!(f, ",{key}={value}"),
+        None => Ok(()),
+    }
+}
+
+/// Booleans are written as `0` and `1` in guest configs.
+fn bool_property(value: Option<bool>) -> Option<u8> {
+    value.map(u8::from)
+}
+
 /// Representation of the network device property string of a QEMU guest.
 ///
-/// It currently only cotains properties that are required for the firewall to function, there are
-/// still missing properties that can be contained in the schema.
+/// Properties that are not known to this schema are ignored, they are not contained in the
+/// property string written by the [`Display`](fmt::Display) implementation either.
 #[derive(Debug, Deserialize)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct QemuNetworkDevice {
     model: NetworkDeviceModel,
     #[serde(rename = "macaddr")]
     mac_address: MacAddress,
+    bridge: Option<String>,
     firewall: Option<bool>,
+    link_down: Option<bool>,
+    mtu: Option<u16>,
+    queues: Option<u16>,
+    rate: Option<NetworkRate>,
+    tag: Option<u16>,
+    trunks: Option<VlanTrunks>,
 }
 
 impl ApiType for QemuNetworkDevice {
@@ -59,11 +246,21 @@ impl ApiType for QemuNetworkDevice {
     const API_SCHEMA: proxmox_schema::Schema = ObjectSchema::new(
         "QEMU Network Device",
         &sorted!([
+            (
+                "bridge",
+                true,
+                &StringSchema::new("bridge the network device is attached to").schema(),
+            ),
             (
                 "firewall",
                 true,
                 &BooleanSchema::new("firewall enabled for this network device").schema(),
             ),
+            (
+                "link_down",
+                true,
+                &BooleanSchema::new("whether the link of this network device is down").schema(),
+            ),
             (
                 "macaddr",
                 false,
@@ -74,17 +271,123 @@ impl ApiType for QemuNetworkDevice {
                 false,
                 &StringSchema::new("type of this network device").schema(),
             ),
+            (
+                "mtu",
+                true,
+                &IntegerSchema::new("MTU of this network device, 1 uses the MTU of the bridge")
+                    .minimum(1)
+                    .maximum(65520)
+                    .schema(),
+            ),
+            (
+                "queues",
+                true,
+                &IntegerSchema::new("number of packet queues of this network device")
+                    .minimum(0)
+                    .maximum(64)
+                    .schema(),
+            ),
+            (
+                "rate",
+                true,
+                &StringSchema::new("rate limit in MB/s for this network device").schema(),
+            ),
+            (
+                "tag",
+                true,
+                &IntegerSchema::new("VLAN tag for packets of this network device")
+                    .minimum(VLAN_ID_MIN as isize)
+                    .maximum(VLAN_ID_MAX as isize)
+                    .schema(),
+            ),
+            (
+                "trunks",
+                true,
+                &StringSchema::new("VLAN IDs passed through this network device").schema(),
+            ),
         ]),
     )
     .additional_properties(true)
     .key_alias_info(KeyAliasInfo::new(
         "model",
-        &sorted!(["e1000", "rtl8139", "virtio", "vmxnet3"]),
+        &sorted!([
+            "e1000",
+            "e1000-82540em",
+            "e1000-82544gc",
+            "e1000-82545em",
+            "e1000e",
+            "i82551",
+            "i82557b",
+            "i82559er",
+            "ne2k_isa",
+            "ne2k_pci",
+            "pcnet",
+            "rtl8139",
+            "virtio",
+            "vmxnet3",
+        ]),
         "macaddr",
     ))
     .schema();
 }
 
+impl QemuNetworkDevice {
+    pub fn model(&self) -> NetworkDeviceModel {
+        self.model
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac_address
+    }
+
+    pub fn bridge(&self) -> Option<&str> {
+        self.bridge.as_deref()
+    }
+
+    pub fn firewall(&self) -> Option<bool> {
+        self.firewall
+    }
+
+    pub fn link_down(&self) -> Option<bool> {
+        self.link_down
+    }
+
+    pub fn mtu(&self) -> Option<u16> {
+        self.mtu
+    }
+
+    pub fn queues(&self) -> Option<u16> {
+        self.queues
+    }
+
+    pub fn rate(&self) -> Option<NetworkRate> {
+        self.rate
+    }
+
+    pub fn tag(&self) -> Option<u16> {
+        self.tag
+    }
+
+    pub fn trunks(&self) -> Option<&VlanTrunks> {
+        self.trunks.as_ref()
+    }
+}
+
+impl fmt::Display for QemuNetworkDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.model, self.mac_address)?;
+
+        write_property(f, "bridge", self.bridge.as_ref())?;
+        write_property(f, "firewall", bool_property(self.firewall))?;
+        write_property(f, "link_down", bool_property(self.link_down))?;
+        write_property(f, "mtu", self.mtu)?;
+        write_property(f, "queues", self.queues)?;
+        write_property(f, "rate", self.rate)?;
+        write_property(f, "tag", self.tag)?;
+        write_property(f, "trunks", self.trunks.as_ref())
+    }
+}
+
 /// Representation of possible values for an LXC guest IPv4 field.
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
@@ -117,6 +420,16 @@ impl FromStr for LxcIpv4Addr {
     }
 }
 
+impl fmt::Display for LxcIpv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LxcIpv4Addr::Ip(cidr) => cidr.fmt(f),
+            LxcIpv4Addr::Dhcp => f.write_str("dhcp"),
+            LxcIpv4Addr::Manual => f.write_str("manual"),
+        }
+    }
+}
+
 /// Representation of possible values for an LXC guest IPv6 field.
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
@@ -151,10 +464,21 @@ impl FromStr for LxcIpv6Addr {
     }
 }
 
+impl fmt::Display for LxcIpv6Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LxcIpv6Addr::Ip(cidr) => cidr.fmt(f),
+            LxcIpv6Addr::Dhcp => f.write_str("dhcp"),
+            LxcIpv6Addr::Auto => f.write_str("auto"),
+            LxcIpv6Addr::Manual => f.write_str("manual"),
+        }
+    }
+}
+
 /// Representation of the network device property string of a LXC guest.
 ///
-/// It currently only cotains properties that are required for the firewall to function, there are
-/// still missing properties that can be contained in the schema.
+/// Properties that are not known to this schema are ignored, they are not contained in the
+/// property string written by the [`Display`](fmt::Display) implementation either.
 #[derive(Debug, Deserialize)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct LxcNetworkDevice {
@@ -162,9 +486,18 @@ pub struct LxcNetworkDevice {
     ty: NetworkDeviceModel,
     #[serde(rename = "hwaddr")]
     mac_address: MacAddress,
+    name: String,
+    bridge: Option<String>,
     firewall: Option<bool>,
+    gw: Option<Ipv4Addr>,
+    gw6: Option<Ipv6Addr>,
     ip: Option<LxcIpv4Addr>,
     ip6: Option<LxcIpv6Addr>,
+    link_down: Option<bool>,
+    mtu: Option<u16>,
+    rate: Option<NetworkRate>,
+    tag: Option<u16>,
+    trunks: Option<VlanTrunks>,
 }
 
 impl ApiType for LxcNetworkDevice {
@@ -172,11 +505,26 @@ impl ApiType for LxcNetworkDevice {
     const API_SCHEMA: proxmox_schema::Schema = ObjectSchema::new(
         "LXC Network Device",
         &sorted!([
+            (
+                "bridge",
+                true,
+                &StringSchema::new("bridge the network device is attached to").schema(),
+            ),
             (
                 "firewall",
                 true,
                 &BooleanSchema::new("firewall enabled for this network device").schema(),
             ),
+            (
+                "gw",
+                true,
+                &StringSchema::new("IPv4 default gateway of this network device").schema(),
+            ),
+            (
+                "gw6",
+                true,
+                &StringSchema::new("IPv6 default gateway of this network device").schema(),
+            ),
             (
                 "hwaddr",
                 false,
@@ -192,6 +540,42 @@ impl ApiType for LxcNetworkDevice {
                 true,
                 &StringSchema::new("IPv6 settings for this network device").schema(),
             ),
+            (
+                "link_down",
+                true,
+                &BooleanSchema::new("whether the link of this network device is down").schema(),
+            ),
+            (
+                "mtu",
+                true,
+                &IntegerSchema::new("MTU of this network device")
+                    .minimum(64)
+                    .maximum(65535)
+                    .schema(),
+            ),
+            (
+                "name",
+                false,
+                &StringSchema::new("name of the network device inside the container").schema(),
+            ),
+            (
+                "rate",
+                true,
+                &StringSchema::new("rate limit in MB/s for this network device").schema(),
+            ),
+            (
+                "tag",
+                true,
+                &IntegerSchema::new("VLAN tag for packets of this network device")
+                    .minimum(VLAN_ID_MIN as isize)
+                    .maximum(VLAN_ID_MAX as isize)
+                    .schema(),
+            ),
+            (
+                "trunks",
+                true,
+                &StringSchema::new("VLAN IDs passed through this network device").schema(),
+            ),
             (
                 "type",
                 false,
@@ -203,6 +587,84 @@ impl ApiType for LxcNetworkDevice {
     .schema();
 }
 
+impl LxcNetworkDevice {
+    pub fn ty(&self) -> NetworkDeviceModel {
+        self.ty
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac_address
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn bridge(&self) -> Option<&str> {
+        self.bridge.as_deref()
+    }
+
+    pub fn firewall(&self) -> Option<bool> {
+        self.firewall
+    }
+
+    pub fn gw(&self) -> Option<Ipv4Addr> {
+        self.gw
+    }
+
+    pub fn gw6(&self) -> Option<Ipv6Addr> {
+        self.gw6
+    }
+
+    pub fn ip(&self) -> Option<LxcIpv4Addr> {
+        self.ip
+    }
+
+    pub fn ip6(&self) -> Option<LxcIpv6Addr> {
+        self.ip6
+    }
+
+    pub fn link_down(&self) -> Option<bool> {
+        self.link_down
+    }
+
+    pub fn mtu(&self) -> Option<u16> {
+        self.mtu
+    }
+
+    pub fn rate(&self) -> Option<NetworkRate> {
+        self.rate
+    }
+
+    pub fn tag(&self) -> Option<u16> {
+        self.tag
+    }
+
+    pub fn trunks(&self) -> Option<&VlanTrunks> {
+        self.trunks.as_ref()
+    }
+}
+
+impl fmt::Display for LxcNetworkDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "name={}", self.name)?;
+
+        write_property(f, "bridge", self.bridge.as_ref())?;
+        write_property(f, "firewall", bool_property(self.firewall))?;
+        write_property(f, "gw", self.gw)?;
+        write_property(f, "gw6", self.gw6)?;
+        write_property(f, "hwaddr", Some(self.mac_address))?;
+        write_property(f, "ip", self.ip)?;
+        write_property(f, "ip6", self.ip6)?;
+        write_property(f, "link_down", bool_property(self.link_down))?;
+        write_property(f, "mtu", self.mtu)?;
+        write_property(f, "rate", self.rate)?;
+        write_property(f, "tag", self.tag)?;
+        write_property(f, "trunks", self.trunks.as_ref())?;
+        write_property(f, "type", Some(self.ty))
+    }
+}
+
 /// Container type that can hold both LXC and QEMU network devices.
 #[derive(Debug)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
@@ -248,6 +710,38 @@ impl NetworkDevice {
         None
     }
 
+    /// Returns the bridge the network device is attached to, if any.
+    pub fn bridge(&self) -> Option<&str> {
+        match self {
+            NetworkDevice::Qemu(qemu_network_device) => qemu_network_device.bridge(),
+            NetworkDevice::Lxc(lxc_network_device) => lxc_network_device.bridge(),
+        }
+    }
+
+    /// Returns the VLAN tag of the network device, if any.
+    pub fn tag(&self) -> Option<u16> {
+        match self {
+            NetworkDevice::Qemu(qemu_network_device) => qemu_network_device.tag,
+            NetworkDevice::Lxc(lxc_network_device) => lxc_network_device.tag,
+        }
+    }
+
+    /// Returns the VLANs passed through the network device, if any.
+    pub fn trunks(&self) -> Option<&VlanTrunks> {
+        match self {
+            NetworkDevice::Qemu(qemu_network_device) => qemu_network_device.trunks(),
+            NetworkDevice::Lxc(lxc_network_device) => lxc_network_device.trunks(),
+        }
+    }
+
+    /// Whether packets tagged with a VLAN reach the guest through this network device.
+    ///
+    /// A network device with a VLAN tag only sees the untagged packets of its VLAN. Without a
+    /// tag, all VLANs pass, unless the trunks of the device are restricted.
+    pub fn passes_vlan(&self, vlan: u16) -> bool {
+        self.tag().is_none() && self.trunks().is_none_or(|trunks| trunks.contains(vlan))
+    }
+
     /// Whether the firewall is enabled for this network device, defaults to [`NETWORK_DEVICE_FIREWALL_DEFAULT`]
     pub fn has_firewall(&self) -> bool {
         let firewall_option = match self {
@@ -275,10 +769,171 @@ impl FromStr for NetworkDevice {
     }
 }
 
+impl fmt::Display for NetworkDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkDevice::Qemu(qemu_network_device) => qemu_network_device.fmt(f),
+            NetworkDevice::Lxc(lxc_network_device) => lxc_network_device.fmt(f),
+        }
+    }
+}
+
+/// Representation of possible values for the IPv4 field of a QEMU guest IP config.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub enum QemuIpv4Addr {
+    Ip(Ipv4Cidr),
+    Dhcp,
+}
+
+proxmox_serde::forward_deserialize_to_from_str!(QemuIpv4Addr);
+
+impl QemuIpv4Addr {
+    pub fn cidr(&self) -> Option<Ipv4Cidr> {
+        match self {
+            QemuIpv4Addr::Ip(ipv4_cidr) => Some(*ipv4_cidr),
+            QemuIpv4Addr::Dhcp => None,
+        }
+    }
+}
+
+impl FromStr for QemuIpv4Addr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "dhcp" => QemuIpv4Addr::Dhcp,
+            _ => QemuIpv4Addr::Ip(s.parse()?),
+        })
+    }
+}
+
+impl fmt::Display for QemuIpv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QemuIpv4Addr::Ip(cidr) => cidr.fmt(f),
+            QemuIpv4Addr::Dhcp => f.write_str("dhcp"),
+        }
+    }
+}
+
+/// Representation of possible values for the IPv6 field of a QEMU guest IP config.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub enum QemuIpv6Addr {
+    Ip(Ipv6Cidr),
+    Dhcp,
+    Auto,
+}
+
+proxmox_serde::forward_deserialize_to_from_str!(QemuIpv6Addr);
+
+impl QemuIpv6Addr {
+    pub fn cidr(&self) -> Option<Ipv6Cidr> {
+        match self {
+            QemuIpv6Addr::Ip(ipv6_cidr) => Some(*ipv6_cidr),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for QemuIpv6Addr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "dhcp" => QemuIpv6Addr::Dhcp,
+            "auto" => QemuIpv6Addr::Auto,
+            _ => QemuIpv6Addr::Ip(s.parse()?),
+        })
+    }
+}
+
+impl fmt::Display for QemuIpv6Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QemuIpv6Addr::Ip(cidr) => cidr.fmt(f),
+            QemuIpv6Addr::Dhcp => f.write_str("dhcp"),
+            QemuIpv6Addr::Auto => f.write_str("auto"),
+        }
+    }
+}
+
+/// Representation of the `ipconfigN` property string of a QEMU guest.
+///
+/// The IP config is passed to the guest via cloud-init and applies to the network device with
+/// the same index.
+#[derive(Debug, Default, Deserialize)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct QemuIpConfig {
+    gw: Option<Ipv4Addr>,
+    gw6: Option<Ipv6Addr>,
+    ip: Option<QemuIpv4Addr>,
+    ip6: Option<QemuIpv6Addr>,
+}
+
+impl ApiType for QemuIpConfig {
+    #[sortable]
+    const API_SCHEMA: proxmox_schema::Schema = ObjectSchema::new(
+        "QEMU IP Config",
+        &sorted!([
+            (
+                "gw",
+                true,
+                &StringSchema::new("IPv4 default gateway of this network device").schema(),
+            ),
+            (
+                "gw6",
+                true,
+                &StringSchema::new("IPv6 default gateway of this network device").schema(),
+            ),
+            (
+                "ip",
+                true,
+                &StringSchema::new("IP settings for this network device").schema(),
+            ),
+            (
+                "ip6",
+                true,
+                &StringSchema::new("IPv6 settings for this network device").schema(),
+            ),
+        ]),
+    )
+    .additional_properties(true)
+    .schema();
+}
+
+impl QemuIpConfig {
+    pub fn gw(&self) -> Option<Ipv4Addr> {
+        self.gw
+    }
+
+    pub fn gw6(&self) -> Option<Ipv6Addr> {
+        self.gw6
+    }
+
+    pub fn ip(&self) -> Option<QemuIpv4Addr> {
+        self.ip
+    }
+
+    pub fn ip6(&self) -> Option<QemuIpv6Addr> {
+        self.ip6
+    }
+}
+
+impl FromStr for QemuIpConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse::<PropertyString<QemuIpConfig>>()?.into_inner())
+    }
+}
+
 #[derive(Debug, Default)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct NetworkConfig {
     network_devices: BTreeMap<i64, NetworkDevice>,
+    ip_configs: BTreeMap<i64, QemuIpConfig>,
 }
 
 impl NetworkConfig {
@@ -300,12 +955,31 @@ impl NetworkConfig {
         bail!("No index found in net key string: {key}")
     }
 
+    pub fn index_from_ipconfig_key(key: &str) -> Result<i64, Error> {
+        if let Some(digits) = key.strip_prefix("ipconfig") {
+            if let Some((digits, rest)) = match_digits(digits) {
+                let index: i64 = digits.parse()?;
+
+                if (0..31).contains(&index) && rest.is_empty() {
+                    return Ok(index);
+                }
+            }
+        }
+
+        bail!("No index found in ipconfig key string: {key}")
+    }
+
     pub fn network_devices(&self) -> &BTreeMap<i64, NetworkDevice> {
         &self.network_devices
     }
 
+    /// The cloud-init IP configs of a QEMU guest, by the index of their network device.
+    pub fn ip_configs(&self) -> &BTreeMap<i64, QemuIpConfig> {
+        &self.ip_configs
+    }
+
     pub fn parse<R: io::BufRead>(input: R) -> Result<Self, Error> {
-        let mut network_devices = BTreeMap::new();
+        let mut network_config = Self::new();
 
         for line in input.lines() {
             let line = line?;
@@ -319,40 +993,72 @@ impl NetworkConfig {
                 break;
             }
 
-            if line.starts_with("net") {
-                log::trace!("parsing net config line: {line}");
+            network_config.parse_line(line)?;
+        }
+
+        Ok(network_config)
+    }
+
+    /// Parses a single `key: value` line of a guest config, ignoring all keys other than the
+    /// network devices and their IP configs.
+    pub(crate) fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        if line.starts_with("ipconfig") {
+            return self.parse_ip_config_line(line);
+        }
+
+        if !line.starts_with("net") {
+            return Ok(());
+        }
+
+        log::trace!("parsing net config line: {line}");
 
-                if let Some((mut key, mut value)) = line.split_once(':') {
-                    if key.is_empty() || value.is_empty() {
-                        continue;
-                    }
+        if let Some((mut key, mut value)) = line.split_once(':') {
+            if key.is_empty() || value.is_empty() {
+                return Ok(());
+            }
 
-                    key = key.trim();
-                    value = value.trim();
+            key = key.trim();
+            value = value.trim();
 
-                    if let Ok(index) = Self::index_from_net_key(key) {
-                        let network_device = NetworkDevice::from_str(value)?;
+            if let Ok(index) = Self::index_from_net_key(key) {
+                let network_device = NetworkDevice::from_str(value)?;
 
-                        let exists = network_devices.insert(index, network_device);
+                let exists = self.network_devices.insert(index, network_device);
 
-                        if exists.is_some() {
-                            bail!("Duplicated config key detected: {key}");
-                        }
-                    } else {
-                        bail!("Encountered invalid net key in cfg: {key}");
-                    }
+                if exists.is_some() {
+                    bail!("Duplicated config key detected: {key}");
                 }
+            } else {
+                bail!("Encountered invalid net key in cfg: {key}");
             }
         }
 
-        Ok(Self { network_devices })
+        Ok(())
+    }
+
+    fn parse_ip_config_line(&mut self, line: &str) -> Result<(), Error> {
+        let Some((key, value)) = line.split_once(':') else {
+            return Ok(());
+        };
+
+        let (key, value) = (key.trim(), value.trim());
+
+        if value.is_empty() {
+            return Ok(());
+        }
+
+        let index = Self::index_from_ipconfig_key(key)?;
+
+        if self.ip_configs.insert(index, value.parse()?).is_some() {
+            bail!("Duplicated config key detected: {key}");
+        }
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv6Addr;
-
     use super::*;
 
     #[test]
@@ -403,6 +1109,13 @@ mod tests {
                 model: NetworkDeviceModel::VirtIO,
                 mac_address: MacAddress::new([0xAA, 0xAA, 0xAA, 0x17, 0x19, 0x81]),
                 firewall: Some(true),
+                bridge: Some("public".to_string()),
+                link_down: None,
+                mtu: None,
+                queues: Some(4),
+                rate: None,
+                tag: None,
+                trunks: None,
             })
         );
 
@@ -416,6 +1129,13 @@ mod tests {
                 model: NetworkDeviceModel::VirtIO,
                 mac_address: MacAddress::new([0xAA, 0xAA, 0xAA, 0x17, 0x19, 0x81]),
                 firewall: None,
+                bridge: Some("public".to_string()),
+                link_down: None,
+                mtu: None,
+                queues: None,
+                rate: None,
+                tag: None,
+                trunks: None,
             })
         );
 
@@ -431,6 +1151,13 @@ mod tests {
                 model: NetworkDeviceModel::VirtIO,
                 mac_address: MacAddress::new([0xAA, 0xAA, 0xAA, 0x17, 0x19, 0x81]),
                 firewall: Some(true),
+                bridge: Some("public".to_string()),
+                link_down: None,
+                mtu: None,
+                queues: Some(4),
+                rate: None,
+                tag: None,
+                trunks: None,
             })
         );
 
@@ -447,6 +1174,15 @@ mod tests {
                 ty: NetworkDeviceModel::Veth,
                 mac_address: MacAddress::new([0xAA, 0xAA, 0xAA, 0xE2, 0x3E, 0x24]),
                 firewall: Some(false),
+                name: "eth0".to_string(),
+                bridge: Some("public".to_string()),
+                gw: None,
+                gw6: None,
+                link_down: None,
+                mtu: None,
+                rate: None,
+                tag: None,
+                trunks: None,
                 ip: Some(LxcIpv4Addr::Dhcp),
                 ip6: None,
             })
@@ -468,6 +1204,80 @@ mod tests {
             .expect_err("invalid network configuration");
     }
 
+    #[test]
+    fn test_network_device_properties() {
+        let network_device: NetworkDevice = "e1000e=BC:24:11:12:34:56,bridge=vmbr0,firewall=1,\
+            link_down=1,mtu=1,queues=8,rate=12.5,tag=100,trunks=10;20-30,unknown=1"
+            .parse()
+            .expect("valid network configuration");
+
+        let NetworkDevice::Qemu(qemu_device) = &network_device else {
+            panic!("expected a QEMU network device");
+        };
+
+        assert_eq!(qemu_device.model(), NetworkDeviceModel::E1000e);
+        assert_eq!(qemu_device.link_down(), Some(true));
+        assert_eq!(qemu_device.mtu(), Some(1));
+        assert_eq!(qemu_device.queues(), Some(8));
+        assert_eq!(qemu_device.rate().map(|rate| rate.mbps()), Some(12.5));
+
+        let trunks = qemu_device.trunks().expect("trunks are set");
+        assert_eq!(trunks.ranges(), &[(10, 10), (20, 30)]);
+        assert!(trunks.contains(25));
+        assert!(!trunks.contains(15));
+
+        assert_eq!(network_device.bridge(), Some("vmbr0"));
+        assert_eq!(network_device.tag(), Some(100));
+
+        // unknown properties are not written back
+        assert_eq!(
+            network_device.to_string(),
+            "e1000e=BC:24:11:12:34:56,bridge=vmbr0,firewall=1,link_down=1,mtu=1,queues=8,\
+             rate=12.5,tag=100,trunks=10;20-30"
+        );
+
+        let network_device: NetworkDevice = "name=eth0,bridge=vmbr1,firewall=0,gw=10.0.0.1,\
+            gw6=fd00::1,hwaddr=BC:24:11:12:34:57,ip=10.0.0.2/24,ip6=auto,mtu=9000,rate=100,\
+            tag=20,type=veth"
+            .parse()
+            .expect("valid network configuration");
+
+        let NetworkDevice::Lxc(lxc_device) = &network_device else {
+            panic!("expected a LXC network device");
+        };
+
+        assert_eq!(lxc_device.name(), "eth0");
+        assert_eq!(lxc_device.gw(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(lxc_device.gw6(), Some("fd00::1".parse().unwrap()));
+        assert_eq!(network_device.ip(), Some("10.0.0.2/24".parse().unwrap()));
+
+        let serialized = network_device.to_string();
+        assert_eq!(
+            serialized,
+            "name=eth0,bridge=vmbr1,firewall=0,gw=10.0.0.1,gw6=fd00::1,hwaddr=BC:24:11:12:34:57,\
+             ip=10.0.0.2/24,ip6=auto,mtu=9000,rate=100,tag=20,type=veth"
+        );
+        assert_eq!(serialized.parse::<NetworkDevice>().unwrap(), network_device);
+
+        for input in [
+            "virtio=BC:24:11:12:34:56,tag=0",
+            "virtio=BC:24:11:12:34:56,tag=4095",
+            "virtio=BC:24:11:12:34:56,mtu=65521",
+            "virtio=BC:24:11:12:34:56,queues=65",
+            "virtio=BC:24:11:12:34:56,rate=-1",
+            "virtio=BC:24:11:12:34:56,trunks=30-20",
+            "virtio=BC:24:11:12:34:56,trunks=10;;20",
+            "virtio=BC:24:11:12:34:56,trunks=5000",
+            "name=eth0,hwaddr=BC:24:11:12:34:57,mtu=63,type=veth",
+            "name=eth0,hwaddr=BC:24:11:12:34:57,gw=fd00::1,type=veth",
+            "hwaddr=BC:24:11:12:34:57,type=veth",
+        ] {
+            input
+                .parse::<NetworkDevice>()
+                .expect_err("invalid network configuration");
+        }
+    }
+
     #[test]
     fn test_parse_network_config() {
         let mut guest_config = "\
@@ -537,6 +1347,13 @@ vmgenid: 706fbe99-d28b-4047-a9cd-3677c859ca8a"
                 model: NetworkDeviceModel::VirtIO,
                 mac_address: MacAddress::new([0xAA, 0xBB, 0xCC, 0xF2, 0xFE, 0x75]),
                 firewall: None,
+                bridge: Some("public".to_string()),
+                link_down: None,
+                mtu: None,
+                queues: None,
+                rate: None,
+                tag: None,
+                trunks: None,
             })
         );
 
@@ -565,6 +1382,15 @@ unprivileged: 1"
                 ty: NetworkDeviceModel::Veth,
                 mac_address: MacAddress::new([0xBC, 0x24, 0x11, 0x47, 0x83, 0x11]),
                 firewall: Some(true),
+                name: "eth0".to_string(),
+                bridge: Some("data".to_string()),
+                gw: None,
+                gw6: None,
+                link_down: None,
+                mtu: None,
+                rate: None,
+                tag: None,
+                trunks: None,
                 ip: Some(LxcIpv4Addr::Dhcp),
                 ip6: Some(LxcIpv6Addr::Auto),
             })
@@ -576,6 +1402,15 @@ unprivileged: 1"
                 ty: NetworkDeviceModel::Veth,
                 mac_address: MacAddress::new([0xBC, 0x24, 0x11, 0x47, 0x83, 0x12]),
                 firewall: Some(false),
+                name: "eth0".to_string(),
+                bridge: Some("data".to_string()),
+                gw: None,
+                gw6: None,
+                link_down: None,
+                mtu: None,
+                rate: None,
+                tag: None,
+                trunks: None,
                 ip: Some(LxcIpv4Addr::Ip(
                     Ipv4Cidr::from_str("123.123.123.123/24").expect("valid ipv4")
                 )),
@@ -589,6 +1424,15 @@ unprivileged: 1"
                 ty: NetworkDeviceModel::Veth,
                 mac_address: MacAddress::new([0xBC, 0x24, 0x11, 0x47, 0x83, 0x13]),
                 firewall: Some(true),
+                name: "eth0".to_string(),
+                bridge: Some("data".to_string()),
+                gw: None,
+                gw6: None,
+                link_down: None,
+                mtu: None,
+                rate: None,
+                tag: None,
+                trunks: None,
                 ip: None,
                 ip6: Some(LxcIpv6Addr::Ip(
                     Ipv6Cidr::from_str("fd80::1/64").expect("valid ipv6")
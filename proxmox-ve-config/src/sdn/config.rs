@@ -1,14 +1,9 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    error::Error,
-    fmt::Display,
-    net::IpAddr,
-    str::FromStr,
-};
+use std::{collections::BTreeMap, error::Error, fmt::Display, net::IpAddr, str::FromStr};
 
 use proxmox_network_types::ip_address::{Cidr, IpRange, IpRangeError};
+use proxmox_network_types::mac_address::MacAddress;
 use proxmox_schema::{property_string::PropertyString, ApiType, ObjectSchema, StringSchema};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
 
 use crate::{
     common::Allowlist,
@@ -26,10 +21,14 @@ pub enum SdnConfigError {
     ZoneNotFound,
     VnetNotFound,
     MismatchedCidrGateway,
+    MismatchedCidrDnsServer,
     MismatchedSubnetZone,
     NameError(SdnNameError),
     InvalidDhcpRange(IpRangeError),
     DuplicateVnetName,
+    InvalidVlanProtocol,
+    MissingZoneProperty(&'static str),
+    InvalidZoneProperty(&'static str),
 }
 
 impl Error for SdnConfigError {
@@ -52,12 +51,25 @@ impl Display for SdnConfigError {
             SdnConfigError::MismatchedCidrGateway => {
                 write!(f, "mismatched ip address family for gateway and CIDR")
             }
+            SdnConfigError::MismatchedCidrDnsServer => {
+                write!(
+                    f,
+                    "mismatched ip address family for DHCP DNS server and CIDR"
+                )
+            }
             SdnConfigError::InvalidZoneType => write!(f, "invalid zone type"),
             SdnConfigError::InvalidDhcpType => write!(f, "invalid dhcp type"),
             SdnConfigError::DuplicateVnetName => write!(f, "vnet name occurs in multiple zones"),
             SdnConfigError::MismatchedSubnetZone => {
                 write!(f, "subnet zone does not match actual zone")
             }
+            SdnConfigError::InvalidVlanProtocol => write!(f, "invalid vlan protocol"),
+            SdnConfigError::MissingZoneProperty(property) => {
+                write!(f, "missing zone property {property}")
+            }
+            SdnConfigError::InvalidZoneProperty(property) => {
+                write!(f, "invalid value for zone property {property}")
+            }
         }
     }
 }
@@ -127,22 +139,562 @@ impl Display for DhcpType {
     }
 }
 
-/// Struct for deserializing a zone entry of the SDN running config
+/// The protocol of the service VLAN of a QinQ zone.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum VlanProtocol {
+    #[default]
+    Ieee802_1q,
+    Ieee802_1ad,
+}
+
+proxmox_serde::forward_deserialize_to_from_str!(VlanProtocol);
+proxmox_serde::forward_serialize_to_display!(VlanProtocol);
+
+impl FromStr for VlanProtocol {
+    type Err = SdnConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "802.1q" => Ok(VlanProtocol::Ieee802_1q),
+            "802.1ad" => Ok(VlanProtocol::Ieee802_1ad),
+            _ => Err(SdnConfigError::InvalidVlanProtocol),
+        }
+    }
+}
+
+impl Display for VlanProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VlanProtocol::Ieee802_1q => "802.1q",
+            VlanProtocol::Ieee802_1ad => "802.1ad",
+        })
+    }
+}
+
+/// (De-)serializes the lists of the SDN config, which are strings with comma separated values.
+mod serde_comma_list {
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    use serde::de::{Deserialize, Deserializer, Error};
+    use serde::Serializer;
+
+    pub fn serialize<S, T>(list: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Display,
+    {
+        let list: Vec<String> = list.iter().map(ToString::to_string).collect();
+        serializer.serialize_str(&list.join(","))
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        let list = Option::<String>::deserialize(deserializer)?.unwrap_or_default();
+
+        list.split([',', ';', ' '])
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse().map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// Serializes booleans as integers, the same way the SDN config is written by Proxmox VE.
+fn serialize_perl_bool<S: Serializer>(
+    value: &Option<bool>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_u8(u8::from(*value)),
+        None => serializer.serialize_none(),
+    }
+}
+
+const VLAN_TAG_RANGE: std::ops::RangeInclusive<u32> = 1..=4094;
+const VXLAN_ID_RANGE: std::ops::RangeInclusive<u32> = 1..=16777215;
+
+/// Properties of a simple zone.
+///
+/// Simple zones create an isolated bridge on every node and have no properties besides the
+/// [`ZoneOptions`] shared by all zone types.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct SimpleZoneProperties {
+    #[serde(flatten)]
+    other: BTreeMap<String, PropertyValue>,
+}
+
+/// Properties of a VLAN zone, whose VNets are VLANs on an existing bridge.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct VlanZoneProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bridge: Option<String>,
+    #[serde(flatten)]
+    other: BTreeMap<String, PropertyValue>,
+}
+
+impl VlanZoneProperties {
+    pub fn new(bridge: String) -> Self {
+        Self {
+            bridge: Some(bridge),
+            ..Default::default()
+        }
+    }
+
+    pub fn bridge(&self) -> Option<&str> {
+        self.bridge.as_deref()
+    }
+
+    fn validate(&self) -> Result<(), SdnConfigError> {
+        if self.bridge.is_none() {
+            return Err(SdnConfigError::MissingZoneProperty("bridge"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Properties of a QinQ zone, whose VNets are VLANs stacked inside a service VLAN.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct QinqZoneProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bridge: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_u32",
+        skip_serializing_if = "Option::is_none"
+    )]
+    tag: Option<u32>,
+    #[serde(rename = "vlan-protocol", skip_serializing_if = "Option::is_none")]
+    vlan_protocol: Option<VlanProtocol>,
+    #[serde(flatten)]
+    other: BTreeMap<String, PropertyValue>,
+}
+
+impl QinqZoneProperties {
+    pub fn new(bridge: String, tag: u32, vlan_protocol: Option<VlanProtocol>) -> Self {
+        Self {
+            bridge: Some(bridge),
+            tag: Some(tag),
+            vlan_protocol,
+            ..Default::default()
+        }
+    }
+
+    pub fn bridge(&self) -> Option<&str> {
+        self.bridge.as_deref()
+    }
+
+    /// The tag of the service VLAN.
+    pub fn tag(&self) -> Option<u32> {
+        self.tag
+    }
+
+    /// The protocol of the service VLAN, defaults to 802.1q.
+    pub fn vlan_protocol(&self) -> VlanProtocol {
+        self.vlan_protocol.unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<(), SdnConfigError> {
+        if self.bridge.is_none() {
+            return Err(SdnConfigError::MissingZoneProperty("bridge"));
+        }
+
+        match self.tag {
+            None => Err(SdnConfigError::MissingZoneProperty("tag")),
+            Some(tag) if !VLAN_TAG_RANGE.contains(&tag) => {
+                Err(SdnConfigError::InvalidZoneProperty("tag"))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// Properties of a VXLAN zone, whose VNets are VXLAN tunnels between the peers.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct VxlanZoneProperties {
+    #[serde(
+        default,
+        deserialize_with = "serde_comma_list::deserialize",
+        serialize_with = "serde_comma_list::serialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    peers: Vec<IpAddr>,
+    #[serde(
+        rename = "vxlan-port",
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_u16",
+        skip_serializing_if = "Option::is_none"
+    )]
+    vxlan_port: Option<u16>,
+    #[serde(flatten)]
+    other: BTreeMap<String, PropertyValue>,
+}
+
+impl VxlanZoneProperties {
+    pub fn new(peers: impl IntoIterator<Item = IpAddr>, vxlan_port: Option<u16>) -> Self {
+        Self {
+            peers: peers.into_iter().collect(),
+            vxlan_port,
+            ..Default::default()
+        }
+    }
+
+    pub fn peers(&self) -> &[IpAddr] {
+        &self.peers
+    }
+
+    pub fn vxlan_port(&self) -> Option<u16> {
+        self.vxlan_port
+    }
+
+    fn validate(&self) -> Result<(), SdnConfigError> {
+        if self.peers.is_empty() {
+            return Err(SdnConfigError::MissingZoneProperty("peers"));
+        }
+
+        if self.vxlan_port == Some(0) {
+            return Err(SdnConfigError::InvalidZoneProperty("vxlan-port"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Properties of an EVPN zone, whose VNets are VXLAN tunnels controlled by an EVPN controller.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct EvpnZoneProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    controller: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_u32",
+        skip_serializing_if = "Option::is_none"
+    )]
+    vrf_vxlan: Option<u32>,
+    #[serde(
+        default,
+        deserialize_with = "serde_comma_list::deserialize",
+        serialize_with = "serde_comma_list::serialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    exitnodes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exitnodes_primary: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_bool",
+        serialize_with = "serialize_perl_bool",
+        skip_serializing_if = "Option::is_none"
+    )]
+    exitnodes_local_routing: Option<bool>,
+    #[serde(
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_bool",
+        serialize_with = "serialize_perl_bool",
+        skip_serializing_if = "Option::is_none"
+    )]
+    advertise_subnets: Option<bool>,
+    #[serde(
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_bool",
+        serialize_with = "serialize_perl_bool",
+        skip_serializing_if = "Option::is_none"
+    )]
+    disable_arp_nd_suppression: Option<bool>,
+    #[serde(
+        default,
+        deserialize_with = "serde_comma_list::deserialize",
+        serialize_with = "serde_comma_list::serialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    rt_import: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac: Option<MacAddress>,
+    #[serde(flatten)]
+    other: BTreeMap<String, PropertyValue>,
+}
+
+impl EvpnZoneProperties {
+    pub fn new(controller: String, vrf_vxlan: u32) -> Self {
+        Self {
+            controller: Some(controller),
+            vrf_vxlan: Some(vrf_vxlan),
+            ..Default::default()
+        }
+    }
+
+    pub fn controller(&self) -> Option<&str> {
+        self.controller.as_deref()
+    }
+
+    /// The VXLAN ID of the VRF of the zone.
+    pub fn vrf_vxlan(&self) -> Option<u32> {
+        self.vrf_vxlan
+    }
+
+    /// The nodes that route traffic of the zone to external networks.
+    pub fn exitnodes(&self) -> &[String] {
+        &self.exitnodes
+    }
+
+    pub fn exitnodes_primary(&self) -> Option<&str> {
+        self.exitnodes_primary.as_deref()
+    }
+
+    pub fn exitnodes_local_routing(&self) -> bool {
+        self.exitnodes_local_routing.unwrap_or(false)
+    }
+
+    pub fn advertise_subnets(&self) -> bool {
+        self.advertise_subnets.unwrap_or(false)
+    }
+
+    pub fn disable_arp_nd_suppression(&self) -> bool {
+        self.disable_arp_nd_suppression.unwrap_or(false)
+    }
+
+    /// Additional route targets that are imported into the VRF of the zone.
+    pub fn rt_import(&self) -> &[String] {
+        &self.rt_import
+    }
+
+    /// The anycast MAC address of the gateways of the zone.
+    pub fn mac(&self) -> Option<MacAddress> {
+        self.mac
+    }
+
+    fn validate(&self) -> Result<(), SdnConfigError> {
+        if self.controller.is_none() {
+            return Err(SdnConfigError::MissingZoneProperty("controller"));
+        }
+
+        match self.vrf_vxlan {
+            None => return Err(SdnConfigError::MissingZoneProperty("vrf-vxlan")),
+            Some(id) if !VXLAN_ID_RANGE.contains(&id) => {
+                return Err(SdnConfigError::InvalidZoneProperty("vrf-vxlan"))
+            }
+            Some(_) => (),
+        }
+
+        if let Some(primary) = &self.exitnodes_primary {
+            if !self.exitnodes.contains(primary) {
+                return Err(SdnConfigError::InvalidZoneProperty("exitnodes-primary"));
+            }
+        }
+
+        if self.exitnodes_local_routing() && self.exitnodes.is_empty() {
+            return Err(SdnConfigError::MissingZoneProperty("exitnodes"));
+        }
+
+        Ok(())
+    }
+}
+
+/// The properties of a zone that depend on its type.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ZoneProperties {
+    Simple(SimpleZoneProperties),
+    Vlan(VlanZoneProperties),
+    Qinq(QinqZoneProperties),
+    Vxlan(VxlanZoneProperties),
+    Evpn(EvpnZoneProperties),
+}
+
+impl ZoneProperties {
+    /// Creates empty properties for a zone type.
+    ///
+    /// Apart from simple zones, the returned properties are not valid, since every other zone
+    /// type has required properties.
+    pub fn new(ty: ZoneType) -> Self {
+        match ty {
+            ZoneType::Simple => ZoneProperties::Simple(Default::default()),
+            ZoneType::Vlan => ZoneProperties::Vlan(Default::default()),
+            ZoneType::Qinq => ZoneProperties::Qinq(Default::default()),
+            ZoneType::Vxlan => ZoneProperties::Vxlan(Default::default()),
+            ZoneType::Evpn => ZoneProperties::Evpn(Default::default()),
+        }
+    }
+
+    pub fn ty(&self) -> ZoneType {
+        match self {
+            ZoneProperties::Simple(_) => ZoneType::Simple,
+            ZoneProperties::Vlan(_) => ZoneType::Vlan,
+            ZoneProperties::Qinq(_) => ZoneType::Qinq,
+            ZoneProperties::Vxlan(_) => ZoneType::Vxlan,
+            ZoneProperties::Evpn(_) => ZoneType::Evpn,
+        }
+    }
+
+    /// The properties of the zone that are not interpreted, they are written back unchanged.
+    pub fn other(&self) -> &BTreeMap<String, PropertyValue> {
+        match self {
+            ZoneProperties::Simple(properties) => &properties.other,
+            ZoneProperties::Vlan(properties) => &properties.other,
+            ZoneProperties::Qinq(properties) => &properties.other,
+            ZoneProperties::Vxlan(properties) => &properties.other,
+            ZoneProperties::Evpn(properties) => &properties.other,
+        }
+    }
+
+    /// Checks that all properties required by the zone type are set and have valid values.
+    pub fn validate(&self) -> Result<(), SdnConfigError> {
+        match self {
+            ZoneProperties::Simple(_) => Ok(()),
+            ZoneProperties::Vlan(properties) => properties.validate(),
+            ZoneProperties::Qinq(properties) => properties.validate(),
+            ZoneProperties::Vxlan(properties) => properties.validate(),
+            ZoneProperties::Evpn(properties) => properties.validate(),
+        }
+    }
+}
+
+/// Properties of a zone that are shared by all zone types.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct ZoneOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dhcp: Option<DhcpType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipam: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reversedns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dnszone: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_u16",
+        skip_serializing_if = "Option::is_none"
+    )]
+    mtu: Option<u16>,
+    #[serde(
+        default,
+        deserialize_with = "serde_comma_list::deserialize",
+        serialize_with = "serde_comma_list::serialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    nodes: Vec<String>,
+}
+
+impl ZoneOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_dhcp(mut self, dhcp: DhcpType) -> Self {
+        self.dhcp = Some(dhcp);
+        self
+    }
+
+    pub fn with_ipam(mut self, ipam: impl Into<String>) -> Self {
+        self.ipam = Some(ipam.into());
+        self
+    }
+
+    pub fn with_dns(mut self, dns: impl Into<String>) -> Self {
+        self.dns = Some(dns.into());
+        self
+    }
+
+    pub fn with_reversedns(mut self, reversedns: impl Into<String>) -> Self {
+        self.reversedns = Some(reversedns.into());
+        self
+    }
+
+    pub fn with_dnszone(mut self, dnszone: impl Into<String>) -> Self {
+        self.dnszone = Some(dnszone.into());
+        self
+    }
+
+    pub fn with_mtu(mut self, mtu: u16) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    pub fn with_nodes(mut self, nodes: impl IntoIterator<Item = String>) -> Self {
+        self.nodes = nodes.into_iter().collect();
+        self
+    }
+
+    pub fn dhcp(&self) -> Option<DhcpType> {
+        self.dhcp
+    }
+
+    /// The name of the IPAM plugin of the zone.
+    pub fn ipam(&self) -> Option<&str> {
+        self.ipam.as_deref()
+    }
+
+    /// The name of the DNS plugin of the zone.
+    pub fn dns(&self) -> Option<&str> {
+        self.dns.as_deref()
+    }
+
+    /// The name of the DNS plugin for reverse lookups of the zone.
+    pub fn reversedns(&self) -> Option<&str> {
+        self.reversedns.as_deref()
+    }
+
+    pub fn dnszone(&self) -> Option<&str> {
+        self.dnszone.as_deref()
+    }
+
+    pub fn mtu(&self) -> Option<u16> {
+        self.mtu
+    }
+
+    /// The nodes the zone is restricted to, the zone is available on all nodes if empty.
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+}
+
+/// Struct for (de-)serializing a zone entry of the SDN running config
+///
+/// The options have to come first, so they take their keys out of the entry before the
+/// properties are deserialized. All keys that neither belong to the options nor to the zone
+/// type end up in [`ZoneProperties::other`].
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct ZoneRunningConfig {
-    #[serde(rename = "type")]
-    ty: ZoneType,
-    dhcp: Option<DhcpType>,
+    #[serde(flatten)]
+    options: ZoneOptions,
+    #[serde(flatten)]
+    properties: ZoneProperties,
+}
+
+impl Serialize for ZoneRunningConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // write the type and its properties before the options, like Proxmox VE does
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            #[serde(flatten)]
+            properties: &'a ZoneProperties,
+            #[serde(flatten)]
+            options: &'a ZoneOptions,
+        }
+
+        Entry {
+            properties: &self.properties,
+            options: &self.options,
+        }
+        .serialize(serializer)
+    }
 }
 
-/// Struct for deserializing the zones of the SDN running config
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Default)]
+/// Struct for (de-)serializing the zones of the SDN running config
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
 pub struct ZonesRunningConfig {
-    ids: HashMap<ZoneName, ZoneRunningConfig>,
+    ids: BTreeMap<ZoneName, ZoneRunningConfig>,
 }
 
 /// Represents the dhcp-range property string used in the SDN configuration
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct DhcpRange {
     #[serde(rename = "start-address")]
     start: IpAddr,
@@ -169,6 +721,21 @@ impl ApiType for DhcpRange {
     .schema();
 }
 
+impl From<&IpRange> for DhcpRange {
+    fn from(value: &IpRange) -> Self {
+        match value {
+            IpRange::V4(range) => Self {
+                start: (*range.start()).into(),
+                end: (*range.last()).into(),
+            },
+            IpRange::V6(range) => Self {
+                start: (*range.start()).into(),
+                end: (*range.last()).into(),
+            },
+        }
+    }
+}
+
 impl TryFrom<DhcpRange> for IpRange {
     type Error = IpRangeError;
 
@@ -177,43 +744,129 @@ impl TryFrom<DhcpRange> for IpRange {
     }
 }
 
-/// Struct for deserializing a subnet entry of the SDN running config
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// The `type` of a subnet entry of the SDN running config.
+///
+/// This is a field instead of a `#[serde(tag)]`, since the tag would end up in the map of the
+/// flattened properties that are not interpreted.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default,
+)]
+#[serde(rename_all = "lowercase")]
+enum SubnetType {
+    #[default]
+    Subnet,
+}
+
+/// Struct for (de-)serializing a subnet entry of the SDN running config
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct SubnetRunningConfig {
+    #[serde(rename = "type", default)]
+    ty: SubnetType,
     vnet: VnetName,
+    #[serde(skip_serializing_if = "Option::is_none")]
     gateway: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     snat: Option<u8>,
-    #[serde(rename = "dhcp-range")]
+    #[serde(rename = "dhcp-range", skip_serializing_if = "Option::is_none")]
     dhcp_range: Option<Vec<PropertyString<DhcpRange>>>,
+    #[serde(rename = "dhcp-dns-server", skip_serializing_if = "Option::is_none")]
+    dhcp_dns_server: Option<IpAddr>,
+    #[serde(flatten)]
+    other: BTreeMap<String, PropertyValue>,
 }
 
-/// Struct for deserializing the subnets of the SDN running config
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Default)]
+/// Struct for (de-)serializing the subnets of the SDN running config
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
 pub struct SubnetsRunningConfig {
-    ids: HashMap<SubnetName, SubnetRunningConfig>,
+    ids: BTreeMap<SubnetName, SubnetRunningConfig>,
 }
 
-/// Struct for deserializing a vnet entry of the SDN running config
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// The `type` of a vnet entry of the SDN running config, see [`SubnetType`].
+#[derive(
+    Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default,
+)]
+#[serde(rename_all = "lowercase")]
+enum VnetType {
+    #[default]
+    Vnet,
+}
+
+/// Struct for (de-)serializing a vnet entry of the SDN running config
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct VnetRunningConfig {
+    #[serde(rename = "type", default)]
+    ty: VnetType,
+    #[serde(skip_serializing_if = "Option::is_none")]
     tag: Option<u32>,
     zone: ZoneName,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_bool",
+        serialize_with = "serialize_perl_bool",
+        skip_serializing_if = "Option::is_none"
+    )]
+    vlanaware: Option<bool>,
+    #[serde(
+        rename = "isolate-ports",
+        default,
+        deserialize_with = "proxmox_serde::perl::deserialize_bool",
+        serialize_with = "serialize_perl_bool",
+        skip_serializing_if = "Option::is_none"
+    )]
+    isolate_ports: Option<bool>,
+    #[serde(flatten)]
+    other: BTreeMap<String, PropertyValue>,
 }
 
-/// struct for deserializing the vnets of the SDN running config
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Default)]
+/// struct for (de-)serializing the vnets of the SDN running config
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
 pub struct VnetsRunningConfig {
-    ids: HashMap<VnetName, VnetRunningConfig>,
+    ids: BTreeMap<VnetName, VnetRunningConfig>,
 }
 
-/// Struct for deserializing the SDN running config
+/// A property value of the SDN running config that is not interpreted.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Integer(i64),
+    String(String),
+}
+
+/// Struct for (de-)serializing a controller entry of the SDN running config
+///
+/// The properties of controllers are not interpreted, they are only kept so the running config
+/// can be written back.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ControllerRunningConfig {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(flatten)]
+    properties: BTreeMap<String, PropertyValue>,
+}
+
+/// Struct for (de-)serializing the controllers of the SDN running config
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+pub struct ControllersRunningConfig {
+    ids: BTreeMap<String, ControllerRunningConfig>,
+}
+
+/// Struct for (de-)serializing the SDN running config
 ///
 /// usually taken from the content of /etc/pve/sdn/.running-config
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
 pub struct RunningConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     zones: Option<ZonesRunningConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     subnets: Option<SubnetsRunningConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     vnets: Option<VnetsRunningConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    controllers: Option<ControllersRunningConfig>,
 }
 
 /// A struct containing the configuration for an SDN subnet
@@ -223,6 +876,8 @@ pub struct SubnetConfig {
     gateway: Option<IpAddr>,
     snat: bool,
     dhcp_range: Vec<IpRange>,
+    dhcp_dns_server: Option<IpAddr>,
+    other: BTreeMap<String, PropertyValue>,
 }
 
 impl SubnetConfig {
@@ -247,9 +902,21 @@ impl SubnetConfig {
             gateway,
             snat,
             dhcp_range: dhcp_range.into_iter().collect(),
+            dhcp_dns_server: None,
+            other: BTreeMap::new(),
         })
     }
 
+    /// Sets the DNS server that is announced to DHCP clients in the subnet.
+    pub fn with_dhcp_dns_server(mut self, dns_server: IpAddr) -> Result<Self, SdnConfigError> {
+        if dns_server.is_ipv4() != self.name.cidr().is_ipv4() {
+            return Err(SdnConfigError::MismatchedCidrDnsServer);
+        }
+
+        self.dhcp_dns_server = Some(dns_server);
+        Ok(self)
+    }
+
     pub fn try_from_running_config(
         name: SubnetName,
         running_config: SubnetRunningConfig,
@@ -269,7 +936,13 @@ impl SubnetConfig {
             None => Vec::new(),
         };
 
-        Self::new(name, running_config.gateway, snat, dhcp_range)
+        let mut config = Self::new(name, running_config.gateway, snat, dhcp_range)?;
+        config.other = running_config.other;
+
+        match running_config.dhcp_dns_server {
+            Some(dns_server) => config.with_dhcp_dns_server(dns_server),
+            None => Ok(config),
+        }
     }
 
     pub fn name(&self) -> &SubnetName {
@@ -291,13 +964,26 @@ impl SubnetConfig {
     pub fn dhcp_ranges(&self) -> impl Iterator<Item = &IpRange> + '_ {
         self.dhcp_range.iter()
     }
+
+    pub fn dhcp_dns_server(&self) -> Option<&IpAddr> {
+        self.dhcp_dns_server.as_ref()
+    }
+
+    /// The properties of the subnet that are not interpreted, they are written back unchanged.
+    pub fn other(&self) -> &BTreeMap<String, PropertyValue> {
+        &self.other
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct VnetConfig {
     name: VnetName,
     tag: Option<u32>,
+    alias: Option<String>,
+    vlan_aware: bool,
+    isolate_ports: bool,
     subnets: BTreeMap<Cidr, SubnetConfig>,
+    other: BTreeMap<String, PropertyValue>,
 }
 
 impl VnetConfig {
@@ -306,9 +992,22 @@ impl VnetConfig {
             name,
             subnets: BTreeMap::default(),
             tag,
+            alias: None,
+            vlan_aware: false,
+            isolate_ports: false,
+            other: BTreeMap::new(),
         }
     }
 
+    fn from_running_config(name: VnetName, running_config: VnetRunningConfig) -> Self {
+        let mut config = Self::new(name, running_config.tag);
+        config.alias = running_config.alias;
+        config.vlan_aware = running_config.vlanaware.unwrap_or(false);
+        config.isolate_ports = running_config.isolate_ports.unwrap_or(false);
+        config.other = running_config.other;
+        config
+    }
+
     pub fn from_subnets(
         name: VnetName,
         subnets: impl IntoIterator<Item = SubnetConfig>,
@@ -360,32 +1059,66 @@ impl VnetConfig {
     pub fn tag(&self) -> &Option<u32> {
         &self.tag
     }
+
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    /// Whether guests can use VLANs inside the VNet.
+    pub fn vlan_aware(&self) -> bool {
+        self.vlan_aware
+    }
+
+    /// Whether guests in the VNet can only communicate with the outside, but not each other.
+    pub fn isolate_ports(&self) -> bool {
+        self.isolate_ports
+    }
+
+    /// The properties of the VNet that are not interpreted, they are written back unchanged.
+    pub fn other(&self) -> &BTreeMap<String, PropertyValue> {
+        &self.other
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct ZoneConfig {
     name: ZoneName,
-    ty: ZoneType,
+    properties: ZoneProperties,
+    options: ZoneOptions,
     vnets: BTreeMap<VnetName, VnetConfig>,
 }
 
 impl ZoneConfig {
-    pub fn new(name: ZoneName, ty: ZoneType) -> Self {
+    /// Creates a zone from its properties, which are validated for the zone type.
+    pub fn with_properties(
+        name: ZoneName,
+        properties: ZoneProperties,
+        options: ZoneOptions,
+    ) -> Result<Self, SdnConfigError> {
+        properties.validate()?;
+
+        Ok(Self {
+            name,
+            properties,
+            options,
+            vnets: BTreeMap::default(),
+        })
+    }
+
+    /// Creates a zone from the running config without validating its properties, see
+    /// [`SdnConfig::validate`].
+    fn from_running_config(name: ZoneName, running_config: ZoneRunningConfig) -> Self {
         Self {
             name,
-            ty,
+            properties: running_config.properties,
+            options: running_config.options,
             vnets: BTreeMap::default(),
         }
     }
 
-    pub fn from_vnets(
-        name: ZoneName,
-        ty: ZoneType,
-        vnets: impl IntoIterator<Item = VnetConfig>,
-    ) -> Result<Self, SdnConfigError> {
-        let mut config = Self::new(name, ty);
-        config.add_vnets(vnets)?;
-        Ok(config)
+    /// Checks that the properties of the zone are valid for its type.
+    pub fn validate(&self) -> Result<(), SdnConfigError> {
+        self.properties.validate()
     }
 
     pub fn add_vnets(
@@ -419,7 +1152,54 @@ impl ZoneConfig {
     }
 
     pub fn ty(&self) -> ZoneType {
-        self.ty
+        self.properties.ty()
+    }
+
+    pub fn properties(&self) -> &ZoneProperties {
+        &self.properties
+    }
+
+    pub fn options(&self) -> &ZoneOptions {
+        &self.options
+    }
+}
+
+/// The configuration of an SDN controller, like the EVPN controller referenced by EVPN zones.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ControllerConfig {
+    name: String,
+    ty: String,
+    properties: BTreeMap<String, PropertyValue>,
+}
+
+impl ControllerConfig {
+    pub fn new(
+        name: String,
+        ty: String,
+        properties: impl IntoIterator<Item = (String, PropertyValue)>,
+    ) -> Self {
+        Self {
+            name,
+            ty,
+            properties: properties.into_iter().collect(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The type of the controller, e.g. `evpn` or `bgp`.
+    pub fn ty(&self) -> &str {
+        &self.ty
+    }
+
+    pub fn property(&self, key: &str) -> Option<&PropertyValue> {
+        self.properties.get(key)
+    }
+
+    pub fn properties(&self) -> &BTreeMap<String, PropertyValue> {
+        &self.properties
     }
 }
 
@@ -434,7 +1214,9 @@ impl ZoneConfig {
 /// * Subnets can only be added to a zone if their name contains the same zone they are added to
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
 pub struct SdnConfig {
+    version: Option<u64>,
     zones: BTreeMap<ZoneName, ZoneConfig>,
+    controllers: BTreeMap<String, ControllerConfig>,
 }
 
 impl SdnConfig {
@@ -442,6 +1224,39 @@ impl SdnConfig {
         Self::default()
     }
 
+    /// Sets the version of the config, which is increased every time SDN is applied.
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn version(&self) -> Option<u64> {
+        self.version
+    }
+
+    /// Checks the properties of all zones.
+    ///
+    /// Reading a running config does not validate the zones, so a config that was accepted by
+    /// Proxmox VE can always be read. This should be called before changes to the config are
+    /// applied.
+    pub fn validate(&self) -> Result<(), SdnConfigError> {
+        self.zones().try_for_each(ZoneConfig::validate)
+    }
+
+    /// adds a controller to the configuration, returning the old controller config if the
+    /// controller already existed
+    pub fn add_controller(&mut self, controller: ControllerConfig) -> Option<ControllerConfig> {
+        self.controllers.insert(controller.name.clone(), controller)
+    }
+
+    pub fn controller(&self, name: &str) -> Option<&ControllerConfig> {
+        self.controllers.get(name)
+    }
+
+    pub fn controllers(&self) -> impl Iterator<Item = &ControllerConfig> + '_ {
+        self.controllers.values()
+    }
+
     pub fn from_zones(zones: impl IntoIterator<Item = ZoneConfig>) -> Result<Self, SdnConfigError> {
         let mut config = Self::default();
         config.add_zones(zones)?;
@@ -542,6 +1357,12 @@ impl SdnConfig {
         None
     }
 
+    pub fn subnet(&self, name: &SubnetName) -> Option<&SubnetConfig> {
+        self.zone(name.zone())?
+            .vnets()
+            .find_map(|vnet| vnet.subnet(name.cidr()))
+    }
+
     pub fn vnets(&self) -> impl Iterator<Item = (&ZoneConfig, &VnetConfig)> + '_ {
         self.zones()
             .flat_map(|zone| zone.vnets().map(move |vnet| (zone, vnet)))
@@ -622,23 +1443,31 @@ impl TryFrom<RunningConfig> for SdnConfig {
     type Error = SdnConfigError;
 
     fn try_from(mut value: RunningConfig) -> Result<Self, Self::Error> {
-        let mut config = SdnConfig::default();
+        let mut config = SdnConfig {
+            version: value.version,
+            ..Default::default()
+        };
+
+        if let Some(running_controllers) = value.controllers.take() {
+            for (name, running_config) in running_controllers.ids {
+                config.add_controller(ControllerConfig::new(
+                    name,
+                    running_config.ty,
+                    running_config.properties,
+                ));
+            }
+        }
 
         if let Some(running_zones) = value.zones.take() {
-            config.add_zones(
-                running_zones
-                    .ids
-                    .into_iter()
-                    .map(|(name, running_config)| ZoneConfig::new(name, running_config.ty)),
-            )?;
+            for (name, running_config) in running_zones.ids {
+                config.add_zone(ZoneConfig::from_running_config(name, running_config))?;
+            }
         }
 
         if let Some(running_vnets) = value.vnets.take() {
             for (name, running_config) in running_vnets.ids {
-                config.add_vnet(
-                    &running_config.zone,
-                    VnetConfig::new(name, running_config.tag),
-                )?;
+                let zone = running_config.zone.clone();
+                config.add_vnet(&zone, VnetConfig::from_running_config(name, running_config))?;
             }
         }
 
@@ -658,3 +1487,79 @@ impl TryFrom<RunningConfig> for SdnConfig {
         Ok(config)
     }
 }
+
+impl From<&SdnConfig> for RunningConfig {
+    fn from(value: &SdnConfig) -> Self {
+        let mut zones = BTreeMap::new();
+        let mut vnets = BTreeMap::new();
+        let mut subnets = BTreeMap::new();
+
+        for zone in value.zones() {
+            zones.insert(
+                zone.name().clone(),
+                ZoneRunningConfig {
+                    options: zone.options.clone(),
+                    properties: zone.properties.clone(),
+                },
+            );
+
+            for vnet in zone.vnets() {
+                vnets.insert(
+                    vnet.name().clone(),
+                    VnetRunningConfig {
+                        ty: VnetType::Vnet,
+                        tag: vnet.tag,
+                        zone: zone.name().clone(),
+                        alias: vnet.alias.clone(),
+                        vlanaware: vnet.vlan_aware.then_some(true),
+                        isolate_ports: vnet.isolate_ports.then_some(true),
+                        other: vnet.other.clone(),
+                    },
+                );
+
+                for subnet in vnet.subnets() {
+                    let dhcp_range = (!subnet.dhcp_range.is_empty()).then(|| {
+                        subnet
+                            .dhcp_ranges()
+                            .map(|range| PropertyString::new(DhcpRange::from(range)))
+                            .collect()
+                    });
+
+                    subnets.insert(
+                        subnet.name().clone(),
+                        SubnetRunningConfig {
+                            ty: SubnetType::Subnet,
+                            vnet: vnet.name().clone(),
+                            gateway: subnet.gateway,
+                            snat: subnet.snat.then_some(1),
+                            dhcp_range,
+                            dhcp_dns_server: subnet.dhcp_dns_server,
+                            other: subnet.other.clone(),
+                        },
+                    );
+                }
+            }
+        }
+
+        let controllers = value
+            .controllers()
+            .map(|controller| {
+                (
+                    controller.name.clone(),
+                    ControllerRunningConfig {
+                        ty: controller.ty.clone(),
+                        properties: controller.properties.clone(),
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            version: value.version,
+            zones: Some(ZonesRunningConfig { ids: zones }),
+            subnets: Some(SubnetsRunningConfig { ids: subnets }),
+            vnets: Some(VnetsRunningConfig { ids: vnets }),
+            controllers: Some(ControllersRunningConfig { ids: controllers }),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Differences between the pending and the running SDN config.
+//!
+//! The pending config in `/etc/pve/sdn` only takes effect once SDN is applied, which replaces the
+//! running config with it. [`SdnConfigDiff`] lists the zones, VNets and subnets that are added,
+//! removed or changed by applying the pending config.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::sdn::config::{SdnConfig, SubnetConfig, VnetConfig, ZoneConfig};
+use crate::sdn::{SubnetName, VnetName, ZoneName};
+
+/// The change of a single entry of the SDN config.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change<'a, T> {
+    /// The entry only exists in the pending config.
+    Added(&'a T),
+    /// The entry only exists in the running config.
+    Removed(&'a T),
+    /// The entry exists in both configs, but with different properties.
+    Changed { running: &'a T, pending: &'a T },
+}
+
+impl<'a, T> Change<'a, T> {
+    /// The entry in the running config, if it exists there.
+    pub fn running(&self) -> Option<&'a T> {
+        match self {
+            Change::Added(_) => None,
+            Change::Removed(running) | Change::Changed { running, .. } => Some(running),
+        }
+    }
+
+    /// The entry in the pending config, if it exists there.
+    pub fn pending(&self) -> Option<&'a T> {
+        match self {
+            Change::Removed(_) => None,
+            Change::Added(pending) | Change::Changed { pending, .. } => Some(pending),
+        }
+    }
+
+    fn symbol(&self) -> char {
+        match self {
+            Change::Added(_) => '+',
+            Change::Removed(_) => '-',
+            Change::Changed { .. } => '~',
+        }
+    }
+
+    /// The entry of the change, which is the pending entry unless the entry is removed.
+    fn entry(&self) -> &'a T {
+        match self {
+            Change::Added(entry) | Change::Removed(entry) => entry,
+            Change::Changed { pending, .. } => pending,
+        }
+    }
+}
+
+/// Compares the entries of two configs by their key.
+///
+/// Besides the entry itself, every entry has a parent, like the zone of a VNet, so moving an
+/// entry to another parent is a change as well.
+fn diff_entries<'a, K: Ord, P: PartialEq, T>(
+    running: BTreeMap<K, (P, &'a T)>,
+    mut pending: BTreeMap<K, (P, &'a T)>,
+    equal: impl Fn(&T, &T) -> bool,
+) -> Vec<(K, Change<'a, T>)> {
+    let mut changes = Vec::new();
+
+    for (key, (running_parent, running_entry)) in running {
+        let change = match pending.remove(&key) {
+            None => Change::Removed(running_entry),
+            Some((pending_parent, pending_entry)) => {
+                if running_parent == pending_parent && equal(running_entry, pending_entry) {
+                    continue;
+                }
+
+                Change::Changed {
+                    running: running_entry,
+                    pending: pending_entry,
+                }
+            }
+        };
+
+        changes.push((key, change));
+    }
+
+    changes.extend(
+        pending
+            .into_iter()
+            .map(|(key, (_, entry))| (key, Change::Added(entry))),
+    );
+
+    changes.sort_by(|(key, _), (other, _)| key.cmp(other));
+    changes
+}
+
+fn zones(config: &SdnConfig) -> BTreeMap<&ZoneName, ((), &ZoneConfig)> {
+    config
+        .zones()
+        .map(|zone| (zone.name(), ((), zone)))
+        .collect()
+}
+
+fn vnets(config: &SdnConfig) -> BTreeMap<&VnetName, (&ZoneName, &VnetConfig)> {
+    config
+        .vnets()
+        .map(|(zone, vnet)| (vnet.name(), (zone.name(), vnet)))
+        .collect()
+}
+
+fn subnets(config: &SdnConfig) -> BTreeMap<&SubnetName, (&VnetName, &SubnetConfig)> {
+    config
+        .vnets()
+        .flat_map(|(_, vnet)| {
+            vnet.subnets()
+                .map(move |subnet| (subnet.name(), (vnet.name(), subnet)))
+        })
+        .collect()
+}
+
+/// The changes between the running and the pending SDN config, ordered by the name of the
+/// changed entries.
+///
+/// Zones and VNets are compared without their VNets and subnets respectively, which are listed
+/// separately. Adding a subnet to a VNet therefore only shows up as an added subnet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SdnConfigDiff<'a> {
+    zones: Vec<(&'a ZoneName, Change<'a, ZoneConfig>)>,
+    vnets: Vec<(&'a VnetName, Change<'a, VnetConfig>)>,
+    subnets: Vec<(&'a SubnetName, Change<'a, SubnetConfig>)>,
+}
+
+impl<'a> SdnConfigDiff<'a> {
+    pub fn new(running: &'a SdnConfig, pending: &'a SdnConfig) -> Self {
+        Self {
+            zones: diff_entries(zones(running), zones(pending), |running, pending| {
+                running.properties() == pending.properties()
+                    && running.options() == pending.options()
+            }),
+            vnets: diff_entries(vnets(running), vnets(pending), |running, pending| {
+                running.tag() == pending.tag()
+                    && running.alias() == pending.alias()
+                    && running.vlan_aware() == pending.vlan_aware()
+                    && running.isolate_ports() == pending.isolate_ports()
+            }),
+            subnets: diff_entries(subnets(running), subnets(pending), SubnetConfig::eq),
+        }
+    }
+
+    pub fn zones(&self) -> &[(&'a ZoneName, Change<'a, ZoneConfig>)] {
+        &self.zones
+    }
+
+    pub fn vnets(&self) -> &[(&'a VnetName, Change<'a, VnetConfig>)] {
+        &self.vnets
+    }
+
+    pub fn subnets(&self) -> &[(&'a SubnetName, Change<'a, SubnetConfig>)] {
+        &self.subnets
+    }
+
+    /// Whether applying the pending config would change nothing.
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty() && self.vnets.is_empty() && self.subnets.is_empty()
+    }
+}
+
+/// Lists one change per line, prefixed with `+` for added, `-` for removed and `~` for changed
+/// entries.
+impl fmt::Display for SdnConfigDiff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, change) in &self.zones {
+            writeln!(
+                f,
+                "{} zone {name} ({})",
+                change.symbol(),
+                change.entry().ty()
+            )?;
+        }
+
+        for (name, change) in &self.vnets {
+            writeln!(f, "{} vnet {name}", change.symbol())?;
+        }
+
+        for (name, change) in &self.subnets {
+            writeln!(f, "{} subnet {name}", change.symbol())?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+//! Generation of the dnsmasq configuration for zones that use dnsmasq as DHCP server.
+//!
+//! Every zone runs its own dnsmasq instance, which is configured by the files in
+//! `/etc/dnsmasq.d/<zone>`:
+//!
+//! * `00-default.conf` contains the options of the instance, like the lease file.
+//! * `10-<vnet>.conf` contains the DHCP ranges and options of the subnets of a VNet.
+//! * `ethers` contains the static reservations of the guests, taken from the IPAM.
+//!
+//! Only subnets with DHCP ranges are served. Guests get the address that is reserved for their
+//! MAC address in the IPAM, which does not have to be inside of a DHCP range.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::net::{IpAddr, Ipv4Addr};
+
+use proxmox_network_types::ip_address::{Cidr, IpRange};
+
+use crate::sdn::config::{DhcpType, SdnConfig, SubnetConfig, VnetConfig, ZoneConfig};
+use crate::sdn::ipam::{Ipam, IpamData};
+use crate::sdn::ZoneName;
+
+/// The directory that contains the configuration directories of all zones.
+pub const DNSMASQ_CONFIG_DIR: &str = "/etc/dnsmasq.d";
+
+const DNSMASQ_LEASE_DIR: &str = "/var/lib/misc";
+
+const DEFAULT_CONFIG_FILE: &str = "00-default.conf";
+const ETHERS_FILE: &str = "ethers";
+
+const LEASE_TIME: &str = "24h";
+
+/// The lifetime of the router advertisements for subnets with a gateway, in seconds.
+const ROUTER_LIFETIME: u32 = 1800;
+
+/// The interval between unsolicited router advertisements, in seconds.
+const RA_INTERVAL: u32 = 60;
+
+/// The dnsmasq configuration files of a zone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DnsmasqConfig {
+    zone: ZoneName,
+    files: BTreeMap<String, String>,
+}
+
+impl DnsmasqConfig {
+    /// Generates the configuration of a zone, if the zone uses dnsmasq as DHCP server.
+    pub fn from_zone(zone: &ZoneConfig, ipam: &Ipam) -> Option<Self> {
+        if zone.options().dhcp() != Some(DhcpType::Dnsmasq) {
+            return None;
+        }
+
+        let mut files = BTreeMap::new();
+
+        let has_ipv6 = zone
+            .vnets()
+            .flat_map(VnetConfig::subnets)
+            .any(|subnet| has_dhcp(subnet) && subnet.cidr().is_ipv6());
+
+        files.insert(
+            DEFAULT_CONFIG_FILE.to_string(),
+            render(|f| default_config(f, zone.name(), has_ipv6)),
+        );
+
+        for vnet in zone.vnets() {
+            if vnet.subnets().any(has_dhcp) {
+                files.insert(
+                    format!("10-{}.conf", vnet.name()),
+                    render(|f| vnet_config(f, zone, vnet)),
+                );
+            }
+        }
+
+        files.insert(ETHERS_FILE.to_string(), render(|f| ethers(f, zone, ipam)));
+
+        Some(Self {
+            zone: zone.name().clone(),
+            files,
+        })
+    }
+
+    pub fn zone(&self) -> &ZoneName {
+        &self.zone
+    }
+
+    /// The directory the files of the configuration are written to.
+    pub fn directory(&self) -> String {
+        format!("{DNSMASQ_CONFIG_DIR}/{}", self.zone)
+    }
+
+    /// The names of the files, relative to [`DnsmasqConfig::directory`], and their content.
+    pub fn files(&self) -> &BTreeMap<String, String> {
+        &self.files
+    }
+
+    pub fn file(&self, name: &str) -> Option<&str> {
+        self.files.get(name).map(String::as_str)
+    }
+}
+
+/// Generates the dnsmasq configuration of all zones that use dnsmasq as DHCP server.
+pub fn generate(sdn: &SdnConfig, ipam: &Ipam) -> Vec<DnsmasqConfig> {
+    sdn.zones()
+        .filter_map(|zone| DnsmasqConfig::from_zone(zone, ipam))
+        .collect()
+}
+
+fn render(write: impl FnOnce(&mut String) -> fmt::Result) -> String {
+    let mut output = String::new();
+    write(&mut output).expect("writing to a String cannot fail");
+    output
+}
+
+fn has_dhcp(subnet: &SubnetConfig) -> bool {
+    subnet.dhcp_ranges().next().is_some()
+}
+
+fn default_config(f: &mut impl Write, zone: &ZoneName, enable_ra: bool) -> fmt::Result {
+    writeln!(f, "except-interface=lo")?;
+    writeln!(f, "bind-dynamic")?;
+    writeln!(f, "no-resolv")?;
+    writeln!(f, "no-hosts")?;
+    writeln!(
+        f,
+        "dhcp-leasefile={DNSMASQ_LEASE_DIR}/dnsmasq.{zone}.leases"
+    )?;
+    writeln!(
+        f,
+        "dhcp-hostsfile={DNSMASQ_CONFIG_DIR}/{zone}/{ETHERS_FILE}"
+    )?;
+
+    if enable_ra {
+        writeln!(f, "enable-ra")?;
+        writeln!(f, "quiet-ra")?;
+    }
+
+    Ok(())
+}
+
+/// Formats the netmask of an IPv4 subnet, or the prefix length of an IPv6 subnet.
+fn netmask(cidr: &Cidr) -> String {
+    match cidr {
+        Cidr::Ipv4(cidr) => {
+            let bits = u32::MAX
+                .checked_shl(32 - u32::from(cidr.mask()))
+                .unwrap_or(0);
+
+            Ipv4Addr::from(bits).to_string()
+        }
+        Cidr::Ipv6(cidr) => cidr.mask().to_string(),
+    }
+}
+
+fn range_bounds(range: &IpRange) -> (IpAddr, IpAddr) {
+    match range {
+        IpRange::V4(range) => ((*range.start()).into(), (*range.last()).into()),
+        IpRange::V6(range) => ((*range.start()).into(), (*range.last()).into()),
+    }
+}
+
+fn subnet_config(f: &mut impl Write, zone: &ZoneConfig, subnet: &SubnetConfig) -> fmt::Result {
+    let tag = subnet.name();
+    let netmask = netmask(subnet.cidr());
+
+    for range in subnet.dhcp_ranges() {
+        let (start, end) = range_bounds(range);
+        writeln!(
+            f,
+            "dhcp-range=set:{tag},{start},{end},{netmask},{LEASE_TIME}"
+        )?;
+    }
+
+    if subnet.cidr().is_ipv4() {
+        if let Some(gateway) = subnet.gateway() {
+            writeln!(f, "dhcp-option=tag:{tag},option:router,{gateway}")?;
+        }
+
+        if let Some(dns_server) = subnet.dhcp_dns_server() {
+            writeln!(f, "dhcp-option=tag:{tag},option:dns-server,{dns_server}")?;
+        }
+
+        if let Some(dnszone) = zone.options().dnszone() {
+            writeln!(f, "dhcp-option=tag:{tag},option:domain-name,{dnszone}")?;
+        }
+
+        if let Some(mtu) = zone.options().mtu() {
+            writeln!(f, "dhcp-option=tag:{tag},option:mtu,{mtu}")?;
+        }
+    } else {
+        if let Some(dns_server) = subnet.dhcp_dns_server() {
+            writeln!(f, "dhcp-option=tag:{tag},option6:dns-server,[{dns_server}]")?;
+        }
+
+        if let Some(dnszone) = zone.options().dnszone() {
+            writeln!(f, "dhcp-option=tag:{tag},option6:domain-search,{dnszone}")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn vnet_config(f: &mut impl Write, zone: &ZoneConfig, vnet: &VnetConfig) -> fmt::Result {
+    let subnets: Vec<&SubnetConfig> = vnet.subnets().filter(|subnet| has_dhcp(subnet)).collect();
+
+    writeln!(f, "interface={}", vnet.name())?;
+
+    for subnet in &subnets {
+        subnet_config(f, zone, subnet)?;
+    }
+
+    let ipv6_subnets: Vec<&&SubnetConfig> = subnets
+        .iter()
+        .filter(|subnet| subnet.cidr().is_ipv6())
+        .collect();
+
+    if !ipv6_subnets.is_empty() {
+        // only announce the VNet as default router if one of the subnets has a gateway
+        let router_lifetime = if ipv6_subnets.iter().any(|subnet| subnet.gateway().is_some()) {
+            ROUTER_LIFETIME
+        } else {
+            0
+        };
+
+        let mtu = zone
+            .options()
+            .mtu()
+            .map(|mtu| format!("mtu:{mtu},"))
+            .unwrap_or_default();
+
+        writeln!(
+            f,
+            "ra-param={},{mtu}high,{RA_INTERVAL},{router_lifetime}",
+            vnet.name()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Generates the static reservations of all guests in subnets of the zone that are served by
+/// dnsmasq, ordered by their address.
+fn ethers(f: &mut impl Write, zone: &ZoneConfig, ipam: &Ipam) -> fmt::Result {
+    let mut hosts = BTreeMap::new();
+
+    for subnet in zone.vnets().flat_map(VnetConfig::subnets) {
+        if !has_dhcp(subnet) {
+            continue;
+        }
+
+        for entry in ipam.subnet_entries(subnet.name()) {
+            let IpamData::Vm(data) = entry.data() else {
+                continue;
+            };
+
+            let address = match data.ip() {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{ip}]"),
+            };
+
+            let host = match data.hostname() {
+                Some(hostname) => format!("{},{address},{hostname}", data.mac()),
+                None => format!("{},{address}", data.mac()),
+            };
+
+            hosts.insert(*data.ip(), host);
+        }
+    }
+
+    for host in hosts.values() {
+        writeln!(f, "{host}")?;
+    }
+
+    Ok(())
+}
@@ -1,26 +1,29 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     error::Error,
     fmt::Display,
-    net::IpAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
 };
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use proxmox_network_types::ip_address::Cidr;
+use proxmox_network_types::ip_address::{Cidr, Family};
 use proxmox_network_types::mac_address::MacAddress;
 
 use crate::{
     common::Allowlist,
-    firewall::types::{ipset::IpsetScope, Ipset},
+    firewall::{
+        coverage::ip_interval,
+        types::{address::IpEntry, ipset::IpsetScope, Ipset},
+    },
     guest::types::Vmid,
-    sdn::{SdnNameError, SubnetName, ZoneName},
+    sdn::{config::SubnetConfig, SdnNameError, SubnetName, ZoneName},
 };
 
 /// Struct for deserializing a gateway entry in PVE IPAM.
 ///
 /// They are automatically generated by the PVE SDN module when creating a new subnet.
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct IpamJsonDataGateway {
     #[serde(rename = "gateway")]
     _gateway: u8,
@@ -30,9 +33,10 @@ pub struct IpamJsonDataGateway {
 ///
 /// They are automatically created when adding a guest to a VNet that has a Subnet with DHCP
 /// configured.
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct IpamJsonDataVm {
     vmid: Vmid,
+    #[serde(skip_serializing_if = "Option::is_none")]
     hostname: Option<String>,
     mac: MacAddress,
 }
@@ -40,7 +44,7 @@ pub struct IpamJsonDataVm {
 /// Struct for deserializing a custom entry in PVE IPAM.
 ///
 /// Custom entries are created manually by the user via the Web UI / API.
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct IpamJsonDataCustom {
     mac: MacAddress,
 }
@@ -49,7 +53,7 @@ pub struct IpamJsonDataCustom {
 ///
 /// For more information about the members see the documentation of the respective structs in the
 /// enum.
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(untagged)]
 pub enum IpamJsonData {
     Vm(IpamJsonDataVm),
@@ -58,25 +62,42 @@ pub enum IpamJsonData {
 }
 
 /// Struct for deserializing IPs from the PVE IPAM.
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
 pub struct IpJson {
     ips: BTreeMap<IpAddr, IpamJsonData>,
 }
 
 /// Struct for deserializing subnets from the PVE IPAM.
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
 pub struct SubnetJson {
     subnets: BTreeMap<Cidr, IpJson>,
 }
 
-/// Struct for deserializing the PVE IPAM.
+/// Struct for (de-)serializing the PVE IPAM.
 ///
 /// It is usually located in `/etc/pve/priv/ipam.db`
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
 pub struct IpamJson {
     zones: BTreeMap<ZoneName, SubnetJson>,
 }
 
+impl IpamJson {
+    /// Returns the subnet and data of all entries, without checking if the addresses are inside
+    /// their subnet.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (SubnetName, IpamData)> + '_ {
+        self.zones.iter().flat_map(|(zone_name, subnet_json)| {
+            subnet_json.subnets.iter().flat_map(move |(cidr, ip_json)| {
+                ip_json.ips.iter().map(move |(ip, json_data)| {
+                    (
+                        SubnetName::new(zone_name.clone(), *cidr),
+                        IpamData::from_json_data(*ip, json_data.clone()),
+                    )
+                })
+            })
+        })
+    }
+}
+
 /// Holds the data for the IPAM entry of a VM.
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct IpamDataVm {
@@ -192,6 +213,27 @@ impl IpamData {
             IpamData::Custom(data) => data.ip(),
         }
     }
+
+    /// Returns the MAC address of the entry, gateway entries have none.
+    pub fn mac(&self) -> Option<&MacAddress> {
+        match &self {
+            IpamData::Vm(data) => Some(data.mac()),
+            IpamData::Gateway(_) => None,
+            IpamData::Custom(data) => Some(data.mac()),
+        }
+    }
+
+    fn to_json_data(&self) -> IpamJsonData {
+        match self {
+            IpamData::Vm(data) => IpamJsonData::Vm(IpamJsonDataVm {
+                vmid: data.vmid,
+                hostname: data.hostname.clone(),
+                mac: data.mac,
+            }),
+            IpamData::Gateway(_) => IpamJsonData::Gateway(IpamJsonDataGateway { _gateway: 1 }),
+            IpamData::Custom(data) => IpamJsonData::Custom(IpamJsonDataCustom { mac: data.mac }),
+        }
+    }
 }
 
 impl From<IpamDataVm> for IpamData {
@@ -218,13 +260,27 @@ pub enum IpamError {
     InvalidIpAddress,
     DuplicateIpAddress,
     IpAddressOutOfBounds,
+    ReservedIpAddress,
+    NoFreeIpAddress,
 }
 
 impl Error for IpamError {}
 
 impl Display for IpamError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("")
+        match self {
+            IpamError::NameError(err) => write!(f, "invalid name: {err}"),
+            IpamError::InvalidIpAddress => write!(f, "invalid ip address"),
+            IpamError::DuplicateIpAddress => write!(f, "ip address is already allocated"),
+            IpamError::IpAddressOutOfBounds => write!(f, "ip address is not inside the subnet"),
+            IpamError::ReservedIpAddress => {
+                write!(
+                    f,
+                    "ip address is reserved for the network, broadcast or gateway"
+                )
+            }
+            IpamError::NoFreeIpAddress => write!(f, "no free ip address left in the subnet"),
+        }
     }
 }
 
@@ -311,6 +367,209 @@ impl Ipam {
 
         Ok(())
     }
+
+    pub fn entries(&self) -> impl Iterator<Item = &IpamEntry> + '_ {
+        self.entries.values().flatten()
+    }
+
+    pub fn subnet_entries(&self, subnet: &SubnetName) -> &[IpamEntry] {
+        self.entries
+            .get(subnet)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+/// The addresses of a subnet that [`Ipam::next_free_ip`] allocates from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AllocationPool {
+    /// Any address of the subnet.
+    Subnet,
+    /// Only the addresses inside the DHCP ranges of the subnet.
+    DhcpRanges,
+}
+
+/// Converts an address to a number, so the addresses of a subnet can be iterated.
+fn ip_to_number(ip: &IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(*ip).into(),
+        IpAddr::V6(ip) => u128::from(*ip),
+    }
+}
+
+fn number_to_ip(number: u128, family: Family) -> IpAddr {
+    match family {
+        Family::V4 => Ipv4Addr::from(number as u32).into(),
+        Family::V6 => Ipv6Addr::from(number).into(),
+    }
+}
+
+/// Returns whether an address of a subnet cannot be assigned to guests.
+///
+/// These are the network address, the broadcast address of IPv4 subnets and the gateway of the
+/// subnet. Point-to-point subnets (`/31` and `/127`) and single addresses have no network or
+/// broadcast address.
+fn is_subnet_address(subnet: &SubnetConfig, ip: &IpAddr) -> bool {
+    if subnet.gateway() == Some(ip) {
+        return true;
+    }
+
+    let (family, first, last) = ip_interval(&IpEntry::Cidr(*subnet.cidr()));
+    let number = ip_to_number(ip);
+
+    if last - first < 2 {
+        return false;
+    }
+
+    number == first || (family == Family::V4 && number == last)
+}
+
+impl Ipam {
+    fn is_allocated(&self, subnet: &SubnetName, ip: &IpAddr) -> bool {
+        self.subnet_entries(subnet)
+            .iter()
+            .any(|entry| entry.ip_address() == ip)
+    }
+
+    /// Returns the first address of a subnet that can be assigned to a guest.
+    ///
+    /// Addresses that are already allocated, as well as the network, broadcast and gateway
+    /// addresses of the subnet are skipped. With [`AllocationPool::DhcpRanges`] the DHCP ranges
+    /// are searched in the order they are configured, and only the parts of them that are inside
+    /// of the subnet are considered.
+    pub fn next_free_ip(&self, subnet: &SubnetConfig, pool: AllocationPool) -> Option<IpAddr> {
+        let subnet_interval = ip_interval(&IpEntry::Cidr(*subnet.cidr()));
+
+        let intervals: Vec<(Family, u128, u128)> = match pool {
+            AllocationPool::Subnet => vec![subnet_interval],
+            AllocationPool::DhcpRanges => {
+                let (subnet_family, subnet_first, subnet_last) = subnet_interval;
+
+                subnet
+                    .dhcp_ranges()
+                    .map(|range| ip_interval(&IpEntry::Range(*range)))
+                    .filter(|(family, _, _)| *family == subnet_family)
+                    .map(|(family, first, last)| {
+                        (family, first.max(subnet_first), last.min(subnet_last))
+                    })
+                    .filter(|(_, first, last)| first <= last)
+                    .collect()
+            }
+        };
+
+        let allocated: BTreeSet<u128> = self
+            .subnet_entries(subnet.name())
+            .iter()
+            .map(|entry| ip_to_number(entry.ip_address()))
+            .collect();
+
+        for (family, first, last) in intervals {
+            // all intervals are inside of the subnet, so every skipped address is either allocated
+            // or one of the few subnet addresses, which terminates quickly even for large IPv6
+            // subnets
+            let mut number = first;
+
+            loop {
+                let ip = number_to_ip(number, family);
+
+                if !allocated.contains(&number) && !is_subnet_address(subnet, &ip) {
+                    return Some(ip);
+                }
+
+                if number >= last {
+                    break;
+                }
+
+                number += 1;
+            }
+        }
+
+        None
+    }
+
+    /// Allocates the next free address of a subnet for a guest, see [`Ipam::next_free_ip`].
+    ///
+    /// If the MAC address already has an address in the subnet, that address is returned instead
+    /// of allocating another one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there is no free address left.
+    pub fn allocate(
+        &mut self,
+        subnet: &SubnetConfig,
+        pool: AllocationPool,
+        vmid: Vmid,
+        mac: MacAddress,
+        hostname: Option<String>,
+    ) -> Result<IpAddr, IpamError> {
+        if let Some(entry) = self
+            .subnet_entries(subnet.name())
+            .iter()
+            .find(|entry| entry.data().mac() == Some(&mac))
+        {
+            return Ok(*entry.ip_address());
+        }
+
+        let ip = self
+            .next_free_ip(subnet, pool)
+            .ok_or(IpamError::NoFreeIpAddress)?;
+
+        let data = IpamDataVm::new(ip, vmid, mac, hostname);
+        self.add_entry(IpamEntry::new(subnet.name().clone(), data.into())?)?;
+
+        Ok(ip)
+    }
+
+    /// Reserves a specific address of a subnet.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the address is outside of the subnet, already
+    /// allocated, or the network or broadcast address of the subnet. The gateway address can only
+    /// be reserved by a gateway entry.
+    pub fn reserve(&mut self, subnet: &SubnetConfig, data: IpamData) -> Result<(), IpamError> {
+        let ip = *data.ip_address();
+
+        let is_gateway_entry =
+            matches!(data, IpamData::Gateway(_)) && subnet.gateway() == Some(&ip);
+
+        if !is_gateway_entry && is_subnet_address(subnet, &ip) {
+            return Err(IpamError::ReservedIpAddress);
+        }
+
+        if self.is_allocated(subnet.name(), &ip) {
+            return Err(IpamError::DuplicateIpAddress);
+        }
+
+        self.add_entry(IpamEntry::new(subnet.name().clone(), data)?)
+    }
+
+    fn release(&mut self, mut predicate: impl FnMut(&IpamEntry) -> bool) -> Vec<IpamEntry> {
+        let mut released = Vec::new();
+
+        // subnets without entries are kept, so they are still written back
+        for entries in self.entries.values_mut() {
+            let (removed, kept): (Vec<_>, Vec<_>) = std::mem::take(entries)
+                .into_iter()
+                .partition(|entry| predicate(entry));
+
+            released.extend(removed);
+            *entries = kept;
+        }
+
+        released
+    }
+
+    /// Removes all entries of a guest, returning the removed entries.
+    pub fn release_vmid(&mut self, vmid: Vmid) -> Vec<IpamEntry> {
+        self.release(|entry| matches!(entry.data(), IpamData::Vm(data) if data.vmid() == vmid))
+    }
+
+    /// Removes all guest and custom entries with a MAC address, returning the removed entries.
+    pub fn release_mac(&mut self, mac: &MacAddress) -> Vec<IpamEntry> {
+        self.release(|entry| entry.data().mac() == Some(mac))
+    }
 }
 
 impl Ipam {
@@ -322,9 +581,7 @@ impl Ipam {
     /// It contains all IPs in all VNets, that a guest has stored in IPAM.
     /// Ipset name is of the form `guest-ipam-<vmid>`
     pub fn ipsets(&self, filter: Option<&Allowlist<Vmid>>) -> impl Iterator<Item = Ipset> + '_ {
-        self.entries
-            .iter()
-            .flat_map(|(_, entries)| entries.iter())
+        self.entries()
             .filter_map(|entry| {
                 if let IpamData::Vm(data) = &entry.data() {
                     if filter.is_none_or(|list| list.is_allowed(&data.vmid)) {
@@ -355,6 +612,12 @@ impl TryFrom<IpamJson> for Ipam {
 
         for (zone_name, subnet_json) in value.zones {
             for (cidr, ip_json) in subnet_json.subnets {
+                // subnets without entries have to be kept as well, the IPAM plugin of Proxmox VE
+                // only adds entries to subnets that exist in the database
+                ipam.entries
+                    .entry(SubnetName::new(zone_name.clone(), cidr))
+                    .or_default();
+
                 for (ip, json_data) in ip_json.ips {
                     let data = IpamData::from_json_data(ip, json_data);
                     let subnet = SubnetName::new(zone_name.clone(), cidr);
@@ -366,3 +629,31 @@ impl TryFrom<IpamJson> for Ipam {
         Ok(ipam)
     }
 }
+
+impl From<&Ipam> for IpamJson {
+    fn from(ipam: &Ipam) -> Self {
+        let mut json = IpamJson::default();
+
+        for subnet in ipam.entries.keys() {
+            json.zones
+                .entry(subnet.zone().clone())
+                .or_default()
+                .subnets
+                .entry(*subnet.cidr())
+                .or_default();
+        }
+
+        for entry in ipam.entries() {
+            json.zones
+                .entry(entry.subnet().zone().clone())
+                .or_default()
+                .subnets
+                .entry(*entry.subnet().cidr())
+                .or_default()
+                .ips
+                .insert(*entry.ip_address(), entry.data().to_json_data());
+        }
+
+        json
+    }
+}
@@ -0,0 +1,206 @@
+//! Consistency checks of the IPAM against the SDN config and the guest configs.
+//!
+//! Entries of the IPAM are not always removed when the SDN config or a guest changes, for
+//! example after deleting a subnet or a guest. [`check`] reports such orphaned entries, as well
+//! as entries that contradict the SDN config.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::IpAddr;
+
+use proxmox_network_types::mac_address::MacAddress;
+
+use crate::guest::types::Vmid;
+use crate::guest::vm::NetworkConfig;
+use crate::sdn::config::SdnConfig;
+use crate::sdn::ipam::{Ipam, IpamData, IpamEntry, IpamJson};
+use crate::sdn::SubnetName;
+
+/// An inconsistency found by [`check`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IpamIssue {
+    /// The subnet of the entry does not exist in the SDN config.
+    UnknownSubnet { subnet: SubnetName, ip: IpAddr },
+    /// The address of the entry is not inside the CIDR of its subnet.
+    OutOfSubnet { subnet: SubnetName, ip: IpAddr },
+    /// The guest of the entry has no network device with the MAC address of the entry, or does
+    /// not exist at all.
+    StaleGuestEntry {
+        subnet: SubnetName,
+        ip: IpAddr,
+        vmid: Vmid,
+        mac: MacAddress,
+    },
+    /// The address of a gateway entry is not the gateway of its subnet.
+    GatewayMismatch {
+        subnet: SubnetName,
+        ip: IpAddr,
+        gateway: Option<IpAddr>,
+    },
+    /// The MAC address is used by network devices of multiple guests.
+    DuplicateMac { mac: MacAddress, vmids: Vec<Vmid> },
+}
+
+impl fmt::Display for IpamIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpamIssue::UnknownSubnet { subnet, ip } => {
+                write!(f, "{ip} in {subnet}: subnet does not exist")
+            }
+            IpamIssue::OutOfSubnet { subnet, ip } => {
+                write!(f, "{ip} in {subnet}: address is outside of the subnet")
+            }
+            IpamIssue::StaleGuestEntry {
+                subnet,
+                ip,
+                vmid,
+                mac,
+            } => write!(
+                f,
+                "{ip} in {subnet}: guest {vmid} has no network device with MAC address {mac}"
+            ),
+            IpamIssue::GatewayMismatch {
+                subnet,
+                ip,
+                gateway: Some(gateway),
+            } => write!(f, "{ip} in {subnet}: gateway of the subnet is {gateway}"),
+            IpamIssue::GatewayMismatch {
+                subnet,
+                ip,
+                gateway: None,
+            } => write!(f, "{ip} in {subnet}: subnet has no gateway"),
+            IpamIssue::DuplicateMac { mac, vmids } => {
+                write!(f, "MAC address {mac} is used by guests ")?;
+
+                for (index, vmid) in vmids.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(", ")?;
+                    }
+
+                    write!(f, "{vmid}")?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+fn check_entry(
+    entry: &IpamEntry,
+    sdn: &SdnConfig,
+    guest_macs: &BTreeMap<Vmid, BTreeSet<MacAddress>>,
+) -> Option<IpamIssue> {
+    let subnet_name = entry.subnet().clone();
+    let ip = *entry.ip_address();
+
+    let Some(subnet) = sdn.subnet(&subnet_name) else {
+        return Some(IpamIssue::UnknownSubnet {
+            subnet: subnet_name,
+            ip,
+        });
+    };
+
+    match entry.data() {
+        IpamData::Vm(data) => {
+            let has_mac = guest_macs
+                .get(&data.vmid())
+                .is_some_and(|macs| macs.contains(data.mac()));
+
+            (!has_mac).then(|| IpamIssue::StaleGuestEntry {
+                subnet: subnet_name,
+                ip,
+                vmid: data.vmid(),
+                mac: *data.mac(),
+            })
+        }
+        IpamData::Gateway(_) => {
+            (subnet.gateway() != Some(&ip)).then(|| IpamIssue::GatewayMismatch {
+                subnet: subnet_name,
+                ip,
+                gateway: subnet.gateway().copied(),
+            })
+        }
+        IpamData::Custom(_) => None,
+    }
+}
+
+fn check_entries<'a>(
+    entries: impl IntoIterator<Item = &'a IpamEntry>,
+    sdn: &SdnConfig,
+    guests: &BTreeMap<Vmid, NetworkConfig>,
+) -> Vec<IpamIssue> {
+    let guest_macs: BTreeMap<Vmid, BTreeSet<MacAddress>> = guests
+        .iter()
+        .map(|(vmid, config)| {
+            let macs = config
+                .network_devices()
+                .values()
+                .map(|device| device.mac_address())
+                .collect();
+
+            (*vmid, macs)
+        })
+        .collect();
+
+    let mut issues: Vec<IpamIssue> = entries
+        .into_iter()
+        .filter_map(|entry| check_entry(entry, sdn, &guest_macs))
+        .collect();
+
+    let mut mac_users: BTreeMap<MacAddress, Vec<Vmid>> = BTreeMap::new();
+
+    for (vmid, macs) in &guest_macs {
+        for mac in macs {
+            mac_users.entry(*mac).or_default().push(*vmid);
+        }
+    }
+
+    issues.extend(
+        mac_users
+            .into_iter()
+            .filter(|(_, vmids)| vmids.len() > 1)
+            .map(|(mac, vmids)| IpamIssue::DuplicateMac { mac, vmids }),
+    );
+
+    issues
+}
+
+/// Checks the entries of the IPAM against the SDN config and the network devices of guests.
+///
+/// `guests` has to contain the network config of every guest in the cluster, otherwise the
+/// entries of missing guests are reported as stale. Issues about entries are returned in the
+/// order of the entries, followed by the MAC addresses that are used by more than one guest.
+pub fn check(
+    ipam: &Ipam,
+    sdn: &SdnConfig,
+    guests: &BTreeMap<Vmid, NetworkConfig>,
+) -> Vec<IpamIssue> {
+    check_entries(ipam.entries(), sdn, guests)
+}
+
+/// Checks the entries of an IPAM database, see [`check`].
+///
+/// Unlike [`Ipam::try_from`], entries with an address outside of their subnet are reported
+/// instead of failing the whole conversion.
+pub fn check_json(
+    ipam: &IpamJson,
+    sdn: &SdnConfig,
+    guests: &BTreeMap<Vmid, NetworkConfig>,
+) -> Vec<IpamIssue> {
+    let mut issues = Vec::new();
+    let mut entries = Vec::new();
+
+    for (subnet, data) in ipam.entries() {
+        let ip = *data.ip_address();
+
+        match IpamEntry::new(subnet.clone(), data) {
+            Ok(entry) => entries.push(entry),
+            // the address being outside of the subnet is the only possible error
+            Err(_) => issues.push(IpamIssue::OutOfSubnet { subnet, ip }),
+        }
+    }
+
+    issues.extend(check_entries(&entries, sdn, guests));
+    issues
+}
@@ -1,6 +1,9 @@
 pub mod config;
+pub mod diff;
+pub mod dnsmasq;
 pub mod fabric;
 pub mod ipam;
+pub mod ipam_check;
 pub mod prefix_list;
 pub mod route_map;
 pub mod wireguard;
@@ -58,6 +61,7 @@ fn validate_sdn_name(name: &str) -> Result<(), SdnNameError> {
 pub struct ZoneName(String);
 
 proxmox_serde::forward_deserialize_to_from_str!(ZoneName);
+proxmox_serde::forward_serialize_to_display!(ZoneName);
 
 impl ZoneName {
     /// construct a new zone name
@@ -97,6 +101,7 @@ impl Display for ZoneName {
 pub struct VnetName(String);
 
 proxmox_serde::forward_deserialize_to_from_str!(VnetName);
+proxmox_serde::forward_serialize_to_display!(VnetName);
 
 impl VnetName {
     /// construct a new vnet name
@@ -143,6 +148,7 @@ impl Display for VnetName {
 pub struct SubnetName(ZoneName, Cidr);
 
 proxmox_serde::forward_deserialize_to_from_str!(SubnetName);
+proxmox_serde::forward_serialize_to_display!(SubnetName);
 
 impl SubnetName {
     pub fn new(zone: ZoneName, cidr: Cidr) -> Self {
@@ -178,6 +184,15 @@ impl FromStr for SubnetName {
     }
 }
 
+impl Display for SubnetName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.1 {
+            Cidr::Ipv4(cidr) => write!(f, "{}-{}-{}", self.0, cidr.address(), cidr.mask()),
+            Cidr::Ipv6(cidr) => write!(f, "{}-{}-{}", self.0, cidr.address(), cidr.mask()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -248,6 +263,11 @@ mod tests {
             Err(SdnNameError::InvalidSubnetCidr),
         );
 
+        assert_eq!(
+            "zone0-fd80::-64".parse::<SubnetName>().unwrap().to_string(),
+            "zone0-fd80::-64"
+        );
+
         assert_eq!(
             "zone0-10.101.0.0-16".parse::<SubnetName>().unwrap(),
             SubnetName::new(
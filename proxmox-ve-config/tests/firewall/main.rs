@@ -0,0 +1,70 @@
+use proxmox_ve_config::firewall::bridge::Config as BridgeConfig;
+use proxmox_ve_config::firewall::cluster::Config as ClusterConfig;
+use proxmox_ve_config::firewall::guest::Config as GuestConfig;
+use proxmox_ve_config::firewall::host::Config as HostConfig;
+use proxmox_ve_config::firewall::nftables::RulesetBuilder;
+use proxmox_ve_config::guest::types::Vmid;
+
+fn cluster_config() -> ClusterConfig {
+    ClusterConfig::parse(include_str!("resources/cluster.fw").as_bytes()).unwrap()
+}
+
+fn host_config() -> HostConfig {
+    HostConfig::parse(include_str!("resources/host.fw").as_bytes()).unwrap()
+}
+
+fn guest_config() -> GuestConfig {
+    GuestConfig::parse(
+        &Vmid::new(100),
+        "tap",
+        include_str!("resources/100.fw").as_bytes(),
+        include_str!("resources/100.conf").as_bytes(),
+    )
+    .unwrap()
+}
+
+fn bridge_config() -> BridgeConfig {
+    BridgeConfig::parse(include_str!("resources/vmbr0.fw").as_bytes()).unwrap()
+}
+
+#[test]
+fn nftables_ruleset() {
+    let cluster = cluster_config();
+    let host = host_config();
+    let guest = guest_config();
+    let bridge = bridge_config();
+
+    let ruleset = RulesetBuilder::new(&cluster)
+        .host(&host)
+        .guest(&guest)
+        .bridge("vmbr0", &bridge)
+        .build()
+        .unwrap();
+
+    insta::assert_snapshot!(ruleset.to_string());
+}
+
+#[test]
+fn nftables_ruleset_host_only() {
+    let cluster = cluster_config();
+    let host = host_config();
+
+    let ruleset = RulesetBuilder::new(&cluster).host(&host).build().unwrap();
+
+    insta::assert_snapshot!(ruleset.to_string());
+}
+
+#[test]
+fn nftables_ruleset_disabled() {
+    let cluster = ClusterConfig::parse("[OPTIONS]\nenable: 0\n".as_bytes()).unwrap();
+    let host = host_config();
+    let guest = guest_config();
+
+    let ruleset = RulesetBuilder::new(&cluster)
+        .host(&host)
+        .guest(&guest)
+        .build()
+        .unwrap();
+
+    insta::assert_snapshot!(ruleset.to_string());
+}
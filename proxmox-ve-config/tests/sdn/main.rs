@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     str::FromStr,
 };
@@ -6,12 +7,20 @@ use std::{
 use proxmox_network_types::ip_address::{Cidr, IpRange};
 use proxmox_network_types::mac_address::MacAddress;
 
+use proxmox_ve_config::guest::vm::NetworkConfig;
 use proxmox_ve_config::sdn::{
     config::{
-        RunningConfig, SdnConfig, SdnConfigError, SubnetConfig, VnetConfig, ZoneConfig, ZoneType,
+        DhcpType, EvpnZoneProperties, PropertyValue, QinqZoneProperties, RunningConfig, SdnConfig,
+        SdnConfigError, SubnetConfig, VlanProtocol, VnetConfig, VxlanZoneProperties, ZoneConfig,
+        ZoneOptions, ZoneProperties, ZoneType,
     },
-    ipam::{Ipam, IpamDataVm, IpamEntry, IpamJson},
-    SubnetName, VnetName, ZoneName,
+    diff::{Change, SdnConfigDiff},
+    dnsmasq,
+    ipam::{
+        AllocationPool, Ipam, IpamDataCustom, IpamDataGateway, IpamDataVm, IpamEntry, IpamError,
+        IpamJson,
+    },
+    ipam_check, SubnetName, VnetName, ZoneName,
 };
 
 #[test]
@@ -21,10 +30,17 @@ fn parse_running_config() {
 
     let parsed_config = SdnConfig::try_from(running_config).unwrap();
 
-    let sdn_config = SdnConfig::from_zones([ZoneConfig::from_vnets(
+    let mut zone0 = ZoneConfig::with_properties(
         ZoneName::from_str("zone0").unwrap(),
-        ZoneType::Simple,
-        [
+        ZoneProperties::new(ZoneType::Simple),
+        ZoneOptions::new()
+            .with_dhcp(DhcpType::Dnsmasq)
+            .with_ipam("pve"),
+    )
+    .unwrap();
+
+    zone0
+        .add_vnets([
             VnetConfig::from_subnets_and_tag(
                 VnetName::from_str("vnet0").unwrap(),
                 Some(100),
@@ -64,12 +80,128 @@ fn parse_running_config() {
                 .unwrap()],
             )
             .unwrap(),
-        ],
+        ])
+        .unwrap();
+
+    let sdn_config = SdnConfig::from_zones([zone0]).unwrap().with_version(10);
+
+    assert_eq!(sdn_config, parsed_config);
+}
+
+#[test]
+fn parse_zone_properties() {
+    let running_config: RunningConfig =
+        serde_json::from_str(include_str!("resources/running-config-zones.json")).unwrap();
+
+    let config = SdnConfig::try_from(running_config).unwrap();
+    config.validate().unwrap();
+
+    let zone = |name: &str| config.zone(&ZoneName::from_str(name).unwrap()).unwrap();
+
+    let ZoneProperties::Vlan(vlan) = zone("vlan0").properties() else {
+        panic!("expected a vlan zone");
+    };
+    assert_eq!(vlan.bridge(), Some("vmbr0"));
+    assert_eq!(zone("vlan0").options().mtu(), Some(1500));
+
+    assert_eq!(
+        zone("qinq0").properties(),
+        &ZoneProperties::Qinq(QinqZoneProperties::new(
+            "vmbr1".to_string(),
+            20,
+            Some(VlanProtocol::Ieee802_1ad),
+        ))
+    );
+
+    let ZoneProperties::Vxlan(vxlan) = zone("vxlan0").properties() else {
+        panic!("expected a vxlan zone");
+    };
+    assert_eq!(
+        vxlan.peers(),
+        &[
+            IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::from(Ipv4Addr::new(192, 0, 2, 2)),
+        ]
+    );
+    assert_eq!(vxlan.vxlan_port(), Some(4790));
+
+    let ZoneProperties::Evpn(evpn) = zone("evpn0").properties() else {
+        panic!("expected an evpn zone");
+    };
+    assert_eq!(evpn.controller(), Some("evpnctl"));
+    assert_eq!(evpn.vrf_vxlan(), Some(10000));
+    assert_eq!(evpn.exitnodes(), ["pve1", "pve2"]);
+    assert_eq!(evpn.exitnodes_primary(), Some("pve1"));
+    assert!(evpn.advertise_subnets());
+    assert!(!evpn.exitnodes_local_routing());
+    assert_eq!(
+        evpn.mac(),
+        Some(MacAddress::new([0xBC, 0x24, 0x11, 0xAA, 0xBB, 0xCC]))
+    );
+    assert_eq!(zone("evpn0").options().nodes(), ["pve1", "pve2", "pve3"]);
+
+    let (_, vnet) = config.vnet(&VnetName::from_str("vnet10").unwrap()).unwrap();
+    assert_eq!(vnet.tag(), &Some(10000));
+    assert_eq!(vnet.alias(), Some("tenant a"));
+    assert!(vnet.vlan_aware());
+    assert!(!vnet.isolate_ports());
+
+    for (properties, err) in [
+        (
+            ZoneProperties::new(ZoneType::Vlan),
+            SdnConfigError::MissingZoneProperty("bridge"),
+        ),
+        (
+            ZoneProperties::Qinq(QinqZoneProperties::new("vmbr0".to_string(), 4095, None)),
+            SdnConfigError::InvalidZoneProperty("tag"),
+        ),
+        (
+            ZoneProperties::new(ZoneType::Vxlan),
+            SdnConfigError::MissingZoneProperty("peers"),
+        ),
+        (
+            ZoneProperties::new(ZoneType::Evpn),
+            SdnConfigError::MissingZoneProperty("controller"),
+        ),
+        (
+            ZoneProperties::Evpn(EvpnZoneProperties::new("evpnctl".to_string(), 1 << 24)),
+            SdnConfigError::InvalidZoneProperty("vrf-vxlan"),
+        ),
+    ] {
+        assert_eq!(
+            ZoneConfig::with_properties(
+                ZoneName::from_str("zone0").unwrap(),
+                properties,
+                ZoneOptions::new()
+            ),
+            Err(err)
+        );
+    }
+
+    let running_config: RunningConfig = serde_json::from_str(
+        r#"{
+            "zones": {
+                "ids": {
+                    "evpn0": {
+                        "type": "evpn",
+                        "controller": "evpnctl",
+                        "vrf-vxlan": 10000,
+                        "exitnodes": "pve1",
+                        "exitnodes-primary": "pve2"
+                    }
+                }
+            }
+        }"#,
     )
-    .unwrap()])
     .unwrap();
 
-    assert_eq!(sdn_config, parsed_config);
+    // invalid properties are only reported when validating the config
+    let config = SdnConfig::try_from(running_config).unwrap();
+
+    assert_eq!(
+        config.validate(),
+        Err(SdnConfigError::InvalidZoneProperty("exitnodes-primary"))
+    );
 }
 
 #[test]
@@ -82,7 +214,12 @@ fn sdn_config() {
     let vnet0_name = VnetName::new("vnet0".to_string()).unwrap();
     let vnet1_name = VnetName::new("vnet1".to_string()).unwrap();
 
-    let zone0 = ZoneConfig::new(zone0_name.clone(), ZoneType::Qinq);
+    let zone0 = ZoneConfig::with_properties(
+        zone0_name.clone(),
+        ZoneProperties::Qinq(QinqZoneProperties::new("vmbr0".to_string(), 20, None)),
+        ZoneOptions::new(),
+    )
+    .unwrap();
     sdn_config.add_zone(zone0).unwrap();
 
     let vnet0 = VnetConfig::new(vnet0_name.clone(), None);
@@ -110,10 +247,15 @@ fn sdn_config() {
         .add_subnet(&zone0_name, &vnet0_name, subnet)
         .unwrap();
 
-    let zone1 = ZoneConfig::from_vnets(
+    let mut zone1 = ZoneConfig::with_properties(
         zone1_name.clone(),
-        ZoneType::Evpn,
-        [VnetConfig::from_subnets(
+        ZoneProperties::Evpn(EvpnZoneProperties::new("evpnctl".to_string(), 10000)),
+        ZoneOptions::new(),
+    )
+    .unwrap();
+
+    zone1
+        .add_vnets([VnetConfig::from_subnets(
             vnet1_name.clone(),
             [SubnetConfig::new(
                 SubnetName::new(
@@ -126,16 +268,20 @@ fn sdn_config() {
             )
             .unwrap()],
         )
-        .unwrap()],
-    )
-    .unwrap();
+        .unwrap()])
+        .unwrap();
 
     assert_eq!(
         sdn_config.add_zones([zone1]),
         Err(SdnConfigError::MismatchedSubnetZone),
     );
 
-    let zone1 = ZoneConfig::new(zone1_name.clone(), ZoneType::Evpn);
+    let zone1 = ZoneConfig::with_properties(
+        zone1_name.clone(),
+        ZoneProperties::Evpn(EvpnZoneProperties::new("evpnctl".to_string(), 10000)),
+        ZoneOptions::new(),
+    )
+    .unwrap();
     sdn_config.add_zone(zone1).unwrap();
 
     assert_eq!(
@@ -186,3 +332,493 @@ fn parse_ipam() {
         ipam
     )
 }
+
+#[test]
+fn ipam_allocation() {
+    let zone_name = ZoneName::new("zone0".to_string()).unwrap();
+
+    let subnet = SubnetConfig::new(
+        SubnetName::new(zone_name.clone(), Cidr::new_v4([192, 0, 2, 0], 29).unwrap()),
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        false,
+        [IpRange::new_v4([192, 0, 2, 4], [192, 0, 2, 6]).unwrap()],
+    )
+    .unwrap();
+
+    let mac = |last: u8| MacAddress::new([0xBC, 0x24, 0x11, 0, 0, last]);
+    let ip = |last: u8| IpAddr::V4(Ipv4Addr::new(192, 0, 2, last));
+
+    let mut ipam = Ipam::new();
+
+    ipam.reserve(&subnet, IpamDataGateway::new(ip(1)).into())
+        .unwrap();
+    ipam.reserve(&subnet, IpamDataVm::new(ip(2), 100, mac(1), None).into())
+        .unwrap();
+
+    assert_eq!(
+        ipam.next_free_ip(&subnet, AllocationPool::Subnet),
+        Some(ip(3))
+    );
+    assert_eq!(
+        ipam.next_free_ip(&subnet, AllocationPool::DhcpRanges),
+        Some(ip(4))
+    );
+
+    for (address, err) in [
+        (ip(0), IpamError::ReservedIpAddress),
+        (ip(1), IpamError::ReservedIpAddress),
+        (ip(7), IpamError::ReservedIpAddress),
+        (ip(2), IpamError::DuplicateIpAddress),
+        (ip(8), IpamError::IpAddressOutOfBounds),
+    ] {
+        assert_eq!(
+            ipam.reserve(&subnet, IpamDataCustom::new(address, mac(9)).into()),
+            Err(err)
+        );
+    }
+
+    let allocate = |ipam: &mut Ipam, vmid: u32, mac: MacAddress| {
+        ipam.allocate(
+            &subnet,
+            AllocationPool::DhcpRanges,
+            vmid.into(),
+            mac,
+            Some(format!("guest{vmid}")),
+        )
+    };
+
+    assert_eq!(allocate(&mut ipam, 101, mac(2)), Ok(ip(4)));
+    // a MAC address that already has an entry keeps its address
+    assert_eq!(allocate(&mut ipam, 101, mac(2)), Ok(ip(4)));
+    assert_eq!(allocate(&mut ipam, 101, mac(3)), Ok(ip(5)));
+    assert_eq!(allocate(&mut ipam, 102, mac(4)), Ok(ip(6)));
+    assert_eq!(
+        allocate(&mut ipam, 103, mac(5)),
+        Err(IpamError::NoFreeIpAddress)
+    );
+
+    // the written database contains the same entries
+    let json = serde_json::to_string(&IpamJson::from(&ipam)).unwrap();
+    let reparsed = Ipam::try_from(serde_json::from_str::<IpamJson>(&json).unwrap()).unwrap();
+    assert_eq!(IpamJson::from(&reparsed), IpamJson::from(&ipam));
+    assert!(json
+        .contains(r#""192.0.2.4":{"vmid":"101","hostname":"guest101","mac":"BC:24:11:00:00:02"}"#));
+    assert!(json.contains(r#""192.0.2.1":{"gateway":1}"#));
+
+    let released = ipam.release_vmid(101.into());
+    assert_eq!(
+        released
+            .iter()
+            .map(|entry| *entry.ip_address())
+            .collect::<Vec<_>>(),
+        [ip(4), ip(5)]
+    );
+    assert_eq!(allocate(&mut ipam, 103, mac(5)), Ok(ip(4)));
+
+    assert_eq!(ipam.release_mac(&mac(1)).len(), 1);
+    assert_eq!(ipam.release_mac(&mac(1)).len(), 0);
+    assert_eq!(ipam.subnet_entries(subnet.name()).len(), 3);
+    assert_eq!(
+        ipam.next_free_ip(&subnet, AllocationPool::Subnet),
+        Some(ip(2))
+    );
+
+    let subnet = SubnetConfig::new(
+        SubnetName::new(
+            zone_name.clone(),
+            Cidr::new_v6([0xFD80, 0, 0, 0, 0, 0, 0, 0], 64).unwrap(),
+        ),
+        IpAddr::V6(Ipv6Addr::new(0xFD80, 0, 0, 0, 0, 0, 0, 1)),
+        false,
+        [],
+    )
+    .unwrap();
+
+    assert_eq!(
+        Ipam::new().next_free_ip(&subnet, AllocationPool::Subnet),
+        Some(Ipv6Addr::new(0xFD80, 0, 0, 0, 0, 0, 0, 2).into())
+    );
+    assert_eq!(
+        Ipam::new().next_free_ip(&subnet, AllocationPool::DhcpRanges),
+        None
+    );
+
+    // only the parts of the DHCP ranges inside of the subnet are searched
+    let subnet = SubnetConfig::new(
+        SubnetName::new(
+            zone_name.clone(),
+            Cidr::new_v6([0xFD80, 0, 0, 0, 0, 0, 0, 0], 64).unwrap(),
+        ),
+        IpAddr::V6(Ipv6Addr::new(0xFD80, 0, 0, 0, 0, 0, 0, 1)),
+        false,
+        [
+            IpRange::new_v4([192, 0, 2, 4], [192, 0, 2, 6]).unwrap(),
+            IpRange::new_v6(
+                [0xFD81, 0, 0, 0, 0, 0, 0, 0],
+                [0xFD81, 0, 0, 0, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF],
+            )
+            .unwrap(),
+        ],
+    )
+    .unwrap();
+
+    assert_eq!(
+        Ipam::new().next_free_ip(&subnet, AllocationPool::DhcpRanges),
+        None
+    );
+
+    let subnet = SubnetConfig::new(
+        subnet.name().clone(),
+        IpAddr::V6(Ipv6Addr::new(0xFD80, 0, 0, 0, 0, 0, 0, 1)),
+        false,
+        [IpRange::new_v6(
+            [
+                0xFD7F, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFF0,
+            ],
+            [0xFD80, 0, 0, 0, 0, 0, 0, 5],
+        )
+        .unwrap()],
+    )
+    .unwrap();
+
+    assert_eq!(
+        Ipam::new().next_free_ip(&subnet, AllocationPool::DhcpRanges),
+        Some(Ipv6Addr::new(0xFD80, 0, 0, 0, 0, 0, 0, 2).into())
+    );
+}
+
+#[test]
+fn ipam_keeps_empty_subnets() {
+    let ipam_json: IpamJson = serde_json::from_str(
+        r#"{
+            "zones": {
+                "zone0": {
+                    "subnets": {
+                        "10.101.0.0/16": {
+                            "ips": {
+                                "10.101.99.101": { "vmid": "1000", "mac": "BC:24:11:00:00:01" }
+                            }
+                        },
+                        "10.102.0.0/16": { "ips": {} }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let mut ipam = Ipam::try_from(ipam_json.clone()).unwrap();
+    assert_eq!(IpamJson::from(&ipam), ipam_json);
+
+    assert_eq!(ipam.release_vmid(1000.into()).len(), 1);
+
+    let json = serde_json::to_string(&IpamJson::from(&ipam)).unwrap();
+    assert_eq!(
+        json,
+        r#"{"zones":{"zone0":{"subnets":{"10.101.0.0/16":{"ips":{}},"10.102.0.0/16":{"ips":{}}}}}}"#
+    );
+}
+
+#[test]
+fn ipam_consistency() {
+    let running_config: RunningConfig =
+        serde_json::from_str(include_str!("resources/running-config.json")).unwrap();
+    let sdn_config = SdnConfig::try_from(running_config).unwrap();
+
+    let ipam_json: IpamJson = serde_json::from_str(
+        r#"{
+            "zones": {
+                "zone0": {
+                    "subnets": {
+                        "10.101.0.0/16": {
+                            "ips": {
+                                "10.101.1.1": { "gateway": 1 },
+                                "10.101.99.101": { "vmid": "1000", "mac": "BC:24:11:00:00:01" },
+                                "10.101.99.102": { "vmid": "1001", "mac": "BC:24:11:00:00:02" },
+                                "10.101.99.103": { "vmid": "1000", "mac": "BC:24:11:00:00:03" }
+                            }
+                        },
+                        "10.102.0.0/16": {
+                            "ips": {
+                                "10.102.0.1": { "gateway": 1 }
+                            }
+                        },
+                        "192.168.0.0/24": {
+                            "ips": {
+                                "192.168.0.10": { "mac": "BC:24:11:00:00:04" }
+                            }
+                        },
+                        "fd80::/64": {
+                            "ips": {
+                                "10.0.0.1": { "mac": "BC:24:11:00:00:05" }
+                            }
+                        }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let guests = BTreeMap::from([
+        (
+            1000.into(),
+            NetworkConfig::parse("net0: virtio=BC:24:11:00:00:01,bridge=vnet0".as_bytes()).unwrap(),
+        ),
+        (
+            1002.into(),
+            NetworkConfig::parse(
+                "net0: virtio=BC:24:11:00:00:06,bridge=vmbr0\n\
+                 net1: virtio=BC:24:11:00:00:01,bridge=vnet1"
+                    .as_bytes(),
+            )
+            .unwrap(),
+        ),
+    ]);
+
+    let issues: Vec<String> = ipam_check::check_json(&ipam_json, &sdn_config, &guests)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+    assert_eq!(
+        issues,
+        [
+            "10.0.0.1 in zone0-fd80::-64: address is outside of the subnet",
+            "10.101.99.102 in zone0-10.101.0.0-16: guest 1001 has no network device with MAC \
+             address BC:24:11:00:00:02",
+            "10.101.99.103 in zone0-10.101.0.0-16: guest 1000 has no network device with MAC \
+             address BC:24:11:00:00:03",
+            "10.102.0.1 in zone0-10.102.0.0-16: subnet has no gateway",
+            "192.168.0.10 in zone0-192.168.0.0-24: subnet does not exist",
+            "MAC address BC:24:11:00:00:01 is used by guests 1000, 1002",
+        ]
+    );
+
+    // the IPAM cannot contain addresses outside of their subnet
+    Ipam::try_from(ipam_json).unwrap_err();
+}
+
+#[test]
+fn dnsmasq_config() {
+    let running_config: RunningConfig =
+        serde_json::from_str(include_str!("resources/running-config-dhcp.json")).unwrap();
+    let sdn_config = SdnConfig::try_from(running_config).unwrap();
+
+    let ipam_json: IpamJson = serde_json::from_str(include_str!("resources/ipam-dhcp.db")).unwrap();
+    let ipam = Ipam::try_from(ipam_json).unwrap();
+
+    let configs = dnsmasq::generate(&sdn_config, &ipam);
+
+    // zone simple0 does not use dnsmasq
+    assert_eq!(configs.len(), 1);
+
+    let config = &configs[0];
+    assert_eq!(config.zone(), &ZoneName::from_str("dhcp0").unwrap());
+    assert_eq!(config.directory(), "/etc/dnsmasq.d/dhcp0");
+
+    // vnet2 has no subnet with a DHCP range
+    assert_eq!(
+        config.files().keys().collect::<Vec<_>>(),
+        [
+            "00-default.conf",
+            "10-vnet0.conf",
+            "10-vnet1.conf",
+            "ethers"
+        ]
+    );
+
+    for (name, content) in config.files() {
+        insta::assert_snapshot!(format!("dnsmasq_{}_{name}", config.zone()), content);
+    }
+
+    assert_eq!(
+        SubnetConfig::new(
+            SubnetName::from_str("dhcp0-10.101.0.0-16").unwrap(),
+            None,
+            false,
+            [],
+        )
+        .unwrap()
+        .with_dhcp_dns_server(IpAddr::from(Ipv6Addr::new(0xfd80, 0, 0, 0, 0, 0, 0, 0x53))),
+        Err(SdnConfigError::MismatchedCidrDnsServer)
+    );
+}
+
+#[test]
+fn write_running_config() {
+    for input in [
+        include_str!("resources/running-config.json"),
+        include_str!("resources/running-config-zones.json"),
+        include_str!("resources/running-config-dhcp.json"),
+        include_str!("resources/running-config-full.json"),
+    ] {
+        let running_config: RunningConfig = serde_json::from_str(input).unwrap();
+        let sdn_config = SdnConfig::try_from(running_config).unwrap();
+
+        let written = serde_json::to_string(&RunningConfig::from(&sdn_config)).unwrap();
+        let reparsed: RunningConfig = serde_json::from_str(&written).unwrap();
+
+        assert_eq!(SdnConfig::try_from(reparsed).unwrap(), sdn_config);
+    }
+
+    let running_config: RunningConfig =
+        serde_json::from_str(include_str!("resources/running-config-zones.json")).unwrap();
+    let sdn_config = SdnConfig::try_from(running_config).unwrap();
+
+    insta::assert_snapshot!(
+        "running_config_zones",
+        serde_json::to_string_pretty(&RunningConfig::from(&sdn_config)).unwrap()
+    );
+
+    let input: serde_json::Value =
+        serde_json::from_str(include_str!("resources/running-config-full.json")).unwrap();
+    let sdn_config =
+        SdnConfig::try_from(serde_json::from_value::<RunningConfig>(input.clone()).unwrap())
+            .unwrap();
+
+    assert_eq!(sdn_config.version(), Some(27));
+    assert_eq!(
+        sdn_config.controller("evpnctl").unwrap().property("asn"),
+        Some(&PropertyValue::Integer(65000))
+    );
+
+    // only the keys that are not known to the zone end up in its other properties
+    let zone = sdn_config
+        .zone(&ZoneName::from_str("evpn0").unwrap())
+        .unwrap();
+    assert_eq!(
+        zone.properties().other(),
+        &BTreeMap::from([(
+            "bridge-disable-mac-learning".to_string(),
+            PropertyValue::Integer(1)
+        )])
+    );
+    assert_eq!(zone.options().ipam(), Some("pve"));
+    assert_eq!(
+        sdn_config
+            .subnet(&SubnetName::from_str("evpn0-10.200.0.0-24").unwrap())
+            .unwrap()
+            .other()["dnszoneprefix"],
+        PropertyValue::String("tenant".to_string())
+    );
+
+    let written = serde_json::to_value(RunningConfig::from(&sdn_config)).unwrap();
+
+    // the keys that are not interpreted are written back unchanged
+    for key in ["version", "controllers", "zones", "vnets", "subnets"] {
+        assert_eq!(written[key], input[key], "{key}");
+    }
+
+    let reparsed =
+        SdnConfig::try_from(serde_json::from_value::<RunningConfig>(written).unwrap()).unwrap();
+    assert_eq!(reparsed, sdn_config);
+}
+
+#[test]
+fn write_built_config() {
+    let mut zone = ZoneConfig::with_properties(
+        ZoneName::from_str("vxlan0").unwrap(),
+        ZoneProperties::Vxlan(VxlanZoneProperties::new(
+            [
+                IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::from(Ipv4Addr::new(192, 0, 2, 2)),
+            ],
+            None,
+        )),
+        ZoneOptions::new().with_mtu(1450),
+    )
+    .unwrap();
+
+    zone.add_vnet(VnetConfig::new(
+        VnetName::from_str("vnet0").unwrap(),
+        Some(100),
+    ))
+    .unwrap();
+
+    let sdn_config = SdnConfig::from_zones([zone]).unwrap();
+
+    let written = serde_json::to_string(&RunningConfig::from(&sdn_config)).unwrap();
+    let reparsed: RunningConfig = serde_json::from_str(&written).unwrap();
+
+    assert_eq!(SdnConfig::try_from(reparsed).unwrap(), sdn_config);
+}
+
+#[test]
+fn diff_pending_config() {
+    let running_json: serde_json::Value =
+        serde_json::from_str(include_str!("resources/running-config.json")).unwrap();
+
+    let mut pending_json = running_json.clone();
+
+    // remove vnet1 and its subnet, change the gateway of a subnet and add a zone with a vnet
+    pending_json["vnets"]["ids"]
+        .as_object_mut()
+        .unwrap()
+        .remove("vnet1");
+    pending_json["subnets"]["ids"]
+        .as_object_mut()
+        .unwrap()
+        .remove("zone0-10.102.0.0-16");
+    pending_json["subnets"]["ids"]["zone0-fd80::-64"]["gateway"] = "fd80::fffe".into();
+    pending_json["zones"]["ids"]["zone1"] =
+        serde_json::json!({ "type": "vlan", "bridge": "vmbr0" });
+    pending_json["vnets"]["ids"]["vnet2"] = serde_json::json!({ "zone": "zone1", "tag": 200 });
+
+    let running =
+        SdnConfig::try_from(serde_json::from_value::<RunningConfig>(running_json).unwrap())
+            .unwrap();
+    let pending =
+        SdnConfig::try_from(serde_json::from_value::<RunningConfig>(pending_json).unwrap())
+            .unwrap();
+
+    assert!(SdnConfigDiff::new(&running, &running).is_empty());
+
+    let diff = SdnConfigDiff::new(&running, &pending);
+
+    let zone1 = ZoneName::from_str("zone1").unwrap();
+    assert_eq!(
+        diff.zones(),
+        [(&zone1, Change::Added(pending.zone(&zone1).unwrap()))]
+    );
+
+    let vnet1 = VnetName::from_str("vnet1").unwrap();
+    let vnet2 = VnetName::from_str("vnet2").unwrap();
+    assert_eq!(
+        diff.vnets(),
+        [
+            (&vnet1, Change::Removed(running.vnet(&vnet1).unwrap().1)),
+            (&vnet2, Change::Added(pending.vnet(&vnet2).unwrap().1)),
+        ]
+    );
+
+    let changed_subnet = SubnetName::from_str("zone0-fd80::-64").unwrap();
+    let removed_subnet = SubnetName::from_str("zone0-10.102.0.0-16").unwrap();
+    assert_eq!(
+        diff.subnets(),
+        [
+            (
+                &removed_subnet,
+                Change::Removed(running.subnet(&removed_subnet).unwrap())
+            ),
+            (
+                &changed_subnet,
+                Change::Changed {
+                    running: running.subnet(&changed_subnet).unwrap(),
+                    pending: pending.subnet(&changed_subnet).unwrap(),
+                }
+            ),
+        ]
+    );
+
+    assert_eq!(
+        diff.to_string(),
+        "\
++ zone zone1 (vlan)
+- vnet vnet1
++ vnet vnet2
+- subnet zone0-10.102.0.0-16
+~ subnet zone0-fd80::-64
+"
+    );
+}